@@ -1,11 +1,11 @@
+use crate::metrics::{self, MlMetric};
+use crate::iaf;
+use crate::muse_types::{BandRange, PipelineSettings};
 use anyhow::{Context, Result};
 use brainflow::board_shim::{get_eeg_channels, BoardShim};
 use brainflow::brainflow_input_params::BrainFlowInputParamsBuilder;
-use brainflow::brainflow_model_params::BrainFlowModelParamsBuilder;
 use brainflow::data_filter::{self, Band};
-use brainflow::{
-    BoardIds, BrainFlowClassifiers, BrainFlowMetrics, BrainFlowPresets, WindowOperations,
-};
+use brainflow::{BoardIds, BrainFlowPresets, WindowOperations};
 use flutter_rust_bridge::frb;
 use log::info;
 use std::sync::Mutex;
@@ -140,8 +140,8 @@ pub async fn get_latest_data(num_samples: i32) -> Result<EegData> {
 
     let mut result_data = Vec::new();
     for &channel_idx in &eeg_channels {
-        if (channel_idx as usize) < data.nrows() {
-            let channel_data = data.row(channel_idx as usize).to_vec();
+        if channel_idx < data.nrows() {
+            let channel_data = data.row(channel_idx).to_vec();
             result_data.push(channel_data);
         }
     }
@@ -152,6 +152,15 @@ pub async fn get_latest_data(num_samples: i32) -> Result<EegData> {
     })
 }
 
+// Runs `f` on the connected BoardShim
+pub(crate) fn with_board<T>(f: impl FnOnce(&BoardShim) -> Result<T>) -> Result<T> {
+    let board_guard = BOARD
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Failed to lock BOARD mutex"))?;
+    let board = board_guard.as_ref().context("Board not initialized")?;
+    f(board)
+}
+
 pub fn verify_brainflow_version() -> Result<String> {
     brainflow::board_shim::get_version().map_err(|e| anyhow::anyhow!("BrainFlow error: {:?}", e))
 }
@@ -164,7 +173,9 @@ pub fn test_output() -> String {
     "Test output from Rust".to_string()
 }
 
+// sampling_rate is unused but named in the Dart API
 #[frb]
+#[allow(unused_variables)]
 pub fn calculate_signal_quality(data: Vec<f64>, sampling_rate: usize) -> f64 {
     let data_len = data.len();
     if data_len < 32 {
@@ -203,47 +214,16 @@ fn calculate_std(data: &[f64]) -> f64 {
 
 #[frb]
 pub fn predict_mindfulness_from_band_powers(band_powers: BandPowers) -> Option<f64> {
-    info!("[API] predict_mindfulness_from_band_powers called");
-    
-    // Create feature vector from band powers
-    // Format: [delta, theta, alpha, beta, gamma] for each channel
-    // Since we have 1 aggregated value per band, we duplicate for all 4 EEG channels
-    let mut feature_vector = vec![
-        band_powers.delta, band_powers.theta, band_powers.alpha, band_powers.beta, band_powers.gamma,
-        band_powers.delta, band_powers.theta, band_powers.alpha, band_powers.beta, band_powers.gamma,
-        band_powers.delta, band_powers.theta, band_powers.alpha, band_powers.beta, band_powers.gamma,
-        band_powers.delta, band_powers.theta, band_powers.alpha, band_powers.beta, band_powers.gamma,
-    ];
-    
-    info!("[API] Feature vector created: {:?}", feature_vector);
-
-    let params = BrainFlowModelParamsBuilder::new()
-        .metric(BrainFlowMetrics::Mindfulness)
-        .classifier(BrainFlowClassifiers::DefaultClassifier)
-        .build();
-
-    match brainflow::ml_model::MlModel::new(params) {
-        Ok(mut model) => {
-            info!("[API] MlModel created, preparing...");
-            if model.prepare().is_ok() {
-                info!("[API] Model prepared, predicting...");
-                if let Ok(result) = model.predict(&mut feature_vector) {
-                    info!("[API] Prediction result: {:?}", result);
-                    if let Some(&score) = result.first() {
-                        return Some(score.clamp(0.0, 100.0));
-                    }
-                } else {
-                    info!("[API] Prediction failed");
-                }
-            } else {
-                info!("[API] Model prepare failed");
-            }
-        }
+    // The default classifier expects 5 average + 5 SD relative band powers;
+    // a single aggregated set has no spread over channels
+    let mut feature_vector = metrics::single_channel_features(&band_powers)?;
+    match metrics::predict(MlMetric::Mindfulness, &mut feature_vector) {
+        Ok(score) => Some(score.clamp(0.0, 1.0)),
         Err(e) => {
-            info!("[API] MlModel creation failed: {:?}", e);
+            info!("[API] Mindfulness prediction failed: {:?}", e);
+            None
         }
     }
-    None
 }
 
 // Band-power-ratio-based concentration metric (no ML model needed)
@@ -301,6 +281,17 @@ pub struct BandPowers {
 
 #[frb]
 pub fn calculate_band_powers(eeg_data: Vec<f64>, sampling_rate: usize) -> Option<BandPowers> {
+    let bands = iaf::anchor_bands(&PipelineSettings::default().bands);
+    calculate_custom_band_powers(eeg_data, sampling_rate, bands)
+}
+
+// Bands are taken in delta, theta, alpha, beta, gamma order
+#[frb]
+pub fn calculate_custom_band_powers(
+    eeg_data: Vec<f64>,
+    sampling_rate: usize,
+    bands: Vec<BandRange>,
+) -> Option<BandPowers> {
     info!("[API] calculate_band_powers called with {} samples, sampling_rate {}", eeg_data.len(), sampling_rate);
     
     if eeg_data.len() < 256 {
@@ -315,32 +306,13 @@ pub fn calculate_band_powers(eeg_data: Vec<f64>, sampling_rate: usize) -> Option
     match data_filter::get_psd(&mut data, sampling_rate, window) {
         Ok(psd) => {
             info!("[API] PSD calculated successfully");
-            let bands = vec![
-                Band {
-                    freq_start: 1.0,
-                    freq_stop: 4.0,
-                },
-                Band {
-                    freq_start: 4.0,
-                    freq_stop: 8.0,
-                },
-                Band {
-                    freq_start: 8.0,
-                    freq_stop: 13.0,
-                },
-                Band {
-                    freq_start: 13.0,
-                    freq_stop: 30.0,
-                },
-                Band {
-                    freq_start: 30.0,
-                    freq_stop: 45.0,
-                },
-            ];
-
             let mut powers = Vec::new();
             let mut psd = psd;
             for band in bands {
+                let band = Band {
+                    freq_start: band.freq_start,
+                    freq_stop: band.freq_stop,
+                };
                 if let Ok(power) = data_filter::get_band_power(&mut psd, band) {
                     powers.push(power);
                 } else {
@@ -349,7 +321,7 @@ pub fn calculate_band_powers(eeg_data: Vec<f64>, sampling_rate: usize) -> Option
             }
 
             Some(BandPowers {
-                delta: powers.get(0).copied().unwrap_or(0.0),
+                delta: powers.first().copied().unwrap_or(0.0),
                 theta: powers.get(1).copied().unwrap_or(0.0),
                 alpha: powers.get(2).copied().unwrap_or(0.0),
                 beta: powers.get(3).copied().unwrap_or(0.0),
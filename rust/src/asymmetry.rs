@@ -0,0 +1,431 @@
+use crate::channel_buffers::ChannelBuffers;
+use crate::iaf;
+use crate::muse_types::BandRange;
+use crate::spectral;
+use anyhow::{bail, Result};
+use flutter_rust_bridge::frb;
+use std::collections::VecDeque;
+
+// Alpha asymmetry for neurofeedback.
+//
+// Every step, the last window of each hemisphere pair (AF7 / AF8 frontal,
+// TP9 / TP10 temporal) goes through a Welch PSD and the asymmetry index is
+// ln(alpha right) - ln(alpha left): positive means more right alpha, i.e.
+// relatively more left activation. The alpha band moves to IAF ± 2 Hz once
+// an individual alpha frequency is set. Windows with head motion or a channel
+// beyond the peak-to-peak limit are rejected and leave the index untouched.
+// Accepted indices are averaged over the last smoothing_windows, and once a
+// baseline is recorded they are also reported as a difference and z-score
+// against it. The feedback value maps that onto 0-1 for display or audio.
+
+const MAX_QUEUED: usize = 256;
+// Baseline SD floor, keeps the z-score finite on a flat baseline
+const MIN_BASELINE_SD: f64 = 1e-3;
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsymmetryPair {
+    // AF7 left, AF8 right
+    Frontal,
+    // TP9 left, TP10 right
+    Temporal,
+}
+
+impl AsymmetryPair {
+    fn channel_names(&self) -> (&'static str, &'static str) {
+        match self {
+            AsymmetryPair::Frontal => ("AF7", "AF8"),
+            AsymmetryPair::Temporal => ("TP9", "TP10"),
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsymmetrySettings {
+    // Alpha band while no individual alpha frequency is set
+    pub alpha_low: f64,
+    pub alpha_high: f64,
+    pub window_seconds: f64,
+    pub step_seconds: f64,
+    // Accepted windows averaged into the smoothed index
+    pub smoothing_windows: u32,
+    pub reject_peak_to_peak_uv: f64,
+    // Reject windows the motion gate excludes
+    pub motion_gating: bool,
+    // Pair driving the feedback value
+    pub feedback_pair: AsymmetryPair,
+}
+
+impl Default for AsymmetrySettings {
+    fn default() -> Self {
+        Self {
+            alpha_low: 8.0,
+            alpha_high: 13.0,
+            window_seconds: 2.0,
+            step_seconds: 0.5,
+            smoothing_windows: 4,
+            reject_peak_to_peak_uv: 150.0,
+            motion_gating: true,
+            feedback_pair: AsymmetryPair::Frontal,
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsymmetryArtifact {
+    Motion,
+    Amplitude,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsymmetryValue {
+    pub pair: AsymmetryPair,
+    // Index of the latest accepted window
+    pub raw: f64,
+    pub smoothed: f64,
+    // Smoothed minus the baseline mean, once a baseline is set
+    pub relative: Option<f64>,
+    pub z_score: Option<f64>,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsymmetryUpdate {
+    pub timestamp: f64,
+    // Last accepted values; unchanged on a rejected window
+    pub values: Vec<AsymmetryValue>,
+    pub artifact: Option<AsymmetryArtifact>,
+    // 0-1, 0.5 at the baseline mean (or a zero index without a baseline)
+    pub feedback: Option<f64>,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsymmetryPairBaseline {
+    pub pair: AsymmetryPair,
+    pub windows: u32,
+    pub mean: f64,
+    pub sd: f64,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsymmetryBaseline {
+    pub pairs: Vec<AsymmetryPairBaseline>,
+}
+
+// ln(alpha right) - ln(alpha left); None without alpha power on either side
+pub(crate) fn asymmetry_index(
+    left: &[f64],
+    right: &[f64],
+    fs: f64,
+    low: f64,
+    high: f64,
+) -> Option<f64> {
+    let samples = left.len().min(right.len()).min(fs as usize);
+    if samples < 2 {
+        return None;
+    }
+    let nfft = spectral::nfft_for(samples);
+    let left = spectral::welch(left, fs, nfft)?.band_power(low, high);
+    let right = spectral::welch(right, fs, nfft)?.band_power(low, high);
+    if left <= 0.0 || right <= 0.0 {
+        return None;
+    }
+    Some(right.ln() - left.ln())
+}
+
+fn peak_to_peak(data: &[f64]) -> f64 {
+    let max = data.iter().copied().fold(f64::MIN, f64::max);
+    let min = data.iter().copied().fold(f64::MAX, f64::min);
+    max - min
+}
+
+fn logistic(x: f64) -> f64 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+struct PairState {
+    pair: AsymmetryPair,
+    // Indices of the left and right channel in the tracker buffers
+    left: usize,
+    right: usize,
+    recent: VecDeque<f64>,
+    latest: Option<AsymmetryValue>,
+    baseline: Option<AsymmetryPairBaseline>,
+    calibration: Vec<f64>,
+}
+
+pub(crate) struct AsymmetryTracker {
+    pub settings: AsymmetrySettings,
+    sampling_rate: f64,
+    buffers: ChannelBuffers,
+    pairs: Vec<PairState>,
+    calibrating: bool,
+    latest: Option<AsymmetryUpdate>,
+    queued: VecDeque<AsymmetryUpdate>,
+}
+
+impl AsymmetryTracker {
+    // Pairs with a missing channel are left out
+    pub fn new(settings: AsymmetrySettings, sampling_rate: f64, channel_names: &[String]) -> Self {
+        let mut channels = Vec::new();
+        let mut index = |name: &str| {
+            let channel = channel_names.iter().position(|n| n == name)?;
+            Some(match channels.iter().position(|c| *c == channel) {
+                Some(i) => i,
+                None => {
+                    channels.push(channel);
+                    channels.len() - 1
+                }
+            })
+        };
+        let mut pairs = Vec::new();
+        for pair in [AsymmetryPair::Frontal, AsymmetryPair::Temporal] {
+            let (left, right) = pair.channel_names();
+            if let (Some(left), Some(right)) = (index(left), index(right)) {
+                pairs.push(PairState {
+                    pair,
+                    left,
+                    right,
+                    recent: VecDeque::new(),
+                    latest: None,
+                    baseline: None,
+                    calibration: Vec::new(),
+                });
+            }
+        }
+        let buffers = ChannelBuffers::for_window(channels, settings.window_seconds, sampling_rate);
+        Self {
+            settings,
+            sampling_rate,
+            buffers,
+            pairs,
+            calibrating: false,
+            latest: None,
+            queued: VecDeque::new(),
+        }
+    }
+
+    fn samples(&self, seconds: f64) -> usize {
+        (seconds * self.sampling_rate) as usize
+    }
+
+    // True when a window is due; the caller then runs the motion gate over
+    // it and calls evaluate
+    pub fn push(&mut self, channel: usize, samples: &[f64]) -> bool {
+        if self.buffers.push(channel, samples).is_none() {
+            return false;
+        }
+        let window = self.samples(self.settings.window_seconds);
+        let step = self.samples(self.settings.step_seconds);
+        self.buffers.step_due(window, step)
+    }
+
+    pub fn evaluate(&mut self, timestamp: f64, motion_excluded: bool) {
+        let n = self.samples(self.settings.window_seconds);
+        let Some(windows) = self.buffers.latest_windows(n) else {
+            return;
+        };
+        let artifact = if self.settings.motion_gating && motion_excluded {
+            Some(AsymmetryArtifact::Motion)
+        } else if windows
+            .iter()
+            .any(|w| peak_to_peak(w) > self.settings.reject_peak_to_peak_uv)
+        {
+            Some(AsymmetryArtifact::Amplitude)
+        } else {
+            None
+        };
+
+        if artifact.is_none() {
+            let smoothing = self.settings.smoothing_windows.max(1) as usize;
+            let alpha = iaf::anchor_bands(&[BandRange::new(
+                "alpha",
+                self.settings.alpha_low,
+                self.settings.alpha_high,
+            )])
+            .remove(0);
+            for state in &mut self.pairs {
+                let Some(raw) = asymmetry_index(
+                    windows[state.left],
+                    windows[state.right],
+                    self.sampling_rate,
+                    alpha.freq_start,
+                    alpha.freq_stop,
+                ) else {
+                    continue;
+                };
+                if self.calibrating {
+                    state.calibration.push(raw);
+                }
+                if state.recent.len() == smoothing {
+                    state.recent.pop_front();
+                }
+                state.recent.push_back(raw);
+                state.latest = Some(value(state, raw));
+            }
+        }
+
+        let feedback = self
+            .pairs
+            .iter()
+            .find(|s| s.pair == self.settings.feedback_pair)
+            .and_then(|s| s.latest.as_ref())
+            .map(|v| logistic(v.z_score.unwrap_or(v.smoothed)));
+        let update = AsymmetryUpdate {
+            timestamp,
+            values: self.pairs.iter().filter_map(|s| s.latest.clone()).collect(),
+            artifact,
+            feedback,
+        };
+        if self.queued.len() == MAX_QUEUED {
+            self.queued.pop_front();
+        }
+        self.queued.push_back(update.clone());
+        self.latest = Some(update);
+    }
+
+    // Accepted windows from now on make up the baseline
+    pub fn start_baseline(&mut self) {
+        self.calibrating = true;
+        for state in &mut self.pairs {
+            state.calibration.clear();
+        }
+    }
+
+    pub fn finish_baseline(&mut self) -> Result<AsymmetryBaseline> {
+        if !self.calibrating {
+            bail!("No asymmetry baseline recording in progress");
+        }
+        self.calibrating = false;
+        let pairs: Vec<AsymmetryPairBaseline> = self
+            .pairs
+            .iter_mut()
+            .filter(|s| s.calibration.len() >= 2)
+            .map(|s| {
+                let values = std::mem::take(&mut s.calibration);
+                let mean = values.iter().sum::<f64>() / values.len() as f64;
+                let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>()
+                    / (values.len() - 1) as f64;
+                AsymmetryPairBaseline {
+                    pair: s.pair,
+                    windows: values.len() as u32,
+                    mean,
+                    sd: variance.sqrt(),
+                }
+            })
+            .collect();
+        if pairs.is_empty() {
+            bail!("Too few artifact-free windows for an asymmetry baseline");
+        }
+        let baseline = AsymmetryBaseline { pairs };
+        self.set_baseline(&baseline);
+        Ok(baseline)
+    }
+
+    pub fn set_baseline(&mut self, baseline: &AsymmetryBaseline) {
+        for state in &mut self.pairs {
+            state.baseline = baseline
+                .pairs
+                .iter()
+                .find(|b| b.pair == state.pair)
+                .cloned();
+            if let Some(raw) = state.latest.as_ref().map(|v| v.raw) {
+                state.latest = Some(value(state, raw));
+            }
+        }
+    }
+
+    pub fn latest(&self) -> Option<&AsymmetryUpdate> {
+        self.latest.as_ref()
+    }
+
+    pub fn take_queued(&mut self) -> Vec<AsymmetryUpdate> {
+        self.queued.drain(..).collect()
+    }
+}
+
+fn value(state: &PairState, raw: f64) -> AsymmetryValue {
+    let smoothed = state.recent.iter().sum::<f64>() / state.recent.len().max(1) as f64;
+    let relative = state.baseline.as_ref().map(|b| smoothed - b.mean);
+    let z_score = state
+        .baseline
+        .as_ref()
+        .map(|b| (smoothed - b.mean) / b.sd.max(MIN_BASELINE_SD));
+    AsymmetryValue {
+        pair: state.pair,
+        raw,
+        smoothed,
+        relative,
+        z_score,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn index_follows_alpha_balance_and_rejects_artifacts() {
+        let fs = 256.0;
+        let names: Vec<String> = ["TP9", "AF7", "AF8", "TP10"]
+            .iter()
+            .map(|n| n.to_string())
+            .collect();
+        let mut noise = test_util::noise(5);
+        // Right channels carry twice the alpha amplitude: index ln(4)
+        let amplitudes = [10.0, 10.0, 20.0, 20.0];
+        let signal = |c: usize, t: usize, noise: f64| {
+            amplitudes[c] * (2.0 * PI * 10.0 * t as f64 / fs).sin() + noise
+        };
+
+        let mut tracker = AsymmetryTracker::new(AsymmetrySettings::default(), fs, &names);
+        tracker.start_baseline();
+        let mut t = 0;
+        for packet in 0..(8 * 256 / 12) {
+            for c in 0..4 {
+                let samples: Vec<f64> = (t..t + 12).map(|i| signal(c, i, noise())).collect();
+                if tracker.push(c, &samples) {
+                    tracker.evaluate(packet as f64, false);
+                }
+            }
+            t += 12;
+        }
+        let update = tracker.latest().unwrap().clone();
+        assert_eq!(update.artifact, None);
+        assert_eq!(update.values.len(), 2);
+        for v in &update.values {
+            assert!((v.smoothed - 4f64.ln()).abs() < 0.1, "{:?}", v);
+        }
+        let baseline = tracker.finish_baseline().unwrap();
+        assert_eq!(baseline.pairs.len(), 2);
+        assert!(tracker.finish_baseline().is_err());
+
+        // A blink-sized step on AF7 is rejected and keeps the last values
+        tracker.take_queued();
+        for c in 0..4 {
+            let samples: Vec<f64> = (t..t + 128)
+                .map(|i| signal(c, i, if c == 1 && i >= t + 64 { 300.0 } else { 0.0 }))
+                .collect();
+            if tracker.push(c, &samples) {
+                tracker.evaluate(100.0, false);
+            }
+        }
+        let rejected = tracker.take_queued();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].artifact, Some(AsymmetryArtifact::Amplitude));
+        assert!(rejected[0].values[0].relative.unwrap().abs() < 0.1);
+
+        tracker.evaluate(101.0, true);
+        assert_eq!(
+            tracker.latest().unwrap().artifact,
+            Some(AsymmetryArtifact::Motion)
+        );
+    }
+}
@@ -0,0 +1,574 @@
+use crate::api::BandPowers;
+use crate::iaf;
+use crate::recording;
+use anyhow::{bail, Context, Result};
+use flutter_rust_bridge::frb;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Personal baseline for the concentration and relaxation metrics.
+//
+// A calibration records the beta / (alpha + theta) and alpha / beta ratios of
+// one EEG window per band power window step (1 s by default) during an
+// eyes-open and an eyes-closed phase. Their pooled distribution (quantiles, plus mean / SD of the log ratio) is the
+// user's baseline: live ratios are then scored as percentiles or z-scores
+// against it instead of the fixed ratio * 33.33 scale, exponentially
+// smoothed and only moved when the change exceeds the hysteresis band.
+// Baselines are saved as JSON per user and device and reloaded from there.
+
+static BASELINE: Mutex<Option<BaselineState>> = Mutex::new(None);
+
+// Quantiles stored per ratio, every 5th percentile
+const QUANTILE_COUNT: usize = 21;
+// Windows (one per band power window step) needed in each calibration phase
+const MIN_PHASE_WINDOWS: usize = 10;
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaselinePhase {
+    EyesOpen,
+    EyesClosed,
+}
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MetricScale {
+    // 0-100 against the baseline distribution
+    Percentile,
+    // SD of the log ratio from the baseline mean
+    ZScore,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RatioDistribution {
+    pub windows: u32,
+    // Ratio at 0, 5, ..., 100 %
+    pub quantiles: Vec<f64>,
+    pub log_mean: f64,
+    pub log_sd: f64,
+    pub eyes_open_median: f64,
+    pub eyes_closed_median: f64,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PersonalBaseline {
+    pub user_id: String,
+    pub device_id: String,
+    pub created_at: f64,
+    pub concentration: RatioDistribution,
+    pub relaxation: RatioDistribution,
+    // IAF the bands were anchored to while calibrating, None for the fixed
+    // bands; the baseline only scores windows of the same bands
+    #[serde(default)]
+    pub individual_alpha: Option<f64>,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BaselineSettings {
+    pub scale: MetricScale,
+    // EMA factor per window step, 1 = no smoothing
+    pub smoothing: f64,
+    // Smallest change of the smoothed score that moves the output, in
+    // percentile points and in SD respectively
+    pub percentile_hysteresis: f64,
+    pub z_hysteresis: f64,
+}
+
+impl BaselineSettings {
+    fn hysteresis(&self) -> f64 {
+        match self.scale {
+            MetricScale::Percentile => self.percentile_hysteresis,
+            MetricScale::ZScore => self.z_hysteresis,
+        }
+    }
+}
+
+impl Default for BaselineSettings {
+    fn default() -> Self {
+        Self {
+            scale: MetricScale::Percentile,
+            smoothing: 0.2,
+            percentile_hysteresis: 3.0,
+            z_hysteresis: 0.1,
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BaselineMetrics {
+    // Smoothed, hysteresis-held scores in the configured scale
+    pub concentration: f64,
+    pub relaxation: f64,
+    // Unsmoothed scores of the latest window
+    pub concentration_percentile: f64,
+    pub concentration_z: f64,
+    pub relaxation_percentile: f64,
+    pub relaxation_z: f64,
+    pub scale: MetricScale,
+}
+
+// beta / (alpha + theta)
+pub(crate) fn concentration_ratio(bands: &BandPowers) -> Option<f64> {
+    let denominator = bands.alpha + bands.theta;
+    (denominator > 0.0).then(|| bands.beta / denominator)
+}
+
+// alpha / beta
+pub(crate) fn relaxation_ratio(bands: &BandPowers) -> Option<f64> {
+    (bands.beta > 0.0).then(|| bands.alpha / bands.beta)
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    sorted[sorted.len() / 2]
+}
+
+impl RatioDistribution {
+    pub fn new(eyes_open: &[f64], eyes_closed: &[f64]) -> Option<Self> {
+        if eyes_open.len() < MIN_PHASE_WINDOWS || eyes_closed.len() < MIN_PHASE_WINDOWS {
+            return None;
+        }
+        let mut all: Vec<f64> = eyes_open.iter().chain(eyes_closed).copied().collect();
+        all.sort_by(f64::total_cmp);
+        let quantiles = (0..QUANTILE_COUNT)
+            .map(|i| {
+                let pos = i as f64 / (QUANTILE_COUNT - 1) as f64 * (all.len() - 1) as f64;
+                let (low, high) = (pos.floor() as usize, pos.ceil() as usize);
+                all[low] + (all[high] - all[low]) * (pos - low as f64)
+            })
+            .collect();
+        let logs: Vec<f64> = all.iter().map(|r| r.max(f64::MIN_POSITIVE).ln()).collect();
+        let n = logs.len() as f64;
+        let log_mean = logs.iter().sum::<f64>() / n;
+        let log_sd = (logs.iter().map(|l| (l - log_mean).powi(2)).sum::<f64>() / n).sqrt();
+        Some(Self {
+            windows: all.len() as u32,
+            quantiles,
+            log_mean,
+            log_sd,
+            eyes_open_median: median(eyes_open),
+            eyes_closed_median: median(eyes_closed),
+        })
+    }
+
+    // Interpolated between the stored quantiles, 0-100
+    pub fn percentile(&self, ratio: f64) -> f64 {
+        let q = &self.quantiles;
+        let step = 100.0 / (q.len() - 1) as f64;
+        if ratio <= q[0] {
+            return 0.0;
+        }
+        for (i, pair) in q.windows(2).enumerate() {
+            if ratio <= pair[1] {
+                let span = pair[1] - pair[0];
+                let within = if span > 0.0 {
+                    (ratio - pair[0]) / span
+                } else {
+                    0.5
+                };
+                return (i as f64 + within) * step;
+            }
+        }
+        100.0
+    }
+
+    pub fn z_score(&self, ratio: f64) -> f64 {
+        (ratio.max(f64::MIN_POSITIVE).ln() - self.log_mean) / self.log_sd.max(1e-9)
+    }
+}
+
+// Weighted EMA whose output only follows moves beyond the hysteresis band
+#[derive(Default)]
+struct HeldScore {
+    smoothed: Option<f64>,
+    shown: Option<f64>,
+}
+
+impl HeldScore {
+    fn update(&mut self, score: f64, settings: &BaselineSettings) -> f64 {
+        let alpha = settings.smoothing.clamp(0.0, 1.0);
+        let smoothed = match self.smoothed {
+            Some(prev) => prev + alpha * (score - prev),
+            None => score,
+        };
+        self.smoothed = Some(smoothed);
+        let shown = match self.shown {
+            Some(shown) if (smoothed - shown).abs() < settings.hysteresis() => shown,
+            _ => smoothed,
+        };
+        self.shown = Some(shown);
+        shown
+    }
+}
+
+#[derive(Default)]
+struct Calibration {
+    phase: Option<BaselinePhase>,
+    // IAF when the calibration started
+    individual_alpha: Option<f64>,
+    // (concentration, relaxation) ratios per phase
+    eyes_open: (Vec<f64>, Vec<f64>),
+    eyes_closed: (Vec<f64>, Vec<f64>),
+}
+
+#[derive(Default)]
+struct BaselineState {
+    settings: BaselineSettings,
+    calibration: Option<Calibration>,
+    baseline: Option<PersonalBaseline>,
+    concentration: HeldScore,
+    relaxation: HeldScore,
+    latest: Option<BaselineMetrics>,
+    // Timestamp of the last window taken in
+    last_window: Option<f64>,
+}
+
+impl BaselineState {
+    // Records the window while calibrating and scores it against the
+    // active baseline; `individual_alpha` is the IAF the bands are anchored to
+    fn process(
+        &mut self,
+        bands: &BandPowers,
+        individual_alpha: Option<f64>,
+    ) -> Option<BaselineMetrics> {
+        let concentration = concentration_ratio(bands)?;
+        let relaxation = relaxation_ratio(bands)?;
+        if let Some(calibration) = self.calibration.as_mut() {
+            let phase = match calibration.phase {
+                Some(BaselinePhase::EyesOpen) => Some(&mut calibration.eyes_open),
+                Some(BaselinePhase::EyesClosed) => Some(&mut calibration.eyes_closed),
+                None => None,
+            };
+            if let Some((c, r)) = phase {
+                c.push(concentration);
+                r.push(relaxation);
+            }
+        }
+
+        let baseline = self
+            .baseline
+            .as_ref()
+            .filter(|b| b.individual_alpha == individual_alpha)?;
+        let scale = self.settings.scale;
+        let c_pct = baseline.concentration.percentile(concentration);
+        let c_z = baseline.concentration.z_score(concentration);
+        let r_pct = baseline.relaxation.percentile(relaxation);
+        let r_z = baseline.relaxation.z_score(relaxation);
+        let (c, r) = match scale {
+            MetricScale::Percentile => (c_pct, r_pct),
+            MetricScale::ZScore => (c_z, r_z),
+        };
+        let metrics = BaselineMetrics {
+            concentration: self.concentration.update(c, &self.settings),
+            relaxation: self.relaxation.update(r, &self.settings),
+            concentration_percentile: c_pct,
+            concentration_z: c_z,
+            relaxation_percentile: r_pct,
+            relaxation_z: r_z,
+            scale,
+        };
+        self.latest = Some(metrics.clone());
+        Some(metrics)
+    }
+
+    // True when the window ending at `timestamp` starts a new step. A clock
+    // that went backwards (replay seek, new stream) starts one as well.
+    fn take_window(&mut self, timestamp: f64, step_seconds: f64) -> bool {
+        if self
+            .last_window
+            .is_some_and(|last| (0.0..step_seconds).contains(&(timestamp - last)))
+        {
+            return false;
+        }
+        self.last_window = Some(timestamp);
+        true
+    }
+
+    fn activate(&mut self, baseline: PersonalBaseline) {
+        info!(
+            "[BASELINE] Active for {} on {}",
+            baseline.user_id, baseline.device_id
+        );
+        self.baseline = Some(baseline);
+        self.concentration = HeldScore::default();
+        self.relaxation = HeldScore::default();
+        self.latest = None;
+        self.last_window = None;
+    }
+}
+
+fn with_state<T>(f: impl FnOnce(&mut BaselineState) -> T) -> T {
+    let mut state = BASELINE.lock().unwrap();
+    f(state.get_or_insert_with(BaselineState::default))
+}
+
+// Called per EEG packet with the band powers of the rolling window ending at
+// `timestamp`. Only one window per `step_seconds` (the band power window
+// length) is taken in, so calibration counts and smoothing are per step
+// rather than per packet; in between the latest scores are repeated.
+// Some(concentration, relaxation) in the configured scale once a baseline is
+// active.
+pub(crate) fn score_window(
+    bands: &BandPowers,
+    timestamp: f64,
+    step_seconds: f64,
+) -> Option<(f64, f64)> {
+    with_state(|s| {
+        if !s.take_window(timestamp, step_seconds) {
+            return s.latest.clone();
+        }
+        s.process(bands, iaf::get_individual_alpha_frequency())
+    })
+    .map(|m| (m.concentration, m.relaxation))
+}
+
+// Forgets the last window when the parser starts on a new or replayed stream
+pub(crate) fn reset_window_step() {
+    with_state(|s| s.last_window = None);
+}
+
+// Starts (or switches) the calibration phase being recorded
+#[frb]
+pub fn start_baseline_phase(phase: BaselinePhase) {
+    with_state(|s| {
+        s.calibration
+            .get_or_insert_with(|| Calibration {
+                individual_alpha: iaf::get_individual_alpha_frequency(),
+                ..Calibration::default()
+            })
+            .phase = Some(phase);
+    });
+    info!("[BASELINE] Recording {:?}", phase);
+}
+
+// Pauses recording without discarding the windows so far
+#[frb]
+pub fn stop_baseline_phase() {
+    with_state(|s| {
+        if let Some(calibration) = s.calibration.as_mut() {
+            calibration.phase = None;
+        }
+    });
+}
+
+#[frb]
+pub fn cancel_baseline_calibration() {
+    with_state(|s| s.calibration = None);
+}
+
+// Builds the baseline from both phases and makes it active
+#[frb]
+pub fn finish_baseline_calibration(user_id: String, device_id: String) -> Result<PersonalBaseline> {
+    with_state(|s| {
+        let calibration = s
+            .calibration
+            .take()
+            .context("No baseline calibration running")?;
+        if calibration.individual_alpha != iaf::get_individual_alpha_frequency() {
+            bail!("The IAF changed during the baseline calibration");
+        }
+        let (open, closed) = (&calibration.eyes_open, &calibration.eyes_closed);
+        let (Some(concentration), Some(relaxation)) = (
+            RatioDistribution::new(&open.0, &closed.0),
+            RatioDistribution::new(&open.1, &closed.1),
+        ) else {
+            bail!(
+                "Need {} windows per phase, got {} eyes open and {} eyes closed",
+                MIN_PHASE_WINDOWS,
+                open.0.len(),
+                closed.0.len()
+            );
+        };
+        let baseline = PersonalBaseline {
+            user_id,
+            device_id,
+            created_at: now(),
+            concentration,
+            relaxation,
+            individual_alpha: calibration.individual_alpha,
+        };
+        s.activate(baseline.clone());
+        Ok(baseline)
+    })
+}
+
+#[frb]
+pub fn set_baseline_settings(settings: BaselineSettings) {
+    with_state(|s| {
+        s.settings = settings;
+        s.concentration = HeldScore::default();
+        s.relaxation = HeldScore::default();
+    });
+}
+
+#[frb]
+pub fn get_baseline_settings() -> BaselineSettings {
+    with_state(|s| s.settings.clone())
+}
+
+#[frb]
+pub fn get_personal_baseline() -> Option<PersonalBaseline> {
+    with_state(|s| s.baseline.clone())
+}
+
+// Back to the fixed ratio scale
+#[frb]
+pub fn clear_personal_baseline() {
+    with_state(|s| {
+        s.baseline = None;
+        s.latest = None;
+    });
+}
+
+#[frb]
+pub fn get_baseline_metrics() -> Option<BaselineMetrics> {
+    with_state(|s| s.latest.clone())
+}
+
+fn baseline_path(dir: &str, user_id: &str, device_id: &str) -> PathBuf {
+    Path::new(dir).join(format!(
+        "baseline_{}_{}.json",
+        recording::file_name_part(user_id),
+        recording::file_name_part(device_id)
+    ))
+}
+
+// Writes the active baseline to `dir`, returns the file path
+#[frb]
+pub fn save_personal_baseline(dir: String) -> Result<String> {
+    let baseline = get_personal_baseline().context("No personal baseline")?;
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir))?;
+    let path = baseline_path(&dir, &baseline.user_id, &baseline.device_id);
+    recording::write_json_atomic(&path, &baseline)?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+// Loads and activates the baseline saved for this user and device
+#[frb]
+pub fn load_personal_baseline(
+    dir: String,
+    user_id: String,
+    device_id: String,
+) -> Result<PersonalBaseline> {
+    let path = baseline_path(&dir, &user_id, &device_id);
+    let json =
+        fs::read_to_string(&path).with_context(|| format!("No baseline at {}", path.display()))?;
+    let baseline: PersonalBaseline = serde_json::from_str(&json)
+        .with_context(|| format!("Invalid baseline file {}", path.display()))?;
+    let individual_alpha = iaf::get_individual_alpha_frequency();
+    if baseline.individual_alpha != individual_alpha {
+        bail!(
+            "Baseline was calibrated with IAF {:?}, the active IAF is {:?}",
+            baseline.individual_alpha,
+            individual_alpha
+        );
+    }
+    with_state(|s| s.activate(baseline.clone()));
+    Ok(baseline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bands(alpha: f64, beta: f64) -> BandPowers {
+        BandPowers {
+            delta: 1.0,
+            theta: 1.0,
+            alpha,
+            beta,
+            gamma: 1.0,
+        }
+    }
+
+    #[test]
+    fn scores_against_calibrated_distribution() {
+        let mut state = BaselineState {
+            calibration: Some(Calibration::default()),
+            ..Default::default()
+        };
+        // Eyes open: beta 1-2, alpha 1; eyes closed: alpha 3-4, beta 1
+        for phase in [BaselinePhase::EyesOpen, BaselinePhase::EyesClosed] {
+            state.calibration.as_mut().unwrap().phase = Some(phase);
+            for i in 0..20 {
+                let x = i as f64 / 19.0;
+                let b = match phase {
+                    BaselinePhase::EyesOpen => bands(1.0, 1.0 + x),
+                    BaselinePhase::EyesClosed => bands(3.0 + x, 1.0),
+                };
+                assert!(state.process(&b, None).is_none());
+            }
+        }
+        let calibration = state.calibration.take().unwrap();
+        let relaxation =
+            RatioDistribution::new(&calibration.eyes_open.1, &calibration.eyes_closed.1).unwrap();
+        assert_eq!(relaxation.windows, 40);
+        assert!(relaxation.eyes_closed_median > 3.0 && relaxation.eyes_open_median < 1.0);
+        assert_eq!(relaxation.percentile(0.1), 0.0);
+        assert_eq!(relaxation.percentile(10.0), 100.0);
+        assert!((relaxation.percentile(relaxation.quantiles[10]) - 50.0).abs() < 1e-9);
+        assert!(relaxation.z_score(relaxation.log_mean.exp()).abs() < 1e-9);
+
+        let concentration =
+            RatioDistribution::new(&calibration.eyes_open.0, &calibration.eyes_closed.0).unwrap();
+        state.activate(PersonalBaseline {
+            user_id: "u".into(),
+            device_id: "d".into(),
+            created_at: 0.0,
+            concentration,
+            relaxation,
+            individual_alpha: None,
+        });
+        state.settings.smoothing = 0.5;
+        // Bands anchored to another IAF than the calibration's aren't scored
+        assert!(state.process(&bands(4.0, 1.0), Some(10.0)).is_none());
+        // Fully relaxed, then a small wobble inside the hysteresis band
+        let first = state.process(&bands(4.0, 1.0), None).unwrap();
+        assert!(first.relaxation_percentile > 95.0);
+        assert_eq!(first.relaxation, first.relaxation_percentile);
+        let wobble = state.process(&bands(3.9, 1.0), None).unwrap();
+        assert_eq!(wobble.relaxation, first.relaxation);
+        let drop = state.process(&bands(0.5, 1.0), None).unwrap();
+        assert!(drop.relaxation < first.relaxation - 3.0);
+        assert!(drop.relaxation > drop.relaxation_percentile);
+
+        let json = serde_json::to_string(state.baseline.as_ref().unwrap()).unwrap();
+        let loaded: PersonalBaseline = serde_json::from_str(&json).unwrap();
+        let active = state.baseline.as_ref().unwrap();
+        assert_eq!(loaded.user_id, active.user_id);
+        assert!(
+            (loaded.relaxation.percentile(2.5) - active.relaxation.percentile(2.5)).abs() < 1e-9
+        );
+        assert_eq!(
+            baseline_path("/b", "a b", "Muse-S/1"),
+            Path::new("/b/baseline_a_b_Muse-S_1.json")
+        );
+    }
+
+    #[test]
+    fn backward_timestamps_start_a_new_step() {
+        let mut state = BaselineState::default();
+        assert!(state.take_window(100.0, 1.0));
+        assert!(!state.take_window(100.5, 1.0));
+        assert!(state.take_window(101.0, 1.0));
+        // Replay seek back to the start of the session
+        assert!(state.take_window(10.0, 1.0));
+        assert!(!state.take_window(10.5, 1.0));
+    }
+}
@@ -0,0 +1,173 @@
+// Rolling buffers of a multi-channel EEG stream whose channels arrive in
+// separate packets. Every channel keeps its newest samples plus the count
+// received since the start, so windows can be cut at the same absolute
+// sample on all channels even while one of them is a packet ahead.
+
+// Kept beyond the longest window a consumer cuts, so that window stays whole
+// on channels a packet (or a board read) ahead of the others
+const SLACK_SECONDS: f64 = 1.0;
+
+// Indices and names of `selected`, every non-AUX channel if empty
+pub(crate) fn select_channels(
+    selected: &[String],
+    channel_names: &[String],
+) -> (Vec<usize>, Vec<String>) {
+    channel_names
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| {
+            if selected.is_empty() {
+                !name.starts_with("AUX")
+            } else {
+                selected.contains(name)
+            }
+        })
+        .map(|(i, name)| (i, name.clone()))
+        .unzip()
+}
+
+pub(crate) struct ChannelBuffers {
+    // Source index (Muse channel or board data row) per buffer
+    channels: Vec<usize>,
+    buffers: Vec<Vec<f64>>,
+    received: Vec<usize>,
+    capacity: usize,
+    next_step: usize,
+}
+
+impl ChannelBuffers {
+    pub fn new(channels: Vec<usize>, capacity: usize) -> Self {
+        Self {
+            buffers: vec![Vec::new(); channels.len()],
+            received: vec![0; channels.len()],
+            channels,
+            capacity,
+            next_step: 0,
+        }
+    }
+
+    // Room for windows of up to `window_seconds` plus the slack
+    pub fn for_window(channels: Vec<usize>, window_seconds: f64, sampling_rate: f64) -> Self {
+        let capacity = ((window_seconds + SLACK_SECONDS) * sampling_rate) as usize;
+        Self::new(channels, capacity)
+    }
+
+    pub fn channels(&self) -> &[usize] {
+        &self.channels
+    }
+
+    // Appends the samples of source `channel`; the buffer index, None for a
+    // channel that is not buffered
+    pub fn push(&mut self, channel: usize, samples: &[f64]) -> Option<usize> {
+        let c = self.channels.iter().position(|i| *i == channel)?;
+        let buffer = &mut self.buffers[c];
+        buffer.extend_from_slice(samples);
+        if buffer.len() > self.capacity {
+            buffer.drain(..buffer.len() - self.capacity);
+        }
+        self.received[c] += samples.len();
+        Some(c)
+    }
+
+    // Starts over at sample 0, for a stream that restarted
+    pub fn reset(&mut self) {
+        self.buffers.iter_mut().for_each(Vec::clear);
+        self.received.iter_mut().for_each(|r| *r = 0);
+        self.next_step = 0;
+    }
+
+    pub fn received(&self, c: usize) -> usize {
+        self.received[c]
+    }
+
+    // Samples received on every channel
+    pub fn complete(&self) -> usize {
+        self.received.iter().copied().min().unwrap_or(0)
+    }
+
+    // Samples still buffered on every channel up to `complete`
+    pub fn aligned_len(&self) -> usize {
+        let complete = self.complete();
+        self.buffers
+            .iter()
+            .zip(&self.received)
+            .map(|(b, &r)| b.len().saturating_sub(r - complete))
+            .min()
+            .unwrap_or(0)
+    }
+
+    // `len` samples of buffer `c` ending at absolute sample `end`, None once
+    // dropped from the buffer or not received yet
+    pub fn segment(&self, c: usize, end: usize, len: usize) -> Option<&[f64]> {
+        let first = self.received[c] - self.buffers[c].len();
+        let start = end.checked_sub(len)?.checked_sub(first)?;
+        self.buffers[c].get(start..start + len)
+    }
+
+    // Segments of every channel ending at the same absolute sample
+    pub fn windows(&self, end: usize, len: usize) -> Option<Vec<&[f64]>> {
+        (0..self.channels.len())
+            .map(|c| self.segment(c, end, len))
+            .collect()
+    }
+
+    // Windows ending at the newest sample every channel has
+    pub fn latest_windows(&self, len: usize) -> Option<Vec<&[f64]>> {
+        self.windows(self.complete(), len)
+    }
+
+    // True once per `step` samples after `window` samples arrived on every
+    // channel
+    pub fn step_due(&mut self, window: usize, step: usize) -> bool {
+        let complete = self.complete();
+        if complete >= window.max(self.next_step) {
+            self.next_step = complete + step.max(1);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cuts_aligned_windows_across_channels() {
+        let names: Vec<String> = ["TP9", "AF7", "AUX"].map(String::from).to_vec();
+        assert_eq!(
+            select_channels(&[], &names),
+            (vec![0, 1], names[..2].to_vec())
+        );
+        assert_eq!(select_channels(&["AUX".to_string()], &names).0, vec![2]);
+
+        let mut buffers = ChannelBuffers::new(vec![0, 1], 6);
+        assert_eq!(buffers.push(2, &[9.0]), None);
+        buffers.push(0, &[0.0, 1.0, 2.0, 3.0]);
+        assert!(!buffers.step_due(2, 2));
+        buffers.push(1, &[10.0, 11.0]);
+        assert!(buffers.step_due(2, 2));
+        assert!(!buffers.step_due(2, 2));
+        // Channel 0 is two samples ahead, windows end at sample 2 on both
+        assert_eq!(
+            buffers.windows(2, 2).unwrap(),
+            vec![&[0.0, 1.0][..], &[10.0, 11.0]]
+        );
+        assert_eq!(buffers.aligned_len(), 2);
+
+        buffers.push(0, &[4.0, 5.0, 6.0, 7.0]);
+        buffers.push(1, &[12.0, 13.0]);
+        assert!(buffers.step_due(2, 2));
+        // Samples 0 and 1 of channel 0 dropped out of the capacity
+        assert_eq!(buffers.segment(0, 3, 2), None);
+        assert_eq!(buffers.windows(4, 2).unwrap()[0], &[2.0, 3.0]);
+        assert_eq!(buffers.aligned_len(), 2);
+
+        buffers.reset();
+        assert_eq!((buffers.complete(), buffers.aligned_len()), (0, 0));
+        buffers.push(0, &[20.0, 21.0]);
+        buffers.push(1, &[30.0, 31.0]);
+        assert!(buffers.step_due(2, 2));
+        assert_eq!(buffers.windows(2, 2).unwrap()[1], &[30.0, 31.0]);
+    }
+}
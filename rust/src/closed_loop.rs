@@ -0,0 +1,412 @@
+use crate::sleep::SleepStage;
+use flutter_rust_bridge::frb;
+use log::info;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// Closed-loop auditory stimulation phase locked to slow oscillation
+// up-states.
+//
+// One frontal channel runs through a causal biquad band pass (0.5-2 Hz, low
+// group delay). Its phase comes from the signal and its derivative, with the
+// filter's phase lag at the current frequency (from the last half wave)
+// added back. After a trough below min_trough_uv, the stimulator predicts
+// when the wave reaches target_phase_deg and, once that is less than a
+// packet away, emits a "stimulate at" time moved earlier by the audio
+// output latency. Safety rules: only while the latest sleep epoch is N2/N3,
+// never within the refractory period of the previous stimulus, and an
+// arousal (head movement or a jump in 16-30 Hz power) pauses stimulation
+// until an N2/N3 epoch is staged after it.
+
+// Label and value of the recording marker logged for every stimulus; the
+// marker is timestamped with the stimulate_at time
+pub(crate) const STIMULUS_MARKER: &str = "stimulus";
+pub(crate) const STIMULUS_MARKER_VALUE: f64 = 1.0;
+
+const SLOW_BAND: (f64, f64) = (0.5, 2.0);
+const AROUSAL_BAND: (f64, f64) = (16.0, 30.0);
+// Time constants of the short and long arousal band power averages
+const AROUSAL_SHORT_SECONDS: f64 = 1.0;
+const AROUSAL_LONG_SECONDS: f64 = 30.0;
+// Long average needed before arousals are judged
+const AROUSAL_WARMUP_SECONDS: f64 = 10.0;
+const MAX_QUEUED_EVENTS: usize = 256;
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClosedLoopSettings {
+    // EEG channel index, default AF7
+    pub channel: usize,
+    // 0 = up-state peak, ±180 = trough
+    pub target_phase_deg: f64,
+    // Filtered trough that arms the next up-state, µV
+    pub min_trough_uv: f64,
+    // Delay from the stimulate event to sound at the ear
+    pub latency_seconds: f64,
+    pub refractory_seconds: f64,
+    // 16-30 Hz power over its long average that counts as an arousal
+    pub arousal_power_ratio: f64,
+    // RMS head rotation that counts as an arousal, deg/s
+    pub arousal_motion_dps: f64,
+}
+
+impl Default for ClosedLoopSettings {
+    fn default() -> Self {
+        Self {
+            channel: 1,
+            target_phase_deg: 0.0,
+            min_trough_uv: -40.0,
+            latency_seconds: 0.05,
+            refractory_seconds: 2.5,
+            arousal_power_ratio: 4.0,
+            arousal_motion_dps: 10.0,
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StimulationBlock {
+    // Sleep staging is not running
+    NoStaging,
+    // Latest epoch is not N2/N3
+    Stage,
+    Refractory,
+    Arousal,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct StimulationEvent {
+    // When to start the sound, latency already subtracted
+    pub stimulate_at: f64,
+    // Predicted time the sound reaches the target phase
+    pub target_time: f64,
+    pub predicted_phase_deg: f64,
+    // Phase and time of the estimate the prediction was made from
+    pub phase_deg: f64,
+    pub estimated_at: f64,
+    pub frequency_hz: f64,
+    pub latency_seconds: f64,
+    // Filtered trough before this up-state, µV
+    pub trough_uv: f64,
+    pub stage: SleepStage,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClosedLoopStatus {
+    pub blocked: Option<StimulationBlock>,
+    pub stimuli: u32,
+    pub frequency_hz: f64,
+    pub phase_deg: f64,
+}
+
+// RBJ band pass biquad, 0 dB at the center frequency
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn band_pass(band: (f64, f64), sampling_rate: f64) -> Self {
+        let center = (band.0 * band.1).sqrt();
+        let q = center / (band.1 - band.0);
+        let w0 = 2.0 * PI * center / sampling_rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        Self {
+            b: [alpha / a0, 0.0, -alpha / a0],
+            a: [-2.0 * w0.cos() / a0, (1.0 - alpha) / a0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+
+    // Phase shift at `frequency`, radians (negative = lag)
+    fn phase_at(&self, frequency: f64, sampling_rate: f64) -> f64 {
+        let w = 2.0 * PI * frequency / sampling_rate;
+        let (num_re, num_im) = (
+            self.b[0] + self.b[1] * w.cos() + self.b[2] * (2.0 * w).cos(),
+            -self.b[1] * w.sin() - self.b[2] * (2.0 * w).sin(),
+        );
+        let (den_re, den_im) = (
+            1.0 + self.a[0] * w.cos() + self.a[1] * (2.0 * w).cos(),
+            -self.a[0] * w.sin() - self.a[1] * (2.0 * w).sin(),
+        );
+        num_im.atan2(num_re) - den_im.atan2(den_re)
+    }
+}
+
+fn wrap(phase: f64) -> f64 {
+    (phase + PI).rem_euclid(2.0 * PI) - PI
+}
+
+pub(crate) struct ClosedLoopStimulator {
+    pub settings: ClosedLoopSettings,
+    sampling_rate: f64,
+    slow: Biquad,
+    arousal: Biquad,
+    previous: f64,
+    frequency: f64,
+    phase: f64,
+    last_crossing: Option<f64>,
+    trough: Option<f64>,
+    short_power: f64,
+    long_power: f64,
+    samples: usize,
+    last_stimulus: f64,
+    arousal_at: Option<f64>,
+    blocked: Option<StimulationBlock>,
+    stimuli: u32,
+    queued: VecDeque<StimulationEvent>,
+}
+
+impl ClosedLoopStimulator {
+    pub fn new(settings: ClosedLoopSettings, sampling_rate: f64) -> Self {
+        Self {
+            settings,
+            sampling_rate,
+            slow: Biquad::band_pass(SLOW_BAND, sampling_rate),
+            arousal: Biquad::band_pass(AROUSAL_BAND, sampling_rate),
+            previous: 0.0,
+            frequency: 1.0,
+            phase: 0.0,
+            last_crossing: None,
+            trough: None,
+            short_power: 0.0,
+            long_power: 0.0,
+            samples: 0,
+            last_stimulus: f64::NEG_INFINITY,
+            arousal_at: None,
+            blocked: None,
+            stimuli: 0,
+            queued: VecDeque::new(),
+        }
+    }
+
+    fn push_sample(&mut self, value: f64, time: f64) {
+        let y = self.slow.process(value);
+        if (y < 0.0) != (self.previous < 0.0) {
+            if let Some(last) = self.last_crossing {
+                let half_period = time - last;
+                if half_period > 0.0 {
+                    self.frequency = (0.5 / half_period).clamp(SLOW_BAND.0, SLOW_BAND.1);
+                }
+            }
+            self.last_crossing = Some(time);
+            if y < 0.0 {
+                // A new negative half wave, the previous up-state is gone
+                self.trough = None;
+            }
+        }
+        if y < self.settings.min_trough_uv {
+            self.trough = Some(self.trough.map_or(y, |t: f64| t.min(y)));
+        }
+        let omega = 2.0 * PI * self.frequency;
+        let derivative = (y - self.previous) * self.sampling_rate;
+        let lag = self.slow.phase_at(self.frequency, self.sampling_rate);
+        self.phase = wrap((-derivative / omega).atan2(y) - lag);
+        self.previous = y;
+
+        let beta = self.arousal.process(value);
+        let power = beta * beta;
+        let short = 1.0 / (AROUSAL_SHORT_SECONDS * self.sampling_rate);
+        let long = 1.0 / (AROUSAL_LONG_SECONDS * self.sampling_rate);
+        self.short_power += short * (power - self.short_power);
+        self.long_power += long * (power - self.long_power);
+        self.samples += 1;
+    }
+
+    fn aroused(&self, motion_dps: f64) -> bool {
+        let warm = self.samples as f64 >= AROUSAL_WARMUP_SECONDS * self.sampling_rate;
+        motion_dps >= self.settings.arousal_motion_dps
+            || warm && self.short_power > self.settings.arousal_power_ratio * self.long_power
+    }
+
+    // Feeds one packet of the stimulation channel ending at `packet_time`.
+    // `latest_epoch` is the start and stage of the newest staged epoch.
+    pub fn process(
+        &mut self,
+        samples: &[f64],
+        packet_time: f64,
+        latest_epoch: Option<(f64, SleepStage)>,
+        motion_dps: f64,
+    ) -> Option<StimulationEvent> {
+        let n = samples.len();
+        for (i, &value) in samples.iter().enumerate() {
+            let time = packet_time - (n - 1 - i) as f64 / self.sampling_rate;
+            self.push_sample(value, time);
+        }
+
+        if self.aroused(motion_dps) {
+            if self.arousal_at.is_none() {
+                info!("[STIM] Arousal at {:.1}, pausing", packet_time);
+            }
+            self.arousal_at = Some(packet_time);
+        }
+        let deep = |stage: SleepStage| matches!(stage, SleepStage::N2 | SleepStage::N3);
+        if let (Some(at), Some((start, stage))) = (self.arousal_at, latest_epoch) {
+            if start > at && deep(stage) {
+                self.arousal_at = None;
+            }
+        }
+        self.blocked = match latest_epoch {
+            None => Some(StimulationBlock::NoStaging),
+            Some(_) if self.arousal_at.is_some() => Some(StimulationBlock::Arousal),
+            Some((_, stage)) if !deep(stage) => Some(StimulationBlock::Stage),
+            Some(_) if packet_time - self.last_stimulus < self.settings.refractory_seconds => {
+                Some(StimulationBlock::Refractory)
+            }
+            Some(_) => None,
+        };
+
+        // Rising from an armed trough towards the up-state
+        let trough = self.trough?;
+        if self.blocked.is_some() || self.phase >= 0.0 {
+            return None;
+        }
+        let target = self.settings.target_phase_deg.to_radians();
+        let omega = 2.0 * PI * self.frequency;
+        let delay = (target - self.phase).rem_euclid(2.0 * PI) / omega;
+        let stimulate_at = packet_time + delay - self.settings.latency_seconds;
+        let horizon = n as f64 / self.sampling_rate;
+        if stimulate_at - packet_time > horizon {
+            return None;
+        }
+        self.trough = None;
+        if stimulate_at < packet_time {
+            // Too late for this wave
+            return None;
+        }
+
+        let event = StimulationEvent {
+            stimulate_at,
+            target_time: packet_time + delay,
+            predicted_phase_deg: self.settings.target_phase_deg,
+            phase_deg: self.phase.to_degrees(),
+            estimated_at: packet_time,
+            frequency_hz: self.frequency,
+            latency_seconds: self.settings.latency_seconds,
+            trough_uv: trough,
+            stage: latest_epoch.map(|(_, s)| s)?,
+        };
+        self.last_stimulus = stimulate_at;
+        self.stimuli += 1;
+        if self.queued.len() == MAX_QUEUED_EVENTS {
+            self.queued.pop_front();
+        }
+        self.queued.push_back(event.clone());
+        Some(event)
+    }
+
+    pub fn status(&self) -> ClosedLoopStatus {
+        ClosedLoopStatus {
+            blocked: self.blocked,
+            stimuli: self.stimuli,
+            frequency_hz: self.frequency,
+            phase_deg: self.phase.to_degrees(),
+        }
+    }
+
+    pub fn take_queued(&mut self) -> Vec<StimulationEvent> {
+        self.queued.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FS: f64 = 256.0;
+    const PACKET: usize = 12;
+
+    // Feeds `seconds` of a 0.8 Hz, 80 µV cosine (peaks every 1.25 s) plus
+    // `extra(t)`, with the stage epoch returned by `epoch(t)`
+    fn run(
+        stimulator: &mut ClosedLoopStimulator,
+        seconds: f64,
+        extra: impl Fn(f64) -> f64,
+        epoch: impl Fn(f64) -> Option<(f64, SleepStage)>,
+    ) -> Vec<StimulationEvent> {
+        let total = (seconds * FS) as usize;
+        let mut events = Vec::new();
+        for start in (0..total).step_by(PACKET) {
+            let samples: Vec<f64> = (start..start + PACKET)
+                .map(|i| {
+                    let t = i as f64 / FS;
+                    80.0 * (2.0 * PI * 0.8 * t).cos() + extra(t)
+                })
+                .collect();
+            let packet_time = (start + PACKET - 1) as f64 / FS;
+            events.extend(stimulator.process(&samples, packet_time, epoch(packet_time), 0.0));
+        }
+        events
+    }
+
+    #[test]
+    fn stimulates_at_up_state_peaks_with_refractory() {
+        let mut stimulator = ClosedLoopStimulator::new(ClosedLoopSettings::default(), FS);
+        let events = run(
+            &mut stimulator,
+            40.0,
+            |_| 0.0,
+            |_| Some((0.0, SleepStage::N3)),
+        );
+        // Every third peak fits the 2.5 s refractory period
+        let settled: Vec<_> = events.iter().filter(|e| e.estimated_at > 5.0).collect();
+        assert!(settled.len() >= 8, "{} events", settled.len());
+        for e in &settled {
+            let peak = (e.target_time / 1.25).round() * 1.25;
+            assert!((e.target_time - peak).abs() < 0.06, "{:?}", e);
+            assert!((e.stimulate_at + 0.05 - e.target_time).abs() < 1e-9);
+            assert!((e.frequency_hz - 0.8).abs() < 0.05);
+        }
+        for pair in events.windows(2) {
+            assert!(pair[1].stimulate_at - pair[0].stimulate_at >= 2.5);
+        }
+        assert_eq!(stimulator.take_queued().len(), events.len());
+    }
+
+    #[test]
+    fn safety_rules_block_stimulation() {
+        let mut stimulator = ClosedLoopStimulator::new(ClosedLoopSettings::default(), FS);
+        assert!(run(&mut stimulator, 20.0, |_| 0.0, |_| None).is_empty());
+        assert_eq!(
+            stimulator.status().blocked,
+            Some(StimulationBlock::NoStaging)
+        );
+        let light = |_| Some((0.0, SleepStage::N1));
+        assert!(run(&mut stimulator, 20.0, |_| 0.0, light).is_empty());
+        assert_eq!(stimulator.status().blocked, Some(StimulationBlock::Stage));
+
+        // A 20 Hz burst at 30 s pauses stimulation until an N3 epoch starts
+        // after it, at 60 s
+        let mut stimulator = ClosedLoopStimulator::new(ClosedLoopSettings::default(), FS);
+        let burst = |t: f64| {
+            if (30.0..32.0).contains(&t) {
+                60.0 * (2.0 * PI * 20.0 * t).sin()
+            } else {
+                0.0
+            }
+        };
+        let epoch = |t: f64| Some((if t >= 60.0 { 60.0 } else { 0.0 }, SleepStage::N3));
+        let events = run(&mut stimulator, 80.0, burst, epoch);
+        assert!(events.iter().any(|e| e.stimulate_at < 30.0));
+        assert!(!events
+            .iter()
+            .any(|e| e.stimulate_at > 30.0 && e.stimulate_at < 60.0));
+        assert!(events.iter().any(|e| e.stimulate_at > 60.0));
+    }
+}
@@ -0,0 +1,479 @@
+use crate::muse_types::BandRange;
+use crate::personal_classifier::TrainedClassifier;
+use crate::spectral;
+use anyhow::{bail, Context, Result};
+use brainflow::brainflow_model_params::BrainFlowModelParamsBuilder;
+use brainflow::ml_model::MlModel;
+use brainflow::{BrainFlowClassifiers, BrainFlowMetrics};
+use flutter_rust_bridge::frb;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Mutex;
+
+// User supplied ONNX / dynamic library classifiers.
+//
+// A model is registered together with the features it was trained on
+// (channels, bands, window, scaling, how channels are combined) and the
+// meaning of its outputs. The metrics service computes that feature vector
+// from its rolling EEG windows at every tick and runs each registered model.
+// Models are prepared on registration and released when unregistered.
+// Classifiers trained in the app (personal_classifier.rs) share the registry
+// and the feature code.
+
+static CUSTOM_MODELS: Mutex<Vec<RegisteredModel>> = Mutex::new(Vec::new());
+
+const MIN_POWER: f64 = 1e-12;
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CustomClassifier {
+    Onnx,
+    DynLib,
+}
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BandPowerScale {
+    // Integrated PSD, µV²
+    Absolute,
+    // Fraction of the summed power of the spec's bands
+    Relative,
+    Log10,
+}
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FeatureAggregation {
+    // Bands of the first channel, then of the second, ...
+    PerChannel,
+    // Mean over channels of every band, then their SDs, like
+    // get_avg_band_powers
+    MeanStd,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeatureSpec {
+    // Channel names in order; empty uses every EEG channel
+    pub channels: Vec<String>,
+    // Used as given, never anchored to the individual alpha frequency, so
+    // features match the ones the model was trained on
+    pub bands: Vec<BandRange>,
+    pub window_seconds: f64,
+    pub scale: BandPowerScale,
+    pub aggregation: FeatureAggregation,
+    // Appends Hjorth activity, mobility and complexity after the bands
+    pub hjorth: bool,
+}
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    // Already probabilities; a single output is P(last label)
+    Probabilities,
+    // Softmax is applied, or a sigmoid for a single output
+    Logits,
+    // Passed through as is, no label is picked
+    Scores,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputSpec {
+    pub kind: OutputKind,
+    // Class (or score) names, in output order
+    pub labels: Vec<String>,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomModelConfig {
+    pub id: String,
+    pub classifier: CustomClassifier,
+    pub file: String,
+    // ONNX output to read; empty for the first one
+    pub output_name: String,
+    pub features: FeatureSpec,
+    pub output: OutputSpec,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomPrediction {
+    pub model_id: String,
+    pub values: Vec<f64>,
+    pub labels: Vec<String>,
+    // Most likely class, None for scores
+    pub label: Option<String>,
+}
+
+enum Predictor {
+    BrainFlow {
+        config: CustomModelConfig,
+        model: MlModel,
+    },
+    Trained(TrainedClassifier),
+}
+
+struct RegisteredModel {
+    id: String,
+    features: FeatureSpec,
+    output: OutputSpec,
+    predictor: Predictor,
+}
+
+impl RegisteredModel {
+    fn predict(&self, features: &mut [f64]) -> Result<Vec<f64>> {
+        match &self.predictor {
+            Predictor::BrainFlow { model, .. } => Ok(model.predict(features)?),
+            Predictor::Trained(classifier) => Ok(classifier.predict_proba(features)),
+        }
+    }
+
+    fn release(&self) {
+        if let Predictor::BrainFlow { model, .. } = &self.predictor {
+            if let Err(e) = model.release() {
+                warn!(
+                    "[METRICS] Failed to release custom model {}: {:?}",
+                    self.id, e
+                );
+            }
+        }
+    }
+}
+
+fn register(registered: RegisteredModel) {
+    unregister_custom_model(registered.id.clone());
+    CUSTOM_MODELS.lock().unwrap().push(registered);
+}
+
+fn validate(config: &CustomModelConfig) -> Result<()> {
+    if config.id.trim().is_empty() {
+        bail!("Model id is empty");
+    }
+    if !Path::new(&config.file).is_file() {
+        bail!("Model file {} not found", config.file);
+    }
+    let spec = &config.features;
+    if spec.bands.is_empty() {
+        bail!("Feature spec has no bands");
+    }
+    if let Some(band) = spec
+        .bands
+        .iter()
+        .find(|b| b.freq_start < 0.0 || b.freq_stop <= b.freq_start)
+    {
+        bail!(
+            "Invalid band {} ({}-{} Hz)",
+            band.name,
+            band.freq_start,
+            band.freq_stop
+        );
+    }
+    if spec.window_seconds <= 0.0 {
+        bail!("Feature window must be positive");
+    }
+    Ok(())
+}
+
+// Registers (or replaces) a custom model and prepares it
+#[frb]
+pub fn register_custom_model(config: CustomModelConfig) -> Result<()> {
+    validate(&config)?;
+    let classifier = match config.classifier {
+        CustomClassifier::Onnx => BrainFlowClassifiers::OnnxClassifier,
+        CustomClassifier::DynLib => BrainFlowClassifiers::DynLibClassifier,
+    };
+    let params = BrainFlowModelParamsBuilder::new()
+        .metric(BrainFlowMetrics::UserDefined)
+        .classifier(classifier)
+        .file(&config.file)
+        .output_name(&config.output_name)
+        .build();
+    let model = MlModel::new(params)?;
+    model
+        .prepare()
+        .with_context(|| format!("Failed to prepare model {}", config.id))?;
+    info!("[METRICS] Registered custom model {}", config.id);
+    register(RegisteredModel {
+        id: config.id.clone(),
+        features: config.features.clone(),
+        output: config.output.clone(),
+        predictor: Predictor::BrainFlow { config, model },
+    });
+    Ok(())
+}
+
+// Runs a classifier trained in the app like any registered model, its
+// outputs being class probabilities
+pub(crate) fn register_trained(classifier: TrainedClassifier) {
+    info!("[METRICS] Registered trained classifier {}", classifier.id);
+    register(RegisteredModel {
+        id: classifier.id.clone(),
+        features: classifier.features.clone(),
+        output: OutputSpec {
+            kind: OutputKind::Probabilities,
+            labels: classifier.labels.clone(),
+        },
+        predictor: Predictor::Trained(classifier),
+    });
+}
+
+#[frb]
+pub fn unregister_custom_model(id: String) -> bool {
+    let mut models = CUSTOM_MODELS.lock().unwrap();
+    let Some(index) = models.iter().position(|m| m.id == id) else {
+        return false;
+    };
+    models.remove(index).release();
+    true
+}
+
+// ONNX / dynamic library models; trained classifiers are not listed
+#[frb]
+pub fn list_custom_models() -> Vec<CustomModelConfig> {
+    let models = CUSTOM_MODELS.lock().unwrap();
+    models
+        .iter()
+        .filter_map(|m| match &m.predictor {
+            Predictor::BrainFlow { config, .. } => Some(config.clone()),
+            Predictor::Trained(_) => None,
+        })
+        .collect()
+}
+
+// Longest window any registered model needs
+pub(crate) fn max_window_seconds() -> f64 {
+    let models = CUSTOM_MODELS.lock().unwrap();
+    models
+        .iter()
+        .map(|m| m.features.window_seconds)
+        .fold(0.0, f64::max)
+}
+
+// Hjorth activity (variance), mobility and complexity of a window
+pub(crate) fn hjorth(data: &[f64]) -> (f64, f64, f64) {
+    let variance = |v: &[f64]| -> f64 {
+        if v.is_empty() {
+            return 0.0;
+        }
+        let mean = v.iter().sum::<f64>() / v.len() as f64;
+        v.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / v.len() as f64
+    };
+    let diff = |v: &[f64]| -> Vec<f64> { v.windows(2).map(|w| w[1] - w[0]).collect() };
+    let d1 = diff(data);
+    let d2 = diff(&d1);
+    let (v0, v1, v2) = (variance(data), variance(&d1), variance(&d2));
+    if v0 <= 0.0 || v1 <= 0.0 {
+        return (v0, 0.0, 0.0);
+    }
+    let mobility = (v1 / v0).sqrt();
+    let complexity = (v2 / v1).sqrt() / mobility;
+    (v0, mobility, complexity)
+}
+
+// Band powers (and Hjorth parameters) of every channel window shaped as the spec describes; the
+// windows are the last `window_seconds` of the spec's channels, in order
+pub(crate) fn compute_features(
+    spec: &FeatureSpec,
+    windows: &[&[f64]],
+    sampling_rate: f64,
+) -> Option<Vec<f64>> {
+    if windows.is_empty() {
+        return None;
+    }
+    let samples = windows.iter().map(|w| w.len()).min()?;
+    // Up to 2 s segments, so several are averaged on longer windows
+    let limit = samples.min((2.0 * sampling_rate) as usize);
+    if limit < 2 {
+        return None;
+    }
+    let nfft = spectral::nfft_for(limit);
+    let mut per_channel = Vec::with_capacity(windows.len());
+    for window in windows {
+        let psd = spectral::welch(window, sampling_rate, nfft)?;
+        let mut powers: Vec<f64> = spec
+            .bands
+            .iter()
+            .map(|b| psd.band_power(b.freq_start, b.freq_stop))
+            .collect();
+        match spec.scale {
+            BandPowerScale::Absolute => {}
+            BandPowerScale::Relative => {
+                let total: f64 = powers.iter().sum();
+                if total <= 0.0 {
+                    return None;
+                }
+                powers.iter_mut().for_each(|p| *p /= total);
+            }
+            BandPowerScale::Log10 => {
+                powers
+                    .iter_mut()
+                    .for_each(|p| *p = p.max(MIN_POWER).log10());
+            }
+        }
+        if spec.hjorth {
+            let (activity, mobility, complexity) = hjorth(window);
+            let activity = match spec.scale {
+                BandPowerScale::Log10 => activity.max(MIN_POWER).log10(),
+                _ => activity,
+            };
+            powers.extend([activity, mobility, complexity]);
+        }
+        per_channel.push(powers);
+    }
+    Some(match spec.aggregation {
+        FeatureAggregation::PerChannel => per_channel.concat(),
+        FeatureAggregation::MeanStd => {
+            let n = per_channel.len() as f64;
+            let count = per_channel[0].len();
+            let means: Vec<f64> = (0..count)
+                .map(|b| per_channel.iter().map(|c| c[b]).sum::<f64>() / n)
+                .collect();
+            let sds: Vec<f64> = (0..count)
+                .map(|b| {
+                    let var = per_channel
+                        .iter()
+                        .map(|c| (c[b] - means[b]).powi(2))
+                        .sum::<f64>()
+                        / n;
+                    var.sqrt()
+                })
+                .collect();
+            [means, sds].concat()
+        }
+    })
+}
+
+// Applies the output semantics to the raw model output
+pub(crate) fn interpret(output: &OutputSpec, raw: Vec<f64>) -> (Vec<f64>, Option<String>) {
+    let values = match output.kind {
+        OutputKind::Logits if raw.len() == 1 => vec![1.0 / (1.0 + (-raw[0]).exp())],
+        OutputKind::Logits => {
+            let max = raw.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            let exp: Vec<f64> = raw.iter().map(|v| (v - max).exp()).collect();
+            let sum: f64 = exp.iter().sum();
+            exp.iter().map(|e| e / sum).collect()
+        }
+        OutputKind::Probabilities | OutputKind::Scores => raw,
+    };
+    if output.kind == OutputKind::Scores || values.is_empty() {
+        return (values, None);
+    }
+    let index = if values.len() == 1 {
+        // P(last label) against the first one
+        if values[0] >= 0.5 {
+            output.labels.len().saturating_sub(1)
+        } else {
+            0
+        }
+    } else {
+        values
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(i, _)| i)
+            .unwrap_or(0)
+    };
+    (values, output.labels.get(index).cloned())
+}
+
+// Runs every registered model on the service's windows; models whose
+// channels are missing or whose window is not filled yet are skipped
+pub(crate) fn predict_registered(
+    channel_names: &[String],
+    buffers: &[Vec<f64>],
+    sampling_rate: f64,
+) -> Vec<CustomPrediction> {
+    let models = CUSTOM_MODELS.lock().unwrap();
+    let mut predictions = Vec::new();
+    for registered in models.iter() {
+        let spec = &registered.features;
+        let indices: Option<Vec<usize>> = if spec.channels.is_empty() {
+            Some((0..channel_names.len()).collect())
+        } else {
+            spec.channels
+                .iter()
+                .map(|c| channel_names.iter().position(|n| n == c))
+                .collect()
+        };
+        let Some(indices) = indices else {
+            continue;
+        };
+        let samples = (spec.window_seconds * sampling_rate) as usize;
+        if indices.iter().any(|&i| buffers[i].len() < samples) {
+            continue;
+        }
+        let windows: Vec<&[f64]> = indices
+            .iter()
+            .map(|&i| &buffers[i][buffers[i].len() - samples..])
+            .collect();
+        let Some(mut features) = compute_features(spec, &windows, sampling_rate) else {
+            continue;
+        };
+        match registered.predict(&mut features) {
+            Ok(raw) => {
+                let (values, label) = interpret(&registered.output, raw);
+                predictions.push(CustomPrediction {
+                    model_id: registered.id.clone(),
+                    values,
+                    labels: registered.output.labels.clone(),
+                    label,
+                });
+            }
+            Err(e) => warn!("[METRICS] Custom model {} failed: {:?}", registered.id, e),
+        }
+    }
+    predictions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn features_follow_spec_and_outputs_are_interpreted() {
+        let fs = 256.0;
+        let sine = |freq: f64, amp: f64| -> Vec<f64> {
+            (0..512)
+                .map(|i| amp * (2.0 * PI * freq * i as f64 / fs).sin())
+                .collect()
+        };
+        let alpha = sine(10.0, 2.0);
+        let theta = sine(6.0, 1.0);
+        let mut spec = FeatureSpec {
+            channels: vec![],
+            bands: vec![
+                BandRange::new("theta", 4.0, 8.0),
+                BandRange::new("alpha", 8.0, 13.0),
+            ],
+            window_seconds: 2.0,
+            scale: BandPowerScale::Relative,
+            aggregation: FeatureAggregation::PerChannel,
+            hjorth: false,
+        };
+        let windows = [alpha.as_slice(), theta.as_slice()];
+        let per_channel = compute_features(&spec, &windows, fs).unwrap();
+        assert_eq!(per_channel.len(), 4);
+        assert!(per_channel[1] > 0.95 && per_channel[2] > 0.95);
+
+        spec.aggregation = FeatureAggregation::MeanStd;
+        let mean_std = compute_features(&spec, &windows, fs).unwrap();
+        assert_eq!(mean_std.len(), 4);
+        assert!((mean_std[1] - 0.5).abs() < 0.05);
+        assert!((mean_std[3] - 0.5).abs() < 0.05);
+
+        let output = OutputSpec {
+            kind: OutputKind::Logits,
+            labels: vec!["relaxed".into(), "focused".into()],
+        };
+        let (values, label) = interpret(&output, vec![0.0, 2.0]);
+        assert!((values.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert_eq!(label.as_deref(), Some("focused"));
+        let (values, label) = interpret(&output, vec![-3.0]);
+        assert!(values[0] < 0.1);
+        assert_eq!(label.as_deref(), Some("relaxed"));
+    }
+}
@@ -0,0 +1,384 @@
+use crate::recording::Record;
+use anyhow::{bail, Result};
+
+// Lossless block codec for recording frames.
+//
+// Muse packets carry 2 bytes of sequence number and 18 bytes of big-endian
+// bit-packed ADC counts: 12 x 12 bit for EEG, 9 x 16 bit for IMU (3 samples
+// of x/y/z) and 6 x 24 bit for PPG. The codec unpacks the counts, predicts
+// each one from the previous sample of the same signal and stores the
+// residual as zigzag varint. Timestamps are delta coded on their f64 bit
+// pattern, so decoding gives back the exact bytes and timestamps.
+//
+// Every block is self-contained (predictor state starts at zero), which is
+// what makes the block index usable for random access.
+
+const CODEC_VERSION: u8 = 1;
+
+const MODE_RAW: u8 = 0;
+const MODE_12: u8 = 1;
+const MODE_16: u8 = 2;
+const MODE_24: u8 = 3;
+
+const PACKET_LEN: usize = 20;
+const PAYLOAD_BITS: usize = 18 * 8;
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn get_varint(buf: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let Some(&byte) = buf.get(*pos) else {
+            bail!("truncated varint");
+        };
+        *pos += 1;
+        if shift >= 64 {
+            bail!("varint overflow");
+        }
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+fn get_u8(buf: &[u8], pos: &mut usize) -> Result<u8> {
+    let Some(&byte) = buf.get(*pos) else {
+        bail!("truncated block");
+    };
+    *pos += 1;
+    Ok(byte)
+}
+
+fn get_bytes<'a>(buf: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8]> {
+    if *pos + len > buf.len() {
+        bail!("truncated block");
+    }
+    let bytes = &buf[*pos..*pos + len];
+    *pos += len;
+    Ok(bytes)
+}
+
+// (bits per value, predictor stride)
+fn mode_layout(mode: u8) -> (usize, usize) {
+    match mode {
+        MODE_12 => (12, 1),
+        MODE_16 => (16, 3), // x/y/z interleaved, predict from the same axis
+        _ => (24, 1),
+    }
+}
+
+fn packet_mode(channel: u8, len: usize, eeg_channels: usize) -> u8 {
+    if len != PACKET_LEN {
+        return MODE_RAW;
+    }
+    match channel as usize {
+        c if c < eeg_channels => MODE_12,
+        5 | 6 => MODE_16,
+        7..=9 => MODE_24,
+        _ => MODE_RAW,
+    }
+}
+
+fn unpack(bytes: &[u8], bits: usize) -> Vec<i64> {
+    let mut values = Vec::with_capacity(PAYLOAD_BITS / bits);
+    let mut acc = 0u64;
+    let mut acc_bits = 0;
+    for &byte in bytes {
+        acc = (acc << 8) | byte as u64;
+        acc_bits += 8;
+        while acc_bits >= bits {
+            acc_bits -= bits;
+            values.push(((acc >> acc_bits) & ((1 << bits) - 1)) as i64);
+        }
+    }
+    values
+}
+
+fn pack(values: &[i64], bits: usize, out: &mut Vec<u8>) {
+    let mut acc = 0u64;
+    let mut acc_bits = 0;
+    for &value in values {
+        acc = (acc << bits) | (value as u64 & ((1 << bits) - 1));
+        acc_bits += bits;
+        while acc_bits >= 8 {
+            acc_bits -= 8;
+            out.push((acc >> acc_bits) as u8);
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+struct ChannelState {
+    seq: i64,
+    values: Vec<i64>,
+}
+
+impl ChannelState {
+    fn predict(&self, current: &[i64], i: usize, stride: usize) -> i64 {
+        if i >= stride {
+            current[i - stride]
+        } else if self.values.len() >= stride {
+            self.values[self.values.len() - stride + i]
+        } else {
+            0
+        }
+    }
+}
+
+// `eeg_channels` is the model's EEG channel count; packets on those BLE
+// channels are coded as 12 bit EEG, see parse_muse_packet for the mapping.
+pub fn encode_block(records: &[Record], eeg_channels: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(records.len() * 16);
+    out.push(CODEC_VERSION);
+    put_varint(&mut out, records.len() as u64);
+
+    let mut last_ts = 0u64;
+    let mut channels = vec![ChannelState::default(); 256];
+    for record in records {
+        let ts = record.timestamp().to_bits();
+        match record {
+            Record::Packet { channel, data, .. } => {
+                let mode = packet_mode(*channel, data.len(), eeg_channels);
+                out.push(0);
+                put_varint(&mut out, zigzag(ts.wrapping_sub(last_ts) as i64));
+                out.push(*channel);
+                out.push(mode);
+                if mode == MODE_RAW {
+                    put_varint(&mut out, data.len() as u64);
+                    out.extend_from_slice(data);
+                } else {
+                    let (bits, stride) = mode_layout(mode);
+                    let state = &mut channels[*channel as usize];
+                    let seq = ((data[0] as i64) << 8) | data[1] as i64;
+                    put_varint(&mut out, zigzag(seq - state.seq));
+                    let values = unpack(&data[2..], bits);
+                    for i in 0..values.len() {
+                        put_varint(
+                            &mut out,
+                            zigzag(values[i] - state.predict(&values, i, stride)),
+                        );
+                    }
+                    state.seq = seq;
+                    state.values = values;
+                }
+            }
+            _ => {
+                // Markers and sample rows are rare, keep their plain encoding
+                let mut raw = Vec::new();
+                record.encode(&mut raw)?;
+                out.push(1);
+                put_varint(&mut out, zigzag(ts.wrapping_sub(last_ts) as i64));
+                put_varint(&mut out, raw.len() as u64);
+                out.extend_from_slice(&raw);
+            }
+        }
+        last_ts = ts;
+    }
+    Ok(out)
+}
+
+pub fn decode_block(buf: &[u8]) -> Result<Vec<Record>> {
+    let mut pos = 0;
+    let version = get_u8(buf, &mut pos)?;
+    if version != CODEC_VERSION {
+        bail!("unsupported codec version {}", version);
+    }
+    let count = get_varint(buf, &mut pos)? as usize;
+    let mut records = Vec::with_capacity(count.min(1 << 16));
+
+    let mut last_ts = 0u64;
+    let mut channels = vec![ChannelState::default(); 256];
+    for _ in 0..count {
+        let tag = get_u8(buf, &mut pos)?;
+        let ts = last_ts.wrapping_add(unzigzag(get_varint(buf, &mut pos)?) as u64);
+        last_ts = ts;
+        let timestamp = f64::from_bits(ts);
+
+        if tag != 0 {
+            let len = get_varint(buf, &mut pos)? as usize;
+            let (record, _) = Record::decode(get_bytes(buf, &mut pos, len)?)?;
+            records.push(record);
+            continue;
+        }
+
+        let channel = get_u8(buf, &mut pos)?;
+        let mode = get_u8(buf, &mut pos)?;
+        let data = if mode == MODE_RAW {
+            let len = get_varint(buf, &mut pos)? as usize;
+            get_bytes(buf, &mut pos, len)?.to_vec()
+        } else {
+            if mode > MODE_24 {
+                bail!("invalid packet mode {}", mode);
+            }
+            let (bits, stride) = mode_layout(mode);
+            let state = &mut channels[channel as usize];
+            let seq = state.seq + unzigzag(get_varint(buf, &mut pos)?);
+            let mut values = Vec::with_capacity(PAYLOAD_BITS / bits);
+            for i in 0..PAYLOAD_BITS / bits {
+                let residual = unzigzag(get_varint(buf, &mut pos)?);
+                values.push(residual + state.predict(&values, i, stride));
+            }
+            let mut data = Vec::with_capacity(PACKET_LEN);
+            data.push((seq >> 8) as u8);
+            data.push(seq as u8);
+            pack(&values, bits, &mut data);
+            state.seq = seq;
+            state.values = values;
+            data
+        };
+        records.push(Record::Packet {
+            timestamp,
+            channel,
+            data,
+        });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // Deterministic pseudo-EEG: alpha + drift + noise around mid-scale
+    fn synthetic_session(seconds: usize) -> Vec<Record> {
+        let mut seed = 0x2545F4914F6CDD1Du64;
+        let mut noise = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % 41) as f64 - 20.0
+        };
+        let mut records = Vec::new();
+        let mut t = 1_760_000_000.0;
+        for packet in 0..seconds * 256 / 12 {
+            for channel in 0..4u8 {
+                let mut values = Vec::with_capacity(12);
+                for s in 0..12 {
+                    let n = (packet * 12 + s) as f64 / 256.0;
+                    let v = 2048.0
+                        + 60.0 * (2.0 * std::f64::consts::PI * 10.0 * n + channel as f64).sin()
+                        + 30.0 * (0.3 * n).sin()
+                        + noise();
+                    values.push(v.round() as i64);
+                }
+                let mut data = vec![(packet >> 8) as u8, packet as u8];
+                pack(&values, 12, &mut data);
+                records.push(Record::Packet {
+                    timestamp: t,
+                    channel,
+                    data,
+                });
+            }
+            if packet % 3 == 0 {
+                let mut data = vec![(packet >> 8) as u8, packet as u8];
+                pack(
+                    &[
+                        100,
+                        -200i64 & 0xFFFF,
+                        16000,
+                        101,
+                        -198i64 & 0xFFFF,
+                        16003,
+                        99,
+                        -201i64 & 0xFFFF,
+                        15998,
+                    ],
+                    16,
+                    &mut data,
+                );
+                records.push(Record::Packet {
+                    timestamp: t,
+                    channel: 5,
+                    data,
+                });
+            }
+            t += 12.0 / 256.0 + noise() * 1e-5;
+        }
+        records
+    }
+
+    #[test]
+    fn varint_zigzag_roundtrip() {
+        for v in [0i64, 1, -1, 63, -64, 1 << 40, i64::MIN, i64::MAX] {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, zigzag(v));
+            let mut pos = 0;
+            assert_eq!(unzigzag(get_varint(&buf, &mut pos).unwrap()), v);
+            assert_eq!(pos, buf.len());
+        }
+    }
+
+    #[test]
+    fn block_roundtrip_is_lossless() {
+        let mut records = synthetic_session(2);
+        records.push(Record::Marker {
+            timestamp: 1.5,
+            value: 2.0,
+            label: "blink".to_string(),
+        });
+        records.push(Record::Packet {
+            timestamp: 2.0,
+            channel: 10,
+            data: vec![1, 2, 3],
+        });
+        records.push(Record::Packet {
+            timestamp: 2.1,
+            channel: 8,
+            data: (0..20).map(|i| i * 13).collect(),
+        });
+        let block = encode_block(&records, 4).unwrap();
+        assert_eq!(decode_block(&block).unwrap(), records);
+    }
+
+    // cargo test --release eeg_codec -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn codec_benchmark() {
+        let records = synthetic_session(600);
+        let mut raw = Vec::new();
+        for r in &records {
+            r.encode(&mut raw).unwrap();
+        }
+        let blocks: Vec<Vec<u8>> = records
+            .chunks(2048)
+            .map(|b| encode_block(b, 4).unwrap())
+            .collect();
+        let compressed: usize = blocks.iter().map(|b| b.len()).sum();
+
+        let start = Instant::now();
+        let mut decoded = 0;
+        for block in &blocks {
+            decoded += decode_block(block).unwrap().len();
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        assert_eq!(decoded, records.len());
+
+        let samples = 600 * 256 * 4;
+        println!(
+            "raw {} B, compressed {} B, ratio {:.2}x, decode {:.1} MB/s, {:.1} M EEG samples/s",
+            raw.len(),
+            compressed,
+            raw.len() as f64 / compressed as f64,
+            raw.len() as f64 / elapsed / 1e6,
+            samples as f64 / elapsed / 1e6
+        );
+    }
+}
@@ -0,0 +1,590 @@
+use crate::api;
+use crate::channel_buffers::{select_channels, ChannelBuffers};
+use crate::recording::{self, Record, SessionReader};
+use crate::replay;
+use crate::session_metadata;
+use anyhow::{bail, Context, Result};
+use brainflow::board_shim::{self, BoardShim};
+use brainflow::{BoardIds, BrainFlowPresets};
+use flutter_rust_bridge::frb;
+use log::info;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Event-related potentials.
+//
+// Epochs [-pre, +post] are cut around marker codes, baseline corrected with
+// the mean of their pre-stimulus interval and rejected when any channel's
+// peak-to-peak amplitude exceeds the threshold. Accepted epochs are summed
+// per condition (a condition groups one or more codes), giving running
+// averages and difference waves between conditions (e.g. oddball minus
+// standard for a P300).
+//
+// Live, markers come from insert_erp_marker: on the Muse stream they are
+// placed by time, on a BoardShim device they go through insert_marker into
+// the ring buffer's marker channel. Recorded sessions are averaged from
+// their markers, whose values are the codes.
+
+static ERP: Mutex<Option<ErpStream>> = Mutex::new(None);
+
+// Recorded marker label of live ERP markers
+pub(crate) const ERP_MARKER: &str = "erp";
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErpCondition {
+    pub name: String,
+    pub codes: Vec<f64>,
+}
+
+// Difference wave `minuend` - `subtrahend`, by condition name
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErpContrast {
+    pub minuend: String,
+    pub subtrahend: String,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErpSettings {
+    pub conditions: Vec<ErpCondition>,
+    pub contrasts: Vec<ErpContrast>,
+    pub pre_seconds: f64,
+    pub post_seconds: f64,
+    pub baseline_correction: bool,
+    // Peak-to-peak limit per channel, µV; 0 disables rejection
+    pub reject_peak_to_peak_uv: f64,
+}
+
+impl Default for ErpSettings {
+    fn default() -> Self {
+        Self {
+            conditions: vec![
+                ErpCondition {
+                    name: "standard".into(),
+                    codes: vec![1.0],
+                },
+                ErpCondition {
+                    name: "oddball".into(),
+                    codes: vec![2.0],
+                },
+            ],
+            contrasts: vec![ErpContrast {
+                minuend: "oddball".into(),
+                subtrahend: "standard".into(),
+            }],
+            pre_seconds: 0.2,
+            post_seconds: 0.8,
+            baseline_correction: true,
+            reject_peak_to_peak_uv: 100.0,
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErpWave {
+    pub name: String,
+    pub accepted: u32,
+    pub rejected: u32,
+    // One average per channel, sampled at ErpReport.times
+    pub channels: Vec<Vec<f64>>,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErpReport {
+    pub channel_names: Vec<String>,
+    // Seconds relative to the marker
+    pub times: Vec<f64>,
+    pub averages: Vec<ErpWave>,
+    // Accepted and rejected are those of the minuend
+    pub differences: Vec<ErpWave>,
+    pub updated_at: f64,
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
+
+pub(crate) struct ErpAverager {
+    settings: ErpSettings,
+    sampling_rate: f64,
+    channel_names: Vec<String>,
+    // Per condition, per channel sums of accepted epochs
+    sums: Vec<Vec<Vec<f64>>>,
+    accepted: Vec<u32>,
+    rejected: Vec<u32>,
+}
+
+impl ErpAverager {
+    pub fn new(settings: ErpSettings, sampling_rate: f64, channel_names: Vec<String>) -> Self {
+        let conditions = settings.conditions.len();
+        let mut averager = Self {
+            settings,
+            sampling_rate,
+            sums: Vec::new(),
+            accepted: vec![0; conditions],
+            rejected: vec![0; conditions],
+            channel_names,
+        };
+        averager.sums =
+            vec![vec![vec![0.0; averager.epoch_len()]; averager.channel_names.len()]; conditions];
+        averager
+    }
+
+    pub fn pre_samples(&self) -> usize {
+        (self.settings.pre_seconds * self.sampling_rate).round() as usize
+    }
+
+    pub fn epoch_len(&self) -> usize {
+        self.pre_samples() + (self.settings.post_seconds * self.sampling_rate).round() as usize
+    }
+
+    pub fn condition(&self, code: f64) -> Option<usize> {
+        self.settings
+            .conditions
+            .iter()
+            .position(|c| c.codes.iter().any(|k| (k - code).abs() < 1e-9))
+    }
+
+    // Baseline corrects, screens and accumulates one epoch (channels x
+    // epoch_len); false if rejected
+    pub fn add_epoch(&mut self, condition: usize, epoch: &[Vec<f64>]) -> bool {
+        let pre = self.pre_samples();
+        let corrected: Vec<Vec<f64>> = epoch
+            .iter()
+            .map(|channel| {
+                let offset = if self.settings.baseline_correction && pre > 0 {
+                    channel[..pre].iter().sum::<f64>() / pre as f64
+                } else {
+                    0.0
+                };
+                channel.iter().map(|v| v - offset).collect()
+            })
+            .collect();
+        let limit = self.settings.reject_peak_to_peak_uv;
+        let artifact = corrected.iter().any(|channel| {
+            let max = channel.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            let min = channel.iter().copied().fold(f64::INFINITY, f64::min);
+            !(max - min).is_finite() || (limit > 0.0 && max - min > limit)
+        });
+        if artifact {
+            self.rejected[condition] += 1;
+            return false;
+        }
+        for (sum, channel) in self.sums[condition].iter_mut().zip(&corrected) {
+            sum.iter_mut().zip(channel).for_each(|(s, v)| *s += v);
+        }
+        self.accepted[condition] += 1;
+        true
+    }
+
+    fn average(&self, condition: usize) -> Vec<Vec<f64>> {
+        let n = self.accepted[condition].max(1) as f64;
+        self.sums[condition]
+            .iter()
+            .map(|sum| sum.iter().map(|s| s / n).collect())
+            .collect()
+    }
+
+    pub fn report(&self) -> ErpReport {
+        let pre = self.pre_samples() as f64;
+        let averages: Vec<ErpWave> = self
+            .settings
+            .conditions
+            .iter()
+            .enumerate()
+            .map(|(i, condition)| ErpWave {
+                name: condition.name.clone(),
+                accepted: self.accepted[i],
+                rejected: self.rejected[i],
+                channels: self.average(i),
+            })
+            .collect();
+        let find = |name: &str| averages.iter().find(|w| w.name == name);
+        let differences = self
+            .settings
+            .contrasts
+            .iter()
+            .filter_map(|contrast| {
+                let (a, b) = (find(&contrast.minuend)?, find(&contrast.subtrahend)?);
+                if a.accepted == 0 || b.accepted == 0 {
+                    return None;
+                }
+                Some(ErpWave {
+                    name: format!("{} - {}", a.name, b.name),
+                    accepted: a.accepted,
+                    rejected: a.rejected,
+                    channels: a
+                        .channels
+                        .iter()
+                        .zip(&b.channels)
+                        .map(|(x, y)| x.iter().zip(y).map(|(x, y)| x - y).collect())
+                        .collect(),
+                })
+            })
+            .collect();
+        ErpReport {
+            channel_names: self.channel_names.clone(),
+            times: (0..self.epoch_len())
+                .map(|i| (i as f64 - pre) / self.sampling_rate)
+                .collect(),
+            averages,
+            differences,
+            updated_at: now(),
+        }
+    }
+}
+
+enum Source {
+    Muse,
+    Board {
+        board_id: BoardIds,
+        timestamp_row: usize,
+        marker_row: usize,
+        last_timestamp: f64,
+    },
+}
+
+// Live epoching on a continuous multi-channel stream
+pub(crate) struct ErpStream {
+    averager: ErpAverager,
+    source: Source,
+    // By source index (Muse channel or board data row)
+    buffers: ChannelBuffers,
+    // Time of the newest sample on all channels
+    latest_time: f64,
+    // Condition and absolute marker sample
+    pending: Vec<(usize, i64)>,
+    dropped: u32,
+    fresh: bool,
+}
+
+impl ErpStream {
+    fn new(averager: ErpAverager, source: Source, channels: Vec<usize>) -> Self {
+        // An epoch plus the board's largest read (5 s)
+        let fs = averager.sampling_rate;
+        let seconds = averager.epoch_len() as f64 / fs + 5.0;
+        Self {
+            buffers: ChannelBuffers::for_window(channels, seconds, fs),
+            averager,
+            source,
+            latest_time: 0.0,
+            pending: Vec::new(),
+            dropped: 0,
+            fresh: false,
+        }
+    }
+
+    fn complete(&self) -> usize {
+        self.buffers.complete()
+    }
+
+    fn push(&mut self, source_index: usize, samples: &[f64], timestamp: f64) {
+        let Some(c) = self.buffers.push(source_index, samples) else {
+            return;
+        };
+        if self.buffers.received(c) == self.complete() {
+            self.latest_time = timestamp;
+        }
+        self.collect_epochs();
+    }
+
+    // Marker at absolute sample `sample` of the stream
+    fn mark_sample(&mut self, code: f64, sample: i64) {
+        if let Some(condition) = self.averager.condition(code) {
+            self.pending.push((condition, sample));
+        }
+    }
+
+    // Marker at a wall clock time, placed relative to the newest sample
+    fn mark_time(&mut self, code: f64, time: f64) {
+        let lag = ((self.latest_time - time) * self.averager.sampling_rate).round() as i64;
+        self.mark_sample(code, self.complete() as i64 - 1 - lag);
+    }
+
+    fn collect_epochs(&mut self) {
+        let complete = self.complete() as i64;
+        let pre = self.averager.pre_samples() as i64;
+        let len = self.averager.epoch_len();
+        let (ready, pending): (Vec<_>, Vec<_>) = self
+            .pending
+            .iter()
+            .partition(|(_, sample)| sample - pre + len as i64 <= complete);
+        self.pending = pending;
+        for (condition, sample) in ready {
+            let epoch: Option<Vec<Vec<f64>>> = usize::try_from(sample - pre)
+                .ok()
+                .and_then(|start| self.buffers.windows(start + len, len))
+                .map(|windows| windows.into_iter().map(<[f64]>::to_vec).collect());
+            match epoch {
+                Some(epoch) => {
+                    self.averager.add_epoch(condition, &epoch);
+                    self.fresh = true;
+                }
+                None => self.dropped += 1,
+            }
+        }
+    }
+}
+
+pub(crate) fn start_muse(settings: ErpSettings, sampling_rate: f64, channel_names: &[String]) {
+    let (channels, names) = select_channels(&[], channel_names);
+    let averager = ErpAverager::new(settings, sampling_rate, names);
+    *ERP.lock().unwrap() = Some(ErpStream::new(averager, Source::Muse, channels));
+}
+
+pub(crate) fn push_muse_samples(channel: usize, samples: &[f64], timestamp: f64) {
+    let mut erp = ERP.lock().unwrap();
+    if let Some(stream) = erp.as_mut().filter(|s| matches!(s.source, Source::Muse)) {
+        stream.push(channel, samples, timestamp);
+    }
+}
+
+// The Muse stream restarted (parser init, replay open or seek): sample
+// positions start over and pending markers are dropped. The averages are
+// kept for the same sampling rate and channels; otherwise averaging stops.
+pub(crate) fn restart_muse(sampling_rate: f64, channel_names: &[String]) {
+    let mut erp = ERP.lock().unwrap();
+    let Some(stream) = erp.as_mut().filter(|s| matches!(s.source, Source::Muse)) else {
+        return;
+    };
+    let (_, names) = select_channels(&[], channel_names);
+    if stream.averager.sampling_rate != sampling_rate || stream.averager.channel_names != names {
+        info!("[ERP] Muse stream changed, stopping ERP averaging");
+        *erp = None;
+        return;
+    }
+    stream.buffers.reset();
+    stream.latest_time = 0.0;
+    stream.pending.clear();
+}
+
+// Starts live ERP averaging on the EEG channels of the connected BoardShim
+#[frb]
+pub fn start_erp_for_board(settings: ErpSettings) -> Result<Vec<String>> {
+    let board_id = api::with_board(|board| Ok(board.get_board_id()))?;
+    let preset = BrainFlowPresets::DefaultPreset;
+    let rows = board_shim::get_eeg_channels(board_id, preset)?;
+    let names = board_shim::get_eeg_names(board_id, preset)
+        .unwrap_or_else(|_| rows.iter().map(|r| format!("EEG{}", r)).collect());
+    let sampling_rate = board_shim::get_sampling_rate(board_id, preset)? as f64;
+    let source = Source::Board {
+        board_id,
+        timestamp_row: board_shim::get_timestamp_channel(board_id, preset)?,
+        marker_row: board_shim::get_marker_channel(board_id, preset)?,
+        last_timestamp: f64::NEG_INFINITY,
+    };
+    let averager = ErpAverager::new(settings, sampling_rate, names.clone());
+    *ERP.lock().unwrap() = Some(ErpStream::new(averager, source, rows));
+    Ok(names)
+}
+
+// Feeds the board samples and markers that arrived since the last call;
+// call it at least every few seconds. Returns the number of new samples.
+#[frb]
+pub fn update_erp_from_board() -> Result<u32> {
+    let mut guard = ERP.lock().unwrap();
+    let stream = guard.as_mut().context("ERP not started")?;
+    let Source::Board {
+        board_id,
+        timestamp_row,
+        marker_row,
+        last_timestamp,
+    } = stream.source
+    else {
+        bail!("ERP runs on the Muse stream");
+    };
+    let recent = (stream.averager.sampling_rate * 5.0) as usize;
+    let data = api::with_board(|board: &BoardShim| {
+        if board.get_board_id() != board_id {
+            bail!("Board changed since ERP started");
+        }
+        Ok(board.get_current_board_data(recent, BrainFlowPresets::DefaultPreset)?)
+    })?;
+    let timestamps = data.row(timestamp_row);
+    let new: Vec<usize> = (0..timestamps.len())
+        .filter(|&i| timestamps[i] > last_timestamp)
+        .collect();
+    let Some(&last) = new.last() else {
+        return Ok(0);
+    };
+    let first_sample = stream.complete() as i64;
+    for (k, &i) in new.iter().enumerate() {
+        let code = data[[marker_row, i]];
+        if code != 0.0 {
+            stream.mark_sample(code, first_sample + k as i64);
+        }
+    }
+    let newest = timestamps[last];
+    for row in stream.buffers.channels().to_vec() {
+        let samples: Vec<f64> = new.iter().map(|&i| data[[row, i]]).collect();
+        stream.push(row, &samples, newest);
+    }
+    stream.source = Source::Board {
+        board_id,
+        timestamp_row,
+        marker_row,
+        last_timestamp: newest,
+    };
+    Ok(new.len() as u32)
+}
+
+// Marks a stimulus of `code`, at `timestamp` (seconds since the epoch) or
+// now. Also written to the recording, if one runs.
+#[frb]
+pub fn insert_erp_marker(code: f64, timestamp: Option<f64>) -> Result<()> {
+    if code == 0.0 {
+        bail!("Marker code 0 is reserved");
+    }
+    let mut guard = ERP.lock().unwrap();
+    let stream = guard.as_mut().context("ERP not started")?;
+    let timestamp = timestamp.unwrap_or_else(now);
+    match stream.source {
+        // The board timestamps its marker with the sample it arrives at
+        Source::Board { .. } => {
+            api::with_board(
+                |board| Ok(board.insert_marker(code, BrainFlowPresets::DefaultPreset)?),
+            )?
+        }
+        Source::Muse => stream.mark_time(code, timestamp),
+    }
+    recording::record_marker_at(timestamp, code, ERP_MARKER);
+    Ok(())
+}
+
+// Running averages, only when epochs were added since the last call
+#[frb]
+pub fn take_erp_update() -> Option<ErpReport> {
+    let mut guard = ERP.lock().unwrap();
+    let stream = guard.as_mut()?;
+    if !stream.fresh {
+        return None;
+    }
+    stream.fresh = false;
+    Some(stream.averager.report())
+}
+
+#[frb]
+pub fn get_erp_report() -> Option<ErpReport> {
+    let guard = ERP.lock().unwrap();
+    guard.as_ref().map(|stream| stream.averager.report())
+}
+
+#[frb]
+pub fn stop_erp() -> Option<ErpReport> {
+    ERP.lock()
+        .unwrap()
+        .take()
+        .map(|stream| stream.averager.report())
+}
+
+// Averages a recorded session around its ERP markers, whose values are
+// condition codes
+#[frb]
+pub fn compute_session_erp(session_dir: String, settings: ErpSettings) -> Result<ErpReport> {
+    let metadata = session_metadata::read_session_metadata(session_dir.clone())?;
+    let model = metadata.device.model;
+    let sampling_rate = metadata.pipeline.eeg_sampling_rate as f64;
+    let dir = Path::new(&session_dir);
+    let eeg = replay::decode_eeg_channels(dir, model)?;
+    let (indices, names) = select_channels(&[], &model.eeg_channel_names());
+    let mut averager = ErpAverager::new(settings, sampling_rate, names);
+    let pre = averager.pre_samples() as i64;
+    let len = averager.epoch_len();
+    for record in SessionReader::open(dir)? {
+        let Record::Marker {
+            timestamp,
+            value,
+            label,
+        } = record?
+        else {
+            continue;
+        };
+        if label != ERP_MARKER {
+            continue;
+        }
+        let Some(condition) = averager.condition(value) else {
+            continue;
+        };
+        let marker = ((timestamp - eeg.start_time) * sampling_rate).round() as i64;
+        let Ok(start) = usize::try_from(marker - pre) else {
+            continue;
+        };
+        let epoch: Option<Vec<Vec<f64>>> = indices
+            .iter()
+            .map(|&c| eeg.channels[c].get(start..start + len).map(<[f64]>::to_vec))
+            .collect();
+        if let Some(epoch) = epoch {
+            averager.add_epoch(condition, &epoch);
+        }
+    }
+    Ok(averager.report())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_baseline_corrected_epochs_and_differences() {
+        let settings = ErpSettings {
+            pre_seconds: 0.1,
+            post_seconds: 0.4,
+            reject_peak_to_peak_uv: 50.0,
+            ..Default::default()
+        };
+        let averager = ErpAverager::new(settings, 100.0, vec!["AF7".into(), "AF8".into()]);
+        let mut stream = ErpStream::new(averager, Source::Muse, vec![1, 2]);
+
+        // DC offset of 500 µV, a 10 µV deflection 300 ms after oddballs,
+        // one oddball with a 200 µV blink
+        let oddballs = [150usize, 250, 350];
+        let signal: Vec<f64> = (0..500)
+            .map(|i| {
+                let mut v = 500.0;
+                if oddballs.iter().any(|&m| i >= m + 25 && i < m + 35) {
+                    v += 10.0;
+                }
+                if (360..370).contains(&i) {
+                    v += 200.0;
+                }
+                v
+            })
+            .collect();
+        for (packet, chunk) in signal.chunks(10).enumerate() {
+            let time = (packet * 10 + 9) as f64 / 100.0;
+            for channel in [1, 2] {
+                stream.push(channel, chunk, time);
+            }
+            let complete = stream.complete();
+            for m in [50, 100, 200].into_iter().chain(oddballs) {
+                if m + 10 == complete {
+                    let code = if oddballs.contains(&m) { 2.0 } else { 1.0 };
+                    // Marked by time, at the first sample of the packet
+                    stream.mark_time(code, time - 0.09);
+                }
+            }
+        }
+
+        let report = stream.averager.report();
+        assert_eq!(report.times.len(), 50);
+        assert!((report.times[0] + 0.1).abs() < 1e-9);
+        let standard = &report.averages[0];
+        let oddball = &report.averages[1];
+        assert_eq!((standard.accepted, standard.rejected), (3, 0));
+        assert_eq!((oddball.accepted, oddball.rejected), (2, 1));
+        assert!(standard.channels[0].iter().all(|v| v.abs() < 1e-9));
+        // 300 ms after the marker is index 40
+        assert!((oddball.channels[1][40] - 10.0).abs() < 1e-9);
+        assert!(oddball.channels[1][5].abs() < 1e-9);
+        assert_eq!(report.differences[0].name, "oddball - standard");
+        assert!((report.differences[0].channels[0][40] - 10.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,456 @@
+use crate::recording::{self, MarkerEntry};
+use crate::replay;
+use crate::session_crypto;
+use crate::session_metadata::{self, SessionMetadata};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use flutter_rust_bridge::frb;
+use log::info;
+use std::io::Write;
+use std::path::Path;
+
+// Export of recorded sessions to EDF+ and XDF.
+//
+// XDF carries the full metadata JSON in the EEG stream's <desc>. EDF+ header
+// fields are limited to 80 ASCII chars, so they get the condensed form
+// (subject, session, device/firmware) and markers become annotations.
+// Exports of encrypted sessions are sealed with the session's key (see
+// session_crypto::decrypt_export_file).
+
+struct ExportInput {
+    metadata: SessionMetadata,
+    start_time: f64,
+    // Only the channels that streamed (AUX often doesn't)
+    names: Vec<String>,
+    channels: Vec<Vec<f64>>,
+    // Samples every exported channel has
+    sample_count: usize,
+    markers: Vec<MarkerEntry>,
+}
+
+fn load_export_input(session_dir: &str) -> Result<ExportInput> {
+    let metadata = session_metadata::read_session_metadata(session_dir.to_string())?;
+    let model = metadata.device.model;
+    let eeg = replay::decode_eeg_channels(Path::new(session_dir), model)?;
+    let markers = recording::read_session_manifest(session_dir)
+        .map(|m| m.markers)
+        .unwrap_or_default();
+    // Channels stripped from the session are gone from the metadata list
+    let (names, channels): (Vec<String>, Vec<Vec<f64>>) = model
+        .eeg_channel_names()
+        .into_iter()
+        .zip(eeg.channels)
+        .filter(|(name, data)| !data.is_empty() && metadata.sampling.eeg_channels.contains(name))
+        .unzip();
+    if channels.is_empty() {
+        bail!("Session {} has no EEG data", session_dir);
+    }
+    let sample_count = channels.iter().map(Vec::len).min().unwrap_or(0);
+    Ok(ExportInput {
+        metadata,
+        start_time: eeg.start_time,
+        names,
+        channels,
+        sample_count,
+        markers,
+    })
+}
+
+// EDF fields are space padded ASCII; EDF+ subfields must not contain spaces
+fn edf_field(value: &str, len: usize) -> Vec<u8> {
+    let mut bytes: Vec<u8> = value
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c as u8
+            } else {
+                b'_'
+            }
+        })
+        .take(len)
+        .collect();
+    bytes.resize(len, b' ');
+    bytes
+}
+
+fn edf_subfield(value: Option<&str>) -> String {
+    match value {
+        Some(v) if !v.trim().is_empty() => v.trim().replace(' ', "_"),
+        _ => "X".to_string(),
+    }
+}
+
+fn edf_tal(onset: f64, text: &str) -> Vec<u8> {
+    let mut tal = format!("{:+}\x14{}\x14", onset, text).into_bytes();
+    tal.push(0);
+    tal
+}
+
+#[frb]
+pub fn export_edf(session_dir: String, file_name: String) -> Result<u64> {
+    let input = load_export_input(&session_dir)?;
+    let metadata = &input.metadata;
+    let sampling_rate = metadata.pipeline.eeg_sampling_rate;
+    let record_count = input.sample_count / sampling_rate;
+    if record_count == 0 {
+        bail!("Session is shorter than one EDF data record");
+    }
+
+    let resolution = metadata.device.model.resolution();
+    let scale = resolution.scale_factor();
+    let digital_max = resolution.offset() as i32 - 1;
+    let digital_min = -(resolution.offset() as i32);
+    let start: DateTime<Utc> =
+        DateTime::from_timestamp(input.start_time as i64, 0).unwrap_or_default();
+
+    // One TAL per record for time keeping, markers go into the record they fall in
+    let mut annotations: Vec<Vec<u8>> = (0..record_count)
+        .map(|r| {
+            let mut tal = format!("{:+}\x14\x14", r).into_bytes();
+            tal.push(0);
+            tal
+        })
+        .collect();
+    for marker in &input.markers {
+        let onset = marker.timestamp - input.start_time;
+        // Markers outside the exported records have no valid onset
+        if onset < 0.0 || onset >= record_count as f64 {
+            continue;
+        }
+        let record = onset as usize;
+        let text = if marker.label.is_empty() {
+            marker.value.to_string()
+        } else {
+            format!("{} ({})", marker.label, marker.value)
+        };
+        annotations[record].extend(edf_tal(onset, &text));
+    }
+    let annotation_samples = annotations
+        .iter()
+        .map(|a| a.len())
+        .max()
+        .unwrap_or(0)
+        .div_ceil(2);
+
+    let eeg_signals = input.channels.len();
+    let signals = eeg_signals + 1;
+    let device = format!(
+        "{:?}{}",
+        metadata.device.model,
+        metadata
+            .device
+            .firmware_version
+            .as_deref()
+            .map(|fw| format!("_fw{}", fw))
+            .unwrap_or_default()
+    );
+
+    let mut header = Vec::with_capacity(256 * (signals + 1));
+    header.extend(edf_field("0", 8));
+    header.extend(edf_field(
+        &format!("{} X X X", edf_subfield(metadata.subject_id.as_deref())),
+        80,
+    ));
+    header.extend(edf_field(
+        &format!(
+            "Startdate {} {} X {}",
+            start.format("%d-%b-%Y").to_string().to_uppercase(),
+            edf_subfield(Path::new(&session_dir).file_name().and_then(|n| n.to_str())),
+            edf_subfield(Some(&device)),
+        ),
+        80,
+    ));
+    header.extend(edf_field(&start.format("%d.%m.%y").to_string(), 8));
+    header.extend(edf_field(&start.format("%H.%M.%S").to_string(), 8));
+    header.extend(edf_field(&(256 * (signals + 1)).to_string(), 8));
+    header.extend(edf_field("EDF+C", 44));
+    header.extend(edf_field(&record_count.to_string(), 8));
+    header.extend(edf_field("1", 8));
+    header.extend(edf_field(&signals.to_string(), 4));
+
+    let labels: Vec<String> = input
+        .names
+        .iter()
+        .map(|name| format!("EEG {}", name))
+        .chain(std::iter::once("EDF Annotations".to_string()))
+        .collect();
+    for label in &labels {
+        header.extend(edf_field(label, 16));
+    }
+    for _ in 0..signals {
+        header.extend(edf_field("", 80));
+    }
+    for i in 0..signals {
+        header.extend(edf_field(if i < eeg_signals { "uV" } else { "" }, 8));
+    }
+    for i in 0..signals {
+        let v = if i < eeg_signals {
+            format!("{:.3}", digital_min as f64 * scale)
+        } else {
+            "-1".into()
+        };
+        header.extend(edf_field(&v, 8));
+    }
+    for i in 0..signals {
+        let v = if i < eeg_signals {
+            format!("{:.3}", digital_max as f64 * scale)
+        } else {
+            "1".into()
+        };
+        header.extend(edf_field(&v, 8));
+    }
+    for i in 0..signals {
+        let v = if i < eeg_signals { digital_min } else { -32768 };
+        header.extend(edf_field(&v.to_string(), 8));
+    }
+    for i in 0..signals {
+        let v = if i < eeg_signals { digital_max } else { 32767 };
+        header.extend(edf_field(&v.to_string(), 8));
+    }
+    for _ in 0..signals {
+        header.extend(edf_field("", 80));
+    }
+    for i in 0..signals {
+        let n = if i < eeg_signals {
+            sampling_rate
+        } else {
+            annotation_samples
+        };
+        header.extend(edf_field(&n.to_string(), 8));
+    }
+    for _ in 0..signals {
+        header.extend(edf_field("", 32));
+    }
+
+    let mut out = session_crypto::export_writer(&file_name, &input.metadata)?;
+    out.write_all(&header)?;
+    for (record, annotation) in annotations.iter().enumerate() {
+        for channel in &input.channels {
+            for value in &channel[record * sampling_rate..(record + 1) * sampling_rate] {
+                let digital = (value / scale).round() as i32;
+                out.write_all(&(digital.clamp(digital_min, digital_max) as i16).to_le_bytes())?;
+            }
+        }
+        let mut padded = annotation.clone();
+        padded.resize(annotation_samples * 2, 0);
+        out.write_all(&padded)?;
+    }
+    out.finish()?;
+
+    info!(
+        "[EXPORT] Wrote {} EDF records to {}",
+        record_count, file_name
+    );
+    Ok(record_count as u64)
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn xdf_chunk(out: &mut impl Write, tag: u16, content: &[u8]) -> Result<()> {
+    let len = content.len() as u64 + 2;
+    if len <= u8::MAX as u64 {
+        out.write_all(&[1, len as u8])?;
+    } else if len <= u32::MAX as u64 {
+        out.write_all(&[4])?;
+        out.write_all(&(len as u32).to_le_bytes())?;
+    } else {
+        out.write_all(&[8])?;
+        out.write_all(&len.to_le_bytes())?;
+    }
+    out.write_all(&tag.to_le_bytes())?;
+    out.write_all(content)?;
+    Ok(())
+}
+
+fn xdf_varlen(out: &mut Vec<u8>, value: usize) {
+    if value <= u8::MAX as usize {
+        out.extend([1, value as u8]);
+    } else {
+        out.push(4);
+        out.extend((value as u32).to_le_bytes());
+    }
+}
+
+const XDF_EEG_STREAM: u32 = 1;
+const XDF_MARKER_STREAM: u32 = 2;
+const XDF_SAMPLES_PER_CHUNK: usize = 1024;
+
+#[frb]
+pub fn export_xdf(session_dir: String, file_name: String) -> Result<u64> {
+    let input = load_export_input(&session_dir)?;
+    let metadata = &input.metadata;
+    let sampling_rate = metadata.pipeline.eeg_sampling_rate as f64;
+    let sample_count = input.sample_count;
+    let eeg_channels = input.channels.len();
+    let start = input.start_time;
+    let source_id = Path::new(&session_dir)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut out = session_crypto::export_writer(&file_name, &input.metadata)?;
+    out.write_all(b"XDF:")?;
+
+    let datetime: DateTime<Utc> = DateTime::from_timestamp(start as i64, 0).unwrap_or_default();
+    xdf_chunk(
+        &mut out,
+        1,
+        format!(
+            "<?xml version=\"1.0\"?><info><version>1.0</version><datetime>{}</datetime></info>",
+            datetime.to_rfc3339()
+        )
+        .as_bytes(),
+    )?;
+
+    let channels_xml: String = input
+        .names
+        .iter()
+        .map(|name| {
+            format!(
+                "<channel><label>{}</label><unit>microvolts</unit><type>EEG</type></channel>",
+                xml_escape(name)
+            )
+        })
+        .collect();
+    let mut stream_header = XDF_EEG_STREAM.to_le_bytes().to_vec();
+    stream_header.extend(
+        format!(
+            "<?xml version=\"1.0\"?><info><name>{:?} EEG</name><type>EEG</type>\
+             <channel_count>{}</channel_count><nominal_srate>{}</nominal_srate>\
+             <channel_format>float32</channel_format><source_id>{}</source_id>\
+             <desc><channels>{}</channels><session_metadata>{}</session_metadata></desc></info>",
+            metadata.device.model,
+            eeg_channels,
+            sampling_rate,
+            xml_escape(&source_id),
+            channels_xml,
+            xml_escape(&metadata.to_json()?)
+        )
+        .as_bytes(),
+    );
+    xdf_chunk(&mut out, 2, &stream_header)?;
+
+    let mut marker_header = XDF_MARKER_STREAM.to_le_bytes().to_vec();
+    marker_header.extend(
+        format!(
+            "<?xml version=\"1.0\"?><info><name>Markers</name><type>Markers</type>\
+             <channel_count>1</channel_count><nominal_srate>0</nominal_srate>\
+             <channel_format>string</channel_format><source_id>{}</source_id></info>",
+            xml_escape(&source_id)
+        )
+        .as_bytes(),
+    );
+    xdf_chunk(&mut out, 2, &marker_header)?;
+
+    // Only the first sample of each chunk carries a timestamp, the rest follow nominal_srate
+    for chunk_start in (0..sample_count).step_by(XDF_SAMPLES_PER_CHUNK) {
+        let chunk_end = (chunk_start + XDF_SAMPLES_PER_CHUNK).min(sample_count);
+        let mut content = XDF_EEG_STREAM.to_le_bytes().to_vec();
+        xdf_varlen(&mut content, chunk_end - chunk_start);
+        for i in chunk_start..chunk_end {
+            if i == chunk_start {
+                content.push(8);
+                content.extend((start + i as f64 / sampling_rate).to_le_bytes());
+            } else {
+                content.push(0);
+            }
+            for channel in &input.channels {
+                content.extend((channel[i] as f32).to_le_bytes());
+            }
+        }
+        xdf_chunk(&mut out, 3, &content)?;
+    }
+
+    if !input.markers.is_empty() {
+        let mut content = XDF_MARKER_STREAM.to_le_bytes().to_vec();
+        xdf_varlen(&mut content, input.markers.len());
+        for marker in &input.markers {
+            content.push(8);
+            content.extend(marker.timestamp.to_le_bytes());
+            let text = if marker.label.is_empty() {
+                marker.value.to_string()
+            } else {
+                marker.label.clone()
+            };
+            xdf_varlen(&mut content, text.len());
+            content.extend(text.as_bytes());
+        }
+        xdf_chunk(&mut out, 3, &content)?;
+    }
+
+    let last = start + sample_count.saturating_sub(1) as f64 / sampling_rate;
+    for (stream, first, last, count) in [
+        (XDF_EEG_STREAM, start, last, sample_count),
+        (
+            XDF_MARKER_STREAM,
+            input.markers.first().map(|m| m.timestamp).unwrap_or(0.0),
+            input.markers.last().map(|m| m.timestamp).unwrap_or(0.0),
+            input.markers.len(),
+        ),
+    ] {
+        let mut footer = stream.to_le_bytes().to_vec();
+        footer.extend(
+            format!(
+                "<?xml version=\"1.0\"?><info><first_timestamp>{}</first_timestamp>\
+                 <last_timestamp>{}</last_timestamp><sample_count>{}</sample_count></info>",
+                first, last, count
+            )
+            .as_bytes(),
+        );
+        xdf_chunk(&mut out, 6, &footer)?;
+    }
+    out.finish()?;
+
+    info!(
+        "[EXPORT] Wrote {} XDF samples to {}",
+        sample_count, file_name
+    );
+    Ok(sample_count as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::muse_types::{MuseModel, PipelineSettings};
+    use crate::recording::{Record, RecorderConfig, SessionRecorder};
+    use std::fs;
+
+    #[test]
+    fn edf_export_writes_one_record_per_second() {
+        let dir = std::env::temp_dir().join(format!("muse_export_edf_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let metadata = SessionMetadata::new(MuseModel::MuseS, PipelineSettings::default());
+        let mut rec = SessionRecorder::create(&dir, RecorderConfig::default(), metadata).unwrap();
+        // 2.5 s of EEG on 4 channels, 12 samples per packet; AUX never streams
+        for i in 0..54u16 {
+            for channel in 0..4u8 {
+                let mut data = vec![0x80; 20];
+                data[..2].copy_from_slice(&i.to_be_bytes());
+                rec.write(&Record::Packet {
+                    timestamp: 1000.0 + i as f64 * 12.0 / 256.0,
+                    channel,
+                    data,
+                })
+                .unwrap();
+            }
+        }
+        rec.write_marker(3.0, "task").unwrap();
+        rec.finish().unwrap();
+
+        let file = dir.join("out.edf");
+        let records = export_edf(dir.display().to_string(), file.display().to_string()).unwrap();
+        assert_eq!(records, 2);
+        let bytes = fs::read(&file).unwrap();
+        assert_eq!(&bytes[236..244], b"2       ");
+        let header_len = 256 * 6;
+        let annotation_bytes = (bytes.len() - header_len) / 2 - 4 * 256 * 2;
+        assert!(annotation_bytes > 0);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
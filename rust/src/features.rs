@@ -0,0 +1,513 @@
+use crate::channel_buffers::{select_channels, ChannelBuffers};
+use crate::custom_model::hjorth;
+use crate::replay;
+use crate::session_metadata;
+use crate::spectral::{self, Psd};
+use anyhow::{bail, Result};
+use flutter_rust_bridge::frb;
+use std::collections::VecDeque;
+use std::path::Path;
+
+// EEG feature library for research exports and custom models.
+//
+// Per channel and window: Hjorth activity / mobility / complexity, spectral
+// entropy and spectral edge frequency, Higuchi and Katz fractal dimension,
+// sample entropy, and the aperiodic (1/f) exponent and offset. The spectral
+// features share one Welch PSD per window (spectral.rs, as BrainFlow's Psd
+// keeps its bins private). The aperiodic component is a line fitted to
+// log10 power over log10 frequency; bins rising above that first fit (the
+// alpha or beta peaks) are dropped and the line is fitted again.
+//
+// The same per-window computation backs the streaming feature vector of the
+// live Muse stream and the batch API over recorded sessions.
+
+const MAX_QUEUED: usize = 256;
+// Residual (log10 power) above which a bin counts as a periodic peak
+const PEAK_RESIDUAL: f64 = 0.1;
+const MIN_POWER: f64 = 1e-12;
+
+// Order of the per-channel values in FeatureVector::values
+pub const FEATURE_NAMES: [&str; 10] = [
+    "hjorth_activity",
+    "hjorth_mobility",
+    "hjorth_complexity",
+    "spectral_entropy",
+    "spectral_edge_frequency",
+    "higuchi_fd",
+    "katz_fd",
+    "sample_entropy",
+    "aperiodic_exponent",
+    "aperiodic_offset",
+];
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureSettings {
+    pub window_seconds: f64,
+    pub step_seconds: f64,
+    // Channel names; empty uses every EEG channel
+    pub channels: Vec<String>,
+    // Range of the spectral entropy and edge frequency
+    pub spectrum_low: f64,
+    pub spectrum_high: f64,
+    // Fraction of the power below the spectral edge frequency
+    pub spectral_edge: f64,
+    // Range of the aperiodic fit
+    pub aperiodic_low: f64,
+    pub aperiodic_high: f64,
+    pub higuchi_kmax: u32,
+    pub sample_entropy_m: u32,
+    // Tolerance as a fraction of the window SD
+    pub sample_entropy_r: f64,
+    // Sample entropy is quadratic in the window length, so only the last
+    // samples up to this count are used
+    pub sample_entropy_max_samples: u32,
+}
+
+impl Default for FeatureSettings {
+    fn default() -> Self {
+        Self {
+            window_seconds: 4.0,
+            step_seconds: 1.0,
+            channels: vec![],
+            spectrum_low: 1.0,
+            spectrum_high: 40.0,
+            spectral_edge: 0.95,
+            aperiodic_low: 2.0,
+            aperiodic_high: 40.0,
+            higuchi_kmax: 10,
+            sample_entropy_m: 2,
+            sample_entropy_r: 0.2,
+            sample_entropy_max_samples: 512,
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelFeatures {
+    pub channel: String,
+    pub hjorth_activity: f64,
+    pub hjorth_mobility: f64,
+    pub hjorth_complexity: f64,
+    // Normalized 0-1
+    pub spectral_entropy: f64,
+    pub spectral_edge_frequency: f64,
+    pub higuchi_fd: f64,
+    pub katz_fd: f64,
+    // None without matching templates
+    pub sample_entropy: Option<f64>,
+    pub aperiodic_exponent: f64,
+    pub aperiodic_offset: f64,
+}
+
+impl ChannelFeatures {
+    fn values(&self) -> [f64; 10] {
+        [
+            self.hjorth_activity,
+            self.hjorth_mobility,
+            self.hjorth_complexity,
+            self.spectral_entropy,
+            self.spectral_edge_frequency,
+            self.higuchi_fd,
+            self.katz_fd,
+            self.sample_entropy.unwrap_or(f64::NAN),
+            self.aperiodic_exponent,
+            self.aperiodic_offset,
+        ]
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureVector {
+    // End of the window
+    pub timestamp: f64,
+    pub channels: Vec<ChannelFeatures>,
+    // FEATURE_NAMES per channel, channel after channel; NaN for a missing
+    // sample entropy
+    pub names: Vec<String>,
+    pub values: Vec<f64>,
+}
+
+impl FeatureVector {
+    fn new(timestamp: f64, channels: Vec<ChannelFeatures>) -> Self {
+        let names = channels
+            .iter()
+            .flat_map(|c| {
+                FEATURE_NAMES
+                    .iter()
+                    .map(move |f| format!("{}_{}", c.channel, f))
+            })
+            .collect();
+        let values = channels.iter().flat_map(|c| c.values()).collect();
+        Self {
+            timestamp,
+            channels,
+            names,
+            values,
+        }
+    }
+}
+
+fn in_range<'a>(psd: &'a Psd, low: f64, high: f64) -> impl Iterator<Item = (f64, f64)> + 'a {
+    psd.frequencies
+        .iter()
+        .zip(&psd.power)
+        .filter(move |(f, _)| **f >= low && **f <= high)
+        .map(|(f, p)| (*f, *p))
+}
+
+// Shannon entropy of the normalized spectrum over log(bins), 0-1
+pub(crate) fn spectral_entropy(psd: &Psd, low: f64, high: f64) -> f64 {
+    let bins: Vec<f64> = in_range(psd, low, high).map(|(_, p)| p).collect();
+    let total: f64 = bins.iter().sum();
+    if bins.len() < 2 || total <= 0.0 {
+        return 0.0;
+    }
+    let entropy: f64 = bins
+        .iter()
+        .map(|p| p / total)
+        .filter(|p| *p > 0.0)
+        .map(|p| -p * p.ln())
+        .sum();
+    entropy / (bins.len() as f64).ln()
+}
+
+// Frequency below which `fraction` of the power in the range lies
+pub(crate) fn spectral_edge_frequency(psd: &Psd, low: f64, high: f64, fraction: f64) -> f64 {
+    let total: f64 = in_range(psd, low, high).map(|(_, p)| p).sum();
+    let mut cumulative = 0.0;
+    for (f, p) in in_range(psd, low, high) {
+        cumulative += p;
+        if cumulative >= fraction * total {
+            return f;
+        }
+    }
+    high
+}
+
+fn line_fit(points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    let n = points.len() as f64;
+    if points.len() < 2 {
+        return None;
+    }
+    let mx = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let my = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|p| (p.0 - mx).powi(2)).sum();
+    if sxx <= 0.0 {
+        return None;
+    }
+    let slope = points.iter().map(|p| (p.0 - mx) * (p.1 - my)).sum::<f64>() / sxx;
+    Some((slope, my - slope * mx))
+}
+
+// log10 P = offset - exponent * log10 f, fitted without the periodic peaks;
+// (exponent, offset)
+pub(crate) fn aperiodic_fit(psd: &Psd, low: f64, high: f64) -> Option<(f64, f64)> {
+    let points: Vec<(f64, f64)> = in_range(psd, low.max(f64::MIN_POSITIVE), high)
+        .filter(|(f, _)| *f > 0.0)
+        .map(|(f, p)| (f.log10(), p.max(MIN_POWER).log10()))
+        .collect();
+    let (slope, intercept) = line_fit(&points)?;
+    let aperiodic: Vec<(f64, f64)> = points
+        .iter()
+        .copied()
+        .filter(|(x, y)| y - (intercept + slope * x) < PEAK_RESIDUAL)
+        .collect();
+    let (slope, intercept) = line_fit(&aperiodic).unwrap_or((slope, intercept));
+    Some((-slope, intercept))
+}
+
+// Higuchi fractal dimension: slope of ln L(k) over ln(1/k)
+pub(crate) fn higuchi_fd(data: &[f64], kmax: usize) -> f64 {
+    let n = data.len();
+    let points: Vec<(f64, f64)> = (1..=kmax.max(2))
+        .filter_map(|k| {
+            let lengths: Vec<f64> = (0..k)
+                .filter_map(|m| {
+                    let steps = n.saturating_sub(m + 1) / k;
+                    if steps == 0 {
+                        return None;
+                    }
+                    let sum: f64 = (1..=steps)
+                        .map(|i| (data[m + i * k] - data[m + (i - 1) * k]).abs())
+                        .sum();
+                    Some(sum * (n - 1) as f64 / (steps * k) as f64 / k as f64)
+                })
+                .collect();
+            let mean = lengths.iter().sum::<f64>() / lengths.len().max(1) as f64;
+            (mean > 0.0).then(|| ((1.0 / k as f64).ln(), mean.ln()))
+        })
+        .collect();
+    line_fit(&points).map(|(slope, _)| slope).unwrap_or(0.0)
+}
+
+// Katz fractal dimension: log10(n) / (log10(n) + log10(d / L))
+pub(crate) fn katz_fd(data: &[f64]) -> f64 {
+    let Some(&first) = data.first() else {
+        return 0.0;
+    };
+    let length: f64 = data.windows(2).map(|w| (w[1] - w[0]).abs()).sum();
+    let extent = data.iter().map(|x| (x - first).abs()).fold(0.0, f64::max);
+    let n = (data.len() - 1) as f64;
+    if length <= 0.0 || extent <= 0.0 || n < 1.0 {
+        return 0.0;
+    }
+    let log_n = n.log10();
+    log_n / (log_n + (extent / length).log10())
+}
+
+// -ln(A / B) with B the template pairs of length m and A of length m + 1
+// within r (Chebyshev), self-matches excluded
+pub(crate) fn sample_entropy(data: &[f64], m: usize, r: f64) -> Option<f64> {
+    let n = data.len();
+    if n <= m + 1 {
+        return None;
+    }
+    let (mut a, mut b) = (0u64, 0u64);
+    // Templates of length m starting before n - m, so each has a successor
+    for i in 0..n - m {
+        for j in i + 1..n - m {
+            if (0..m).all(|k| (data[i + k] - data[j + k]).abs() <= r) {
+                b += 1;
+                if (data[i + m] - data[j + m]).abs() <= r {
+                    a += 1;
+                }
+            }
+        }
+    }
+    (a > 0 && b > 0).then(|| -(a as f64 / b as f64).ln())
+}
+
+pub(crate) fn channel_features(
+    name: &str,
+    window: &[f64],
+    fs: f64,
+    settings: &FeatureSettings,
+) -> Option<ChannelFeatures> {
+    // Up to 2 s Welch segments, as for the custom model band powers
+    let limit = window.len().min((2.0 * fs) as usize);
+    if limit < 2 {
+        return None;
+    }
+    let nfft = spectral::nfft_for(limit);
+    let psd = spectral::welch(window, fs, nfft)?;
+    let (hjorth_activity, hjorth_mobility, hjorth_complexity) = hjorth(window);
+    let (aperiodic_exponent, aperiodic_offset) =
+        aperiodic_fit(&psd, settings.aperiodic_low, settings.aperiodic_high)
+            .unwrap_or((f64::NAN, f64::NAN));
+    let tail = &window[window
+        .len()
+        .saturating_sub(settings.sample_entropy_max_samples as usize)..];
+    let tolerance = settings.sample_entropy_r * hjorth(tail).0.sqrt();
+    Some(ChannelFeatures {
+        channel: name.to_string(),
+        hjorth_activity,
+        hjorth_mobility,
+        hjorth_complexity,
+        spectral_entropy: spectral_entropy(&psd, settings.spectrum_low, settings.spectrum_high),
+        spectral_edge_frequency: spectral_edge_frequency(
+            &psd,
+            settings.spectrum_low,
+            settings.spectrum_high,
+            settings.spectral_edge,
+        ),
+        higuchi_fd: higuchi_fd(window, settings.higuchi_kmax as usize),
+        katz_fd: katz_fd(window),
+        sample_entropy: sample_entropy(tail, settings.sample_entropy_m as usize, tolerance),
+        aperiodic_exponent,
+        aperiodic_offset,
+    })
+}
+
+// Features of every selected channel; None unless all of them have a
+// window to compute them on, so a vector never silently lacks a channel
+fn window_features(
+    names: &[String],
+    windows: &[&[f64]],
+    fs: f64,
+    settings: &FeatureSettings,
+) -> Option<Vec<ChannelFeatures>> {
+    if windows.len() != names.len() {
+        return None;
+    }
+    names
+        .iter()
+        .zip(windows)
+        .map(|(name, window)| channel_features(name, window, fs, settings))
+        .collect()
+}
+
+// Features of one window given as one sample vector per channel
+#[frb]
+pub fn compute_window_features(
+    channels: Vec<Vec<f64>>,
+    channel_names: Vec<String>,
+    sampling_rate: f64,
+    settings: FeatureSettings,
+) -> Result<FeatureVector> {
+    let (indices, names) = select_channels(&settings.channels, &channel_names);
+    let windows: Vec<&[f64]> = indices
+        .iter()
+        .filter_map(|&i| channels.get(i).map(Vec::as_slice))
+        .collect();
+    let Some(features) = window_features(&names, &windows, sampling_rate, &settings) else {
+        bail!("Every selected channel needs a window of at least 2 samples");
+    };
+    Ok(FeatureVector::new(0.0, features))
+}
+
+// Feature vectors over a whole recorded session, one per step
+#[frb]
+pub fn compute_session_features(
+    session_dir: String,
+    settings: FeatureSettings,
+) -> Result<Vec<FeatureVector>> {
+    let metadata = session_metadata::read_session_metadata(session_dir.clone())?;
+    let model = metadata.device.model;
+    let sampling_rate = metadata.pipeline.eeg_sampling_rate as f64;
+    let eeg = replay::decode_eeg_channels(Path::new(&session_dir), model)?;
+    let (indices, names) = select_channels(&settings.channels, &model.eeg_channel_names());
+    let window = (settings.window_seconds * sampling_rate) as usize;
+    let step = ((settings.step_seconds * sampling_rate) as usize).max(1);
+    let samples = indices
+        .iter()
+        .map(|&c| eeg.channels[c].len())
+        .min()
+        .unwrap_or(0);
+    if window < 2 || samples < window {
+        bail!("Session shorter than one feature window");
+    }
+    Ok((window..=samples)
+        .step_by(step)
+        .filter_map(|end| {
+            let windows: Vec<&[f64]> = indices
+                .iter()
+                .map(|&c| &eeg.channels[c][end - window..end])
+                .collect();
+            let features = window_features(&names, &windows, sampling_rate, &settings)?;
+            Some(FeatureVector::new(
+                eeg.start_time + end as f64 / sampling_rate,
+                features,
+            ))
+        })
+        .collect())
+}
+
+// Streaming feature vectors of the live EEG, one per step
+pub(crate) struct FeatureStream {
+    pub settings: FeatureSettings,
+    sampling_rate: f64,
+    names: Vec<String>,
+    buffers: ChannelBuffers,
+    latest: Option<FeatureVector>,
+    queued: VecDeque<FeatureVector>,
+}
+
+impl FeatureStream {
+    pub fn new(settings: FeatureSettings, sampling_rate: f64, channel_names: &[String]) -> Self {
+        let (channels, names) = select_channels(&settings.channels, channel_names);
+        let buffers = ChannelBuffers::for_window(channels, settings.window_seconds, sampling_rate);
+        Self {
+            settings,
+            sampling_rate,
+            names,
+            buffers,
+            latest: None,
+            queued: VecDeque::new(),
+        }
+    }
+
+    fn samples(&self, seconds: f64) -> usize {
+        (seconds * self.sampling_rate) as usize
+    }
+
+    pub fn push(&mut self, channel: usize, samples: &[f64], timestamp: f64) {
+        if self.buffers.push(channel, samples).is_none() {
+            return;
+        }
+        let window = self.samples(self.settings.window_seconds);
+        let step = self.samples(self.settings.step_seconds);
+        if window >= 2 && self.buffers.step_due(window, step) {
+            self.evaluate(window, timestamp);
+        }
+    }
+
+    fn evaluate(&mut self, window: usize, timestamp: f64) {
+        let Some(features) = self.buffers.latest_windows(window).and_then(|windows| {
+            window_features(&self.names, &windows, self.sampling_rate, &self.settings)
+        }) else {
+            return;
+        };
+        let vector = FeatureVector::new(timestamp, features);
+        if self.queued.len() == MAX_QUEUED {
+            self.queued.pop_front();
+        }
+        self.queued.push_back(vector.clone());
+        self.latest = Some(vector);
+    }
+
+    pub fn latest(&self) -> Option<&FeatureVector> {
+        self.latest.as_ref()
+    }
+
+    pub fn take_queued(&mut self) -> Vec<FeatureVector> {
+        self.queued.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn separates_noise_from_rhythm_and_fits_aperiodic_slope() {
+        let fs = 256.0;
+        let mut noise = test_util::noise(17);
+        let white: Vec<f64> = (0..1024).map(|_| 20.0 * noise()).collect();
+        let sine: Vec<f64> = (0..1024)
+            .map(|i| 20.0 * (2.0 * PI * 10.0 * i as f64 / fs).sin())
+            .collect();
+        let names = vec!["TP9".to_string(), "AF7".to_string(), "AUX".to_string()];
+        let vector = compute_window_features(
+            vec![white.clone(), sine.clone(), white],
+            names,
+            fs,
+            FeatureSettings::default(),
+        )
+        .unwrap();
+        assert_eq!(vector.channels.len(), 2);
+        assert_eq!(vector.values.len(), 2 * FEATURE_NAMES.len());
+        assert_eq!(vector.names[FEATURE_NAMES.len()], "AF7_hjorth_activity");
+        let (noisy, rhythm) = (&vector.channels[0], &vector.channels[1]);
+        assert!(noisy.spectral_entropy > 0.9 && rhythm.spectral_entropy < 0.3);
+        assert!(rhythm.spectral_edge_frequency < 12.0 && noisy.spectral_edge_frequency > 30.0);
+        assert!((noisy.higuchi_fd - 2.0).abs() < 0.1, "{:?}", noisy);
+        assert!(rhythm.higuchi_fd < 1.2, "{:?}", rhythm);
+        assert!(noisy.katz_fd > rhythm.katz_fd);
+        assert!(noisy.sample_entropy.unwrap() > 1.5);
+        assert!(rhythm.sample_entropy.unwrap() < 0.5);
+        // A selected channel without data gives no vector rather than a shorter one
+        let names = vec!["TP9".to_string(), "AF7".to_string()];
+        assert!(
+            compute_window_features(vec![sine], names, fs, FeatureSettings::default()).is_err()
+        );
+
+        // 1/f^1.5 with an alpha bump, which the refit leaves out
+        let frequencies: Vec<f64> = (0..=128).map(|k| k as f64 * 0.5).collect();
+        let power = frequencies
+            .iter()
+            .map(|f| {
+                let bump = if (9.0..=11.0).contains(f) { 5.0 } else { 1.0 };
+                100.0 * f.max(0.5).powf(-1.5) * bump
+            })
+            .collect();
+        let psd = Psd { frequencies, power };
+        let (exponent, offset) = aperiodic_fit(&psd, 2.0, 40.0).unwrap();
+        assert!((exponent - 1.5).abs() < 1e-6, "{}", exponent);
+        assert!((offset - 2.0).abs() < 1e-6, "{}", offset);
+    }
+}
@@ -0,0 +1,301 @@
+use crate::heart_rate::{PpgBuffer, PPG_SAMPLING_RATE};
+use flutter_rust_bridge::frb;
+
+// fNIRS, ported from amused muse_fnirs_processor.py::FNIRSProcessor.
+//
+// The Athena's three PPG wavelengths are read from the parser's 30 s rolling
+// buffer. A baseline (median intensity per wavelength) is taken explicitly
+// via calibrate_baseline, or automatically once enough data is buffered.
+// Changes in optical density against that baseline are converted to HbO2/HbR
+// concentration changes with the modified Beer-Lambert law,
+// ΔOD = ε · Δc · d · DPF, solved by least squares over the three wavelengths.
+//
+// The Python version takes the median of a 0.1-5 Hz band passed window as
+// the current intensity, which removes exactly the slow hemodynamic level it
+// is after (the median is ~0). The raw window median is used here instead.
+
+// Extinction coefficients (1/(cm·mM)), [HbO2, HbR]
+const EXTINCTION_660: [f64; 2] = [0.32, 3.20];
+const EXTINCTION_850: [f64; 2] = [1.05, 0.78];
+const EXTINCTION_950: [f64; 2] = [0.69, 1.10];
+
+// PPG buffer index per wavelength, see parse_muse_packet
+const IR_850: usize = 0;
+const RED_660: usize = 1;
+const NIR_950: usize = 2;
+
+const MAX_INTENSITY: f64 = 1e6;
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FnirsConfig {
+    // Differential path length factor, ~6 for the adult forehead
+    pub dpf: f64,
+    // Source-detector separation in cm
+    pub source_detector_cm: f64,
+    // Absolute levels the changes are added to (μM)
+    pub baseline_hbo2: f64,
+    pub baseline_hbr: f64,
+    pub window_seconds: usize,
+    pub baseline_seconds: usize,
+    // TSI (%) below which hypoxia is flagged
+    pub hypoxia_threshold: f64,
+}
+
+impl Default for FnirsConfig {
+    fn default() -> Self {
+        Self {
+            dpf: 6.0,
+            source_detector_cm: 3.0,
+            baseline_hbo2: 50.0,
+            baseline_hbr: 25.0,
+            window_seconds: 5,
+            baseline_seconds: 10,
+            hypoxia_threshold: 60.0,
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FnirsQuality {
+    Poor,
+    Fair,
+    Good,
+    Excellent,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FnirsResult {
+    // Concentrations in μM
+    pub hbo2: f64,
+    pub hbr: f64,
+    pub hbt: f64,
+    pub delta_hbo2: f64,
+    pub delta_hbr: f64,
+    // Tissue saturation index (= cerebral oxygen saturation ScO2), %
+    pub tsi: f64,
+    // Cerebral oximetry index (HbO2 - HbR) / HbT
+    pub cox: f64,
+    pub quality: FnirsQuality,
+    pub hypoxia: bool,
+    pub timestamp: f64,
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    sorted[sorted.len() / 2]
+}
+
+// ΔOD = -log10(I / I0) per wavelength, 0 where either intensity is invalid
+pub(crate) fn optical_density(current: [f64; 3], baseline: [f64; 3]) -> [f64; 3] {
+    let mut od = [0.0; 3];
+    for i in 0..3 {
+        if current[i] > 0.0 && baseline[i] > 0.0 {
+            od[i] = -(current[i] / baseline[i]).log10();
+        }
+    }
+    od
+}
+
+// Least squares (ΔHbO2, ΔHbR) in μM for ΔOD indexed like the PPG buffer
+pub(crate) fn solve_chromophores(od: [f64; 3], config: &FnirsConfig) -> Option<(f64, f64)> {
+    let path = config.source_detector_cm * config.dpf;
+    if path <= 0.0 {
+        return None;
+    }
+    let rows = [
+        (EXTINCTION_660, od[RED_660]),
+        (EXTINCTION_850, od[IR_850]),
+        (EXTINCTION_950, od[NIR_950]),
+    ];
+    // Normal equations (AᵀA) x = Aᵀb of the 3x2 system
+    let (mut a11, mut a12, mut a22, mut b1, mut b2) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for ([e_oxy, e_deoxy], value) in rows {
+        let b = value / path;
+        a11 += e_oxy * e_oxy;
+        a12 += e_oxy * e_deoxy;
+        a22 += e_deoxy * e_deoxy;
+        b1 += e_oxy * b;
+        b2 += e_deoxy * b;
+    }
+    let det = a11 * a22 - a12 * a12;
+    if det.abs() < f64::EPSILON {
+        return None;
+    }
+    // mM -> μM
+    let hbo2 = (a22 * b1 - a12 * b2) / det * 1000.0;
+    let hbr = (a11 * b2 - a12 * b1) / det * 1000.0;
+    Some((hbo2, hbr))
+}
+
+fn assess_quality(current: [f64; 3], ir_last_second: &[f64]) -> FnirsQuality {
+    if current.iter().any(|v| *v <= 0.0 || *v > MAX_INTENSITY) || ir_last_second.is_empty() {
+        return FnirsQuality::Poor;
+    }
+    let n = ir_last_second.len() as f64;
+    let mean = ir_last_second.iter().sum::<f64>() / n;
+    let std = (ir_last_second
+        .iter()
+        .map(|v| (v - mean).powi(2))
+        .sum::<f64>()
+        / n)
+        .sqrt();
+    let snr = mean / (std + 1e-6);
+    if snr > 10.0 {
+        FnirsQuality::Excellent
+    } else if snr > 5.0 {
+        FnirsQuality::Good
+    } else if snr > 2.0 {
+        FnirsQuality::Fair
+    } else {
+        FnirsQuality::Poor
+    }
+}
+
+pub(crate) struct FnirsProcessor {
+    pub config: FnirsConfig,
+    baseline: Option<[f64; 3]>,
+    samples_since_update: usize,
+    latest: Option<FnirsResult>,
+    fresh: bool,
+}
+
+impl FnirsProcessor {
+    pub fn new() -> Self {
+        Self {
+            config: FnirsConfig::default(),
+            baseline: None,
+            samples_since_update: 0,
+            latest: None,
+            fresh: false,
+        }
+    }
+
+    fn medians(ppg: &PpgBuffer, seconds: usize) -> Option<[f64; 3]> {
+        let needed = seconds * PPG_SAMPLING_RATE;
+        if (0..3).any(|c| ppg.len(c) < needed) {
+            return None;
+        }
+        Some([0, 1, 2].map(|c| median(&ppg.latest(c, seconds))))
+    }
+
+    // Median of the last `seconds` per wavelength becomes the reference;
+    // false if not enough data is buffered yet
+    pub fn calibrate_baseline(&mut self, ppg: &PpgBuffer, seconds: usize) -> bool {
+        match Self::medians(ppg, seconds) {
+            Some(baseline) => {
+                self.baseline = Some(baseline);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Call once the NIR samples of a packet are buffered; recomputes once per second
+    pub fn update(&mut self, ppg: &PpgBuffer, new_samples: usize) {
+        self.samples_since_update += new_samples;
+        if self.samples_since_update < PPG_SAMPLING_RATE {
+            return;
+        }
+        self.samples_since_update = 0;
+        if let Some(result) = self.extract(ppg) {
+            self.latest = Some(result);
+            self.fresh = true;
+        }
+    }
+
+    fn extract(&mut self, ppg: &PpgBuffer) -> Option<FnirsResult> {
+        let current = Self::medians(ppg, self.config.window_seconds)?;
+        if self.baseline.is_none() && !self.calibrate_baseline(ppg, self.config.baseline_seconds) {
+            return None;
+        }
+        let od = optical_density(current, self.baseline?);
+        let (delta_hbo2, delta_hbr) = solve_chromophores(od, &self.config)?;
+
+        let hbo2 = self.config.baseline_hbo2 + delta_hbo2;
+        let hbr = self.config.baseline_hbr + delta_hbr;
+        let hbt = hbo2 + hbr;
+        let (tsi, cox) = if hbt > 0.0 {
+            (hbo2 / hbt * 100.0, (hbo2 - hbr) / hbt)
+        } else {
+            (0.0, 0.0)
+        };
+        let quality = assess_quality(current, &ppg.latest(IR_850, 1));
+        Some(FnirsResult {
+            hbo2,
+            hbr,
+            hbt,
+            delta_hbo2,
+            delta_hbr,
+            tsi,
+            cox,
+            quality,
+            // Only trust the flag when the signal itself is usable
+            hypoxia: quality != FnirsQuality::Poor && tsi < self.config.hypoxia_threshold,
+            timestamp: ppg.last_timestamp,
+        })
+    }
+
+    pub fn latest(&self) -> Option<&FnirsResult> {
+        self.latest.as_ref()
+    }
+
+    pub fn take_fresh(&mut self) -> Option<FnirsResult> {
+        if !self.fresh {
+            return None;
+        }
+        self.fresh = false;
+        self.latest.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Intensities after a known concentration change (mM) from `baseline`
+    fn attenuated(baseline: [f64; 3], hbo2: f64, hbr: f64, config: &FnirsConfig) -> [f64; 3] {
+        let path = config.source_detector_cm * config.dpf;
+        let mut out = baseline;
+        for (index, [e_oxy, e_deoxy]) in [
+            (IR_850, EXTINCTION_850),
+            (RED_660, EXTINCTION_660),
+            (NIR_950, EXTINCTION_950),
+        ] {
+            let od = (e_oxy * hbo2 + e_deoxy * hbr) * path;
+            out[index] = baseline[index] * 10f64.powf(-od);
+        }
+        out
+    }
+
+    #[test]
+    fn recovers_concentration_change_against_baseline() {
+        let config = FnirsConfig::default();
+        let baseline = [50_000.0, 45_000.0, 48_000.0];
+        let mut ppg = PpgBuffer::new(3, 30);
+        for (c, level) in baseline.iter().enumerate() {
+            ppg.push(c, &vec![*level; 64 * 10], 10.0);
+        }
+        let mut processor = FnirsProcessor::new();
+        assert!(processor.calibrate_baseline(&ppg, 10));
+
+        // Desaturation: 10 μM less HbO2, 5 μM more HbR
+        let current = attenuated(baseline, -0.010, 0.005, &config);
+        for (c, level) in current.iter().enumerate() {
+            ppg.push(c, &vec![*level; 64 * 5], 15.0);
+        }
+        processor.update(&ppg, 64 * 5);
+        let result = processor.take_fresh().unwrap();
+        assert!((result.delta_hbo2 + 10.0).abs() < 1e-6);
+        assert!((result.delta_hbr - 5.0).abs() < 1e-6);
+        assert!((result.hbt - 70.0).abs() < 1e-6);
+        assert!((result.tsi - 40.0 / 70.0 * 100.0).abs() < 1e-6);
+        // Flat signal: SNR is huge, so the low TSI is flagged
+        assert_eq!(result.quality, FnirsQuality::Excellent);
+        assert!(result.hypoxia);
+        assert!(processor.take_fresh().is_none());
+    }
+}
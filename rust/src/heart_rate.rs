@@ -0,0 +1,385 @@
+use crate::spectral;
+use brainflow::data_filter;
+use brainflow::{DetrendOperations, FilterTypes};
+use flutter_rust_bridge::frb;
+use std::collections::VecDeque;
+
+// PPG heart rate, ported from amused muse_ppg_heart_rate.py::extract_heart_rate.
+//
+// The parser feeds every PPG packet into a rolling 64 Hz buffer. Once per
+// second the last HR_WINDOW_SECONDS of the IR channel are detrended, band
+// passed to 0.5-4 Hz (zero phase, so beat times are not delayed) and searched
+// for peaks at least 0.4 s apart. BrainFlow's FFT based get_heart_rate on the
+// whole buffer is used as an independent cross-check of the peak estimate.
+
+pub const PPG_SAMPLING_RATE: usize = 64;
+pub const PPG_BUFFER_SECONDS: usize = 30;
+pub(crate) const HR_WINDOW_SECONDS: usize = 10;
+const HR_MIN_SECONDS: usize = 5;
+const MIN_IBI: f64 = 0.4; // 150 BPM
+const MAX_IBI: f64 = 2.0; // 30 BPM
+const PEAK_PROMINENCE: f64 = 0.3;
+// Peak and FFT estimates further apart than this halve the confidence
+const CROSS_CHECK_TOLERANCE: f64 = 0.15;
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PpgQuality {
+    InsufficientData,
+    TooFewPeaks,
+    IrregularRhythm,
+    Poor,
+    Fair,
+    Good,
+    Excellent,
+}
+
+impl PpgQuality {
+    fn from_confidence(confidence: f64) -> Self {
+        if confidence > 0.8 {
+            PpgQuality::Excellent
+        } else if confidence > 0.6 {
+            PpgQuality::Good
+        } else if confidence > 0.4 {
+            PpgQuality::Fair
+        } else {
+            PpgQuality::Poor
+        }
+    }
+
+    pub fn is_usable(&self) -> bool {
+        matches!(
+            self,
+            PpgQuality::Fair | PpgQuality::Good | PpgQuality::Excellent
+        )
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeartRateResult {
+    pub bpm: f64,
+    pub confidence: f64,
+    // Beat (systolic peak) times; absolute timestamps in live results,
+    // seconds from the start of the signal for extract_heart_rate
+    pub beat_times: Vec<f64>,
+    pub quality: PpgQuality,
+    pub brainflow_bpm: Option<f64>,
+    pub timestamp: f64,
+}
+
+impl HeartRateResult {
+    fn empty(quality: PpgQuality) -> Self {
+        Self {
+            bpm: 0.0,
+            confidence: 0.0,
+            beat_times: Vec::new(),
+            quality,
+            brainflow_bpm: None,
+            timestamp: 0.0,
+        }
+    }
+}
+
+// Linear detrend + 4th order zero phase Butterworth 0.5-4 Hz
+pub(crate) fn filter_ppg(ppg: &[f64], sampling_rate: usize) -> Option<Vec<f64>> {
+    let mut data = ppg.to_vec();
+    data_filter::detrend(&mut data, DetrendOperations::Linear).ok()?;
+    data_filter::perform_bandpass(
+        &mut data,
+        sampling_rate,
+        0.5,
+        4.0,
+        4,
+        FilterTypes::ButterworthZeroPhase,
+        0.0,
+    )
+    .ok()?;
+    Some(data)
+}
+
+// scipy.signal.find_peaks(x, height, distance, prominence) for one-sided
+// local maxima; plateaus count at their first sample.
+pub(crate) fn find_peaks(x: &[f64], height: f64, distance: usize, prominence: f64) -> Vec<usize> {
+    let mut peaks: Vec<usize> = (1..x.len().saturating_sub(1))
+        .filter(|&i| x[i] > x[i - 1] && x[i] >= x[i + 1] && x[i] >= height)
+        .collect();
+
+    // Distance: keep the highest peaks, drop lower ones closer than `distance`
+    if distance > 1 {
+        let mut order: Vec<usize> = (0..peaks.len()).collect();
+        order.sort_by(|&a, &b| x[peaks[b]].total_cmp(&x[peaks[a]]));
+        let mut keep = vec![true; peaks.len()];
+        for &i in &order {
+            if !keep[i] {
+                continue;
+            }
+            for (j, k) in keep.iter_mut().enumerate() {
+                if j != i && peaks[j].abs_diff(peaks[i]) < distance {
+                    *k = false;
+                }
+            }
+        }
+        peaks = peaks
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(p, k)| k.then_some(p))
+            .collect();
+    }
+
+    peaks.retain(|&p| {
+        let mut left_min = x[p];
+        for &v in x[..p].iter().rev() {
+            if v > x[p] {
+                break;
+            }
+            left_min = left_min.min(v);
+        }
+        let mut right_min = x[p];
+        for &v in &x[p + 1..] {
+            if v > x[p] {
+                break;
+            }
+            right_min = right_min.min(v);
+        }
+        x[p] - left_min.max(right_min) >= prominence
+    });
+    peaks
+}
+
+// Peak detection and rate on an already band passed signal
+pub(crate) fn analyze_beats(filtered: &[f64], sampling_rate: usize) -> HeartRateResult {
+    if filtered.len() < sampling_rate * HR_MIN_SECONDS {
+        return HeartRateResult::empty(PpgQuality::InsufficientData);
+    }
+    let n = filtered.len() as f64;
+    let mean = filtered.iter().sum::<f64>() / n;
+    let std = (filtered.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt();
+    if std <= f64::EPSILON {
+        return HeartRateResult::empty(PpgQuality::TooFewPeaks);
+    }
+    let normalized: Vec<f64> = filtered.iter().map(|v| (v - mean) / std).collect();
+
+    let min_distance = (MIN_IBI * sampling_rate as f64) as usize;
+    let peaks = find_peaks(&normalized, 0.0, min_distance, PEAK_PROMINENCE);
+    if peaks.len() < 3 {
+        return HeartRateResult::empty(PpgQuality::TooFewPeaks);
+    }
+
+    let beat_times: Vec<f64> = peaks
+        .iter()
+        .map(|&p| p as f64 / sampling_rate as f64)
+        .collect();
+    let ibis: Vec<f64> = beat_times
+        .windows(2)
+        .map(|w| w[1] - w[0])
+        .filter(|ibi| *ibi > MIN_IBI && *ibi < MAX_IBI)
+        .collect();
+    if ibis.len() < 2 {
+        return HeartRateResult {
+            beat_times,
+            ..HeartRateResult::empty(PpgQuality::IrregularRhythm)
+        };
+    }
+
+    let mean_ibi = ibis.iter().sum::<f64>() / ibis.len() as f64;
+    let ibi_std =
+        (ibis.iter().map(|v| (v - mean_ibi).powi(2)).sum::<f64>() / ibis.len() as f64).sqrt();
+    let confidence = (1.0 - ibi_std / mean_ibi).clamp(0.0, 1.0);
+    HeartRateResult {
+        bpm: 60.0 / mean_ibi,
+        confidence,
+        beat_times,
+        quality: PpgQuality::from_confidence(confidence),
+        brainflow_bpm: None,
+        timestamp: 0.0,
+    }
+}
+
+fn cross_check(result: &mut HeartRateResult, brainflow_bpm: Option<f64>) {
+    result.brainflow_bpm = brainflow_bpm;
+    let Some(reference) = brainflow_bpm.filter(|b| *b > 0.0) else {
+        return;
+    };
+    if result.bpm > 0.0 && (result.bpm - reference).abs() / reference > CROSS_CHECK_TOLERANCE {
+        result.confidence *= 0.5;
+        result.quality = PpgQuality::from_confidence(result.confidence);
+    }
+}
+
+// One-shot heart rate of a PPG trace (IR works best), for offline use.
+#[frb]
+pub fn extract_heart_rate(ppg: Vec<f64>, sampling_rate: usize) -> HeartRateResult {
+    if ppg.len() < sampling_rate * HR_MIN_SECONDS {
+        return HeartRateResult::empty(PpgQuality::InsufficientData);
+    }
+    match filter_ppg(&ppg, sampling_rate) {
+        Some(filtered) => analyze_beats(&filtered, sampling_rate),
+        None => HeartRateResult::empty(PpgQuality::InsufficientData),
+    }
+}
+
+// Rolling PPG buffer per wavelength (index as in parse_muse_packet: 0 IR,
+// 1 red, 2 NIR) with the timestamp of the newest sample.
+pub(crate) struct PpgBuffer {
+    pub channels: Vec<VecDeque<f64>>,
+    pub last_timestamp: f64,
+    capacity: usize,
+}
+
+impl PpgBuffer {
+    pub fn new(channel_count: usize, seconds: usize) -> Self {
+        Self {
+            channels: vec![VecDeque::new(); channel_count],
+            last_timestamp: 0.0,
+            capacity: seconds * PPG_SAMPLING_RATE,
+        }
+    }
+
+    pub fn push(&mut self, channel: usize, samples: &[f64], timestamp: f64) {
+        let Some(buf) = self.channels.get_mut(channel) else {
+            return;
+        };
+        buf.extend(samples);
+        while buf.len() > self.capacity {
+            buf.pop_front();
+        }
+        if channel == 0 {
+            self.last_timestamp = timestamp;
+        }
+    }
+
+    // The most recent `seconds` of a channel (fewer if not buffered yet)
+    pub fn latest(&self, channel: usize, seconds: usize) -> Vec<f64> {
+        let buf = &self.channels[channel];
+        let n = (seconds * PPG_SAMPLING_RATE).min(buf.len());
+        buf.iter().skip(buf.len() - n).copied().collect()
+    }
+
+    pub fn len(&self, channel: usize) -> usize {
+        self.channels.get(channel).map(|c| c.len()).unwrap_or(0)
+    }
+}
+
+pub(crate) struct HeartRateTracker {
+    samples_since_update: usize,
+    latest: Option<HeartRateResult>,
+    fresh: bool,
+}
+
+impl HeartRateTracker {
+    pub fn new() -> Self {
+        Self {
+            samples_since_update: 0,
+            latest: None,
+            fresh: false,
+        }
+    }
+
+    // Call after new IR samples were pushed; recomputes once per second and
+    // returns the new result when it did
+    pub fn update(&mut self, ppg: &PpgBuffer, new_samples: usize) -> Option<&HeartRateResult> {
+        self.samples_since_update += new_samples;
+        if self.samples_since_update < PPG_SAMPLING_RATE
+            || ppg.len(0) < PPG_SAMPLING_RATE * HR_MIN_SECONDS
+        {
+            return None;
+        }
+        self.samples_since_update = 0;
+
+        let window = ppg.latest(0, HR_WINDOW_SECONDS);
+        let mut result = match filter_ppg(&window, PPG_SAMPLING_RATE) {
+            Some(filtered) => analyze_beats(&filtered, PPG_SAMPLING_RATE),
+            None => HeartRateResult::empty(PpgQuality::InsufficientData),
+        };
+        cross_check(&mut result, brainflow_heart_rate(ppg));
+
+        // Window relative times -> absolute, newest sample at last_timestamp
+        let start = ppg.last_timestamp - (window.len() - 1) as f64 / PPG_SAMPLING_RATE as f64;
+        for t in &mut result.beat_times {
+            *t += start;
+        }
+        result.timestamp = ppg.last_timestamp;
+        self.latest = Some(result);
+        self.fresh = true;
+        self.latest.as_ref()
+    }
+
+    pub fn latest(&self) -> Option<&HeartRateResult> {
+        self.latest.as_ref()
+    }
+
+    // Scales the latest confidence by the motion gate weight of its window
+    pub fn apply_motion_weight(&mut self, weight: f64) {
+        if let Some(result) = self.latest.as_mut().filter(|r| r.quality.is_usable()) {
+            result.confidence *= weight;
+            result.quality = PpgQuality::from_confidence(result.confidence);
+        }
+    }
+
+    // The result computed since the last call, if any
+    pub fn take_fresh(&mut self) -> Option<HeartRateResult> {
+        if !self.fresh {
+            return None;
+        }
+        self.fresh = false;
+        self.latest.clone()
+    }
+}
+
+fn brainflow_heart_rate(ppg: &PpgBuffer) -> Option<f64> {
+    let n = ppg.len(0).min(ppg.len(1));
+    if n < PPG_SAMPLING_RATE * HR_MIN_SECONDS {
+        return None;
+    }
+    let fft_size = spectral::nfft_for(n);
+    let mut ir = ppg.latest(0, n / PPG_SAMPLING_RATE);
+    let mut red = ppg.latest(1, n / PPG_SAMPLING_RATE);
+    let len = ir.len().min(red.len());
+    data_filter::get_heart_rate(
+        &mut ir[..len],
+        &mut red[..len],
+        PPG_SAMPLING_RATE,
+        fft_size.min(len),
+    )
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Band passed PPG-like wave: 72 BPM with a small dicrotic bump
+    fn pulse_wave(seconds: usize, bpm: f64) -> Vec<f64> {
+        let f = bpm / 60.0;
+        (0..seconds * PPG_SAMPLING_RATE)
+            .map(|i| {
+                let t = i as f64 / PPG_SAMPLING_RATE as f64;
+                let phase = 2.0 * std::f64::consts::PI * f * t;
+                phase.sin() + 0.2 * (2.0 * phase).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_beats_of_regular_pulse() {
+        let result = analyze_beats(&pulse_wave(10, 72.0), PPG_SAMPLING_RATE);
+        assert!((result.bpm - 72.0).abs() < 1.5, "bpm {}", result.bpm);
+        assert_eq!(result.quality, PpgQuality::Excellent);
+        assert!(result.beat_times.len() >= 11);
+
+        let mut checked = result.clone();
+        cross_check(&mut checked, Some(110.0));
+        assert!(checked.confidence < result.confidence);
+
+        let short = analyze_beats(&pulse_wave(3, 72.0), PPG_SAMPLING_RATE);
+        assert_eq!(short.quality, PpgQuality::InsufficientData);
+    }
+
+    #[test]
+    fn peak_distance_keeps_highest_peak() {
+        let x = [0.0, 1.0, 0.5, 2.0, 0.0, 0.0, 0.0, 1.5, 0.0];
+        assert_eq!(find_peaks(&x, 0.0, 3, 0.1), vec![3, 7]);
+        assert_eq!(find_peaks(&x, 0.0, 1, 0.1), vec![1, 3, 7]);
+    }
+}
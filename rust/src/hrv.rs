@@ -0,0 +1,298 @@
+use flutter_rust_bridge::frb;
+use std::collections::VecDeque;
+
+// Heart rate variability from PPG beat times (see heart_rate.rs).
+//
+// Beats from the overlapping heart rate windows are merged into one beat
+// history. Inter-beat intervals outside 0.3-2 s are dropped and ectopic ones
+// (too far from the local median) are replaced by that median before the
+// time domain metrics. LF/HF and coherence use a Lomb-Scargle periodogram of
+// the uneven IBI series, so no resampling is needed.
+
+const MIN_IBI: f64 = 0.3;
+const MAX_IBI: f64 = 2.0;
+const SAME_BEAT_SECONDS: f64 = 0.2;
+const HISTORY_SECONDS: f64 = 600.0;
+const LF_BAND: (f64, f64) = (0.04, 0.15);
+const HF_BAND: (f64, f64) = (0.15, 0.4);
+const TOTAL_BAND: (f64, f64) = (0.0033, 0.4);
+// Coherence peak search range and integration half-width (HeartMath style)
+const COHERENCE_BAND: (f64, f64) = (0.04, 0.26);
+const COHERENCE_HALF_WIDTH: f64 = 0.015;
+pub(crate) const FREQUENCY_STEP: f64 = 0.001;
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HrvSettings {
+    // Sliding window over the most recent beats
+    pub window_seconds: f64,
+    pub min_beats: usize,
+    // Relative deviation from the local median IBI that marks an ectopic beat
+    pub ectopic_threshold: f64,
+}
+
+impl Default for HrvSettings {
+    fn default() -> Self {
+        Self {
+            window_seconds: 60.0,
+            min_beats: 10,
+            ectopic_threshold: 0.2,
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HrvMetrics {
+    pub window_start: f64,
+    pub window_end: f64,
+    pub beat_count: usize,
+    pub corrected_intervals: usize,
+    pub mean_ibi_ms: f64,
+    pub mean_hr_bpm: f64,
+    pub sdnn_ms: f64,
+    pub rmssd_ms: f64,
+    pub pnn50: f64,
+    // Frequency domain needs a window of at least 1 / 0.04 Hz = 25 s
+    pub lf_power: Option<f64>,
+    pub hf_power: Option<f64>,
+    pub lf_hf_ratio: Option<f64>,
+    // Peak power / remaining power, higher = more regular resonance breathing
+    pub coherence: Option<f64>,
+    pub resonance_frequency: Option<f64>,
+}
+
+// Deduplicated beat times of the live stream
+pub(crate) struct BeatHistory {
+    beats: VecDeque<f64>,
+}
+
+impl BeatHistory {
+    pub fn new() -> Self {
+        Self {
+            beats: VecDeque::new(),
+        }
+    }
+
+    pub fn merge(&mut self, beat_times: &[f64]) {
+        for &t in beat_times {
+            if self.beats.iter().any(|b| (b - t).abs() < SAME_BEAT_SECONDS) {
+                continue;
+            }
+            let pos = self.beats.partition_point(|b| *b < t);
+            self.beats.insert(pos, t);
+        }
+        if let Some(&last) = self.beats.back() {
+            while self
+                .beats
+                .front()
+                .is_some_and(|b| *b < last - HISTORY_SECONDS)
+            {
+                self.beats.pop_front();
+            }
+        }
+    }
+
+    pub fn window(&self, seconds: f64) -> Vec<f64> {
+        let Some(&last) = self.beats.back() else {
+            return Vec::new();
+        };
+        self.beats
+            .iter()
+            .copied()
+            .filter(|b| *b >= last - seconds)
+            .collect()
+    }
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+// Returns (interval end times, corrected IBIs in seconds, corrections made)
+fn corrected_intervals(beats: &[f64], threshold: f64) -> (Vec<f64>, Vec<f64>, usize) {
+    let mut times = Vec::new();
+    let mut ibis = Vec::new();
+    for w in beats.windows(2) {
+        let ibi = w[1] - w[0];
+        if (MIN_IBI..=MAX_IBI).contains(&ibi) {
+            times.push(w[1]);
+            ibis.push(ibi);
+        }
+    }
+    let mut corrected = ibis.clone();
+    let mut corrections = 0;
+    for i in 0..ibis.len() {
+        let lo = i.saturating_sub(2);
+        let hi = (i + 3).min(ibis.len());
+        let local = median(&ibis[lo..hi]);
+        if (ibis[i] - local).abs() > threshold * local {
+            corrected[i] = local;
+            corrections += 1;
+        }
+    }
+    (times, corrected, corrections)
+}
+
+// Lomb-Scargle periodogram of mean-removed samples, power in units of x²
+pub(crate) fn lomb_scargle(times: &[f64], values: &[f64], frequencies: &[f64]) -> Vec<f64> {
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let y: Vec<f64> = values.iter().map(|v| v - mean).collect();
+    frequencies
+        .iter()
+        .map(|f| {
+            let w = 2.0 * std::f64::consts::PI * f;
+            let (s2, c2) = times.iter().fold((0.0, 0.0), |(s, c), t| {
+                (s + (2.0 * w * t).sin(), c + (2.0 * w * t).cos())
+            });
+            let tau = s2.atan2(c2) / (2.0 * w);
+            let (mut yc, mut ys, mut cc, mut ss) = (0.0, 0.0, 0.0, 0.0);
+            for (t, v) in times.iter().zip(&y) {
+                let (sin, cos) = (w * (t - tau)).sin_cos();
+                yc += v * cos;
+                ys += v * sin;
+                cc += cos * cos;
+                ss += sin * sin;
+            }
+            let mut p = 0.0;
+            if cc > 0.0 {
+                p += yc * yc / cc;
+            }
+            if ss > 0.0 {
+                p += ys * ys / ss;
+            }
+            p / 2.0
+        })
+        .collect()
+}
+
+pub(crate) fn band_power(frequencies: &[f64], power: &[f64], band: (f64, f64)) -> f64 {
+    frequencies
+        .iter()
+        .zip(power)
+        .filter(|(f, _)| **f >= band.0 && **f < band.1)
+        .map(|(_, p)| p * FREQUENCY_STEP)
+        .sum()
+}
+
+// HRV of the beats in the last `settings.window_seconds`
+#[frb]
+pub fn calculate_hrv(beat_times: Vec<f64>, settings: HrvSettings) -> Option<HrvMetrics> {
+    let last = *beat_times.last()?;
+    let beats: Vec<f64> = beat_times
+        .into_iter()
+        .filter(|b| *b >= last - settings.window_seconds)
+        .collect();
+    if beats.len() < settings.min_beats.max(3) {
+        return None;
+    }
+    let (times, ibis, corrections) = corrected_intervals(&beats, settings.ectopic_threshold);
+    if ibis.len() < 2 {
+        return None;
+    }
+
+    let ms: Vec<f64> = ibis.iter().map(|i| i * 1000.0).collect();
+    let n = ms.len() as f64;
+    let mean_ibi = ms.iter().sum::<f64>() / n;
+    let sdnn = (ms.iter().map(|v| (v - mean_ibi).powi(2)).sum::<f64>() / n).sqrt();
+    let diffs: Vec<f64> = ms.windows(2).map(|w| w[1] - w[0]).collect();
+    let rmssd = (diffs.iter().map(|d| d * d).sum::<f64>() / diffs.len() as f64).sqrt();
+    let pnn50 = diffs.iter().filter(|d| d.abs() > 50.0).count() as f64 / diffs.len() as f64 * 100.0;
+
+    let mut metrics = HrvMetrics {
+        window_start: beats[0],
+        window_end: last,
+        beat_count: beats.len(),
+        corrected_intervals: corrections,
+        mean_ibi_ms: mean_ibi,
+        mean_hr_bpm: 60_000.0 / mean_ibi,
+        sdnn_ms: sdnn,
+        rmssd_ms: rmssd,
+        pnn50,
+        lf_power: None,
+        hf_power: None,
+        lf_hf_ratio: None,
+        coherence: None,
+        resonance_frequency: None,
+    };
+
+    if last - beats[0] >= 1.0 / LF_BAND.0 {
+        let frequencies: Vec<f64> = (0..)
+            .map(|i| TOTAL_BAND.0 + i as f64 * FREQUENCY_STEP)
+            .take_while(|f| *f < TOTAL_BAND.1)
+            .collect();
+        let power = lomb_scargle(&times, &ms, &frequencies);
+        let lf = band_power(&frequencies, &power, LF_BAND);
+        let hf = band_power(&frequencies, &power, HF_BAND);
+        metrics.lf_power = Some(lf);
+        metrics.hf_power = Some(hf);
+        metrics.lf_hf_ratio = (hf > 0.0).then(|| lf / hf);
+
+        let peak = frequencies
+            .iter()
+            .zip(&power)
+            .filter(|(f, _)| **f >= COHERENCE_BAND.0 && **f <= COHERENCE_BAND.1)
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(f, _)| *f);
+        if let Some(peak) = peak {
+            let peak_power = band_power(
+                &frequencies,
+                &power,
+                (peak - COHERENCE_HALF_WIDTH, peak + COHERENCE_HALF_WIDTH),
+            );
+            let total = band_power(&frequencies, &power, TOTAL_BAND);
+            metrics.resonance_frequency = Some(peak);
+            metrics.coherence = (total > peak_power).then(|| peak_power / (total - peak_power));
+        }
+    }
+    Some(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Beats with 0.1 Hz sinus arrhythmia (6 breaths/min) around 60 BPM
+    fn resonance_beats(seconds: f64) -> Vec<f64> {
+        let mut beats = vec![0.0];
+        while *beats.last().unwrap() < seconds {
+            let t = *beats.last().unwrap();
+            beats.push(t + 1.0 + 0.08 * (2.0 * std::f64::consts::PI * 0.1 * t).sin());
+        }
+        beats
+    }
+
+    #[test]
+    fn resonance_breathing_is_coherent() {
+        let beats = resonance_beats(120.0);
+        let metrics = calculate_hrv(beats, HrvSettings::default()).unwrap();
+        assert!((metrics.mean_hr_bpm - 60.0).abs() < 2.0);
+        assert!(metrics.rmssd_ms > 10.0 && metrics.sdnn_ms > 30.0);
+        assert_eq!(metrics.corrected_intervals, 0);
+        let peak = metrics.resonance_frequency.unwrap();
+        assert!((peak - 0.1).abs() < 0.01, "peak {}", peak);
+        assert!(metrics.lf_hf_ratio.unwrap() > 5.0);
+        assert!(metrics.coherence.unwrap() > 1.0);
+    }
+
+    #[test]
+    fn ectopic_beats_are_corrected_and_duplicates_merged() {
+        let mut beats: Vec<f64> = (0..40).map(|i| i as f64 * 0.8).collect();
+        beats[20] = 15.7; // premature beat, long compensatory pause after it
+        let (_, ibis, corrections) = corrected_intervals(&beats, 0.2);
+        assert_eq!(corrections, 2);
+        assert!(ibis.iter().all(|i| (i - 0.8).abs() < 1e-9));
+
+        let mut history = BeatHistory::new();
+        history.merge(&[1.0, 2.0, 3.0]);
+        history.merge(&[2.02, 3.01, 4.0]);
+        assert_eq!(history.window(10.0), vec![1.0, 2.0, 3.0, 4.0]);
+    }
+}
@@ -0,0 +1,407 @@
+use crate::channel_buffers::{select_channels, ChannelBuffers};
+use crate::muse_types::BandRange;
+use crate::recording::{self, Record, SessionReader};
+use crate::replay;
+use crate::session_metadata;
+use crate::spectral;
+use anyhow::{bail, Result};
+use flutter_rust_bridge::frb;
+use std::path::Path;
+use std::sync::Mutex;
+
+// Individual alpha frequency.
+//
+// The alpha peak of eyes-closed EEG is estimated per channel from a Welch PSD
+// averaged over the eyes-closed segments, both as the local maximum in the
+// search range (refined by a parabola through the log power of its bins) and
+// as the power-weighted center of gravity of that range. Segments come from
+// a live recording (bracketed by eyes_closed markers, so the session can be
+// re-estimated later) or from a saved session.
+//
+// Once set, the IAF anchors the theta / alpha / beta boundaries of every
+// band power consumer (live band powers, calculate_band_powers, the metrics
+// service, the asymmetry alpha band): theta IAF-6 to IAF-2, alpha IAF-2 to
+// IAF+2, beta from IAF+2, delta ending where theta starts. At an IAF of
+// 10 Hz this is close to the fixed bands. Two consumers keep fixed bands on
+// purpose: sleep staging, whose rules follow the AASM band definitions, and
+// custom models / trained classifiers, whose FeatureSpec bands must match
+// the bands they were trained on. The IAF is part of PipelineSettings, so a
+// recording keeps it and its replay runs with the same bands.
+
+static IAF: Mutex<Option<EyesClosedRecording>> = Mutex::new(None);
+// IAF anchoring the band boundaries, None for the fixed bands
+static INDIVIDUAL_ALPHA: Mutex<Option<f64>> = Mutex::new(None);
+
+pub(crate) const EYES_CLOSED_MARKER: &str = "eyes_closed";
+// Eyes-closed data kept per channel
+const MAX_RECORDING_SECONDS: f64 = 600.0;
+// Narrowest delta band left when a low IAF moves theta down
+const MIN_DELTA_WIDTH: f64 = 0.5;
+// Range of plausible IAFs, also the default search range
+const MIN_IAF: f64 = 7.0;
+const MAX_IAF: f64 = 14.0;
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct IafSettings {
+    pub search_low: f64,
+    pub search_high: f64,
+    // Channel names; empty uses every EEG channel
+    pub channels: Vec<String>,
+    // Target PSD bin width, limited by the segment length
+    pub resolution_hz: f64,
+}
+
+impl Default for IafSettings {
+    fn default() -> Self {
+        Self {
+            search_low: MIN_IAF,
+            search_high: MAX_IAF,
+            channels: vec![],
+            resolution_hz: 0.25,
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelIaf {
+    pub channel: String,
+    // None without a local maximum inside the search range
+    pub peak_frequency: Option<f64>,
+    pub center_of_gravity: Option<f64>,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct IafEstimate {
+    // Means over the channels that have one
+    pub peak_frequency: Option<f64>,
+    pub center_of_gravity: Option<f64>,
+    pub channels: Vec<ChannelIaf>,
+    // Eyes-closed data the estimate is based on
+    pub seconds: f64,
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = values.fold((0.0, 0), |(s, n), v| (s + v, n + 1));
+    (count > 0).then(|| sum / count as f64)
+}
+
+// Welch PSD averaged over the segments long enough for the FFT length
+fn averaged_psd(segments: &[&[f64]], fs: f64, resolution: f64) -> Option<spectral::Psd> {
+    let longest = segments.iter().map(|s| s.len()).max()?;
+    let target = ((fs / resolution.max(1e-3)) as usize).min(longest);
+    if target < 2 {
+        return None;
+    }
+    let nfft = spectral::nfft_for(target);
+    let mut total: Option<spectral::Psd> = None;
+    let mut weight = 0.0;
+    for segment in segments {
+        let Some(psd) = spectral::welch(segment, fs, nfft) else {
+            continue;
+        };
+        let w = segment.len() as f64;
+        match total.as_mut() {
+            Some(t) => t
+                .power
+                .iter_mut()
+                .zip(&psd.power)
+                .for_each(|(t, p)| *t += w * p),
+            None => {
+                total = Some(spectral::Psd {
+                    power: psd.power.iter().map(|p| w * p).collect(),
+                    frequencies: psd.frequencies,
+                })
+            }
+        }
+        weight += w;
+    }
+    let mut psd = total?;
+    psd.power.iter_mut().for_each(|p| *p /= weight);
+    Some(psd)
+}
+
+// Strongest local maximum strictly inside [low, high] and the center of
+// gravity of that range
+pub(crate) fn alpha_peak(psd: &spectral::Psd, low: f64, high: f64) -> (Option<f64>, Option<f64>) {
+    let f = &psd.frequencies;
+    let p = &psd.power;
+    let range: Vec<usize> = (0..f.len())
+        .filter(|&k| f[k] >= low && f[k] <= high)
+        .collect();
+    let total: f64 = range.iter().map(|&k| p[k]).sum();
+    let cog = (total > 0.0).then(|| range.iter().map(|&k| f[k] * p[k]).sum::<f64>() / total);
+
+    let peak = range
+        .iter()
+        .copied()
+        .filter(|&k| k > 0 && k + 1 < p.len() && p[k] > p[k - 1] && p[k] >= p[k + 1])
+        .filter(|&k| f[k] > low && f[k] < high)
+        .max_by(|&a, &b| p[a].total_cmp(&p[b]))
+        .map(|k| {
+            let df = f[1] - f[0];
+            let (a, b, c) = (p[k - 1].ln(), p[k].ln(), p[k + 1].ln());
+            let curvature = a - 2.0 * b + c;
+            let offset = if curvature < 0.0 && curvature.is_finite() {
+                (0.5 * (a - c) / curvature).clamp(-0.5, 0.5)
+            } else {
+                0.0
+            };
+            f[k] + offset * df
+        });
+    (peak, cog)
+}
+
+// Estimate over eyes-closed segments, each holding one slice per channel
+pub(crate) fn estimate(
+    segments: &[Vec<&[f64]>],
+    names: &[String],
+    fs: f64,
+    settings: &IafSettings,
+) -> IafEstimate {
+    let channels: Vec<ChannelIaf> = names
+        .iter()
+        .enumerate()
+        .map(|(c, name)| {
+            let data: Vec<&[f64]> = segments.iter().filter_map(|s| s.get(c).copied()).collect();
+            let (peak_frequency, center_of_gravity) =
+                averaged_psd(&data, fs, settings.resolution_hz)
+                    .map(|psd| alpha_peak(&psd, settings.search_low, settings.search_high))
+                    .unwrap_or((None, None));
+            ChannelIaf {
+                channel: name.clone(),
+                peak_frequency,
+                center_of_gravity,
+            }
+        })
+        .collect();
+    let samples: usize = segments
+        .iter()
+        .map(|s| s.iter().map(|c| c.len()).min().unwrap_or(0))
+        .sum();
+    IafEstimate {
+        peak_frequency: mean(channels.iter().filter_map(|c| c.peak_frequency)),
+        center_of_gravity: mean(channels.iter().filter_map(|c| c.center_of_gravity)),
+        channels,
+        seconds: samples as f64 / fs,
+    }
+}
+
+// Bands with theta / alpha / beta (and the delta end) moved to the IAF;
+// bands are matched by name, others are left alone
+pub(crate) fn bands_for(iaf: f64, bands: &[BandRange]) -> Vec<BandRange> {
+    let delta_start = bands
+        .iter()
+        .find(|b| b.name == "delta")
+        .map(|b| b.freq_start)
+        .unwrap_or(0.0);
+    let theta_start = (iaf - 6.0).max(delta_start + MIN_DELTA_WIDTH);
+    bands
+        .iter()
+        .map(|b| {
+            let (start, stop) = match b.name.as_str() {
+                "delta" => (b.freq_start, theta_start),
+                "theta" => (theta_start, iaf - 2.0),
+                "alpha" => (iaf - 2.0, iaf + 2.0),
+                "beta" => (iaf + 2.0, b.freq_stop),
+                _ => (b.freq_start, b.freq_stop),
+            };
+            BandRange::new(&b.name, start, stop)
+        })
+        .collect()
+}
+
+// Bands every band power consumer uses: anchored to the IAF once set
+pub(crate) fn anchor_bands(bands: &[BandRange]) -> Vec<BandRange> {
+    match *INDIVIDUAL_ALPHA.lock().unwrap() {
+        Some(iaf) => bands_for(iaf, bands),
+        None => bands.to_vec(),
+    }
+}
+
+// IAF-anchored version of `bands`, without changing the active bands
+#[frb]
+pub fn individual_bands(iaf: f64, bands: Vec<BandRange>) -> Vec<BandRange> {
+    bands_for(iaf, &bands)
+}
+
+// Anchors the bands of all band power consumers to `iaf`, which must lie in
+// the 7-14 Hz search range; None restores the fixed bands
+#[frb]
+pub fn set_individual_alpha_frequency(iaf: Option<f64>) -> Result<()> {
+    if let Some(f) = iaf.filter(|f| !(MIN_IAF..=MAX_IAF).contains(f)) {
+        bail!(
+            "IAF {} Hz outside the {}-{} Hz alpha range",
+            f,
+            MIN_IAF,
+            MAX_IAF
+        );
+    }
+    *INDIVIDUAL_ALPHA.lock().unwrap() = iaf;
+    Ok(())
+}
+
+#[frb]
+pub fn get_individual_alpha_frequency() -> Option<f64> {
+    *INDIVIDUAL_ALPHA.lock().unwrap()
+}
+
+// Estimate from raw eyes-closed data, one sample vector per channel
+#[frb]
+pub fn estimate_iaf(
+    channels: Vec<Vec<f64>>,
+    channel_names: Vec<String>,
+    sampling_rate: f64,
+    settings: IafSettings,
+) -> IafEstimate {
+    let (indices, names) = select_channels(&settings.channels, &channel_names);
+    let segment: Vec<&[f64]> = indices
+        .iter()
+        .filter_map(|&i| channels.get(i).map(Vec::as_slice))
+        .collect();
+    estimate(&[segment], &names, sampling_rate, &settings)
+}
+
+pub(crate) struct EyesClosedRecording {
+    settings: IafSettings,
+    sampling_rate: f64,
+    names: Vec<String>,
+    buffers: ChannelBuffers,
+}
+
+pub(crate) fn start_muse(settings: IafSettings, sampling_rate: f64, channel_names: &[String]) {
+    let (channels, names) = select_channels(&settings.channels, channel_names);
+    let capacity = (MAX_RECORDING_SECONDS * sampling_rate) as usize;
+    *IAF.lock().unwrap() = Some(EyesClosedRecording {
+        settings,
+        sampling_rate,
+        names,
+        buffers: ChannelBuffers::new(channels, capacity),
+    });
+    recording::record_marker(1.0, EYES_CLOSED_MARKER);
+}
+
+pub(crate) fn push_muse_samples(channel: usize, samples: &[f64]) {
+    if let Some(rec) = IAF.lock().unwrap().as_mut() {
+        rec.buffers.push(channel, samples);
+    }
+}
+
+// Ends the eyes-closed recording and estimates the IAF from it
+#[frb]
+pub fn finish_iaf_eyes_closed() -> Result<IafEstimate> {
+    let Some(rec) = IAF.lock().unwrap().take() else {
+        bail!("No eyes-closed recording in progress");
+    };
+    recording::record_marker(0.0, EYES_CLOSED_MARKER);
+    // The longest stretch every channel holds, aligned on the same samples
+    let samples = rec.buffers.aligned_len();
+    let segment = rec.buffers.latest_windows(samples).unwrap_or_default();
+    Ok(estimate(
+        &[segment],
+        &rec.names,
+        rec.sampling_rate,
+        &rec.settings,
+    ))
+}
+
+#[frb]
+pub fn cancel_iaf_eyes_closed() {
+    if IAF.lock().unwrap().take().is_some() {
+        recording::record_marker(0.0, EYES_CLOSED_MARKER);
+    }
+}
+
+// Estimate from the eyes-closed segments (between eyes_closed markers 1 and
+// 0) of a recorded session
+#[frb]
+pub fn estimate_session_iaf(session_dir: String, settings: IafSettings) -> Result<IafEstimate> {
+    let metadata = session_metadata::read_session_metadata(session_dir.clone())?;
+    let model = metadata.device.model;
+    let sampling_rate = metadata.pipeline.eeg_sampling_rate as f64;
+    let dir = Path::new(&session_dir);
+    let eeg = replay::decode_eeg_channels(dir, model)?;
+    let (indices, names) = select_channels(&settings.channels, &model.eeg_channel_names());
+    let to_sample = |t: f64| ((t - eeg.start_time) * sampling_rate).round().max(0.0) as usize;
+
+    let mut ranges = Vec::new();
+    let mut open = None;
+    for record in SessionReader::open(dir)? {
+        let Record::Marker {
+            timestamp,
+            value,
+            label,
+        } = record?
+        else {
+            continue;
+        };
+        if label != EYES_CLOSED_MARKER {
+            continue;
+        }
+        if value != 0.0 {
+            open = Some(to_sample(timestamp));
+        } else if let Some(start) = open.take() {
+            ranges.push((start, to_sample(timestamp)));
+        }
+    }
+    let segments: Vec<Vec<&[f64]>> = ranges
+        .iter()
+        .filter_map(|&(start, end)| {
+            indices
+                .iter()
+                .map(|&c| {
+                    let channel = &eeg.channels[c];
+                    let end = end.min(channel.len());
+                    (start < end).then(|| &channel[start..end])
+                })
+                .collect()
+        })
+        .collect();
+    if segments.is_empty() {
+        bail!("No eyes-closed segments in the session");
+    }
+    Ok(estimate(&segments, &names, sampling_rate, &settings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn finds_alpha_peak_and_anchors_bands() {
+        let fs = 256.0;
+        let mut noise = test_util::noise(3);
+        let data: Vec<f64> = (0..256 * 20)
+            .map(|i| 10.0 * (2.0 * PI * 10.5 * i as f64 / fs).sin() + 4.0 * noise())
+            .collect();
+        let flat: Vec<f64> = (0..256 * 20).map(|_| noise()).collect();
+        let names = vec!["TP9".to_string(), "AUX".to_string(), "TP10".to_string()];
+        let estimate = estimate_iaf(
+            vec![data.clone(), data.clone(), flat],
+            names,
+            fs,
+            IafSettings::default(),
+        );
+        assert_eq!(estimate.channels.len(), 2);
+        let peak = estimate.channels[0].peak_frequency.unwrap();
+        assert!((peak - 10.5).abs() < 0.1, "{:?}", estimate);
+        let cog = estimate.channels[0].center_of_gravity.unwrap();
+        assert!((cog - 10.5).abs() < 0.5, "{:?}", estimate);
+        assert!((estimate.seconds - 20.0).abs() < 1e-9);
+
+        let defaults = crate::muse_types::PipelineSettings::default().bands;
+        assert_eq!(
+            bands_for(10.0, &defaults)[2],
+            BandRange::new("alpha", 8.0, 12.0)
+        );
+        let low = bands_for(7.0, &defaults);
+        assert_eq!(low[0], BandRange::new("delta", 1.0, 1.5));
+        assert_eq!(low[1], BandRange::new("theta", 1.5, 5.0));
+        assert_eq!(low[3], BandRange::new("beta", 9.0, 30.0));
+        assert_eq!(low[4], defaults[4]);
+    }
+}
@@ -0,0 +1,479 @@
+use flutter_rust_bridge::frb;
+use std::collections::VecDeque;
+
+// Head orientation, motion and gestures from the Muse IMU.
+//
+// Every accelerometer (g) and gyroscope (deg/s) sample is kept with its
+// timestamp: a 30 s history for the respiration and motion estimates, plus a
+// queue Dart drains with take_imu_samples. Orientation comes from a 6-axis
+// Madgwick filter stepped on each gyro sample with the latest accelerometer
+// reading. Axes are taken as x forward, y left, z up, with the accelerometer
+// reading +1 g on the axis pointing up.
+
+pub(crate) const IMU_SAMPLING_RATE: f64 = 52.0;
+const IMU_HISTORY_SECONDS: f64 = 30.0;
+// Samples queued for Dart before the oldest are dropped (one minute)
+const MAX_PENDING_SAMPLES: usize = 52 * 60;
+const MADGWICK_BETA: f64 = 0.1;
+
+// Head still vs. moving: RMS gyro rate over the last seconds
+const MOTION_WINDOW_SECONDS: f64 = 2.0;
+const MOVING_THRESHOLD_DPS: f64 = 10.0;
+
+// Nod = pitch rate, shake = yaw rate swinging back and forth at least
+// GESTURE_MIN_SWINGS times above GESTURE_RATE_DPS within the window
+const PITCH_AXIS: usize = 1;
+const YAW_AXIS: usize = 2;
+const GESTURE_WINDOW_SECONDS: f64 = 1.5;
+const GESTURE_RATE_DPS: f64 = 60.0;
+const GESTURE_MIN_SWINGS: usize = 3;
+
+// Gravity is the accelerometer low passed with this time constant
+const GRAVITY_TIME_CONSTANT: f64 = 5.0;
+const POSITION_THRESHOLD: f64 = 0.6;
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImuSample {
+    pub timestamp: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl ImuSample {
+    fn axes(&self) -> [f64; 3] {
+        [self.x, self.y, self.z]
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImuSamples {
+    pub accel: Vec<ImuSample>,
+    pub gyro: Vec<ImuSample>,
+}
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyPosition {
+    Upright,
+    Supine,
+    Prone,
+    Left,
+    Right,
+    Unknown,
+}
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeadGestureKind {
+    Nod,
+    Shake,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeadGesture {
+    pub kind: HeadGestureKind,
+    pub timestamp: f64,
+    // Largest angular rate of the gesture, deg/s
+    pub peak_rate: f64,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeadOrientation {
+    // Degrees; yaw is relative to the heading at connection and drifts
+    pub pitch: f64,
+    pub roll: f64,
+    pub yaw: f64,
+    // RMS gyro rate over the last 2 s, deg/s
+    pub motion_level: f64,
+    pub moving: bool,
+    pub body_position: BodyPosition,
+    pub timestamp: f64,
+}
+
+// Madgwick's IMU (gyro + accelerometer) orientation filter
+pub(crate) struct Madgwick {
+    q: [f64; 4],
+    beta: f64,
+}
+
+impl Madgwick {
+    pub fn new(beta: f64) -> Self {
+        Self {
+            q: [1.0, 0.0, 0.0, 0.0],
+            beta,
+        }
+    }
+
+    // Start from the tilt the accelerometer sees instead of converging to it
+    pub fn align(&mut self, accel: [f64; 3]) {
+        let [ax, ay, az] = accel;
+        let roll = ay.atan2(az) / 2.0;
+        let pitch = (-ax).atan2((ay * ay + az * az).sqrt()) / 2.0;
+        let (sr, cr) = roll.sin_cos();
+        let (sp, cp) = pitch.sin_cos();
+        self.q = [cr * cp, sr * cp, cr * sp, -sr * sp];
+    }
+
+    // gyro in rad/s, accel in any unit
+    pub fn update(&mut self, gyro: [f64; 3], accel: [f64; 3], dt: f64) {
+        let [q0, q1, q2, q3] = self.q;
+        let [gx, gy, gz] = gyro;
+        let mut dq = [
+            0.5 * (-q1 * gx - q2 * gy - q3 * gz),
+            0.5 * (q0 * gx + q2 * gz - q3 * gy),
+            0.5 * (q0 * gy - q1 * gz + q3 * gx),
+            0.5 * (q0 * gz + q1 * gy - q2 * gx),
+        ];
+
+        let norm = (accel[0] * accel[0] + accel[1] * accel[1] + accel[2] * accel[2]).sqrt();
+        if norm > 0.0 {
+            let [ax, ay, az] = accel.map(|a| a / norm);
+            // Gradient descent step towards the measured gravity direction
+            let s = [
+                4.0 * q0 * q2 * q2 + 2.0 * q2 * ax + 4.0 * q0 * q1 * q1 - 2.0 * q1 * ay,
+                4.0 * q1 * q3 * q3 - 2.0 * q3 * ax + 4.0 * q0 * q0 * q1 - 2.0 * q0 * ay - 4.0 * q1
+                    + 8.0 * q1 * q1 * q1
+                    + 8.0 * q1 * q2 * q2
+                    + 4.0 * q1 * az,
+                4.0 * q0 * q0 * q2 + 2.0 * q0 * ax + 4.0 * q2 * q3 * q3 - 2.0 * q3 * ay - 4.0 * q2
+                    + 8.0 * q2 * q1 * q1
+                    + 8.0 * q2 * q2 * q2
+                    + 4.0 * q2 * az,
+                4.0 * q1 * q1 * q3 - 2.0 * q1 * ax + 4.0 * q2 * q2 * q3 - 2.0 * q2 * ay,
+            ];
+            let s_norm = s.iter().map(|v| v * v).sum::<f64>().sqrt();
+            if s_norm > 0.0 {
+                for i in 0..4 {
+                    dq[i] -= self.beta * s[i] / s_norm;
+                }
+            }
+        }
+
+        for (q, d) in self.q.iter_mut().zip(dq) {
+            *q += d * dt;
+        }
+        let q_norm = self.q.iter().map(|v| v * v).sum::<f64>().sqrt();
+        self.q = self.q.map(|v| v / q_norm);
+    }
+
+    // (pitch, roll, yaw) in degrees
+    pub fn euler(&self) -> (f64, f64, f64) {
+        let [q0, q1, q2, q3] = self.q;
+        let roll = (2.0 * (q0 * q1 + q2 * q3)).atan2(1.0 - 2.0 * (q1 * q1 + q2 * q2));
+        let pitch = (2.0 * (q0 * q2 - q3 * q1)).clamp(-1.0, 1.0).asin();
+        let yaw = (2.0 * (q0 * q3 + q1 * q2)).atan2(1.0 - 2.0 * (q2 * q2 + q3 * q3));
+        (pitch.to_degrees(), roll.to_degrees(), yaw.to_degrees())
+    }
+}
+
+pub(crate) fn body_position(gravity: [f64; 3]) -> BodyPosition {
+    let norm = gravity.iter().map(|v| v * v).sum::<f64>().sqrt();
+    if norm <= 0.0 {
+        return BodyPosition::Unknown;
+    }
+    let [x, y, z] = gravity.map(|v| v / norm);
+    if z > POSITION_THRESHOLD {
+        BodyPosition::Upright
+    } else if x.abs() >= y.abs() && x > POSITION_THRESHOLD {
+        BodyPosition::Supine
+    } else if x.abs() >= y.abs() && x < -POSITION_THRESHOLD {
+        BodyPosition::Prone
+    } else if y < -POSITION_THRESHOLD {
+        BodyPosition::Left
+    } else if y > POSITION_THRESHOLD {
+        BodyPosition::Right
+    } else {
+        BodyPosition::Unknown
+    }
+}
+
+// Number of alternating excursions above the threshold and the peak rate
+fn swings(rates: impl Iterator<Item = f64>) -> (usize, f64) {
+    let mut count = 0;
+    let mut last_sign = 0.0;
+    let mut peak: f64 = 0.0;
+    for rate in rates {
+        peak = peak.max(rate.abs());
+        if rate.abs() >= GESTURE_RATE_DPS && rate.signum() != last_sign {
+            count += 1;
+            last_sign = rate.signum();
+        }
+    }
+    (count, peak)
+}
+
+pub(crate) struct ImuTracker {
+    accel: VecDeque<ImuSample>,
+    gyro: VecDeque<ImuSample>,
+    pending_accel: VecDeque<ImuSample>,
+    pending_gyro: VecDeque<ImuSample>,
+    filter: Madgwick,
+    aligned: bool,
+    gravity: Option<[f64; 3]>,
+    gestures: Vec<HeadGesture>,
+    last_gesture: f64,
+}
+
+impl ImuTracker {
+    pub fn new() -> Self {
+        Self {
+            accel: VecDeque::new(),
+            gyro: VecDeque::new(),
+            pending_accel: VecDeque::new(),
+            pending_gyro: VecDeque::new(),
+            filter: Madgwick::new(MADGWICK_BETA),
+            aligned: false,
+            gravity: None,
+            gestures: Vec::new(),
+            last_gesture: f64::NEG_INFINITY,
+        }
+    }
+
+    fn keep(
+        history: &mut VecDeque<ImuSample>,
+        pending: &mut VecDeque<ImuSample>,
+        sample: ImuSample,
+    ) {
+        history.push_back(sample);
+        while history
+            .front()
+            .is_some_and(|s| s.timestamp < sample.timestamp - IMU_HISTORY_SECONDS)
+        {
+            history.pop_front();
+        }
+        if pending.len() == MAX_PENDING_SAMPLES {
+            pending.pop_front();
+        }
+        pending.push_back(sample);
+    }
+
+    pub fn push_accel(&mut self, timestamp: f64, xyz: [f64; 3]) {
+        let dt = self
+            .accel
+            .back()
+            .map(|s| (timestamp - s.timestamp).clamp(0.0, 1.0))
+            .unwrap_or(0.0);
+        let alpha = dt / (GRAVITY_TIME_CONSTANT + dt);
+        self.gravity = Some(match self.gravity {
+            Some(g) => [0, 1, 2].map(|i| g[i] + alpha * (xyz[i] - g[i])),
+            None => xyz,
+        });
+        if !self.aligned {
+            self.filter.align(xyz);
+            self.aligned = true;
+        }
+        let [x, y, z] = xyz;
+        Self::keep(
+            &mut self.accel,
+            &mut self.pending_accel,
+            ImuSample { timestamp, x, y, z },
+        );
+    }
+
+    pub fn push_gyro(&mut self, timestamp: f64, xyz: [f64; 3]) {
+        let dt = self
+            .gyro
+            .back()
+            .map(|s| timestamp - s.timestamp)
+            .filter(|dt| *dt > 0.0 && *dt < 0.5)
+            .unwrap_or(1.0 / IMU_SAMPLING_RATE);
+        let accel = self.accel.back().map(|s| s.axes()).unwrap_or([0.0; 3]);
+        self.filter.update(xyz.map(|v| v.to_radians()), accel, dt);
+
+        let [x, y, z] = xyz;
+        Self::keep(
+            &mut self.gyro,
+            &mut self.pending_gyro,
+            ImuSample { timestamp, x, y, z },
+        );
+        self.detect_gesture(timestamp);
+    }
+
+    fn detect_gesture(&mut self, now: f64) {
+        if now - self.last_gesture < GESTURE_WINDOW_SECONDS {
+            return;
+        }
+        let recent = || {
+            self.gyro
+                .iter()
+                .rev()
+                .take_while(move |s| s.timestamp >= now - GESTURE_WINDOW_SECONDS)
+        };
+        let (nods, nod_peak) = swings(recent().map(|s| s.axes()[PITCH_AXIS]));
+        let (shakes, shake_peak) = swings(recent().map(|s| s.axes()[YAW_AXIS]));
+        let gesture = if nods >= GESTURE_MIN_SWINGS && nod_peak > shake_peak {
+            Some((HeadGestureKind::Nod, nod_peak))
+        } else if shakes >= GESTURE_MIN_SWINGS && shake_peak > nod_peak {
+            Some((HeadGestureKind::Shake, shake_peak))
+        } else {
+            None
+        };
+        if let Some((kind, peak_rate)) = gesture {
+            self.gestures.push(HeadGesture {
+                kind,
+                timestamp: now,
+                peak_rate,
+            });
+            self.last_gesture = now;
+        }
+    }
+
+    // Accelerometer samples of the last `seconds` as (timestamp, xyz)
+    pub fn accel_window(&self, seconds: f64) -> Vec<(f64, [f64; 3])> {
+        let Some(last) = self.accel.back().map(|s| s.timestamp) else {
+            return Vec::new();
+        };
+        self.accel
+            .iter()
+            .filter(|s| s.timestamp >= last - seconds)
+            .map(|s| (s.timestamp, s.axes()))
+            .collect()
+    }
+
+    // Std of the accelerometer magnitude between two times, in g
+    pub fn accel_std_between(&self, start: f64, end: f64) -> f64 {
+        let magnitudes: Vec<f64> = self
+            .accel
+            .iter()
+            .filter(|s| s.timestamp >= start && s.timestamp <= end)
+            .map(|s| (s.x * s.x + s.y * s.y + s.z * s.z).sqrt())
+            .collect();
+        if magnitudes.len() < 2 {
+            return 0.0;
+        }
+        let n = magnitudes.len() as f64;
+        let mean = magnitudes.iter().sum::<f64>() / n;
+        (magnitudes.iter().map(|m| (m - mean).powi(2)).sum::<f64>() / n).sqrt()
+    }
+
+    // RMS gyro rate between two times, deg/s
+    pub fn gyro_rms_between(&self, start: f64, end: f64) -> f64 {
+        let squares: Vec<f64> = self
+            .gyro
+            .iter()
+            .filter(|s| s.timestamp >= start && s.timestamp <= end)
+            .map(|s| s.x * s.x + s.y * s.y + s.z * s.z)
+            .collect();
+        if squares.is_empty() {
+            return 0.0;
+        }
+        (squares.iter().sum::<f64>() / squares.len() as f64).sqrt()
+    }
+
+    // RMS gyro rate over the last MOTION_WINDOW_SECONDS, deg/s
+    pub fn motion_level(&self) -> f64 {
+        let Some(last) = self.gyro.back().map(|s| s.timestamp) else {
+            return 0.0;
+        };
+        self.gyro_rms_between(last - MOTION_WINDOW_SECONDS, last)
+    }
+
+    pub fn orientation(&self) -> Option<HeadOrientation> {
+        let timestamp = self.gyro.back()?.timestamp;
+        let (pitch, roll, yaw) = self.filter.euler();
+        let motion_level = self.motion_level();
+        Some(HeadOrientation {
+            pitch,
+            roll,
+            yaw,
+            motion_level,
+            moving: motion_level > MOVING_THRESHOLD_DPS,
+            body_position: self
+                .gravity
+                .map(body_position)
+                .unwrap_or(BodyPosition::Unknown),
+            timestamp,
+        })
+    }
+
+    pub fn take_gestures(&mut self) -> Vec<HeadGesture> {
+        std::mem::take(&mut self.gestures)
+    }
+
+    pub fn take_samples(&mut self) -> ImuSamples {
+        ImuSamples {
+            accel: self.pending_accel.drain(..).collect(),
+            gyro: self.pending_gyro.drain(..).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Steps the tracker one sample with fixed accelerometer and given gyro
+    fn run(
+        imu: &mut ImuTracker,
+        t: &mut f64,
+        accel: [f64; 3],
+        gyro: impl Fn(usize) -> [f64; 3],
+        n: usize,
+    ) {
+        for i in 0..n {
+            *t += 1.0 / IMU_SAMPLING_RATE;
+            imu.push_accel(*t, accel);
+            imu.push_gyro(*t, gyro(i));
+        }
+    }
+
+    #[test]
+    fn tracks_tilt_turns_and_nods() {
+        // Still, pitched 30°
+        let (mut imu, mut t) = (ImuTracker::new(), 0.0);
+        let tilt = 30f64.to_radians();
+        run(
+            &mut imu,
+            &mut t,
+            [-tilt.sin(), 0.0, tilt.cos()],
+            |_| [0.0; 3],
+            52 * 3,
+        );
+        let o = imu.orientation().unwrap();
+        assert!((o.pitch - 30.0).abs() < 1.0, "pitch {}", o.pitch);
+        assert!(o.roll.abs() < 1.0 && !o.moving);
+        assert!(imu.take_gestures().is_empty());
+
+        // Level, turning 90° to the left in one second
+        let (mut imu, mut t) = (ImuTracker::new(), 0.0);
+        let level = [0.0, 0.0, 1.0];
+        run(&mut imu, &mut t, level, |_| [0.0; 3], 52);
+        run(&mut imu, &mut t, level, |_| [0.0, 0.0, 90.0], 52);
+        let o = imu.orientation().unwrap();
+        assert!((o.yaw - 90.0).abs() < 5.0, "yaw {}", o.yaw);
+        assert!(o.moving);
+        assert_eq!(o.body_position, BodyPosition::Upright);
+        imu.take_gestures();
+
+        // Nod: pitch rate swinging ±120 deg/s at 2 Hz for a second
+        run(&mut imu, &mut t, level, |_| [0.0; 3], 104);
+        let nod = |i: usize| {
+            let rate = 120.0 * (2.0 * std::f64::consts::PI * 2.0 * i as f64 / 52.0).sin();
+            [0.0, rate, 0.0]
+        };
+        run(&mut imu, &mut t, level, nod, 52);
+        let gestures = imu.take_gestures();
+        assert_eq!(gestures.len(), 1);
+        assert_eq!(gestures[0].kind, HeadGestureKind::Nod);
+
+        let samples = imu.take_samples();
+        assert_eq!(samples.accel.len(), 52 * 5);
+        assert_eq!(samples.gyro.len(), 52 * 5);
+        assert!(imu.take_samples().gyro.is_empty());
+    }
+
+    #[test]
+    fn body_position_from_gravity() {
+        assert_eq!(body_position([0.0, 0.1, 1.0]), BodyPosition::Upright);
+        assert_eq!(body_position([0.95, 0.2, 0.1]), BodyPosition::Supine);
+        assert_eq!(body_position([-0.9, 0.1, 0.3]), BodyPosition::Prone);
+        assert_eq!(body_position([0.2, -0.95, 0.1]), BodyPosition::Left);
+        assert_eq!(body_position([0.1, 0.9, 0.3]), BodyPosition::Right);
+        assert_eq!(body_position([0.0; 3]), BodyPosition::Unknown);
+    }
+}
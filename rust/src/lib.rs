@@ -10,8 +10,60 @@ pub use android_jni::{
 };
 
 // Muse S specific modules (app logic, not BrainFlow)
+mod asymmetry;
+mod baseline;
+mod channel_buffers;
+mod closed_loop;
+mod custom_model;
+mod erp;
+mod features;
+mod fnirs;
+mod heart_rate;
+mod hrv;
+mod iaf;
+mod imu;
+mod metrics;
+mod motion_gate;
+mod motor_imagery;
 mod muse_parser;
 mod muse_types;
-
+mod personal_classifier;
+mod respiration;
+mod sleep;
+mod sleep_events;
+mod spectral;
+mod spo2;
+mod ssvep;
+#[cfg(test)]
+mod test_util;
+pub use asymmetry::*;
+pub use baseline::*;
+pub use closed_loop::*;
+pub use custom_model::*;
+pub use erp::*;
+pub use features::*;
+pub use fnirs::*;
+pub use heart_rate::*;
+pub use hrv::*;
+pub use iaf::*;
+pub use imu::*;
+pub use metrics::*;
+pub use motion_gate::*;
+pub use motor_imagery::*;
 pub use muse_parser::*;
 pub use muse_types::*;
+pub use personal_classifier::*;
+pub use respiration::*;
+pub use sleep::*;
+pub use sleep_events::*;
+pub use spo2::*;
+pub use ssvep::*;
+
+// Session recording (crash-safe chunks, recovery, metadata, replay, export)
+pub mod eeg_codec;
+pub mod export;
+pub mod recording;
+pub mod recording_edit;
+pub mod replay;
+pub mod session_crypto;
+pub mod session_metadata;
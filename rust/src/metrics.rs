@@ -0,0 +1,369 @@
+use crate::api::BandPowers;
+use crate::custom_model::{self, CustomPrediction};
+use crate::iaf;
+use crate::muse_types::BandRange;
+use anyhow::{Context, Result};
+use brainflow::brainflow_model_params::BrainFlowModelParamsBuilder;
+use brainflow::data_filter::{self, Band};
+use brainflow::ml_model::MlModel;
+use brainflow::{BrainFlowClassifiers, BrainFlowMetrics};
+use flutter_rust_bridge::frb;
+use log::{info, warn};
+use ndarray::Array2;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+// BrainFlow mindfulness / restfulness predictions.
+//
+// BrainFlow's default classifiers take the concatenated average and standard
+// deviation over EEG channels of the five relative band powers, as returned
+// by get_avg_band_powers. The metrics service keeps a rolling window of every
+// real EEG channel (no AUX inputs), builds that vector at a fixed cadence
+// and runs it through one prepared MlModel per metric. The models are
+// prepared once and kept until the service stops, when they are released.
+// Registered custom models (custom_model.rs) run at the same cadence.
+// With an individual alpha frequency set, the bands are IAF-anchored (iaf.rs).
+
+static MODELS: Mutex<Vec<(MlMetric, MlModel)>> = Mutex::new(Vec::new());
+
+const MAX_QUEUED_UPDATES: usize = 256;
+
+// The bands of get_avg_band_powers
+fn avg_band_ranges() -> Vec<BandRange> {
+    vec![
+        BandRange::new("delta", 2.0, 4.0),
+        BandRange::new("theta", 4.0, 8.0),
+        BandRange::new("alpha", 8.0, 13.0),
+        BandRange::new("beta", 13.0, 30.0),
+        BandRange::new("gamma", 30.0, 45.0),
+    ]
+}
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MlMetric {
+    Mindfulness,
+    Restfulness,
+}
+
+impl MlMetric {
+    fn brainflow_metric(&self) -> BrainFlowMetrics {
+        match self {
+            MlMetric::Mindfulness => BrainFlowMetrics::Mindfulness,
+            MlMetric::Restfulness => BrainFlowMetrics::Restfulness,
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsSettings {
+    pub metrics: Vec<MlMetric>,
+    // Time between predictions
+    pub cadence_seconds: f64,
+    // EEG per prediction
+    pub window_seconds: f64,
+    // Let BrainFlow detrend and band pass before the band powers
+    pub apply_filters: bool,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self {
+            metrics: vec![MlMetric::Mindfulness, MlMetric::Restfulness],
+            cadence_seconds: 1.0,
+            window_seconds: 4.0,
+            apply_filters: true,
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricPrediction {
+    pub metric: MlMetric,
+    // 0-1 as returned by the classifier
+    pub value: f64,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsUpdate {
+    pub timestamp: f64,
+    pub predictions: Vec<MetricPrediction>,
+    // Relative delta, theta, alpha, beta, gamma: mean and SD over channels
+    pub band_powers_avg: Vec<f64>,
+    pub band_powers_std: Vec<f64>,
+    // Registered custom models whose windows are filled
+    pub custom: Vec<CustomPrediction>,
+    pub channels: Vec<String>,
+}
+
+fn create_model(metric: MlMetric) -> Result<MlModel> {
+    let params = BrainFlowModelParamsBuilder::new()
+        .metric(metric.brainflow_metric())
+        .classifier(BrainFlowClassifiers::DefaultClassifier)
+        .build();
+    let model = MlModel::new(params)?;
+    model
+        .prepare()
+        .with_context(|| format!("Failed to prepare {:?} model", metric))?;
+    info!("[METRICS] Prepared {:?} model", metric);
+    Ok(model)
+}
+
+// Prepares the models not prepared yet
+pub(crate) fn prepare_models(metrics: &[MlMetric]) -> Result<()> {
+    let mut models = MODELS.lock().unwrap();
+    for &metric in metrics {
+        if !models.iter().any(|(m, _)| *m == metric) {
+            models.push((metric, create_model(metric)?));
+        }
+    }
+    Ok(())
+}
+
+// Runs the prepared model of `metric`, preparing it on first use
+pub(crate) fn predict(metric: MlMetric, features: &mut [f64]) -> Result<f64> {
+    prepare_models(&[metric])?;
+    let models = MODELS.lock().unwrap();
+    let (_, model) = models
+        .iter()
+        .find(|(m, _)| *m == metric)
+        .context("Model not prepared")?;
+    let output = model.predict(features)?;
+    output.first().copied().context("Empty prediction")
+}
+
+pub(crate) fn release_models() {
+    let mut models = MODELS.lock().unwrap();
+    for (metric, model) in models.drain(..) {
+        if let Err(e) = model.release() {
+            warn!("[METRICS] Failed to release {:?} model: {:?}", metric, e);
+        }
+    }
+}
+
+// Feature vector of a single aggregated band power set: relative powers
+// and, with no channels to spread over, zero SD
+pub(crate) fn single_channel_features(bands: &BandPowers) -> Option<Vec<f64>> {
+    let powers = [
+        bands.delta,
+        bands.theta,
+        bands.alpha,
+        bands.beta,
+        bands.gamma,
+    ];
+    let total: f64 = powers.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mut features: Vec<f64> = powers.iter().map(|p| p / total).collect();
+    features.extend([0.0; 5]);
+    Some(features)
+}
+
+pub(crate) struct MetricsService {
+    pub settings: MetricsSettings,
+    sampling_rate: f64,
+    // Indices and names of the EEG channels fed to the classifiers
+    channels: Vec<(usize, String)>,
+    buffers: Vec<Vec<f64>>,
+    last_prediction: f64,
+    latest: Option<MetricsUpdate>,
+    queued: VecDeque<MetricsUpdate>,
+}
+
+impl MetricsService {
+    pub fn new(settings: MetricsSettings, sampling_rate: f64, channel_names: &[String]) -> Self {
+        let channels: Vec<(usize, String)> = channel_names
+            .iter()
+            .enumerate()
+            .filter(|(_, name)| !name.starts_with("AUX"))
+            .map(|(i, name)| (i, name.clone()))
+            .collect();
+        Self {
+            settings,
+            sampling_rate,
+            buffers: vec![Vec::new(); channels.len()],
+            channels,
+            last_prediction: f64::NEG_INFINITY,
+            latest: None,
+            queued: VecDeque::new(),
+        }
+    }
+
+    fn samples(&self, seconds: f64) -> usize {
+        (seconds * self.sampling_rate) as usize
+    }
+
+    // Keeps enough for the built-in metrics and every custom model
+    pub fn push(&mut self, channel: usize, samples: &[f64]) {
+        let seconds = self
+            .settings
+            .window_seconds
+            .max(custom_model::max_window_seconds());
+        let capacity = self.samples(seconds);
+        if let Some(index) = self.channels.iter().position(|(c, _)| *c == channel) {
+            let buffer = &mut self.buffers[index];
+            buffer.extend_from_slice(samples);
+            if buffer.len() > capacity {
+                buffer.drain(..buffer.len() - capacity);
+            }
+        }
+    }
+
+    fn cadence_due(&mut self, now: f64) -> bool {
+        if self.channels.is_empty() || now - self.last_prediction < self.settings.cadence_seconds {
+            return false;
+        }
+        self.last_prediction = now;
+        true
+    }
+
+    // Channels x samples of the built-in metrics window, once filled
+    fn metrics_window(&self) -> Option<Array2<f64>> {
+        let window = self.samples(self.settings.window_seconds);
+        if self.buffers.iter().any(|b| b.len() < window) {
+            return None;
+        }
+        let flat: Vec<f64> = self
+            .buffers
+            .iter()
+            .flat_map(|b| &b[b.len() - window..])
+            .copied()
+            .collect();
+        Array2::from_shape_vec((self.channels.len(), window), flat).ok()
+    }
+
+    // Predicts all metrics and custom models if due
+    pub fn update(&mut self, now: f64) {
+        if !self.cadence_due(now) {
+            return;
+        }
+        let names: Vec<String> = self.channels.iter().map(|(_, n)| n.clone()).collect();
+        let custom = custom_model::predict_registered(&names, &self.buffers, self.sampling_rate);
+        let builtin = if self.settings.metrics.is_empty() {
+            None
+        } else {
+            self.metrics_window()
+                .and_then(|data| self.predict_metrics(data))
+        };
+        if builtin.is_none() && custom.is_empty() {
+            return;
+        }
+        let (predictions, band_powers_avg, band_powers_std) = builtin.unwrap_or_default();
+        let update = MetricsUpdate {
+            timestamp: now,
+            predictions,
+            band_powers_avg,
+            band_powers_std,
+            custom,
+            channels: names,
+        };
+        if self.queued.len() == MAX_QUEUED_UPDATES {
+            self.queued.pop_front();
+        }
+        self.queued.push_back(update.clone());
+        self.latest = Some(update);
+    }
+
+    fn predict_metrics(
+        &self,
+        data: Array2<f64>,
+    ) -> Option<(Vec<MetricPrediction>, Vec<f64>, Vec<f64>)> {
+        let rows = (0..self.channels.len()).collect();
+        let bands = iaf::anchor_bands(&avg_band_ranges())
+            .into_iter()
+            .map(|b| Band {
+                freq_start: b.freq_start,
+                freq_stop: b.freq_stop,
+            })
+            .collect();
+        let (avg, std) = data_filter::get_custom_band_powers(
+            data,
+            bands,
+            rows,
+            self.sampling_rate as usize,
+            self.settings.apply_filters,
+        )
+        .map_err(|e| warn!("[METRICS] Band powers failed: {:?}", e))
+        .ok()?;
+        let features: Vec<f64> = avg.iter().chain(&std).copied().collect();
+        let predictions = self
+            .settings
+            .metrics
+            .iter()
+            .filter_map(|&metric| {
+                // predict takes the features mutably, each model gets a copy
+                match predict(metric, &mut features.clone()) {
+                    Ok(value) => Some(MetricPrediction { metric, value }),
+                    Err(e) => {
+                        warn!("[METRICS] {:?} prediction failed: {:?}", metric, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        Some((predictions, avg, std))
+    }
+
+    pub fn latest(&self) -> Option<&MetricsUpdate> {
+        self.latest.as_ref()
+    }
+
+    pub fn take_queued(&mut self) -> Vec<MetricsUpdate> {
+        self.queued.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_real_channels_at_cadence() {
+        let names: Vec<String> = ["TP9", "AF7", "AF8", "TP10", "AUX"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let settings = MetricsSettings {
+            window_seconds: 1.0,
+            ..Default::default()
+        };
+        let mut service = MetricsService::new(settings, 24.0, &names);
+        assert_eq!(service.channels.len(), 4);
+
+        let mut windows = Vec::new();
+        for packet in 0..8 {
+            let now = packet as f64 * 0.5;
+            for channel in 0..5 {
+                let samples: Vec<f64> = (0..12)
+                    .map(|i| (channel * 100 + packet * 12 + i) as f64)
+                    .collect();
+                service.push(channel, &samples);
+            }
+            if service.cadence_due(now) {
+                windows.extend(service.metrics_window().map(|w| (now, w)));
+            }
+        }
+        // Ticks once per second, the first one before the window is filled
+        let times: Vec<f64> = windows.iter().map(|(t, _)| *t).collect();
+        assert_eq!(times, vec![1.0, 2.0, 3.0]);
+        let (_, last) = &windows[2];
+        assert_eq!(last.shape(), &[4, 24]);
+        assert_eq!(last[[1, 23]], 100.0 + 6.0 * 12.0 + 11.0);
+
+        let bands = BandPowers {
+            delta: 2.0,
+            theta: 1.0,
+            alpha: 1.0,
+            beta: 0.5,
+            gamma: 0.5,
+        };
+        let features = single_channel_features(&bands).unwrap();
+        assert_eq!(features.len(), 10);
+        assert_eq!(features[0], 0.4);
+        assert_eq!(features[9], 0.0);
+    }
+}
@@ -0,0 +1,282 @@
+use crate::api::BandPowers;
+use crate::imu::ImuTracker;
+use flutter_rust_bridge::frb;
+use std::collections::VecDeque;
+
+// Cross-sensor motion artifact gate.
+//
+// Every EEG band power window and PPG heart rate window is checked against
+// the IMU samples recorded over the same time span. Head rotation (RMS gyro
+// rate) or acceleration (std of the accelerometer magnitude) above the
+// threshold marks the window as contaminated and down-weights it; beyond
+// severe_factor times the threshold it is excluded. Weights feed the band
+// power / metric smoothing, the heart rate confidence and the SpO2 gate.
+
+// Contaminated windows kept for take_motion_contaminations
+const MAX_QUEUED_WINDOWS: usize = 256;
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotionGateSettings {
+    pub gyro_threshold_dps: f64,
+    pub accel_threshold_g: f64,
+    // Windows above threshold * severe_factor are excluded outright
+    pub severe_factor: f64,
+    // Weight of a contaminated but not excluded window, 0-1
+    pub down_weight: f64,
+    // EMA factor for band powers and metrics, 1 = no smoothing
+    pub smoothing: f64,
+}
+
+impl Default for MotionGateSettings {
+    fn default() -> Self {
+        Self {
+            gyro_threshold_dps: 15.0,
+            accel_threshold_g: 0.05,
+            severe_factor: 3.0,
+            down_weight: 0.3,
+            smoothing: 1.0,
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MotionStream {
+    Eeg,
+    Ppg,
+}
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContaminationReason {
+    HeadRotation,
+    HeadAcceleration,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowContamination {
+    pub stream: MotionStream,
+    pub start: f64,
+    pub end: f64,
+    pub gyro_rms: f64,
+    pub accel_std: f64,
+    // Empty for a clean window
+    pub reasons: Vec<ContaminationReason>,
+    // 1 clean, down_weight contaminated, 0 excluded
+    pub weight: f64,
+    pub excluded: bool,
+}
+
+impl WindowContamination {
+    pub fn is_clean(&self) -> bool {
+        self.reasons.is_empty()
+    }
+}
+
+pub(crate) fn assess(
+    imu: &ImuTracker,
+    stream: MotionStream,
+    start: f64,
+    end: f64,
+    settings: &MotionGateSettings,
+) -> WindowContamination {
+    let gyro_rms = imu.gyro_rms_between(start, end);
+    let accel_std = imu.accel_std_between(start, end);
+    let rotation = gyro_rms / settings.gyro_threshold_dps;
+    let acceleration = accel_std / settings.accel_threshold_g;
+
+    let mut reasons = Vec::new();
+    if rotation >= 1.0 {
+        reasons.push(ContaminationReason::HeadRotation);
+    }
+    if acceleration >= 1.0 {
+        reasons.push(ContaminationReason::HeadAcceleration);
+    }
+    let excluded = rotation.max(acceleration) >= settings.severe_factor;
+    let weight = if excluded {
+        0.0
+    } else if reasons.is_empty() {
+        1.0
+    } else {
+        settings.down_weight.clamp(0.0, 1.0)
+    };
+    WindowContamination {
+        stream,
+        start,
+        end,
+        gyro_rms,
+        accel_std,
+        reasons,
+        weight,
+        excluded,
+    }
+}
+
+// Weighted EMA; excluded windows leave the state untouched and give None
+#[derive(Default)]
+struct Smoothed {
+    value: Option<f64>,
+}
+
+impl Smoothed {
+    fn update(&mut self, new: Option<f64>, weight: f64, smoothing: f64) -> Option<f64> {
+        let new = new?;
+        if weight <= 0.0 {
+            return None;
+        }
+        let alpha = (smoothing * weight).clamp(0.0, 1.0);
+        let value = match self.value {
+            Some(prev) => prev + alpha * (new - prev),
+            None => new,
+        };
+        self.value = Some(value);
+        Some(value)
+    }
+}
+
+pub(crate) struct MotionGate {
+    pub settings: MotionGateSettings,
+    bands: [Smoothed; 5],
+    concentration: Smoothed,
+    relaxation: Smoothed,
+    last_eeg: Option<WindowContamination>,
+    last_ppg: Option<WindowContamination>,
+    queued: VecDeque<WindowContamination>,
+}
+
+impl MotionGate {
+    pub fn new() -> Self {
+        Self {
+            settings: MotionGateSettings::default(),
+            bands: Default::default(),
+            concentration: Smoothed::default(),
+            relaxation: Smoothed::default(),
+            last_eeg: None,
+            last_ppg: None,
+            queued: VecDeque::new(),
+        }
+    }
+
+    // Remembers the latest assessment per stream and queues contaminated
+    // windows that do not overlap the previously queued one of that stream
+    pub fn record(&mut self, contamination: WindowContamination) {
+        if !contamination.is_clean() {
+            let overlaps = self
+                .queued
+                .iter()
+                .rev()
+                .find(|c| c.stream == contamination.stream)
+                .is_some_and(|c| contamination.start < c.end);
+            if !overlaps {
+                if self.queued.len() == MAX_QUEUED_WINDOWS {
+                    self.queued.pop_front();
+                }
+                self.queued.push_back(contamination.clone());
+            }
+        }
+        match contamination.stream {
+            MotionStream::Eeg => self.last_eeg = Some(contamination),
+            MotionStream::Ppg => self.last_ppg = Some(contamination),
+        }
+    }
+
+    pub fn last(&self, stream: MotionStream) -> Option<&WindowContamination> {
+        match stream {
+            MotionStream::Eeg => self.last_eeg.as_ref(),
+            MotionStream::Ppg => self.last_ppg.as_ref(),
+        }
+    }
+
+    pub fn take_queued(&mut self) -> Vec<WindowContamination> {
+        self.queued.drain(..).collect()
+    }
+
+    // Band powers and metrics of one EEG window after weighting/exclusion
+    pub fn smooth_eeg(
+        &mut self,
+        bands: Option<BandPowers>,
+        concentration: Option<f64>,
+        relaxation: Option<f64>,
+        weight: f64,
+    ) -> (Option<BandPowers>, Option<f64>, Option<f64>) {
+        let smoothing = self.settings.smoothing;
+        let values = bands
+            .as_ref()
+            .map(|b| [b.delta, b.theta, b.alpha, b.beta, b.gamma]);
+        let mut out = [None; 5];
+        for (i, slot) in out.iter_mut().enumerate() {
+            *slot = self.bands[i].update(values.map(|v| v[i]), weight, smoothing);
+        }
+        let bands = match out {
+            [Some(delta), Some(theta), Some(alpha), Some(beta), Some(gamma)] => Some(BandPowers {
+                delta,
+                theta,
+                alpha,
+                beta,
+                gamma,
+            }),
+            _ => None,
+        };
+        (
+            bands,
+            self.concentration.update(concentration, weight, smoothing),
+            self.relaxation.update(relaxation, weight, smoothing),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn motion_down_weights_then_excludes_windows() {
+        let mut imu = ImuTracker::new();
+        let mut t = 0.0;
+        // 2 s still, 1 s slow turn (20 deg/s), 1 s violent shake (±150 deg/s)
+        for i in 0..52 * 4 {
+            t += 1.0 / 52.0;
+            let rate = match i / 52 {
+                0 | 1 => 0.0,
+                2 => 20.0,
+                _ => 150.0 * if i % 2 == 0 { 1.0 } else { -1.0 },
+            };
+            imu.push_accel(t, [0.0, 0.0, 1.0]);
+            imu.push_gyro(t, [0.0, 0.0, rate]);
+        }
+        let settings = MotionGateSettings::default();
+        let clean = assess(&imu, MotionStream::Eeg, 0.5, 1.5, &settings);
+        let turn = assess(&imu, MotionStream::Eeg, 2.1, 2.9, &settings);
+        let shake = assess(&imu, MotionStream::Eeg, 3.1, 3.9, &settings);
+        assert!(clean.is_clean() && clean.weight == 1.0);
+        assert_eq!(turn.reasons, vec![ContaminationReason::HeadRotation]);
+        assert!(!turn.excluded && turn.weight == settings.down_weight);
+        assert!(shake.excluded && shake.weight == 0.0);
+
+        let mut gate = MotionGate::new();
+        gate.settings.smoothing = 0.5;
+        let bands = |alpha| BandPowers {
+            delta: 1.0,
+            theta: 1.0,
+            alpha,
+            beta: 1.0,
+            gamma: 1.0,
+        };
+        let (b, _, _) = gate.smooth_eeg(Some(bands(10.0)), Some(50.0), None, clean.weight);
+        assert_eq!(b.unwrap().alpha, 10.0);
+        let (b, c, _) = gate.smooth_eeg(Some(bands(20.0)), Some(90.0), None, turn.weight);
+        assert!((b.unwrap().alpha - 11.5).abs() < 1e-9);
+        assert!((c.unwrap() - 56.0).abs() < 1e-9);
+        let (b, c, _) = gate.smooth_eeg(Some(bands(99.0)), Some(0.0), None, shake.weight);
+        assert!(b.is_none() && c.is_none());
+
+        for c in [clean, turn, shake] {
+            gate.record(c);
+        }
+        let queued = gate.take_queued();
+        assert_eq!(queued.len(), 2);
+        assert!(gate.last(MotionStream::Eeg).unwrap().excluded);
+    }
+}
@@ -0,0 +1,662 @@
+use crate::api;
+use crate::channel_buffers::{select_channels, ChannelBuffers};
+use crate::personal_classifier::{self, ClassifierAlgorithm, LinearModel};
+use crate::recording;
+use anyhow::{bail, Context, Result};
+use brainflow::board_shim::{self, BoardShim};
+use brainflow::data_filter;
+use brainflow::{BoardIds, BrainFlowPresets, FilterTypes};
+use flutter_rust_bridge::frb;
+use log::{info, warn};
+use ndarray::{Array1, Array2, Array3};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+// Two-class (left / right hand) motor imagery BCI.
+//
+// Calibration collects labeled trials: a cue marks a trial and the window
+// starting `offset_seconds` later is kept once it has arrived. Calibrating
+// band passes the trials to the mu/beta band, computes Common Spatial
+// Patterns with BrainFlow's get_csp, keeps the filters of the most extreme
+// eigenvalues and trains a shrinkage LDA on the normalized log-variances of
+// the filtered signals; accuracy is cross-validated with the CSP refitted
+// in every fold. Once calibrated, sliding windows of the live stream give
+// left/right probabilities every `step_seconds`.
+//
+// Nothing is Muse specific: the channels are whatever the source provides,
+// the Muse packet stream or any BoardIds device behind the BoardShim. With
+// four Muse electrodes, two of them frontal, expect modest accuracy.
+
+static MOTOR_IMAGERY: Mutex<Option<MotorImageryBci>> = Mutex::new(None);
+
+const MAX_QUEUED_OUTPUTS: usize = 256;
+const FILTER_ORDER: usize = 4;
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MotorImageryClass {
+    Left,
+    Right,
+}
+
+impl MotorImageryClass {
+    fn index(&self) -> usize {
+        match self {
+            MotorImageryClass::Left => 0,
+            MotorImageryClass::Right => 1,
+        }
+    }
+
+    fn marker_label(&self) -> &'static str {
+        match self {
+            MotorImageryClass::Left => "mi_left",
+            MotorImageryClass::Right => "mi_right",
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotorImagerySettings {
+    pub low_hz: f64,
+    pub high_hz: f64,
+    // Trial and live window length
+    pub window_seconds: f64,
+    // Trial start after its cue
+    pub offset_seconds: f64,
+    // Time between live outputs
+    pub step_seconds: f64,
+    // CSP filters kept from each end of the eigenvalue spectrum
+    pub filter_pairs: u32,
+    // LDA shrinkage, 0-1
+    pub shrinkage: f64,
+    pub folds: u32,
+}
+
+impl Default for MotorImagerySettings {
+    fn default() -> Self {
+        Self {
+            low_hz: 8.0,
+            high_hz: 30.0,
+            window_seconds: 2.0,
+            offset_seconds: 0.5,
+            step_seconds: 0.25,
+            filter_pairs: 1,
+            shrinkage: 0.1,
+            folds: 5,
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotorImageryCalibration {
+    pub channels: Vec<String>,
+    pub left_trials: u32,
+    pub right_trials: u32,
+    pub fold_accuracies: Vec<f64>,
+    pub cv_accuracy: f64,
+    // Cross-validated counts, rows true class (left, right), columns predicted
+    pub confusion: Vec<Vec<u32>>,
+    // CSP eigenvalues of the final fit, ascending
+    pub eigenvalues: Vec<f64>,
+    pub filters_used: u32,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotorImageryOutput {
+    pub timestamp: f64,
+    pub left: f64,
+    pub right: f64,
+    pub class: MotorImageryClass,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotorImageryStatus {
+    pub channels: Vec<String>,
+    pub left_trials: u32,
+    pub right_trials: u32,
+    // Cued trials whose window has not arrived yet
+    pub pending_trials: u32,
+    // Trials whose data had already left the buffer
+    pub dropped_trials: u32,
+    pub calibrated: bool,
+}
+
+enum Source {
+    Muse,
+    Board {
+        board_id: BoardIds,
+        timestamp_row: usize,
+        last_timestamp: f64,
+    },
+}
+
+struct CspModel {
+    // Rows are spatial filters over the channels
+    filters: Vec<Vec<f64>>,
+    classifier: LinearModel,
+}
+
+pub(crate) struct MotorImageryBci {
+    settings: MotorImagerySettings,
+    sampling_rate: f64,
+    source: Source,
+    names: Vec<String>,
+    // By source index (Muse channel or board data row)
+    buffers: ChannelBuffers,
+    latest_time: f64,
+    pending: Vec<(MotorImageryClass, usize)>,
+    trials: Vec<(MotorImageryClass, Vec<Vec<f64>>)>,
+    dropped_trials: u32,
+    model: Option<CspModel>,
+    next_output: usize,
+    latest: Option<MotorImageryOutput>,
+    queued: VecDeque<MotorImageryOutput>,
+}
+
+impl MotorImageryBci {
+    fn new(
+        settings: MotorImagerySettings,
+        sampling_rate: f64,
+        source: Source,
+        channels: Vec<(usize, String)>,
+    ) -> Self {
+        let (rows, names): (Vec<usize>, Vec<String>) = channels.into_iter().unzip();
+        // Two windows plus the offset for late trials
+        let seconds = 2.0 * settings.window_seconds + settings.offset_seconds.max(0.0);
+        Self {
+            settings,
+            sampling_rate,
+            source,
+            names,
+            buffers: ChannelBuffers::for_window(rows, seconds, sampling_rate),
+            latest_time: 0.0,
+            pending: Vec::new(),
+            trials: Vec::new(),
+            dropped_trials: 0,
+            model: None,
+            next_output: 0,
+            latest: None,
+            queued: VecDeque::new(),
+        }
+    }
+
+    fn window(&self) -> usize {
+        (self.settings.window_seconds * self.sampling_rate) as usize
+    }
+
+    // Samples received on every channel
+    fn complete(&self) -> usize {
+        self.buffers.complete()
+    }
+
+    // Windows of every channel ending at absolute sample `end`
+    fn segments(&self, end: usize) -> Option<Vec<Vec<f64>>> {
+        let windows = self.buffers.windows(end, self.window())?;
+        Some(windows.into_iter().map(<[f64]>::to_vec).collect())
+    }
+
+    fn push(&mut self, source_index: usize, samples: &[f64], timestamp: f64) {
+        self.append(source_index, samples, timestamp);
+        self.predict();
+    }
+
+    // Buffers the samples and keeps the trials they complete
+    fn append(&mut self, source_index: usize, samples: &[f64], timestamp: f64) {
+        if self.buffers.push(source_index, samples).is_none() {
+            return;
+        }
+        self.latest_time = self.latest_time.max(timestamp);
+        self.collect_trials();
+    }
+
+    fn mark_trial(&mut self, class: MotorImageryClass) {
+        let offset = (self.settings.offset_seconds * self.sampling_rate).round() as i64;
+        let start = (self.complete() as i64 + offset).max(0) as usize;
+        self.pending.push((class, start));
+    }
+
+    fn collect_trials(&mut self) {
+        let complete = self.complete();
+        let window = self.window();
+        let (ready, pending): (Vec<_>, Vec<_>) = self
+            .pending
+            .iter()
+            .partition(|(_, start)| start + window <= complete);
+        self.pending = pending;
+        for (class, start) in ready {
+            match self.segments(start + window) {
+                Some(trial) => self.trials.push((class, trial)),
+                None => self.dropped_trials += 1,
+            }
+        }
+    }
+
+    fn predict(&mut self) {
+        let complete = self.complete();
+        if self.model.is_none() || complete < self.next_output {
+            return;
+        }
+        let step = ((self.settings.step_seconds * self.sampling_rate) as usize).max(1);
+        self.next_output = complete + step;
+        let Some(mut window) = self.segments(complete) else {
+            return;
+        };
+        for channel in &mut window {
+            if let Err(e) = bandpass(channel, self.sampling_rate, &self.settings) {
+                warn!("[MI] Band pass failed: {:?}", e);
+                return;
+            }
+        }
+        let Some(model) = self.model.as_ref() else {
+            return;
+        };
+        let p = model
+            .classifier
+            .probabilities(&log_variance_features(&model.filters, &window));
+        let output = MotorImageryOutput {
+            timestamp: self.latest_time,
+            left: p[0],
+            right: p[1],
+            class: if p[1] > p[0] {
+                MotorImageryClass::Right
+            } else {
+                MotorImageryClass::Left
+            },
+        };
+        if self.queued.len() == MAX_QUEUED_OUTPUTS {
+            self.queued.pop_front();
+        }
+        self.queued.push_back(output.clone());
+        self.latest = Some(output);
+    }
+
+    fn calibrate(&mut self) -> Result<MotorImageryCalibration> {
+        let mut trials = Vec::with_capacity(self.trials.len());
+        for (_, trial) in &self.trials {
+            let mut filtered = trial.clone();
+            for channel in &mut filtered {
+                bandpass(channel, self.sampling_rate, &self.settings)?;
+            }
+            trials.push(filtered);
+        }
+        let classes: Vec<usize> = self.trials.iter().map(|(c, _)| c.index()).collect();
+        let counts = [0, 1].map(|k| classes.iter().filter(|&&c| c == k).count());
+        let fewest = counts[0].min(counts[1]);
+        if fewest < 2 {
+            bail!("Need at least two trials per class, got {:?}", counts);
+        }
+        let pairs = (self.settings.filter_pairs as usize).clamp(1, self.names.len() / 2);
+        let folds = (self.settings.folds as usize).clamp(2, fewest);
+        let assignment = personal_classifier::assign_folds(&classes, 2, folds);
+
+        let mut fold_accuracies = Vec::with_capacity(folds);
+        let mut confusion = vec![vec![0u32; 2]; 2];
+        for fold in 0..folds {
+            let (train, test): (Vec<usize>, Vec<usize>) =
+                (0..trials.len()).partition(|&i| assignment[i] != fold);
+            if test.is_empty() {
+                continue;
+            }
+            let train_trials: Vec<&Vec<Vec<f64>>> = train.iter().map(|&i| &trials[i]).collect();
+            let train_classes: Vec<usize> = train.iter().map(|&i| classes[i]).collect();
+            let (model, _) = self.fit(&train_trials, &train_classes, pairs)?;
+            let mut correct = 0;
+            for &i in &test {
+                let features = log_variance_features(&model.filters, &trials[i]);
+                let predicted = personal_classifier::argmax(&model.classifier.scores(&features));
+                confusion[classes[i]][predicted] += 1;
+                if predicted == classes[i] {
+                    correct += 1;
+                }
+            }
+            fold_accuracies.push(correct as f64 / test.len() as f64);
+        }
+
+        let all: Vec<&Vec<Vec<f64>>> = trials.iter().collect();
+        let (model, eigenvalues) = self.fit(&all, &classes, pairs)?;
+        self.model = Some(model);
+        self.next_output = 0;
+        let correct = confusion[0][0] + confusion[1][1];
+        let total: u32 = confusion.iter().flatten().sum();
+        let report = MotorImageryCalibration {
+            channels: self.names.clone(),
+            left_trials: counts[0] as u32,
+            right_trials: counts[1] as u32,
+            fold_accuracies,
+            cv_accuracy: correct as f64 / total.max(1) as f64,
+            confusion,
+            eigenvalues,
+            filters_used: 2 * pairs as u32,
+        };
+        info!(
+            "[MI] Calibrated on {} trials, CV accuracy {:.2}",
+            trials.len(),
+            report.cv_accuracy
+        );
+        Ok(report)
+    }
+
+    // CSP on band passed trials, then LDA on their log-variance features
+    fn fit(
+        &self,
+        trials: &[&Vec<Vec<f64>>],
+        classes: &[usize],
+        pairs: usize,
+    ) -> Result<(CspModel, Vec<f64>)> {
+        let (channels, samples) = (self.names.len(), self.window());
+        let flat: Vec<f64> = trials
+            .iter()
+            .flat_map(|t| t.iter().flatten())
+            .copied()
+            .collect();
+        let data = Array3::from_shape_vec((trials.len(), channels, samples), flat)?;
+        let labels = Array1::from(classes.iter().map(|&c| c as f64).collect::<Vec<f64>>());
+        let (filters, eigenvalues) = data_filter::get_csp::<()>(&data, &labels)?;
+        let filters = select_filters(&filters, eigenvalues.as_slice().unwrap_or_default(), pairs);
+        let features: Vec<Vec<f64>> = trials
+            .iter()
+            .map(|t| log_variance_features(&filters, t))
+            .collect();
+        let classifier = personal_classifier::fit(
+            &features,
+            classes,
+            2,
+            ClassifierAlgorithm::Lda,
+            self.settings.shrinkage,
+        )?;
+        let mut sorted = eigenvalues.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        Ok((
+            CspModel {
+                filters,
+                classifier,
+            },
+            sorted,
+        ))
+    }
+
+    fn status(&self) -> MotorImageryStatus {
+        let count = |class| self.trials.iter().filter(|(c, _)| *c == class).count() as u32;
+        MotorImageryStatus {
+            channels: self.names.clone(),
+            left_trials: count(MotorImageryClass::Left),
+            right_trials: count(MotorImageryClass::Right),
+            pending_trials: self.pending.len() as u32,
+            dropped_trials: self.dropped_trials,
+            calibrated: self.model.is_some(),
+        }
+    }
+}
+
+fn bandpass(data: &mut [f64], sampling_rate: f64, settings: &MotorImagerySettings) -> Result<()> {
+    data_filter::perform_bandpass(
+        data,
+        sampling_rate as usize,
+        settings.low_hz,
+        settings.high_hz,
+        FILTER_ORDER,
+        FilterTypes::ButterworthZeroPhase,
+        0.0,
+    )?;
+    Ok(())
+}
+
+// Rows of `filters` (channels x channels) belonging to the `pairs` smallest
+// and `pairs` largest eigenvalues
+pub(crate) fn select_filters(
+    filters: &Array2<f64>,
+    eigenvalues: &[f64],
+    pairs: usize,
+) -> Vec<Vec<f64>> {
+    let mut order: Vec<usize> = (0..eigenvalues.len()).collect();
+    order.sort_by(|&a, &b| eigenvalues[a].total_cmp(&eigenvalues[b]));
+    let pairs = pairs.min(order.len() / 2);
+    order[..pairs]
+        .iter()
+        .chain(&order[order.len() - pairs..])
+        .map(|&i| filters.row(i).to_vec())
+        .collect()
+}
+
+// ln of each spatially filtered signal's share of the total variance
+pub(crate) fn log_variance_features(filters: &[Vec<f64>], window: &[Vec<f64>]) -> Vec<f64> {
+    let samples = window.first().map(Vec::len).unwrap_or(0);
+    let variances: Vec<f64> = filters
+        .iter()
+        .map(|filter| {
+            let projected: Vec<f64> = (0..samples)
+                .map(|t| filter.iter().zip(window).map(|(w, ch)| w * ch[t]).sum())
+                .collect();
+            let mean = projected.iter().sum::<f64>() / samples.max(1) as f64;
+            projected.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / samples.max(1) as f64
+        })
+        .collect();
+    let total: f64 = variances.iter().sum::<f64>().max(f64::MIN_POSITIVE);
+    variances
+        .iter()
+        .map(|v| (v / total).max(f64::MIN_POSITIVE).ln())
+        .collect()
+}
+
+pub(crate) fn start_muse(
+    settings: MotorImagerySettings,
+    sampling_rate: f64,
+    channel_names: &[String],
+) -> Vec<String> {
+    let (rows, names) = select_channels(&[], channel_names);
+    *MOTOR_IMAGERY.lock().unwrap() = Some(MotorImageryBci::new(
+        settings,
+        sampling_rate,
+        Source::Muse,
+        rows.into_iter().zip(names.clone()).collect(),
+    ));
+    names
+}
+
+// Muse EEG from the packet parser
+pub(crate) fn push_muse_samples(channel: usize, samples: &[f64], timestamp: f64) {
+    let mut bci = MOTOR_IMAGERY.lock().unwrap();
+    if let Some(bci) = bci.as_mut().filter(|b| matches!(b.source, Source::Muse)) {
+        bci.push(channel, samples, timestamp);
+    }
+}
+
+// The Muse stream restarted (parser init, replay open or seek): sample
+// positions start over. Trials and the trained model are kept for the same
+// sampling rate and channels; otherwise the BCI stops.
+pub(crate) fn restart_muse(sampling_rate: f64, channel_names: &[String]) {
+    let mut guard = MOTOR_IMAGERY.lock().unwrap();
+    let Some(bci) = guard.as_mut().filter(|b| matches!(b.source, Source::Muse)) else {
+        return;
+    };
+    let (_, names) = select_channels(&[], channel_names);
+    if bci.sampling_rate != sampling_rate || bci.names != names {
+        info!("[MI] Muse stream changed, stopping the motor imagery BCI");
+        *guard = None;
+        return;
+    }
+    bci.buffers.reset();
+    bci.latest_time = 0.0;
+    bci.pending.clear();
+    bci.next_output = 0;
+}
+
+// Starts the BCI on the EEG channels of the connected BoardShim device
+#[frb]
+pub fn start_motor_imagery_for_board(settings: MotorImagerySettings) -> Result<Vec<String>> {
+    let board_id = api::with_board(|board| Ok(board.get_board_id()))?;
+    let preset = BrainFlowPresets::DefaultPreset;
+    let rows = board_shim::get_eeg_channels(board_id, preset)?;
+    let names = board_shim::get_eeg_names(board_id, preset)
+        .unwrap_or_else(|_| rows.iter().map(|r| format!("EEG{}", r)).collect());
+    let sampling_rate = board_shim::get_sampling_rate(board_id, preset)? as f64;
+    let timestamp_row = board_shim::get_timestamp_channel(board_id, preset)?;
+    let channels: Vec<(usize, String)> = rows.into_iter().zip(names).collect();
+    let names = channels.iter().map(|(_, n)| n.clone()).collect();
+    *MOTOR_IMAGERY.lock().unwrap() = Some(MotorImageryBci::new(
+        settings,
+        sampling_rate,
+        Source::Board {
+            board_id,
+            timestamp_row,
+            last_timestamp: f64::NEG_INFINITY,
+        },
+        channels,
+    ));
+    Ok(names)
+}
+
+// Feeds the board samples that arrived since the last call; call it at
+// least every few seconds. Returns the number of new samples.
+#[frb]
+pub fn update_motor_imagery_from_board() -> Result<u32> {
+    let mut guard = MOTOR_IMAGERY.lock().unwrap();
+    let bci = guard.as_mut().context("Motor imagery not started")?;
+    let Source::Board {
+        board_id,
+        timestamp_row,
+        last_timestamp,
+    } = bci.source
+    else {
+        bail!("Motor imagery runs on the Muse stream");
+    };
+    let recent = (bci.sampling_rate * 5.0) as usize;
+    let data = api::with_board(|board: &BoardShim| {
+        if board.get_board_id() != board_id {
+            bail!("Board changed since motor imagery started");
+        }
+        Ok(board.get_current_board_data(recent, BrainFlowPresets::DefaultPreset)?)
+    })?;
+    let timestamps = data.row(timestamp_row);
+    let new: Vec<usize> = (0..timestamps.len())
+        .filter(|&i| timestamps[i] > last_timestamp)
+        .collect();
+    let Some(&last) = new.last() else {
+        return Ok(0);
+    };
+    let newest = timestamps[last];
+    let rows = bci.buffers.channels().to_vec();
+    for row in rows {
+        let samples: Vec<f64> = new.iter().map(|&i| data[[row, i]]).collect();
+        bci.push(row, &samples, newest);
+    }
+    bci.source = Source::Board {
+        board_id,
+        timestamp_row,
+        last_timestamp: newest,
+    };
+    Ok(new.len() as u32)
+}
+
+// Cues a trial now; its window starts offset_seconds later
+#[frb]
+pub fn mark_motor_imagery_trial(class: MotorImageryClass) -> Result<()> {
+    let mut guard = MOTOR_IMAGERY.lock().unwrap();
+    let bci = guard.as_mut().context("Motor imagery not started")?;
+    bci.mark_trial(class);
+    recording::record_marker(class.index() as f64, class.marker_label());
+    Ok(())
+}
+
+#[frb]
+pub fn calibrate_motor_imagery() -> Result<MotorImageryCalibration> {
+    let mut guard = MOTOR_IMAGERY.lock().unwrap();
+    guard
+        .as_mut()
+        .context("Motor imagery not started")?
+        .calibrate()
+}
+
+#[frb]
+pub fn clear_motor_imagery_trials() {
+    let mut guard = MOTOR_IMAGERY.lock().unwrap();
+    if let Some(bci) = guard.as_mut() {
+        bci.trials.clear();
+        bci.pending.clear();
+        bci.dropped_trials = 0;
+    }
+}
+
+#[frb]
+pub fn get_motor_imagery_status() -> Option<MotorImageryStatus> {
+    let guard = MOTOR_IMAGERY.lock().unwrap();
+    guard.as_ref().map(|bci| bci.status())
+}
+
+#[frb]
+pub fn get_latest_motor_imagery() -> Option<MotorImageryOutput> {
+    let guard = MOTOR_IMAGERY.lock().unwrap();
+    guard.as_ref().and_then(|bci| bci.latest.clone())
+}
+
+#[frb]
+pub fn take_motor_imagery_outputs() -> Vec<MotorImageryOutput> {
+    let mut guard = MOTOR_IMAGERY.lock().unwrap();
+    guard
+        .as_mut()
+        .map(|bci| bci.queued.drain(..).collect())
+        .unwrap_or_default()
+}
+
+#[frb]
+pub fn stop_motor_imagery() {
+    *MOTOR_IMAGERY.lock().unwrap() = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_trials_and_extracts_log_variance() {
+        let settings = MotorImagerySettings {
+            window_seconds: 1.0,
+            offset_seconds: 0.5,
+            ..Default::default()
+        };
+        let channels: Vec<(usize, String)> = ["C3", "Cz", "C4"]
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (i + 1, n.to_string()))
+            .collect();
+        let mut bci = MotorImageryBci::new(settings, 20.0, Source::Muse, channels);
+        let packet = |start: usize| -> Vec<f64> { (start..start + 5).map(|v| v as f64).collect() };
+        for p in 0..4 {
+            for c in 1..4 {
+                bci.append(c, &packet(p * 5), p as f64);
+            }
+        }
+        bci.mark_trial(MotorImageryClass::Right);
+        for p in 4..10 {
+            for c in 1..4 {
+                bci.append(c, &packet(p * 5), p as f64);
+            }
+        }
+        // Cued at sample 20, window 30..50
+        assert_eq!(bci.trials.len(), 1);
+        let (class, trial) = &bci.trials[0];
+        assert_eq!(*class, MotorImageryClass::Right);
+        assert_eq!(trial.len(), 3);
+        assert_eq!(trial[2].first(), Some(&30.0));
+        assert_eq!(trial[2].len(), 20);
+        assert!(bci.pending.is_empty());
+
+        // Filters picking channels 0 and 2, whose variances are 1 and 9
+        let filters =
+            Array2::from_shape_vec((3, 3), vec![0.0, 0.0, 1.0, 0.0, 1.0, 0.0, 1.0, 0.0, 0.0])
+                .unwrap();
+        let selected = select_filters(&filters, &[0.9, 0.5, 0.1], 1);
+        assert_eq!(selected, vec![vec![1.0, 0.0, 0.0], vec![0.0, 0.0, 1.0]]);
+        let window: Vec<Vec<f64>> = [1.0, 2.0, 3.0]
+            .iter()
+            .map(|a| (0..100).map(|t| if t % 2 == 0 { *a } else { -a }).collect())
+            .collect();
+        let features = log_variance_features(&selected, &window);
+        assert!((features[0] - 0.1f64.ln()).abs() < 1e-9);
+        assert!((features[1] - 0.9f64.ln()).abs() < 1e-9);
+    }
+}
@@ -1,41 +1,101 @@
 use crate::api;
+use crate::asymmetry::{AsymmetryBaseline, AsymmetrySettings, AsymmetryTracker, AsymmetryUpdate};
+use crate::baseline;
+use crate::closed_loop::{
+    ClosedLoopSettings, ClosedLoopStatus, ClosedLoopStimulator, StimulationEvent, STIMULUS_MARKER,
+    STIMULUS_MARKER_VALUE,
+};
+use crate::erp::{self, ErpSettings};
+use crate::features::{FeatureSettings, FeatureStream, FeatureVector};
+use crate::fnirs::{FnirsConfig, FnirsProcessor, FnirsResult};
+use crate::heart_rate::{
+    HeartRateResult, HeartRateTracker, PpgBuffer, HR_WINDOW_SECONDS, PPG_BUFFER_SECONDS,
+};
+use crate::hrv::{self, BeatHistory, HrvMetrics, HrvSettings};
+use crate::iaf::{self, IafSettings};
+use crate::imu::{HeadGesture, HeadOrientation, ImuSamples, ImuTracker, IMU_SAMPLING_RATE};
+use crate::metrics::{self, MetricsService, MetricsSettings, MetricsUpdate};
+use crate::motion_gate::{self, MotionGate, MotionGateSettings, MotionStream, WindowContamination};
+use crate::motor_imagery::{self, MotorImagerySettings};
 use crate::muse_types::{
-    EegResolution, MuseModel, MusePacketType, MuseProcessedData, MUSE_ACCEL_SCALE_FACTOR,
-    MUSE_GYRO_SCALE_FACTOR,
+    EegResolution, MuseModel, MusePacketType, MuseProcessedData, PipelineSettings,
+    MUSE_ACCEL_SCALE_FACTOR, MUSE_GYRO_SCALE_FACTOR,
 };
+use crate::recording;
+use crate::respiration::{RespirationResult, RespirationTracker};
+use crate::sleep::{self, Hypnogram, SleepEpoch, SleepStager, EPOCH_SECONDS};
+use crate::sleep_events::{SleepEvent, SleepEventDetector, SleepEventReport};
+use crate::spo2::{Spo2Result, Spo2Tracker};
+use crate::ssvep::{SsvepDetector, SsvepScores, SsvepSelection, SsvepSettings};
 use flutter_rust_bridge::frb;
-use log::info;
+use log::{info, warn};
 use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 static MUSE_STATE: Mutex<Option<MuseState>> = Mutex::new(None);
 
+// Every Muse notification: a 2 byte package number and 18 bytes of samples
+pub(crate) const MUSE_PACKET_LEN: usize = 20;
 const MAX_PPG_CHANNELS: usize = 3;
+// Beats the respiration estimate looks at, the length of the PPG buffer
+const RESPIRATION_WINDOW_SECONDS: f64 = PPG_BUFFER_SECONDS as f64;
 
 struct MuseState {
     model: MuseModel,
+    settings: PipelineSettings,
     eeg_buffers: Vec<Vec<f64>>,
     eeg_accumulator: Vec<Vec<f64>>, // Rolling buffer for band powers (256+ samples)
     accel_buffer: [f64; 3],
     gyro_buffer: [f64; 3],
+    imu: ImuTracker,
+    motion_gate: MotionGate,
     ppg_buffer: Vec<Vec<f64>>,
+    ppg_history: PpgBuffer, // Rolling 30 s per wavelength, ppg_buffer only holds the last packet
+    heart_rate: HeartRateTracker,
+    beats: BeatHistory,
+    hrv_settings: HrvSettings,
+    spo2: Spo2Tracker,
+    fnirs: FnirsProcessor,
+    respiration: RespirationTracker,
+    sleep: Option<SleepStager>, // Only while sleep staging is running
+    sleep_events: Option<SleepEventDetector>,
+    closed_loop: Option<ClosedLoopStimulator>,
+    metrics: Option<MetricsService>, // Only while the metrics service is running
+    ssvep: Option<SsvepDetector>,
+    asymmetry: Option<AsymmetryTracker>,
+    features: Option<FeatureStream>,
     package_count: u16,
-    initialized: bool,
     battery: f64,
 }
 
 impl MuseState {
-    fn new(model: MuseModel) -> Self {
+    fn new(model: MuseModel, settings: PipelineSettings) -> Self {
         let channel_count = model.channel_count();
         Self {
             model,
+            settings,
             eeg_buffers: vec![Vec::new(); channel_count],
             eeg_accumulator: vec![Vec::new(); channel_count], // Initialize accumulator
             accel_buffer: [0.0; 3],
             gyro_buffer: [0.0; 3],
+            imu: ImuTracker::new(),
+            motion_gate: MotionGate::new(),
             ppg_buffer: vec![Vec::new(); MAX_PPG_CHANNELS],
+            ppg_history: PpgBuffer::new(MAX_PPG_CHANNELS, PPG_BUFFER_SECONDS),
+            heart_rate: HeartRateTracker::new(),
+            beats: BeatHistory::new(),
+            hrv_settings: HrvSettings::default(),
+            spo2: Spo2Tracker::new(),
+            fnirs: FnirsProcessor::new(),
+            respiration: RespirationTracker::new(),
+            sleep: None,
+            sleep_events: None,
+            closed_loop: None,
+            metrics: None,
+            ssvep: None,
+            asymmetry: None,
+            features: None,
             package_count: 0,
-            initialized: true,
             battery: -1.0,
         }
     }
@@ -58,8 +118,45 @@ fn get_timestamp() -> f64 {
 
 #[frb]
 pub fn init_muse_parser(model: MuseModel) {
+    // Keeps the IAF set so far
+    let settings = PipelineSettings {
+        individual_alpha: iaf::get_individual_alpha_frequency(),
+        ..PipelineSettings::default()
+    };
+    init_muse_parser_with_settings(model, settings);
+}
+
+#[frb]
+pub fn init_muse_parser_with_settings(model: MuseModel, settings: PipelineSettings) {
     let mut state = MUSE_STATE.lock().unwrap();
-    *state = Some(MuseState::new(model));
+    if state.as_ref().is_some_and(|s| s.metrics.is_some()) {
+        metrics::release_models();
+    }
+    // A replay brings back the IAF the session was recorded with
+    if let Err(e) = iaf::set_individual_alpha_frequency(settings.individual_alpha) {
+        warn!("[RUST] {:#}, using the fixed bands", e);
+        iaf::set_individual_alpha_frequency(None).ok();
+    }
+    // Accumulators fed by this stream must not mix it with the previous one
+    let sampling_rate = settings.eeg_sampling_rate as f64;
+    motor_imagery::restart_muse(sampling_rate, &model.eeg_channel_names());
+    erp::restart_muse(sampling_rate, &model.eeg_channel_names());
+    // An eyes-closed recording can't span two streams
+    iaf::cancel_iaf_eyes_closed();
+    *state = Some(MuseState::new(model, settings));
+    baseline::reset_window_step();
+}
+
+#[frb]
+pub fn get_pipeline_settings() -> PipelineSettings {
+    let state = MUSE_STATE.lock().unwrap();
+    let mut settings = state
+        .as_ref()
+        .map(|s| s.settings.clone())
+        .unwrap_or_default();
+    // The IAF can be set after the parser started
+    settings.individual_alpha = iaf::get_individual_alpha_frequency();
+    settings
 }
 
 #[frb]
@@ -70,12 +167,26 @@ pub fn parse_muse_packet(channel: i32, data: Vec<u8>) -> Vec<MuseProcessedData>
     //     channel,
     //     data.len()
     // );
+
+    // Raw packets go to the recorder (no-op unless a recording is running)
+    recording::record_packet(channel, &data);
+
+    process_muse_packet(channel, &data, get_timestamp())
+}
+
+// Same as parse_muse_packet but without recording, used by the replay engine.
+// `timestamp` is the packet arrival (or recorded) time.
+pub(crate) fn process_muse_packet(
+    channel: i32,
+    data: &[u8],
+    timestamp: f64,
+) -> Vec<MuseProcessedData> {
     let mut results = Vec::new();
 
     {
         let mut state = MUSE_STATE.lock().unwrap();
         if state.is_none() {
-            *state = Some(MuseState::new(MuseModel::MuseS));
+            *state = Some(MuseState::new(MuseModel::MuseS, PipelineSettings::default()));
         }
     }
 
@@ -84,7 +195,7 @@ pub fn parse_muse_packet(channel: i32, data: Vec<u8>) -> Vec<MuseProcessedData>
 
     // println!("[RUST] eeg[0] called, channel={}", channel);
 
-    if data.len() != 20 {
+    if data.len() != MUSE_PACKET_LEN {
         return results;
     }
 
@@ -92,27 +203,29 @@ pub fn parse_muse_packet(channel: i32, data: Vec<u8>) -> Vec<MuseProcessedData>
 
     match channel {
         0..=6 if (channel as usize) < channel_count => {
-            if let Some(data) = parse_eeg_channel(muse_state, channel as usize, &data) {
+            if let Some(data) = parse_eeg_channel(muse_state, channel as usize, data, timestamp)
+            {
                 results.push(data);
             }
         }
         5 => {
-            if let Some(data) = parse_accel_data(muse_state, &data) {
+            if let Some(data) = parse_accel_data(muse_state, data, timestamp) {
                 results.push(data);
             }
         }
         6 => {
-            if let Some(data) = parse_gyro_data(muse_state, &data) {
+            if let Some(data) = parse_gyro_data(muse_state, data, timestamp) {
                 results.push(data);
             }
         }
         7..=9 => {
-            if let Some(data) = parse_ppg_data(muse_state, channel as usize - 7, &data) {
+            if let Some(data) = parse_ppg_data(muse_state, channel as usize - 7, data, timestamp)
+            {
                 results.push(data);
             }
         }
         10 => {
-            if let Some(data) = parse_battery_data(muse_state, &data) {
+            if let Some(data) = parse_battery_data(muse_state, data) {
                 results.push(data);
             }
         }
@@ -126,6 +239,7 @@ fn parse_eeg_channel(
     state: &mut MuseState,
     channel: usize,
     data: &[u8],
+    packet_time: f64,
 ) -> Option<MuseProcessedData> {
     let channel_count = state.channel_count();
     if channel >= channel_count {
@@ -149,15 +263,47 @@ fn parse_eeg_channel(
         state.eeg_accumulator[channel].len()
     );
 
+    if let Some(stager) = state.sleep.as_mut() {
+        stager.push(channel, &new_samples, packet_time);
+    }
+    close_sleep_epoch(state);
+    stimulate(state, channel, &new_samples, packet_time);
+    if let Some(service) = state.metrics.as_mut() {
+        service.push(channel, &new_samples);
+        service.update(packet_time);
+    }
+    if let Some(detector) = state.ssvep.as_mut() {
+        detector.push(channel, &new_samples, packet_time);
+    }
+    if let Some(tracker) = state.asymmetry.as_mut() {
+        if tracker.push(channel, &new_samples) {
+            let gate = motion_gate::assess(
+                &state.imu,
+                MotionStream::Eeg,
+                packet_time - tracker.settings.window_seconds,
+                packet_time,
+                &state.motion_gate.settings,
+            );
+            tracker.evaluate(packet_time, gate.excluded);
+        }
+    }
+    if let Some(stream) = state.features.as_mut() {
+        stream.push(channel, &new_samples, packet_time);
+    }
+    iaf::push_muse_samples(channel, &new_samples);
+    motor_imagery::push_muse_samples(channel, &new_samples, packet_time);
+    erp::push_muse_samples(channel, &new_samples, packet_time);
+
     // Update buffer with the latest batch for this channel (for immediate EEG display)
     state.eeg_buffers[channel] = new_samples.clone();
 
     // ACCUMULATE samples for band power calculation (rolling window)
     state.eeg_accumulator[channel].extend_from_slice(&new_samples);
-    // Keep rolling window of 256 samples per channel (1 second at 256Hz)
-    const MAX_ACCUMULATOR: usize = 256;
-    if state.eeg_accumulator[channel].len() > MAX_ACCUMULATOR {
-        let excess = state.eeg_accumulator[channel].len() - MAX_ACCUMULATOR;
+    // Keep rolling window per channel (default 256 samples = 1 second at 256Hz)
+    let max_accumulator_len = state.settings.band_power_window;
+    let sampling_rate = state.settings.eeg_sampling_rate;
+    if state.eeg_accumulator[channel].len() > max_accumulator_len {
+        let excess = state.eeg_accumulator[channel].len() - max_accumulator_len;
         state.eeg_accumulator[channel].drain(0..excess);
     }
 
@@ -200,7 +346,8 @@ fn parse_eeg_channel(
     }
 
     // Calculate band powers only when we have enough accumulated samples
-    let (sq, concentration, relaxation, band_powers) = if max_accumulator >= 256 {
+    let (sq, concentration, relaxation, band_powers) = if max_accumulator >= max_accumulator_len
+    {
         info!(
             "[RUST] Buffer full ({} samples), calling BrainFlow calculate_band_powers",
             max_accumulator
@@ -209,8 +356,12 @@ fn parse_eeg_channel(
         let all_eeg_flat: Vec<f64> = state.eeg_accumulator.iter().flatten().copied().collect();
         info!("[RUST] Flattened EEG size: {}", all_eeg_flat.len());
 
-        let sq = api::calculate_signal_quality(all_eeg_flat.clone(), 256);
-        let bp = api::calculate_band_powers(all_eeg_flat.clone(), 256);
+        let sq = api::calculate_signal_quality(all_eeg_flat.clone(), sampling_rate);
+        let bp = api::calculate_custom_band_powers(
+            all_eeg_flat.clone(),
+            sampling_rate,
+            iaf::anchor_bands(&state.settings.bands),
+        );
 
         info!(
             "[RUST] Band powers result: alpha={:?}, beta={:?}, delta={:?}, theta={:?}",
@@ -228,6 +379,32 @@ fn parse_eeg_channel(
             .as_ref()
             .map(|bands| api::calculate_relaxation(bands.clone()));
 
+        // Down-weight or drop the window if the head moved during it
+        let window_start = packet_time - max_accumulator_len as f64 / sampling_rate as f64;
+        let gate = motion_gate::assess(
+            &state.imu,
+            MotionStream::Eeg,
+            window_start,
+            packet_time,
+            &state.motion_gate.settings,
+        );
+        let (bp, concentration, relaxation) =
+            state
+                .motion_gate
+                .smooth_eeg(bp, concentration, relaxation, gate.weight);
+        state.motion_gate.record(gate);
+
+        // Once a personal baseline is active its scores replace the fixed
+        // ratio scale; windows also feed a running calibration
+        let window_seconds = max_accumulator_len as f64 / sampling_rate as f64;
+        let (concentration, relaxation) = match bp
+            .as_ref()
+            .and_then(|bands| baseline::score_window(bands, packet_time, window_seconds))
+        {
+            Some((c, r)) => (Some(c), Some(r)),
+            None => (concentration, relaxation),
+        };
+
         info!(
             "[RUST] Concentration: {:?}, Relaxation: {:?}",
             concentration, relaxation
@@ -237,12 +414,12 @@ fn parse_eeg_channel(
     } else {
         // Not enough samples yet - use last known values or defaults
         let all_eeg_flat: Vec<f64> = full_eeg.iter().flatten().copied().collect();
-        let sq = api::calculate_signal_quality(all_eeg_flat.clone(), 256);
+        let sq = api::calculate_signal_quality(all_eeg_flat.clone(), sampling_rate);
         (sq, None, None, None)
     };
 
     // Timestamp with simple drift correction (package_num / sampling rate)
-    let timestamp = get_timestamp() - (package_num as f64 / 256.0);
+    let timestamp = get_timestamp() - (package_num as f64 / sampling_rate as f64);
 
     let result = MuseProcessedData {
         eeg: full_eeg,
@@ -271,7 +448,58 @@ fn parse_eeg_channel(
     Some(result)
 }
 
-fn parse_eeg_samples(data: &[u8], resolution: EegResolution) -> Vec<f64> {
+// Featurizes a completed 30 s sleep epoch with the motion and beats of its
+// span, then looks for spindles and slow waves in it
+fn close_sleep_epoch(state: &mut MuseState) {
+    let Some(stager) = state.sleep.as_mut() else {
+        return;
+    };
+    let Some((start, end)) = stager.pending_epoch() else {
+        return;
+    };
+    let movement = state.imu.gyro_rms_between(start, end);
+    let beats: Vec<f64> = state
+        .beats
+        .window(2.0 * EPOCH_SECONDS)
+        .into_iter()
+        .filter(|b| *b >= start && *b < end)
+        .collect();
+    let Some(epoch) = stager.close_epoch(movement, sleep::epoch_heart_rate(&beats)) else {
+        return;
+    };
+    let stage = stager
+        .hypnogram()
+        .epochs
+        .last()
+        .filter(|e| e.start == start)
+        .map(|e| e.stage);
+    if let Some(detector) = state.sleep_events.as_mut() {
+        detector.process_epoch(start, &epoch, stage);
+    }
+}
+
+// Runs the closed-loop stimulator on its channel and logs each stimulus as a
+// recording marker
+fn stimulate(state: &mut MuseState, channel: usize, samples: &[f64], packet_time: f64) {
+    let Some(stimulator) = state
+        .closed_loop
+        .as_mut()
+        .filter(|s| s.settings.channel == channel)
+    else {
+        return;
+    };
+    let latest_epoch = state
+        .sleep
+        .as_ref()
+        .and_then(|s| s.hypnogram().epochs.last())
+        .map(|e| (e.start, e.stage));
+    let motion = state.imu.motion_level();
+    if let Some(event) = stimulator.process(samples, packet_time, latest_epoch, motion) {
+        recording::record_marker_at(event.stimulate_at, STIMULUS_MARKER_VALUE, STIMULUS_MARKER);
+    }
+}
+
+pub(crate) fn parse_eeg_samples(data: &[u8], resolution: EegResolution) -> Vec<f64> {
     let scale = resolution.scale_factor();
     let offset = resolution.offset();
 
@@ -290,15 +518,32 @@ fn parse_eeg_samples(data: &[u8], resolution: EegResolution) -> Vec<f64> {
     samples
 }
 
-fn parse_accel_data(state: &mut MuseState, data: &[u8]) -> Option<MuseProcessedData> {
-    for i in 0..3 {
-        let offset = 2 + i * 6;
-        if offset + 5 < data.len() {
-            let x = cast_16bit_to_int32(&data[offset..]) as f64 * MUSE_ACCEL_SCALE_FACTOR;
-            let y = cast_16bit_to_int32(&data[offset + 2..]) as f64 * MUSE_ACCEL_SCALE_FACTOR;
-            let z = cast_16bit_to_int32(&data[offset + 4..]) as f64 * MUSE_ACCEL_SCALE_FACTOR;
-            state.accel_buffer = [x, y, z];
-        }
+// Three x/y/z samples of big endian 16 bit values, packet counter stripped
+pub(crate) fn parse_imu_samples(data: &[u8], scale: f64) -> Vec<[f64; 3]> {
+    data.chunks_exact(6)
+        .take(3)
+        .map(|c| {
+            [0, 2, 4].map(|offset| cast_16bit_to_int32(&c[offset..]) as f64 * scale)
+        })
+        .collect()
+}
+
+// Three IMU samples per packet, the last one at the packet time
+fn imu_sample_time(timestamp: f64, index: usize) -> f64 {
+    timestamp - (2 - index) as f64 / IMU_SAMPLING_RATE
+}
+
+fn parse_accel_data(
+    state: &mut MuseState,
+    data: &[u8],
+    timestamp: f64,
+) -> Option<MuseProcessedData> {
+    for (i, xyz) in parse_imu_samples(&data[2..], MUSE_ACCEL_SCALE_FACTOR)
+        .into_iter()
+        .enumerate()
+    {
+        state.accel_buffer = xyz;
+        state.imu.push_accel(imu_sample_time(timestamp, i), xyz);
     }
 
     Some(MuseProcessedData {
@@ -312,7 +557,7 @@ fn parse_accel_data(state: &mut MuseState, data: &[u8]) -> Option<MuseProcessedD
         fnirs_tsi: None,
         accel: state.accel_buffer,
         gyro: [0.0; 3],
-        timestamp: get_timestamp(),
+        timestamp,
         battery: 0.0,
         packet_types: vec![MusePacketType::Accel],
         signal_quality: 100.0,
@@ -326,15 +571,17 @@ fn parse_accel_data(state: &mut MuseState, data: &[u8]) -> Option<MuseProcessedD
     })
 }
 
-fn parse_gyro_data(state: &mut MuseState, data: &[u8]) -> Option<MuseProcessedData> {
-    for i in 0..3 {
-        let offset = 2 + i * 6;
-        if offset + 5 < data.len() {
-            let x = cast_16bit_to_int32(&data[offset..]) as f64 * MUSE_GYRO_SCALE_FACTOR;
-            let y = cast_16bit_to_int32(&data[offset + 2..]) as f64 * MUSE_GYRO_SCALE_FACTOR;
-            let z = cast_16bit_to_int32(&data[offset + 4..]) as f64 * MUSE_GYRO_SCALE_FACTOR;
-            state.gyro_buffer = [x, y, z];
-        }
+fn parse_gyro_data(
+    state: &mut MuseState,
+    data: &[u8],
+    timestamp: f64,
+) -> Option<MuseProcessedData> {
+    for (i, xyz) in parse_imu_samples(&data[2..], MUSE_GYRO_SCALE_FACTOR)
+        .into_iter()
+        .enumerate()
+    {
+        state.gyro_buffer = xyz;
+        state.imu.push_gyro(imu_sample_time(timestamp, i), xyz);
     }
 
     Some(MuseProcessedData {
@@ -348,7 +595,7 @@ fn parse_gyro_data(state: &mut MuseState, data: &[u8]) -> Option<MuseProcessedDa
         fnirs_tsi: None,
         accel: [0.0; 3],
         gyro: state.gyro_buffer,
-        timestamp: get_timestamp(),
+        timestamp,
         battery: 0.0,
         packet_types: vec![MusePacketType::Gyro],
         signal_quality: 100.0,
@@ -396,13 +643,55 @@ fn parse_battery_data(state: &mut MuseState, data: &[u8]) -> Option<MuseProcesse
     })
 }
 
-fn parse_ppg_data(state: &mut MuseState, ppg_idx: usize, data: &[u8]) -> Option<MuseProcessedData> {
+fn parse_ppg_data(
+    state: &mut MuseState,
+    ppg_idx: usize,
+    data: &[u8],
+    timestamp: f64,
+) -> Option<MuseProcessedData> {
     let ppg_count = state.ppg_channel_count();
     if ppg_idx >= ppg_count || !state.model.has_ppg() {
         return None;
     }
 
     let ppg_values = parse_ppg_samples(&data[2..]);
+    state.ppg_history.push(ppg_idx, &ppg_values, timestamp);
+    if ppg_idx == 0 {
+        if state
+            .heart_rate
+            .update(&state.ppg_history, ppg_values.len())
+            .is_some()
+        {
+            let gate = motion_gate::assess(
+                &state.imu,
+                MotionStream::Ppg,
+                timestamp - HR_WINDOW_SECONDS as f64,
+                timestamp,
+                &state.motion_gate.settings,
+            );
+            state.heart_rate.apply_motion_weight(gate.weight);
+            state.motion_gate.record(gate);
+            if let Some(hr) = state.heart_rate.latest().filter(|hr| hr.quality.is_usable()) {
+                state.beats.merge(&hr.beat_times);
+            }
+            let beats = state.beats.window(RESPIRATION_WINDOW_SECONDS);
+            let accel = state.imu.accel_window(RESPIRATION_WINDOW_SECONDS);
+            state
+                .respiration
+                .update(&state.ppg_history, &beats, &accel);
+        }
+    } else if ppg_idx == 1 {
+        let motion_weight = state
+            .motion_gate
+            .last(MotionStream::Ppg)
+            .map(|c| c.weight)
+            .unwrap_or(1.0);
+        state
+            .spo2
+            .update(&state.ppg_history, state.heart_rate.latest(), motion_weight);
+    } else if ppg_idx == 2 && state.model.has_fnirs() {
+        state.fnirs.update(&state.ppg_history, ppg_values.len());
+    }
     state.ppg_buffer[ppg_idx] = ppg_values;
 
     let has_ppg = state
@@ -426,13 +715,14 @@ fn parse_ppg_data(state: &mut MuseState, ppg_idx: usize, data: &[u8]) -> Option<
             vec![]
         };
 
-        let spo2 = calculate_spo2(&ppg_ir, &ppg_red);
+        let spo2 = state.spo2.latest().and_then(|r| r.spo2);
 
-        let (fnirs_hbo2, fnirs_hbr, fnirs_tsi) = if state.model.has_fnirs() && ppg_count >= 3 {
-            calculate_fnirs(&ppg_ir, &ppg_nir, &ppg_red)
-        } else {
-            (None, None, None)
-        };
+        let fnirs = state.fnirs.latest().filter(|_| state.model.has_fnirs());
+        let (fnirs_hbo2, fnirs_hbr, fnirs_tsi) = (
+            fnirs.map(|f| f.hbo2),
+            fnirs.map(|f| f.hbr),
+            fnirs.map(|f| f.tsi),
+        );
 
         for buf in &mut state.ppg_buffer {
             buf.clear();
@@ -449,7 +739,7 @@ fn parse_ppg_data(state: &mut MuseState, ppg_idx: usize, data: &[u8]) -> Option<
             fnirs_tsi,
             accel: [0.0; 3],
             gyro: [0.0; 3],
-            timestamp: get_timestamp(),
+            timestamp,
             battery: 0.0,
             packet_types: if state.model.has_fnirs() {
                 vec![MusePacketType::Fnirs]
@@ -470,7 +760,7 @@ fn parse_ppg_data(state: &mut MuseState, ppg_idx: usize, data: &[u8]) -> Option<
     }
 }
 
-fn parse_ppg_samples(data: &[u8]) -> Vec<f64> {
+pub(crate) fn parse_ppg_samples(data: &[u8]) -> Vec<f64> {
     let mut samples = Vec::with_capacity(6);
     for i in (0..data.len()).step_by(3) {
         if i + 2 < data.len() {
@@ -499,58 +789,513 @@ fn cast_24bit_to_int32(data: &[u8]) -> i32 {
     }
 }
 
-fn calculate_spo2(ppg_ir: &[f64], ppg_red: &[f64]) -> Option<f64> {
-    if ppg_ir.len() < 32 || ppg_red.len() < 32 {
-        return None;
+// Latest heart rate of the live (or replayed) PPG stream, updated once per second
+#[frb]
+pub fn get_latest_heart_rate() -> Option<HeartRateResult> {
+    let state = MUSE_STATE.lock().unwrap();
+    state.as_ref().and_then(|s| s.heart_rate.latest().cloned())
+}
+
+// Heart rate computed since the previous call, None if there is no new one
+#[frb]
+pub fn take_heart_rate_update() -> Option<HeartRateResult> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state.as_mut().and_then(|s| s.heart_rate.take_fresh())
+}
+
+// Latest SpO2 estimate; spo2 is None with a reason while the signal is unusable
+#[frb]
+pub fn get_latest_spo2() -> Option<Spo2Result> {
+    let state = MUSE_STATE.lock().unwrap();
+    state.as_ref().and_then(|s| s.spo2.latest().cloned())
+}
+
+#[frb]
+pub fn take_spo2_update() -> Option<Spo2Result> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state.as_mut().and_then(|s| s.spo2.take_fresh())
+}
+
+#[frb]
+pub fn set_fnirs_config(config: FnirsConfig) {
+    let mut state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_mut() {
+        s.fnirs.config = config;
     }
+}
+
+// Takes the last `duration_seconds` as the fNIRS reference; false until that
+// much PPG is buffered. Without an explicit call the processor calibrates on
+// its first full baseline window.
+#[frb]
+pub fn calibrate_fnirs_baseline(duration_seconds: usize) -> bool {
+    let mut state = MUSE_STATE.lock().unwrap();
+    let Some(s) = state.as_mut() else {
+        return false;
+    };
+    let calibrated = s
+        .fnirs
+        .calibrate_baseline(&s.ppg_history, duration_seconds);
+    info!("[RUST] fNIRS baseline calibrated: {}", calibrated);
+    calibrated
+}
 
-    let ir_mean: f64 = ppg_ir.iter().sum::<f64>() / ppg_ir.len() as f64;
-    let red_mean: f64 = ppg_red.iter().sum::<f64>() / ppg_red.len() as f64;
+#[frb]
+pub fn get_latest_fnirs() -> Option<FnirsResult> {
+    let state = MUSE_STATE.lock().unwrap();
+    state.as_ref().and_then(|s| s.fnirs.latest().cloned())
+}
 
-    if ir_mean <= 0.0 || red_mean <= 0.0 {
-        return None;
+#[frb]
+pub fn take_fnirs_update() -> Option<FnirsResult> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state.as_mut().and_then(|s| s.fnirs.take_fresh())
+}
+
+// Breathing rate and phase, updated with every heart rate result
+#[frb]
+pub fn get_latest_respiration() -> Option<RespirationResult> {
+    let state = MUSE_STATE.lock().unwrap();
+    state.as_ref().and_then(|s| s.respiration.latest().cloned())
+}
+
+#[frb]
+pub fn take_respiration_update() -> Option<RespirationResult> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state.as_mut().and_then(|s| s.respiration.take_fresh())
+}
+
+// Pitch/roll/yaw, motion level and body position from the IMU
+#[frb]
+pub fn get_head_orientation() -> Option<HeadOrientation> {
+    let state = MUSE_STATE.lock().unwrap();
+    state.as_ref().and_then(|s| s.imu.orientation())
+}
+
+// Nods and shakes detected since the previous call
+#[frb]
+pub fn take_head_gestures() -> Vec<HeadGesture> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state
+        .as_mut()
+        .map(|s| s.imu.take_gestures())
+        .unwrap_or_default()
+}
+
+// All timestamped accelerometer and gyro samples since the previous call
+#[frb]
+pub fn take_imu_samples() -> ImuSamples {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state
+        .as_mut()
+        .map(|s| s.imu.take_samples())
+        .unwrap_or_default()
+}
+
+#[frb]
+pub fn set_motion_gate_settings(settings: MotionGateSettings) {
+    let mut state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_mut() {
+        s.motion_gate.settings = settings;
+    }
+}
+
+// Motion assessment of the most recent EEG or PPG window
+#[frb]
+pub fn get_motion_contamination(stream: MotionStream) -> Option<WindowContamination> {
+    let state = MUSE_STATE.lock().unwrap();
+    state
+        .as_ref()
+        .and_then(|s| s.motion_gate.last(stream).cloned())
+}
+
+// Contaminated windows (with their reasons) since the previous call
+#[frb]
+pub fn take_motion_contaminations() -> Vec<WindowContamination> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state
+        .as_mut()
+        .map(|s| s.motion_gate.take_queued())
+        .unwrap_or_default()
+}
+
+// Starts near-real-time sleep staging of the live stream, discarding any
+// previous night
+#[frb]
+pub fn start_sleep_staging() {
+    let mut state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_mut() {
+        let sampling_rate = s.settings.eeg_sampling_rate as f64;
+        s.sleep = Some(SleepStager::new(sampling_rate));
+        s.sleep_events = Some(SleepEventDetector::new(
+            sampling_rate,
+            &s.model.eeg_channel_names(),
+        ));
     }
+}
+
+// Stops staging and returns the final hypnogram
+#[frb]
+pub fn stop_sleep_staging() -> Option<Hypnogram> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    let s = state.as_mut()?;
+    s.sleep_events = None;
+    s.sleep.take().map(|stager| stager.hypnogram().clone())
+}
 
-    let ratio = red_mean / ir_mean;
-    let spo2 = 110.0 - 25.0 * ratio;
+#[frb]
+pub fn get_hypnogram() -> Option<Hypnogram> {
+    let state = MUSE_STATE.lock().unwrap();
+    state
+        .as_ref()
+        .and_then(|s| s.sleep.as_ref())
+        .map(|stager| stager.hypnogram().clone())
+}
+
+#[frb]
+pub fn take_sleep_epoch_update() -> Option<SleepEpoch> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state
+        .as_mut()
+        .and_then(|s| s.sleep.as_mut())
+        .and_then(|stager| stager.take_fresh())
+}
+
+// Spindles and slow waves of the running staging session, with per stage
+// densities
+#[frb]
+pub fn get_sleep_events() -> Option<SleepEventReport> {
+    let state = MUSE_STATE.lock().unwrap();
+    let s = state.as_ref()?;
+    let stager = s.sleep.as_ref()?;
+    s.sleep_events
+        .as_ref()
+        .map(|detector| detector.report(stager.hypnogram()))
+}
+
+#[frb]
+pub fn take_sleep_events() -> Vec<SleepEvent> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state
+        .as_mut()
+        .and_then(|s| s.sleep_events.as_mut())
+        .map(|detector| detector.take_pending())
+        .unwrap_or_default()
+}
 
-    Some(spo2.clamp(0.0, 100.0))
+// Starts closed-loop auditory stimulation; it only fires while sleep
+// staging runs and scores N2/N3
+#[frb]
+pub fn start_closed_loop_stimulation(settings: ClosedLoopSettings) {
+    let mut state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_mut() {
+        let sampling_rate = s.settings.eeg_sampling_rate as f64;
+        s.closed_loop = Some(ClosedLoopStimulator::new(settings, sampling_rate));
+    }
 }
 
-fn calculate_fnirs(
-    ppg_ir: &[f64],
-    ppg_nir: &[f64],
-    ppg_red: &[f64],
-) -> (Option<f64>, Option<f64>, Option<f64>) {
-    if ppg_ir.len() < 64 || ppg_nir.len() < 64 || ppg_red.len() < 64 {
-        return (None, None, None);
+#[frb]
+pub fn stop_closed_loop_stimulation() {
+    let mut state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_mut() {
+        s.closed_loop = None;
     }
+}
 
-    let ir_mean: f64 = ppg_ir.iter().sum::<f64>() / ppg_ir.len() as f64;
-    let nir_mean: f64 = ppg_nir.iter().sum::<f64>() / ppg_nir.len() as f64;
-    let red_mean: f64 = ppg_red.iter().sum::<f64>() / ppg_red.len() as f64;
+#[frb]
+pub fn get_closed_loop_status() -> Option<ClosedLoopStatus> {
+    let state = MUSE_STATE.lock().unwrap();
+    state
+        .as_ref()
+        .and_then(|s| s.closed_loop.as_ref())
+        .map(|stimulator| stimulator.status())
+}
 
-    if ir_mean <= 0.0 || nir_mean <= 0.0 || red_mean <= 0.0 {
-        return (None, None, None);
+// Stimulate events since the last call; Dart plays each at stimulate_at
+#[frb]
+pub fn take_stimulation_events() -> Vec<StimulationEvent> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state
+        .as_mut()
+        .and_then(|s| s.closed_loop.as_mut())
+        .map(|stimulator| stimulator.take_queued())
+        .unwrap_or_default()
+}
+
+// Starts streaming BrainFlow mindfulness / restfulness predictions, preparing
+// the models once for the whole session
+#[frb]
+pub fn start_metrics_service(settings: MetricsSettings) -> anyhow::Result<()> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    let Some(s) = state.as_mut() else {
+        anyhow::bail!("Muse parser not initialized");
+    };
+    metrics::prepare_models(&settings.metrics)?;
+    let sampling_rate = s.settings.eeg_sampling_rate as f64;
+    s.metrics = Some(MetricsService::new(
+        settings,
+        sampling_rate,
+        &s.model.eeg_channel_names(),
+    ));
+    Ok(())
+}
+
+// Stops the service and releases the models
+#[frb]
+pub fn stop_metrics_service() {
+    let mut state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_mut() {
+        s.metrics = None;
     }
+    metrics::release_models();
+}
 
-    let od_ir = (ir_mean / 50000.0).ln();
-    let od_nir = (nir_mean / 50000.0).ln();
-    let _od_red = (red_mean / 50000.0).ln();
+#[frb]
+pub fn get_latest_metrics() -> Option<MetricsUpdate> {
+    let state = MUSE_STATE.lock().unwrap();
+    state
+        .as_ref()
+        .and_then(|s| s.metrics.as_ref())
+        .and_then(|service| service.latest().cloned())
+}
 
-    let hbo2 = (od_ir * 1.05 - od_nir * 0.78) * 500.0;
-    let hbr = (od_nir * 1.10 - od_ir * 0.69) * 500.0;
-    let total = hbo2 + hbr;
-    let tsi = if total > 0.0 {
-        (hbo2 / total) * 100.0
-    } else {
-        0.0
+// Predictions since the last call, one per cadence tick
+#[frb]
+pub fn take_metrics_updates() -> Vec<MetricsUpdate> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state
+        .as_mut()
+        .and_then(|s| s.metrics.as_mut())
+        .map(|service| service.take_queued())
+        .unwrap_or_default()
+}
+
+// Starts the motor imagery BCI on the Muse EEG channels; returns them
+#[frb]
+pub fn start_motor_imagery(settings: MotorImagerySettings) -> anyhow::Result<Vec<String>> {
+    let state = MUSE_STATE.lock().unwrap();
+    let Some(s) = state.as_ref() else {
+        anyhow::bail!("Muse parser not initialized");
     };
+    Ok(motor_imagery::start_muse(
+        settings,
+        s.settings.eeg_sampling_rate as f64,
+        &s.model.eeg_channel_names(),
+    ))
+}
+
+// Starts live ERP averaging on the Muse EEG channels, discarding any
+// previous averages
+#[frb]
+pub fn start_erp(settings: ErpSettings) {
+    let state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_ref() {
+        erp::start_muse(
+            settings,
+            s.settings.eeg_sampling_rate as f64,
+            &s.model.eeg_channel_names(),
+        );
+    }
+}
+
+// Starts recording eyes-closed EEG for the individual alpha frequency;
+// finish_iaf_eyes_closed ends it and returns the estimate
+#[frb]
+pub fn start_iaf_eyes_closed(settings: IafSettings) {
+    let state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_ref() {
+        iaf::start_muse(
+            settings,
+            s.settings.eeg_sampling_rate as f64,
+            &s.model.eeg_channel_names(),
+        );
+    }
+}
+
+// Starts streaming frontal and temporal alpha asymmetry
+#[frb]
+pub fn start_asymmetry(settings: AsymmetrySettings) {
+    let mut state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_mut() {
+        let sampling_rate = s.settings.eeg_sampling_rate as f64;
+        s.asymmetry = Some(AsymmetryTracker::new(
+            settings,
+            sampling_rate,
+            &s.model.eeg_channel_names(),
+        ));
+    }
+}
+
+#[frb]
+pub fn stop_asymmetry() {
+    let mut state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_mut() {
+        s.asymmetry = None;
+    }
+}
 
-    (
-        Some(hbo2.clamp(-100.0, 100.0)),
-        Some(hbr.clamp(-100.0, 100.0)),
-        Some(tsi.clamp(0.0, 100.0)),
+// Accepted asymmetry windows from now on make up the baseline
+#[frb]
+pub fn start_asymmetry_baseline() -> anyhow::Result<()> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    let Some(tracker) = state.as_mut().and_then(|s| s.asymmetry.as_mut()) else {
+        anyhow::bail!("Asymmetry not started");
+    };
+    tracker.start_baseline();
+    Ok(())
+}
+
+#[frb]
+pub fn finish_asymmetry_baseline() -> anyhow::Result<AsymmetryBaseline> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    let Some(tracker) = state.as_mut().and_then(|s| s.asymmetry.as_mut()) else {
+        anyhow::bail!("Asymmetry not started");
+    };
+    tracker.finish_baseline()
+}
+
+// Restores a baseline from an earlier session
+#[frb]
+pub fn set_asymmetry_baseline(baseline: AsymmetryBaseline) -> anyhow::Result<()> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    let Some(tracker) = state.as_mut().and_then(|s| s.asymmetry.as_mut()) else {
+        anyhow::bail!("Asymmetry not started");
+    };
+    tracker.set_baseline(&baseline);
+    Ok(())
+}
+
+#[frb]
+pub fn get_latest_asymmetry() -> Option<AsymmetryUpdate> {
+    let state = MUSE_STATE.lock().unwrap();
+    state
+        .as_ref()
+        .and_then(|s| s.asymmetry.as_ref())
+        .and_then(|tracker| tracker.latest().cloned())
+}
+
+// Updates since the last call, one per step
+#[frb]
+pub fn take_asymmetry_updates() -> Vec<AsymmetryUpdate> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state
+        .as_mut()
+        .and_then(|s| s.asymmetry.as_mut())
+        .map(|tracker| tracker.take_queued())
+        .unwrap_or_default()
+}
+
+// Starts the streaming EEG feature vector (features.rs)
+#[frb]
+pub fn start_feature_stream(settings: FeatureSettings) {
+    let mut state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_mut() {
+        let sampling_rate = s.settings.eeg_sampling_rate as f64;
+        s.features = Some(FeatureStream::new(
+            settings,
+            sampling_rate,
+            &s.model.eeg_channel_names(),
+        ));
+    }
+}
+
+#[frb]
+pub fn stop_feature_stream() {
+    let mut state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_mut() {
+        s.features = None;
+    }
+}
+
+#[frb]
+pub fn get_latest_features() -> Option<FeatureVector> {
+    let state = MUSE_STATE.lock().unwrap();
+    state
+        .as_ref()
+        .and_then(|s| s.features.as_ref())
+        .and_then(|stream| stream.latest().cloned())
+}
+
+// Feature vectors since the last call, one per step
+#[frb]
+pub fn take_feature_vectors() -> Vec<FeatureVector> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state
+        .as_mut()
+        .and_then(|s| s.features.as_mut())
+        .map(|stream| stream.take_queued())
+        .unwrap_or_default()
+}
+
+// Starts SSVEP detection of the settings' flicker frequencies
+#[frb]
+pub fn start_ssvep(settings: SsvepSettings) {
+    let mut state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_mut() {
+        let sampling_rate = s.settings.eeg_sampling_rate as f64;
+        s.ssvep = Some(SsvepDetector::new(
+            settings,
+            sampling_rate,
+            &s.model.eeg_channel_names(),
+        ));
+    }
+}
+
+#[frb]
+pub fn stop_ssvep() {
+    let mut state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_mut() {
+        s.ssvep = None;
+    }
+}
+
+#[frb]
+pub fn get_latest_ssvep_scores() -> Option<SsvepScores> {
+    let state = MUSE_STATE.lock().unwrap();
+    state
+        .as_ref()
+        .and_then(|s| s.ssvep.as_ref())
+        .and_then(|detector| detector.latest().cloned())
+}
+
+// Scores since the last call, one per step
+#[frb]
+pub fn take_ssvep_scores() -> Vec<SsvepScores> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state
+        .as_mut()
+        .and_then(|s| s.ssvep.as_mut())
+        .map(|detector| detector.take_scores())
+        .unwrap_or_default()
+}
+
+// Targets selected (held for the dwell time) since the last call
+#[frb]
+pub fn take_ssvep_selections() -> Vec<SsvepSelection> {
+    let mut state = MUSE_STATE.lock().unwrap();
+    state
+        .as_mut()
+        .and_then(|s| s.ssvep.as_mut())
+        .map(|detector| detector.take_selections())
+        .unwrap_or_default()
+}
+
+#[frb]
+pub fn set_hrv_settings(settings: HrvSettings) {
+    let mut state = MUSE_STATE.lock().unwrap();
+    if let Some(s) = state.as_mut() {
+        s.hrv_settings = settings;
+    }
+}
+
+// HRV over the configured window of the beats seen so far
+#[frb]
+pub fn get_hrv_metrics() -> Option<HrvMetrics> {
+    let state = MUSE_STATE.lock().unwrap();
+    let s = state.as_ref()?;
+    hrv::calculate_hrv(
+        s.beats.window(s.hrv_settings.window_seconds),
+        s.hrv_settings.clone(),
     )
 }
 
@@ -1,4 +1,5 @@
 use flutter_rust_bridge::frb;
+use serde::{Deserialize, Serialize};
 
 pub const MUSE_GATT_ATTR_STREAM_TOGGLE: &str = "273e0001-4c4d-454d-96b4-4b455555494f";
 pub const MUSE_GATT_ATTR_TP9: &str = "273e0002-4c4d-454d-96b4-4b455555494f";
@@ -16,7 +17,7 @@ pub const MUSE_GYRO_SCALE_FACTOR: f64 = 0.007476;
 pub const MUSE_ACCEL_SCALE_FACTOR: f64 = 0.00006103515635;
 
 #[frb]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum EegResolution {
     Bits12,
     Bits14,
@@ -39,7 +40,7 @@ impl EegResolution {
 }
 
 #[frb]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MuseModel {
     Muse2016,
     Muse2,
@@ -59,6 +60,15 @@ impl MuseModel {
         }
     }
 
+    pub fn eeg_channel_names(&self) -> Vec<String> {
+        let names: &[&str] = match self {
+            MuseModel::MuseS => &["TP9", "AF7", "AF8", "TP10", "AUX"],
+            MuseModel::MuseSAthena => &["TP9", "AF7", "AF8", "TP10", "FPz", "AUX_R", "AUX_L"],
+            _ => &["TP9", "AF7", "AF8", "TP10"],
+        };
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
     pub fn resolution(&self) -> EegResolution {
         match self {
             MuseModel::MuseSAthena => EegResolution::Bits14,
@@ -86,6 +96,55 @@ impl MuseModel {
     }
 }
 
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BandRange {
+    pub name: String,
+    pub freq_start: f64,
+    pub freq_stop: f64,
+}
+
+impl BandRange {
+    pub fn new(name: &str, freq_start: f64, freq_stop: f64) -> Self {
+        Self {
+            name: name.to_string(),
+            freq_start,
+            freq_stop,
+        }
+    }
+}
+
+// Settings of the live processing pipeline in muse_parser. Stored with every
+// recording so a replay runs with the same configuration.
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PipelineSettings {
+    pub eeg_sampling_rate: usize,
+    pub band_power_window: usize,
+    pub bands: Vec<BandRange>,
+    // IAF the theta / alpha / beta bands are anchored to, None for the fixed
+    // bands (see iaf)
+    #[serde(default)]
+    pub individual_alpha: Option<f64>,
+}
+
+impl Default for PipelineSettings {
+    fn default() -> Self {
+        Self {
+            eeg_sampling_rate: 256,
+            band_power_window: 256,
+            bands: vec![
+                BandRange::new("delta", 1.0, 4.0),
+                BandRange::new("theta", 4.0, 8.0),
+                BandRange::new("alpha", 8.0, 13.0),
+                BandRange::new("beta", 13.0, 30.0),
+                BandRange::new("gamma", 30.0, 45.0),
+            ],
+            individual_alpha: None,
+        }
+    }
+}
+
 #[frb]
 #[derive(Debug, Clone, Default)]
 pub struct MuseProcessedData {
@@ -0,0 +1,692 @@
+use crate::custom_model::{self, FeatureSpec};
+use crate::muse_parser;
+use crate::recording::{self, Record, SessionReader};
+use crate::replay;
+use crate::session_metadata;
+use anyhow::{bail, Context, Result};
+use flutter_rust_bridge::frb;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// On-device classifier training from labeled marker epochs.
+//
+// A calibration session is recorded with a marker at the start of every
+// trial, its label naming the class ("focused", "mind-wandering"). Training
+// cuts epochs after each marker, computes the same features the metrics
+// service computes live (custom_model::compute_features: band powers and
+// optionally Hjorth parameters), standardizes them and fits a linear model,
+// shrinkage LDA or L2 regularized multinomial logistic regression. Accuracy
+// is estimated by stratified k-fold cross-validation before the final fit
+// on all epochs. A registered classifier runs in the metrics service like
+// any custom model.
+
+const LOGISTIC_ITERATIONS: usize = 500;
+const LOGISTIC_LEARNING_RATE: f64 = 0.5;
+// Keeps the LDA covariance invertible with no shrinkage
+const RIDGE: f64 = 1e-6;
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClassifierAlgorithm {
+    Lda,
+    LogisticRegression,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainingSpec {
+    pub id: String,
+    // Marker labels, one class each
+    pub classes: Vec<String>,
+    // Epoch start relative to its marker
+    pub offset_seconds: f64,
+    // Span after the offset cut into consecutive feature windows; 0 takes
+    // a single window per marker
+    pub block_seconds: f64,
+    pub features: FeatureSpec,
+    pub algorithm: ClassifierAlgorithm,
+    // LDA shrinkage (0-1) or logistic regression L2 penalty
+    pub regularization: f64,
+    pub folds: u32,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrainedClassifier {
+    pub id: String,
+    pub labels: Vec<String>,
+    // Channels resolved to names, window and feature layout
+    pub features: FeatureSpec,
+    pub algorithm: ClassifierAlgorithm,
+    pub regularization: f64,
+    pub sampling_rate: f64,
+    // Standardization of the features
+    pub mean: Vec<f64>,
+    pub scale: Vec<f64>,
+    // One row of weights and a bias per class, softmax of the scores
+    pub weights: Vec<Vec<f64>>,
+    pub bias: Vec<f64>,
+    pub epochs: u32,
+    pub trained_at: f64,
+}
+
+impl TrainedClassifier {
+    // Class probabilities in label order
+    pub(crate) fn predict_proba(&self, features: &[f64]) -> Vec<f64> {
+        self.linear_model().probabilities(features)
+    }
+
+    fn linear_model(&self) -> LinearModel {
+        LinearModel {
+            mean: self.mean.clone(),
+            scale: self.scale.clone(),
+            weights: self.weights.clone(),
+            bias: self.bias.clone(),
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainingReport {
+    pub model: TrainedClassifier,
+    pub epochs_per_class: Vec<u32>,
+    pub fold_accuracies: Vec<f64>,
+    pub cv_accuracy: f64,
+    // Cross-validated counts, rows true class, columns predicted
+    pub confusion: Vec<Vec<u32>>,
+    // Epochs cut short by the end of the recording or not finite
+    pub rejected_epochs: u32,
+}
+
+// Standardization plus one linear score per class, shared with the motor
+// imagery BCI
+pub(crate) struct LinearModel {
+    mean: Vec<f64>,
+    scale: Vec<f64>,
+    weights: Vec<Vec<f64>>,
+    bias: Vec<f64>,
+}
+
+impl LinearModel {
+    pub(crate) fn scores(&self, features: &[f64]) -> Vec<f64> {
+        let z: Vec<f64> = features
+            .iter()
+            .zip(self.mean.iter().zip(&self.scale))
+            .map(|(x, (m, s))| (x - m) / s)
+            .collect();
+        scores(&self.weights, &self.bias, &z)
+    }
+
+    pub(crate) fn probabilities(&self, features: &[f64]) -> Vec<f64> {
+        softmax(&self.scores(features))
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
+
+fn scores(weights: &[Vec<f64>], bias: &[f64], z: &[f64]) -> Vec<f64> {
+    weights
+        .iter()
+        .zip(bias)
+        .map(|(w, b)| w.iter().zip(z).map(|(w, x)| w * x).sum::<f64>() + b)
+        .collect()
+}
+
+fn softmax(scores: &[f64]) -> Vec<f64> {
+    let max = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let exp: Vec<f64> = scores.iter().map(|s| (s - max).exp()).collect();
+    let sum: f64 = exp.iter().sum();
+    exp.iter().map(|e| e / sum).collect()
+}
+
+pub(crate) fn argmax(values: &[f64]) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+// Solves a x = b for a symmetric positive definite a (Cholesky)
+pub(crate) fn solve_spd(a: &[Vec<f64>], b: &[f64]) -> Option<Vec<f64>> {
+    let n = b.len();
+    let mut l = vec![vec![0.0; n]; n];
+    for i in 0..n {
+        for j in 0..=i {
+            let sum: f64 = (0..j).map(|k| l[i][k] * l[j][k]).sum();
+            if i == j {
+                let d = a[i][i] - sum;
+                if d <= 0.0 {
+                    return None;
+                }
+                l[i][j] = d.sqrt();
+            } else {
+                l[i][j] = (a[i][j] - sum) / l[j][j];
+            }
+        }
+    }
+    let mut y = vec![0.0; n];
+    for i in 0..n {
+        y[i] = (b[i] - (0..i).map(|k| l[i][k] * y[k]).sum::<f64>()) / l[i][i];
+    }
+    let mut x = vec![0.0; n];
+    for i in (0..n).rev() {
+        x[i] = (y[i] - (i + 1..n).map(|k| l[k][i] * x[k]).sum::<f64>()) / l[i][i];
+    }
+    Some(x)
+}
+
+fn standardize(features: &[Vec<f64>]) -> (Vec<f64>, Vec<f64>, Vec<Vec<f64>>) {
+    let n = features.len() as f64;
+    let d = features[0].len();
+    let mean: Vec<f64> = (0..d)
+        .map(|j| features.iter().map(|f| f[j]).sum::<f64>() / n)
+        .collect();
+    let scale: Vec<f64> = (0..d)
+        .map(|j| {
+            let sd = (features
+                .iter()
+                .map(|f| (f[j] - mean[j]).powi(2))
+                .sum::<f64>()
+                / n)
+                .sqrt();
+            if sd > 1e-12 {
+                sd
+            } else {
+                1.0
+            }
+        })
+        .collect();
+    let z = features
+        .iter()
+        .map(|f| (0..d).map(|j| (f[j] - mean[j]) / scale[j]).collect())
+        .collect();
+    (mean, scale, z)
+}
+
+// Shrinkage LDA: pooled within-class covariance pulled towards a scaled
+// identity, linear discriminants with class priors
+fn fit_lda(
+    z: &[Vec<f64>],
+    classes: &[usize],
+    n_classes: usize,
+    shrinkage: f64,
+) -> Option<(Vec<Vec<f64>>, Vec<f64>)> {
+    let d = z[0].len();
+    let n = z.len() as f64;
+    let mut means = vec![vec![0.0; d]; n_classes];
+    let mut counts = vec![0.0f64; n_classes];
+    for (x, &c) in z.iter().zip(classes) {
+        counts[c] += 1.0;
+        for j in 0..d {
+            means[c][j] += x[j];
+        }
+    }
+    for c in 0..n_classes {
+        means[c].iter_mut().for_each(|m| *m /= counts[c].max(1.0));
+    }
+    let mut cov = vec![vec![0.0; d]; d];
+    for (x, &c) in z.iter().zip(classes) {
+        for i in 0..d {
+            for j in 0..d {
+                cov[i][j] += (x[i] - means[c][i]) * (x[j] - means[c][j]);
+            }
+        }
+    }
+    let dof = (n - n_classes as f64).max(1.0);
+    let trace: f64 = (0..d).map(|i| cov[i][i] / dof).sum::<f64>() / d as f64;
+    let shrinkage = shrinkage.clamp(0.0, 1.0);
+    for (i, row) in cov.iter_mut().enumerate() {
+        row.iter_mut().for_each(|v| *v *= (1.0 - shrinkage) / dof);
+        row[i] += shrinkage * trace + RIDGE;
+    }
+    let mut weights = Vec::with_capacity(n_classes);
+    let mut bias = Vec::with_capacity(n_classes);
+    for c in 0..n_classes {
+        let w = solve_spd(&cov, &means[c])?;
+        let prior = (counts[c] / n).max(1e-12);
+        bias.push(-0.5 * w.iter().zip(&means[c]).map(|(w, m)| w * m).sum::<f64>() + prior.ln());
+        weights.push(w);
+    }
+    Some((weights, bias))
+}
+
+// Multinomial logistic regression by full batch gradient descent with an
+// L2 penalty on the weights
+fn fit_logistic(
+    z: &[Vec<f64>],
+    classes: &[usize],
+    n_classes: usize,
+    l2: f64,
+) -> (Vec<Vec<f64>>, Vec<f64>) {
+    let d = z[0].len();
+    let n = z.len() as f64;
+    let mut weights = vec![vec![0.0; d]; n_classes];
+    let mut bias = vec![0.0; n_classes];
+    for _ in 0..LOGISTIC_ITERATIONS {
+        let mut grad_w = vec![vec![0.0; d]; n_classes];
+        let mut grad_b = vec![0.0; n_classes];
+        for (x, &c) in z.iter().zip(classes) {
+            let p = softmax(&scores(&weights, &bias, x));
+            for k in 0..n_classes {
+                let err = p[k] - if k == c { 1.0 } else { 0.0 };
+                grad_b[k] += err / n;
+                for j in 0..d {
+                    grad_w[k][j] += err * x[j] / n;
+                }
+            }
+        }
+        for k in 0..n_classes {
+            bias[k] -= LOGISTIC_LEARNING_RATE * grad_b[k];
+            for j in 0..d {
+                weights[k][j] -=
+                    LOGISTIC_LEARNING_RATE * (grad_w[k][j] + l2.max(0.0) * weights[k][j]);
+            }
+        }
+    }
+    (weights, bias)
+}
+
+pub(crate) fn fit(
+    features: &[Vec<f64>],
+    classes: &[usize],
+    n_classes: usize,
+    algorithm: ClassifierAlgorithm,
+    regularization: f64,
+) -> Result<LinearModel> {
+    let (mean, scale, z) = standardize(features);
+    let (weights, bias) = match algorithm {
+        ClassifierAlgorithm::Lda => fit_lda(&z, classes, n_classes, regularization)
+            .context("Covariance not invertible, increase the shrinkage")?,
+        ClassifierAlgorithm::LogisticRegression => {
+            fit_logistic(&z, classes, n_classes, regularization)
+        }
+    };
+    Ok(LinearModel {
+        mean,
+        scale,
+        weights,
+        bias,
+    })
+}
+
+// Stratified folds of contiguous epochs per class, so neighbouring windows
+// of one trial rarely end up on both sides
+pub(crate) fn assign_folds(classes: &[usize], n_classes: usize, folds: usize) -> Vec<usize> {
+    let mut assignment = vec![0; classes.len()];
+    for c in 0..n_classes {
+        let members: Vec<usize> = (0..classes.len()).filter(|&i| classes[i] == c).collect();
+        for (rank, &i) in members.iter().enumerate() {
+            assignment[i] = rank * folds / members.len();
+        }
+    }
+    assignment
+}
+
+// Per fold accuracies and the pooled confusion matrix
+fn cross_validate(
+    features: &[Vec<f64>],
+    classes: &[usize],
+    n_classes: usize,
+    algorithm: ClassifierAlgorithm,
+    regularization: f64,
+    folds: usize,
+) -> Result<(Vec<f64>, Vec<Vec<u32>>)> {
+    let assignment = assign_folds(classes, n_classes, folds);
+    let mut accuracies = Vec::with_capacity(folds);
+    let mut confusion = vec![vec![0u32; n_classes]; n_classes];
+    for fold in 0..folds {
+        let (train, test): (Vec<usize>, Vec<usize>) =
+            (0..classes.len()).partition(|&i| assignment[i] != fold);
+        if test.is_empty() {
+            continue;
+        }
+        let train_features: Vec<Vec<f64>> = train.iter().map(|&i| features[i].clone()).collect();
+        let train_classes: Vec<usize> = train.iter().map(|&i| classes[i]).collect();
+        let model = fit(
+            &train_features,
+            &train_classes,
+            n_classes,
+            algorithm,
+            regularization,
+        )?;
+        let mut correct = 0;
+        for &i in &test {
+            let predicted = argmax(&model.scores(&features[i]));
+            confusion[classes[i]][predicted] += 1;
+            if predicted == classes[i] {
+                correct += 1;
+            }
+        }
+        accuracies.push(correct as f64 / test.len() as f64);
+    }
+    Ok((accuracies, confusion))
+}
+
+// Trains on labeled feature vectors; `features.channels` must already name
+// the channels the vectors were computed from
+pub(crate) fn train(
+    spec: &TrainingSpec,
+    features: &[Vec<f64>],
+    classes: &[usize],
+    sampling_rate: f64,
+) -> Result<TrainingReport> {
+    let n_classes = spec.classes.len();
+    if n_classes < 2 {
+        bail!("At least two classes are needed");
+    }
+    let mut epochs_per_class = vec![0u32; n_classes];
+    for &c in classes {
+        epochs_per_class[c] += 1;
+    }
+    let fewest = epochs_per_class.iter().copied().min().unwrap_or(0) as usize;
+    if fewest < 2 {
+        bail!(
+            "Every class needs at least two epochs, got {:?}",
+            epochs_per_class
+        );
+    }
+    let folds = (spec.folds as usize).clamp(2, fewest);
+    let (fold_accuracies, confusion) = cross_validate(
+        features,
+        classes,
+        n_classes,
+        spec.algorithm,
+        spec.regularization,
+        folds,
+    )?;
+    let correct: u32 = (0..n_classes).map(|c| confusion[c][c]).sum();
+    let total: u32 = confusion.iter().flatten().sum();
+    let model = fit(
+        features,
+        classes,
+        n_classes,
+        spec.algorithm,
+        spec.regularization,
+    )?;
+    Ok(TrainingReport {
+        model: TrainedClassifier {
+            id: spec.id.clone(),
+            labels: spec.classes.clone(),
+            features: spec.features.clone(),
+            algorithm: spec.algorithm,
+            regularization: spec.regularization,
+            sampling_rate,
+            mean: model.mean,
+            scale: model.scale,
+            weights: model.weights,
+            bias: model.bias,
+            epochs: classes.len() as u32,
+            trained_at: now(),
+        },
+        epochs_per_class,
+        fold_accuracies,
+        cv_accuracy: correct as f64 / total.max(1) as f64,
+        confusion,
+        rejected_epochs: 0,
+    })
+}
+
+// Start samples of the feature windows of every labeled marker
+pub(crate) fn epoch_starts(
+    markers: &[(f64, usize)],
+    start_time: f64,
+    sampling_rate: f64,
+    offset_seconds: f64,
+    block_seconds: f64,
+    window_seconds: f64,
+) -> Vec<(usize, i64)> {
+    let per_marker = ((block_seconds / window_seconds).floor() as usize).max(1);
+    let mut starts = Vec::new();
+    for &(time, class) in markers {
+        for k in 0..per_marker {
+            let t = time + offset_seconds + k as f64 * window_seconds - start_time;
+            starts.push((class, (t * sampling_rate).round() as i64));
+        }
+    }
+    starts
+}
+
+// Cuts and featurizes the labeled epochs of one session
+fn session_epochs(
+    session_dir: &str,
+    spec: &mut TrainingSpec,
+    sampling_rate: &mut Option<f64>,
+    features: &mut Vec<Vec<f64>>,
+    classes: &mut Vec<usize>,
+) -> Result<u32> {
+    let metadata = session_metadata::read_session_metadata(session_dir.to_string())?;
+    let fs = metadata.pipeline.eeg_sampling_rate as f64;
+    if sampling_rate.is_some_and(|rate| rate != fs) {
+        bail!("Sessions recorded at different sampling rates");
+    }
+    *sampling_rate = Some(fs);
+
+    let names = metadata.device.model.eeg_channel_names();
+    if spec.features.channels.is_empty() {
+        spec.features.channels = names
+            .iter()
+            .filter(|n| !n.starts_with("AUX"))
+            .cloned()
+            .collect();
+    }
+    let indices = spec
+        .features
+        .channels
+        .iter()
+        .map(|c| {
+            names
+                .iter()
+                .position(|n| n == c)
+                .with_context(|| format!("Channel {} not in {}", c, session_dir))
+        })
+        .collect::<Result<Vec<usize>>>()?;
+
+    let dir = Path::new(session_dir);
+    let eeg = replay::decode_eeg_channels(dir, metadata.device.model)?;
+    let mut markers = Vec::new();
+    for record in SessionReader::open(dir)? {
+        if let Record::Marker {
+            timestamp, label, ..
+        } = record?
+        {
+            if let Some(class) = spec.classes.iter().position(|c| *c == label) {
+                markers.push((timestamp, class));
+            }
+        }
+    }
+
+    let window = (spec.features.window_seconds * fs) as usize;
+    let mut rejected = 0;
+    for (class, start) in epoch_starts(
+        &markers,
+        eeg.start_time,
+        fs,
+        spec.offset_seconds,
+        spec.block_seconds,
+        spec.features.window_seconds,
+    ) {
+        let windows: Option<Vec<&[f64]>> = indices
+            .iter()
+            .map(|&i| {
+                let start = usize::try_from(start).ok()?;
+                eeg.channels[i].get(start..start + window)
+            })
+            .collect();
+        let epoch = windows
+            .and_then(|w| custom_model::compute_features(&spec.features, &w, fs))
+            .filter(|f| f.iter().all(|v| v.is_finite()));
+        match epoch {
+            Some(f) => {
+                features.push(f);
+                classes.push(class);
+            }
+            None => rejected += 1,
+        }
+    }
+    Ok(rejected)
+}
+
+// Trains a classifier on the labeled epochs of recorded calibration
+// sessions and reports its cross-validated accuracy
+#[frb]
+pub fn train_classifier(session_dirs: Vec<String>, spec: TrainingSpec) -> Result<TrainingReport> {
+    if spec.features.window_seconds <= 0.0 {
+        bail!("Feature window must be positive");
+    }
+    let mut spec = spec;
+    let mut sampling_rate = None;
+    let (mut features, mut classes) = (Vec::new(), Vec::new());
+    let mut rejected = 0;
+    for dir in &session_dirs {
+        rejected += session_epochs(
+            dir,
+            &mut spec,
+            &mut sampling_rate,
+            &mut features,
+            &mut classes,
+        )?;
+    }
+    let mut report = train(
+        &spec,
+        &features,
+        &classes,
+        sampling_rate.unwrap_or_default(),
+    )?;
+    report.rejected_epochs = rejected;
+    info!(
+        "[CLASSIFIER] Trained {} on {} epochs, CV accuracy {:.2}",
+        spec.id,
+        features.len(),
+        report.cv_accuracy
+    );
+    Ok(report)
+}
+
+// Runs the classifier in the metrics service; its probabilities arrive in
+// MetricsUpdate.custom. The live EEG stream must run at the sampling rate
+// the classifier was trained on, its feature windows are cut in samples.
+#[frb]
+pub fn register_trained_classifier(model: TrainedClassifier) -> Result<()> {
+    let live_rate = muse_parser::get_pipeline_settings().eeg_sampling_rate as f64;
+    if (model.sampling_rate - live_rate).abs() > 1e-6 {
+        bail!(
+            "Classifier {} was trained at {} Hz, the live stream runs at {} Hz",
+            model.id,
+            model.sampling_rate,
+            live_rate
+        );
+    }
+    custom_model::register_trained(model);
+    Ok(())
+}
+
+#[frb]
+pub fn save_trained_classifier(model: TrainedClassifier, dir: String) -> Result<String> {
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir))?;
+    let id = recording::file_name_part(&model.id);
+    let path = Path::new(&dir).join(format!("classifier_{}.json", id));
+    recording::write_json_atomic(&path, &model)?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+#[frb]
+pub fn load_trained_classifier(path: String) -> Result<TrainedClassifier> {
+    let json = fs::read_to_string(&path).with_context(|| format!("No classifier at {}", path))?;
+    serde_json::from_str(&json).with_context(|| format!("Invalid classifier file {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::custom_model::{BandPowerScale, FeatureAggregation};
+    use crate::muse_types::BandRange;
+    use crate::test_util;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn separates_alpha_from_beta_epochs() {
+        let fs = 256.0;
+        let mut noise = test_util::noise(7);
+        let spec = TrainingSpec {
+            id: "focus".into(),
+            classes: vec!["relaxed".into(), "focused".into()],
+            offset_seconds: 0.0,
+            block_seconds: 0.0,
+            features: FeatureSpec {
+                channels: vec!["AF7".into(), "AF8".into()],
+                bands: vec![
+                    BandRange::new("alpha", 8.0, 13.0),
+                    BandRange::new("beta", 13.0, 30.0),
+                ],
+                window_seconds: 2.0,
+                scale: BandPowerScale::Log10,
+                aggregation: FeatureAggregation::PerChannel,
+                hjorth: true,
+            },
+            algorithm: ClassifierAlgorithm::Lda,
+            regularization: 0.1,
+            folds: 5,
+        };
+
+        let (mut features, mut classes) = (Vec::new(), Vec::new());
+        for epoch in 0..40 {
+            let class = epoch % 2;
+            let freq = if class == 0 { 10.0 } else { 20.0 };
+            let channels: Vec<Vec<f64>> = (0..2)
+                .map(|_| {
+                    let amp = 8.0 + 4.0 * noise();
+                    (0..512)
+                        .map(|i| amp * (2.0 * PI * freq * i as f64 / fs).sin() + 10.0 * noise())
+                        .collect()
+                })
+                .collect();
+            let windows: Vec<&[f64]> = channels.iter().map(Vec::as_slice).collect();
+            features.push(custom_model::compute_features(&spec.features, &windows, fs).unwrap());
+            classes.push(class);
+        }
+        assert_eq!(features[0].len(), 10);
+
+        for algorithm in [
+            ClassifierAlgorithm::Lda,
+            ClassifierAlgorithm::LogisticRegression,
+        ] {
+            let spec = TrainingSpec {
+                algorithm,
+                ..spec.clone()
+            };
+            let report = train(&spec, &features, &classes, fs).unwrap();
+            assert_eq!(report.fold_accuracies.len(), 5);
+            assert!(
+                report.cv_accuracy > 0.9,
+                "{:?}: {}",
+                algorithm,
+                report.cv_accuracy
+            );
+            assert_eq!(report.confusion.iter().flatten().sum::<u32>(), 40);
+
+            let p = report.model.predict_proba(&features[1]);
+            assert!((p.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+            assert!(p[1] > 0.5);
+
+            let json = serde_json::to_string(&report.model).unwrap();
+            let loaded: TrainedClassifier = serde_json::from_str(&json).unwrap();
+            let q = loaded.predict_proba(&features[1]);
+            assert!((p[1] - q[1]).abs() < 1e-9);
+        }
+
+        // Markers 2 s into the recording, 4 s blocks of 2 s windows
+        let starts = epoch_starts(&[(102.0, 1)], 100.0, fs, 0.5, 4.0, 2.0);
+        assert_eq!(starts, vec![(1, 640), (1, 1152)]);
+    }
+}
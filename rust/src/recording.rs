@@ -0,0 +1,1338 @@
+use crate::eeg_codec;
+use crate::session_crypto::{self, FrameCipher, RecordingKey};
+use crate::session_metadata::{self, SessionMetadata};
+use anyhow::{bail, Context, Result};
+use flutter_rust_bridge::frb;
+use log::{info, warn};
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Crash-safe session recorder.
+//
+// A session is a directory with rotating chunk files (chunk_00000.mrec, ...),
+// a journal holding the last fsync'ed offset, and a manifest written on
+// finalize. The session metadata lives in metadata.json and is repeated in
+// every chunk header, so a chunk file on its own is still self-describing.
+// Records are batched into CRC-checked frames; a frame only counts
+// once it has been fsync'ed and the journal points past it, so a killed app
+// loses at most one flush interval of data.
+//
+// Since format version 2 frames are compressed with eeg_codec by default and
+// finalize writes a block index (index.bin) with the time span of every
+// frame, so a time range can be read without decoding the whole session.
+// With a RecordingKey in the config every frame is sealed after compression
+// (see session_crypto); readers decrypt with the keys loaded in the keyring.
+// The seal covers the frame's chunk and its offset in the chunk, so sealed
+// frames can't be reordered or moved to another chunk unnoticed.
+
+static RECORDER: Mutex<Option<SessionRecorder>> = Mutex::new(None);
+
+pub(crate) const CHUNK_MAGIC: &[u8; 4] = b"MREC";
+pub(crate) const FORMAT_VERSION: u16 = 2;
+const FRAME_MAGIC: u8 = 0xA5;
+const FRAME_HEADER_LEN: usize = 10; // magic, flags, payload len, crc32
+const FRAME_FLAG_CODEC: u8 = 0x01;
+const FRAME_FLAG_ENCRYPTED: u8 = 0x02;
+// Sealed with its offset in the chunk; frames written before lack it
+const FRAME_FLAG_POSITION: u8 = 0x04;
+const MARKERS_AAD: &[u8] = b"manifest-markers";
+const JOURNAL_FILE: &str = "journal.json";
+const MANIFEST_FILE: &str = "session.json";
+const INDEX_FILE: &str = "index.bin";
+const INDEX_MAGIC: &[u8; 4] = b"MIDX";
+const INDEX_ENTRY_LEN: usize = 32;
+
+const RECORD_PACKET: u8 = 1;
+const RECORD_MARKER: u8 = 2;
+const RECORD_SAMPLES: u8 = 3;
+
+#[derive(Debug, Clone)]
+pub struct RecorderConfig {
+    pub chunk_max_bytes: u64,
+    pub frame_max_bytes: usize,
+    pub flush_interval_ms: u64,
+    pub compress: bool,
+    pub key: Option<RecordingKey>,
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        Self {
+            chunk_max_bytes: 8 * 1024 * 1024,
+            frame_max_bytes: 64 * 1024,
+            flush_interval_ms: 1000,
+            compress: true,
+            key: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Packet {
+        timestamp: f64,
+        channel: u8,
+        data: Vec<u8>,
+    },
+    Marker {
+        timestamp: f64,
+        value: f64,
+        label: String,
+    },
+    Samples {
+        timestamp: f64,
+        values: Vec<f64>,
+    },
+}
+
+impl Record {
+    pub fn timestamp(&self) -> f64 {
+        match self {
+            Record::Packet { timestamp, .. }
+            | Record::Marker { timestamp, .. }
+            | Record::Samples { timestamp, .. } => *timestamp,
+        }
+    }
+
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        let (kind, timestamp, body) = match self {
+            Record::Packet {
+                timestamp,
+                channel,
+                data,
+            } => {
+                let mut body = Vec::with_capacity(data.len() + 1);
+                body.push(*channel);
+                body.extend_from_slice(data);
+                (RECORD_PACKET, *timestamp, body)
+            }
+            Record::Marker {
+                timestamp,
+                value,
+                label,
+            } => {
+                let mut body = value.to_le_bytes().to_vec();
+                body.extend_from_slice(label.as_bytes());
+                (RECORD_MARKER, *timestamp, body)
+            }
+            Record::Samples { timestamp, values } => {
+                let body = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+                (RECORD_SAMPLES, *timestamp, body)
+            }
+        };
+        let Ok(len) = u16::try_from(body.len()) else {
+            bail!("Record body of {} bytes exceeds {}", body.len(), u16::MAX);
+        };
+        out.push(kind);
+        out.extend_from_slice(&timestamp.to_le_bytes());
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&body);
+        Ok(())
+    }
+
+    fn body_len(&self) -> usize {
+        match self {
+            Record::Packet { data, .. } => 1 + data.len(),
+            Record::Marker { label, .. } => 8 + label.len(),
+            Record::Samples { values, .. } => 8 * values.len(),
+        }
+    }
+
+    // Size of the plain encoding, used to bound frames before compression
+    fn encoded_len(&self) -> usize {
+        11 + self.body_len()
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> Result<(Record, usize)> {
+        if buf.len() < 11 {
+            bail!("truncated record header");
+        }
+        let kind = buf[0];
+        let timestamp = f64::from_le_bytes(buf[1..9].try_into()?);
+        let len = u16::from_le_bytes(buf[9..11].try_into()?) as usize;
+        if buf.len() < 11 + len {
+            bail!("truncated record body");
+        }
+        let body = &buf[11..11 + len];
+        let record = match kind {
+            RECORD_PACKET if !body.is_empty() => Record::Packet {
+                timestamp,
+                channel: body[0],
+                data: body[1..].to_vec(),
+            },
+            RECORD_MARKER if body.len() >= 8 => Record::Marker {
+                timestamp,
+                value: f64::from_le_bytes(body[..8].try_into()?),
+                label: String::from_utf8_lossy(&body[8..]).into_owned(),
+            },
+            RECORD_SAMPLES if body.len().is_multiple_of(8) => Record::Samples {
+                timestamp,
+                values: body
+                    .chunks_exact(8)
+                    .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+                    .collect(),
+            },
+            _ => bail!("invalid record kind {} (len {})", kind, len),
+        };
+        Ok((record, 11 + len))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChunkHeader {
+    session_id: String,
+    chunk_index: u32,
+    created_at: f64,
+    #[serde(default)]
+    metadata: Option<SessionMetadata>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JournalState {
+    Recording,
+    Finalized,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Journal {
+    state: JournalState,
+    session_id: String,
+    started_at: f64,
+    chunk_index: u32,
+    frames: u64,
+    records: u64,
+    updated_at: f64,
+}
+
+#[frb]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarkerEntry {
+    pub timestamp: f64,
+    pub value: f64,
+    pub label: String,
+}
+
+#[frb]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkInfo {
+    pub file_name: String,
+    pub bytes: u64,
+    pub records: u64,
+}
+
+#[frb]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionManifest {
+    pub format_version: u16,
+    pub session_id: String,
+    pub started_at: f64,
+    pub first_timestamp: Option<f64>,
+    pub last_timestamp: Option<f64>,
+    pub record_count: u64,
+    pub chunks: Vec<ChunkInfo>,
+    pub markers: Vec<MarkerEntry>,
+    pub recovered: bool,
+    // Markers of an encrypted session sealed with its data key; `markers` is
+    // empty on disk and filled by read_session_manifest once the key is loaded
+    #[serde(default)]
+    pub sealed_markers: Option<String>,
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
+
+fn chunk_file_name(index: u32) -> String {
+    format!("chunk_{:05}.mrec", index)
+}
+
+pub(crate) fn chunk_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to list session dir {}", dir.display()))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().map(|e| e == "mrec").unwrap_or(false))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+// `value` with everything but ASCII letters, digits and '-' replaced, for
+// ids used in file names
+pub(crate) fn file_name_part(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+// Write to a temp file, fsync and rename so the journal is never half-written.
+pub(crate) fn write_json_atomic<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(value)?)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    if let Ok(dir) = File::open(path.parent().unwrap_or(Path::new("."))) {
+        dir.sync_all().ok();
+    }
+    Ok(())
+}
+
+fn write_chunk_header(file: &mut File, header: &ChunkHeader) -> Result<u64> {
+    let json = serde_json::to_vec(header)?;
+    file.write_all(CHUNK_MAGIC)?;
+    file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    file.write_all(&(json.len() as u32).to_le_bytes())?;
+    file.write_all(&json)?;
+    file.sync_data()?;
+    Ok((4 + 2 + 4 + json.len()) as u64)
+}
+
+fn read_chunk_header(buf: &[u8]) -> Result<(ChunkHeader, usize)> {
+    if buf.len() < 10 || &buf[..4] != CHUNK_MAGIC {
+        bail!("not a recording chunk");
+    }
+    let version = u16::from_le_bytes(buf[4..6].try_into()?);
+    if version > FORMAT_VERSION {
+        bail!("unsupported chunk format version {}", version);
+    }
+    let len = u32::from_le_bytes(buf[6..10].try_into()?) as usize;
+    if buf.len() < 10 + len {
+        bail!("truncated chunk header");
+    }
+    let header = serde_json::from_slice(&buf[10..10 + len])?;
+    Ok((header, 10 + len))
+}
+
+struct Frame {
+    flags: u8,
+    offset: usize,
+    payload: Vec<u8>,
+}
+
+// Returns the decoded frames and the offset just past the last valid one.
+fn scan_frames(buf: &[u8], start: usize) -> (Vec<Frame>, usize) {
+    let mut frames = Vec::new();
+    let mut pos = start;
+    while pos + FRAME_HEADER_LEN <= buf.len() {
+        if buf[pos] != FRAME_MAGIC {
+            break;
+        }
+        let len = u32::from_le_bytes(buf[pos + 2..pos + 6].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(buf[pos + 6..pos + 10].try_into().unwrap());
+        let end = pos + FRAME_HEADER_LEN + len;
+        if end > buf.len() {
+            break;
+        }
+        let payload = &buf[pos + FRAME_HEADER_LEN..end];
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+        frames.push(Frame {
+            flags: buf[pos + 1],
+            offset: pos,
+            payload: payload.to_vec(),
+        });
+        pos = end;
+    }
+    (frames, pos)
+}
+
+// Binds a sealed frame to its chunk, flags and `position`, the frame's
+// offset after the chunk header
+fn frame_aad(chunk_index: u32, flags: u8, position: u64) -> Vec<u8> {
+    let mut aad = chunk_index.to_le_bytes().to_vec();
+    aad.push(flags);
+    if flags & FRAME_FLAG_POSITION != 0 {
+        aad.extend_from_slice(&position.to_le_bytes());
+    }
+    aad
+}
+
+fn decode_frame(
+    frame: &Frame,
+    chunk_index: u32,
+    position: u64,
+    cipher: Option<&FrameCipher>,
+    out: &mut Vec<Record>,
+) -> Result<()> {
+    let decrypted;
+    let payload = if frame.flags & FRAME_FLAG_ENCRYPTED != 0 {
+        let cipher = cipher.context("Session is encrypted but no key is loaded")?;
+        let aad = frame_aad(chunk_index, frame.flags, position);
+        decrypted = cipher.open(&aad, &frame.payload)?;
+        &decrypted
+    } else {
+        &frame.payload
+    };
+    if frame.flags & FRAME_FLAG_CODEC != 0 {
+        out.extend(eeg_codec::decode_block(payload)?);
+        return Ok(());
+    }
+    let mut pos = 0;
+    while pos < payload.len() {
+        let (record, used) = Record::decode(&payload[pos..])?;
+        out.push(record);
+        pos += used;
+    }
+    Ok(())
+}
+
+// One entry of index.bin per frame: chunk number, frame offset in the chunk,
+// record count and time span of the frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockIndexEntry {
+    pub chunk_index: u32,
+    pub offset: u64,
+    pub records: u32,
+    pub first_timestamp: f64,
+    pub last_timestamp: f64,
+}
+
+fn write_block_index(dir: &Path, entries: &[BlockIndexEntry]) -> Result<()> {
+    let mut buf = Vec::with_capacity(10 + entries.len() * INDEX_ENTRY_LEN);
+    buf.extend_from_slice(INDEX_MAGIC);
+    buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    buf.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    for e in entries {
+        buf.extend_from_slice(&e.chunk_index.to_le_bytes());
+        buf.extend_from_slice(&e.offset.to_le_bytes());
+        buf.extend_from_slice(&e.records.to_le_bytes());
+        buf.extend_from_slice(&e.first_timestamp.to_le_bytes());
+        buf.extend_from_slice(&e.last_timestamp.to_le_bytes());
+    }
+    let tmp = dir.join(INDEX_FILE).with_extension("tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, dir.join(INDEX_FILE))?;
+    Ok(())
+}
+
+pub fn read_block_index(dir: impl AsRef<Path>) -> Result<Vec<BlockIndexEntry>> {
+    let path = dir.as_ref().join(INDEX_FILE);
+    let buf = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    if buf.len() < 10 || &buf[..4] != INDEX_MAGIC {
+        bail!("{} is not a block index", path.display());
+    }
+    let count = u32::from_le_bytes(buf[6..10].try_into()?) as usize;
+    if buf.len() < 10 + count * INDEX_ENTRY_LEN {
+        bail!("truncated block index");
+    }
+    Ok(buf[10..10 + count * INDEX_ENTRY_LEN]
+        .chunks_exact(INDEX_ENTRY_LEN)
+        .map(|e| BlockIndexEntry {
+            chunk_index: u32::from_le_bytes(e[0..4].try_into().unwrap()),
+            offset: u64::from_le_bytes(e[4..12].try_into().unwrap()),
+            records: u32::from_le_bytes(e[12..16].try_into().unwrap()),
+            first_timestamp: f64::from_le_bytes(e[16..24].try_into().unwrap()),
+            last_timestamp: f64::from_le_bytes(e[24..32].try_into().unwrap()),
+        })
+        .collect())
+}
+
+// Reads and decodes the single frame an index entry points at
+fn read_indexed_block(
+    dir: &Path,
+    entry: &BlockIndexEntry,
+    cipher: Option<&FrameCipher>,
+) -> Result<Vec<Record>> {
+    let mut file = File::open(dir.join(chunk_file_name(entry.chunk_index)))?;
+    let mut chunk_header = [0u8; 10];
+    file.read_exact(&mut chunk_header)?;
+    let header_len = 10 + u32::from_le_bytes(chunk_header[6..10].try_into()?) as u64;
+    file.seek(SeekFrom::Start(entry.offset))?;
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    file.read_exact(&mut header)?;
+    let len = u32::from_le_bytes(header[2..6].try_into()?) as usize;
+    let mut buf = header.to_vec();
+    buf.resize(FRAME_HEADER_LEN + len, 0);
+    file.read_exact(&mut buf[FRAME_HEADER_LEN..])?;
+    let (frames, _) = scan_frames(&buf, 0);
+    let frame = frames.first().with_context(|| {
+        format!(
+            "Corrupt frame at {} in chunk {}",
+            entry.offset, entry.chunk_index
+        )
+    })?;
+    let mut records = Vec::with_capacity(entry.records as usize);
+    let position = entry.offset.saturating_sub(header_len);
+    decode_frame(frame, entry.chunk_index, position, cipher, &mut records)?;
+    Ok(records)
+}
+
+// Data key of an encrypted session, from metadata.json or else a chunk header
+fn load_session_cipher(dir: &Path) -> Result<Option<FrameCipher>> {
+    let metadata = match session_metadata::read_session_metadata(dir.display().to_string()) {
+        Ok(metadata) => Some(metadata),
+        Err(_) => chunk_paths(dir)?.iter().find_map(|path| {
+            let buf = fs::read(path).ok()?;
+            read_chunk_header(&buf).ok()?.0.metadata
+        }),
+    };
+    metadata
+        .and_then(|m| m.encryption)
+        .map(|info| session_crypto::session_cipher(&info))
+        .transpose()
+}
+
+// Rewrites every chunk header with `metadata` (key rotation). Frames are
+// copied as they are; the block index is shifted by the header size change.
+pub(crate) fn replace_chunk_metadata(dir: &Path, metadata: &SessionMetadata) -> Result<()> {
+    let metadata = session_crypto::stored_metadata(metadata)?;
+    let mut index = read_block_index(dir).ok();
+    for path in chunk_paths(dir)? {
+        let buf = fs::read(&path)?;
+        let (mut header, header_len) = read_chunk_header(&buf)?;
+        header.metadata = Some(metadata.clone());
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            let new_len = write_chunk_header(&mut file, &header)?;
+            file.write_all(&buf[header_len..])?;
+            file.sync_all()?;
+            if let Some(index) = index.as_mut() {
+                for entry in index
+                    .iter_mut()
+                    .filter(|e| e.chunk_index == header.chunk_index)
+                {
+                    entry.offset = entry.offset - header_len as u64 + new_len;
+                }
+            }
+        }
+        fs::rename(&tmp, &path)?;
+        if let Some(index) = &index {
+            write_block_index(dir, index)?;
+        }
+    }
+    Ok(())
+}
+
+pub struct SessionRecorder {
+    dir: PathBuf,
+    config: RecorderConfig,
+    session_id: String,
+    started_at: f64,
+    metadata: SessionMetadata,
+    stored_metadata: SessionMetadata,
+    chunk_index: u32,
+    chunk: File,
+    // Offset of the first frame, just past the chunk header
+    chunk_start: u64,
+    chunk_offset: u64,
+    pending: Vec<Record>,
+    pending_bytes: usize,
+    cipher: Option<FrameCipher>,
+    frames: u64,
+    records: u64,
+    last_flush: Instant,
+}
+
+impl SessionRecorder {
+    pub fn create(
+        dir: impl AsRef<Path>,
+        config: RecorderConfig,
+        metadata: SessionMetadata,
+    ) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create session dir {}", dir.display()))?;
+        if dir.join(JOURNAL_FILE).exists() {
+            bail!("Session dir {} is already in use", dir.display());
+        }
+
+        let session_id = dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "session".to_string());
+        let started_at = now();
+        let mut metadata = metadata;
+        let cipher = match &config.key {
+            Some(key) => {
+                let (info, cipher) = session_crypto::new_session_encryption(key);
+                metadata.encryption = Some(info);
+                Some(cipher)
+            }
+            None => {
+                metadata.encryption = None;
+                None
+            }
+        };
+        // Chunk headers get the stored form; later chunks reuse it without the keyring
+        let stored_metadata = match &cipher {
+            Some(cipher) => session_crypto::seal_details(&metadata, cipher)?,
+            None => metadata.clone(),
+        };
+        session_metadata::write_metadata(&dir, &stored_metadata)?;
+        let (chunk, chunk_offset) = Self::open_chunk(&dir, &session_id, 0, &stored_metadata)?;
+
+        let recorder = Self {
+            dir,
+            config,
+            session_id,
+            started_at,
+            metadata,
+            stored_metadata,
+            chunk_index: 0,
+            chunk,
+            chunk_start: chunk_offset,
+            chunk_offset,
+            pending: Vec::new(),
+            pending_bytes: 0,
+            cipher,
+            frames: 0,
+            records: 0,
+            last_flush: Instant::now(),
+        };
+        recorder.write_journal(JournalState::Recording)?;
+        info!("[REC] Recording started in {}", recorder.dir.display());
+        Ok(recorder)
+    }
+
+    fn open_chunk(
+        dir: &Path,
+        session_id: &str,
+        index: u32,
+        metadata: &SessionMetadata,
+    ) -> Result<(File, u64)> {
+        let path = dir.join(chunk_file_name(index));
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to create chunk {}", path.display()))?;
+        let header = ChunkHeader {
+            session_id: session_id.to_string(),
+            chunk_index: index,
+            created_at: now(),
+            metadata: Some(metadata.clone()),
+        };
+        let offset = write_chunk_header(&mut file, &header)?;
+        Ok((file, offset))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn metadata(&self) -> &SessionMetadata {
+        &self.metadata
+    }
+
+    pub fn write(&mut self, record: &Record) -> Result<()> {
+        // Refused here, at flush it would take the records around it down too
+        if u16::try_from(record.body_len()).is_err() {
+            bail!(
+                "Record body of {} bytes exceeds {}",
+                record.body_len(),
+                u16::MAX
+            );
+        }
+        self.pending_bytes += record.encoded_len();
+        self.pending.push(record.clone());
+        if self.pending_bytes >= self.config.frame_max_bytes
+            || self.last_flush.elapsed().as_millis() as u64 >= self.config.flush_interval_ms
+        {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn write_packet(&mut self, channel: u8, data: &[u8]) -> Result<()> {
+        self.write(&Record::Packet {
+            timestamp: now(),
+            channel,
+            data: data.to_vec(),
+        })
+    }
+
+    pub fn write_marker(&mut self, value: f64, label: &str) -> Result<()> {
+        self.write_marker_at(now(), value, label)
+    }
+
+    pub fn write_marker_at(&mut self, timestamp: f64, value: f64, label: &str) -> Result<()> {
+        self.write(&Record::Marker {
+            timestamp,
+            value,
+            label: label.to_string(),
+        })?;
+        // Markers are rare and important, don't leave them in the buffer
+        self.flush()
+    }
+
+    // BrainFlow layout: one row per channel, one column per sample.
+    pub fn write_board_data(
+        &mut self,
+        data: &Array2<f64>,
+        timestamp_row: Option<usize>,
+    ) -> Result<()> {
+        for column in data.columns() {
+            let timestamp = timestamp_row
+                .and_then(|row| column.get(row).copied())
+                .unwrap_or_else(now);
+            self.write(&Record::Samples {
+                timestamp,
+                values: column.to_vec(),
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.last_flush = Instant::now();
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let records = std::mem::take(&mut self.pending);
+        self.pending_bytes = 0;
+        let (flags, payload) = if self.config.compress {
+            let eeg_channels = self.metadata.device.model.channel_count();
+            (
+                FRAME_FLAG_CODEC,
+                eeg_codec::encode_block(&records, eeg_channels)?,
+            )
+        } else {
+            let mut payload = Vec::new();
+            for record in &records {
+                record.encode(&mut payload)?;
+            }
+            (0, payload)
+        };
+        let (flags, payload) = match &self.cipher {
+            Some(cipher) => {
+                let flags = flags | FRAME_FLAG_ENCRYPTED | FRAME_FLAG_POSITION;
+                let position = self.chunk_offset - self.chunk_start;
+                (
+                    flags,
+                    cipher.seal(&frame_aad(self.chunk_index, flags, position), &payload),
+                )
+            }
+            None => (flags, payload),
+        };
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.push(FRAME_MAGIC);
+        frame.push(flags);
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+
+        self.chunk.write_all(&frame)?;
+        self.chunk.sync_data()?;
+        self.chunk_offset += frame.len() as u64;
+        self.frames += 1;
+        self.records += records.len() as u64;
+
+        if self.chunk_offset >= self.config.chunk_max_bytes {
+            self.rotate()?;
+        }
+        self.write_journal(JournalState::Recording)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.chunk.sync_all()?;
+        let index = self.chunk_index + 1;
+        let (chunk, offset) =
+            Self::open_chunk(&self.dir, &self.session_id, index, &self.stored_metadata)?;
+        self.chunk = chunk;
+        self.chunk_index = index;
+        self.chunk_start = offset;
+        self.chunk_offset = offset;
+        info!("[REC] Rotated to chunk {}", index);
+        Ok(())
+    }
+
+    fn write_journal(&self, state: JournalState) -> Result<()> {
+        let journal = Journal {
+            state,
+            session_id: self.session_id.clone(),
+            started_at: self.started_at,
+            chunk_index: self.chunk_index,
+            frames: self.frames,
+            records: self.records,
+            updated_at: now(),
+        };
+        write_json_atomic(&self.dir.join(JOURNAL_FILE), &journal)
+    }
+
+    pub fn finish(mut self) -> Result<SessionManifest> {
+        self.flush()?;
+        self.chunk.sync_all()?;
+        finalize_session(&self.dir, false)
+    }
+}
+
+// Repairs the trailing partial frame of every chunk and writes the manifest.
+fn finalize_session(dir: &Path, recovered: bool) -> Result<SessionManifest> {
+    let journal_path = dir.join(JOURNAL_FILE);
+    let mut journal: Journal = serde_json::from_slice(
+        &fs::read(&journal_path).with_context(|| format!("No journal in {}", dir.display()))?,
+    )?;
+
+    let mut manifest = SessionManifest {
+        format_version: FORMAT_VERSION,
+        session_id: journal.session_id.clone(),
+        started_at: journal.started_at,
+        first_timestamp: None,
+        last_timestamp: None,
+        record_count: 0,
+        chunks: Vec::new(),
+        markers: Vec::new(),
+        recovered,
+        sealed_markers: None,
+    };
+    let mut index = Vec::new();
+    let cipher = load_session_cipher(dir)?;
+
+    for path in chunk_paths(dir)? {
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let (chunk_index, header_len) = match read_chunk_header(&buf) {
+            Ok((header, len)) => {
+                // metadata.json lost (e.g. killed during the rename), restore it from the chunk
+                if let Some(metadata) = header.metadata {
+                    if !dir.join(session_metadata::METADATA_FILE).exists() {
+                        session_metadata::write_metadata(dir, &metadata)?;
+                    }
+                }
+                (header.chunk_index, len)
+            }
+            Err(e) => {
+                // Killed while the header was being written, nothing to keep
+                warn!("[REC] Dropping unreadable chunk {}: {}", path.display(), e);
+                drop(file);
+                fs::remove_file(&path)?;
+                continue;
+            }
+        };
+        let (frames, valid_end) = scan_frames(&buf, header_len);
+        if valid_end < buf.len() {
+            warn!(
+                "[REC] Truncating {} from {} to {} bytes",
+                path.display(),
+                buf.len(),
+                valid_end
+            );
+            file.set_len(valid_end as u64)?;
+            file.seek(SeekFrom::End(0))?;
+            file.sync_all()?;
+        }
+
+        let mut records = Vec::new();
+        for frame in &frames {
+            let first = records.len();
+            // Keep what decoded so far (e.g. an encrypted session without its
+            // key); the frames stay on disk untouched
+            let position = (frame.offset - header_len) as u64;
+            if let Err(e) =
+                decode_frame(frame, chunk_index, position, cipher.as_ref(), &mut records)
+            {
+                warn!(
+                    "[REC] Stopping at undecodable frame {} of {}: {}",
+                    frame.offset,
+                    path.display(),
+                    e
+                );
+                records.truncate(first);
+                break;
+            }
+            let block = &records[first..];
+            if !block.is_empty() {
+                let span = block.iter().map(|r| r.timestamp());
+                index.push(BlockIndexEntry {
+                    chunk_index,
+                    offset: frame.offset as u64,
+                    records: block.len() as u32,
+                    first_timestamp: span.clone().fold(f64::INFINITY, f64::min),
+                    last_timestamp: span.fold(f64::NEG_INFINITY, f64::max),
+                });
+            }
+        }
+        for record in &records {
+            let ts = record.timestamp();
+            manifest.first_timestamp = Some(manifest.first_timestamp.map_or(ts, |t| t.min(ts)));
+            manifest.last_timestamp = Some(manifest.last_timestamp.map_or(ts, |t| t.max(ts)));
+            if let Record::Marker {
+                timestamp,
+                value,
+                label,
+            } = record
+            {
+                manifest.markers.push(MarkerEntry {
+                    timestamp: *timestamp,
+                    value: *value,
+                    label: label.clone(),
+                });
+            }
+        }
+        manifest.record_count += records.len() as u64;
+        manifest.chunks.push(ChunkInfo {
+            file_name: path.file_name().unwrap().to_string_lossy().into_owned(),
+            bytes: valid_end as u64,
+            records: records.len() as u64,
+        });
+    }
+
+    write_block_index(dir, &index)?;
+    let mut stored = manifest.clone();
+    if let Some(cipher) = &cipher {
+        stored.sealed_markers = Some(cipher.seal_json(MARKERS_AAD, &stored.markers)?);
+        stored.markers.clear();
+    }
+    write_json_atomic(&dir.join(MANIFEST_FILE), &stored)?;
+    journal.state = JournalState::Finalized;
+    journal.records = manifest.record_count;
+    journal.updated_at = now();
+    write_json_atomic(&journal_path, &journal)?;
+
+    info!(
+        "[REC] Session {} finalized: {} records, {} markers{}",
+        manifest.session_id,
+        manifest.record_count,
+        manifest.markers.len(),
+        if recovered { " (recovered)" } else { "" }
+    );
+    Ok(manifest)
+}
+
+pub fn is_unfinished_session(dir: &Path) -> bool {
+    fs::read(dir.join(JOURNAL_FILE))
+        .ok()
+        .and_then(|b| serde_json::from_slice::<Journal>(&b).ok())
+        .map(|j| j.state == JournalState::Recording)
+        .unwrap_or(false)
+}
+
+pub fn recover_session(dir: impl AsRef<Path>) -> Result<SessionManifest> {
+    let dir = dir.as_ref();
+    if !is_unfinished_session(dir) {
+        bail!("{} is not an unfinished session", dir.display());
+    }
+    finalize_session(dir, true)
+}
+
+pub fn read_session_manifest(dir: impl AsRef<Path>) -> Result<SessionManifest> {
+    let path = dir.as_ref().join(MANIFEST_FILE);
+    let bytes = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut manifest: SessionManifest = serde_json::from_slice(&bytes)?;
+    // Without the key the markers stay sealed
+    if let Some(sealed) = &manifest.sealed_markers {
+        if let Ok(Some(cipher)) = load_session_cipher(dir.as_ref()) {
+            manifest.markers = cipher.open_json(MARKERS_AAD, sealed)?;
+            manifest.sealed_markers = None;
+        }
+    }
+    Ok(manifest)
+}
+
+// Streams the records of a session in write order, one chunk (or one indexed
+// block) in memory at a time. Works on finalized as well as unfinished
+// sessions (trailing partial frames are ignored).
+pub struct SessionReader {
+    dir: PathBuf,
+    chunks: Vec<PathBuf>,
+    next_chunk: usize,
+    blocks: Option<VecDeque<BlockIndexEntry>>,
+    range: Option<(f64, f64)>,
+    cipher: Option<FrameCipher>,
+    pending: VecDeque<Record>,
+}
+
+impl SessionReader {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            chunks: chunk_paths(dir.as_ref())?,
+            next_chunk: 0,
+            blocks: None,
+            range: None,
+            cipher: load_session_cipher(dir.as_ref())?,
+            pending: VecDeque::new(),
+        })
+    }
+
+    // Only the records with start <= timestamp <= end. Uses the block index
+    // to skip frames outside the range; sessions without one are scanned.
+    pub fn open_range(dir: impl AsRef<Path>, start: f64, end: f64) -> Result<Self> {
+        let mut reader = Self::open(&dir)?;
+        reader.range = Some((start, end));
+        if let Ok(index) = read_block_index(&dir) {
+            reader.blocks = Some(
+                index
+                    .into_iter()
+                    .filter(|b| b.last_timestamp >= start && b.first_timestamp <= end)
+                    .collect(),
+            );
+        }
+        Ok(reader)
+    }
+
+    fn load_next_chunk(&mut self) -> Result<bool> {
+        while self.next_chunk < self.chunks.len() {
+            let path = &self.chunks[self.next_chunk];
+            self.next_chunk += 1;
+            let buf = fs::read(path)?;
+            let (chunk_index, header_len) = match read_chunk_header(&buf) {
+                Ok((header, len)) => (header.chunk_index, len),
+                Err(_) => continue,
+            };
+            let (frames, _) = scan_frames(&buf, header_len);
+            let mut records = Vec::new();
+            for frame in &frames {
+                let position = (frame.offset - header_len) as u64;
+                decode_frame(
+                    frame,
+                    chunk_index,
+                    position,
+                    self.cipher.as_ref(),
+                    &mut records,
+                )?;
+            }
+            if self.push_in_range(records) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn load_next_block(&mut self) -> Result<bool> {
+        while let Some(entry) = self.blocks.as_mut().and_then(|b| b.pop_front()) {
+            let records = read_indexed_block(&self.dir, &entry, self.cipher.as_ref())?;
+            if self.push_in_range(records) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn push_in_range(&mut self, records: Vec<Record>) -> bool {
+        let before = self.pending.len();
+        match self.range {
+            Some((start, end)) => self.pending.extend(
+                records
+                    .into_iter()
+                    .filter(|r| (start..=end).contains(&r.timestamp())),
+            ),
+            None => self.pending.extend(records),
+        }
+        self.pending.len() > before
+    }
+}
+
+impl Iterator for SessionReader {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pending.is_empty() {
+            let loaded = if self.blocks.is_some() {
+                self.load_next_block()
+            } else {
+                self.load_next_chunk()
+            };
+            match loaded {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+pub fn read_session_records(dir: impl AsRef<Path>) -> Result<Vec<Record>> {
+    SessionReader::open(dir)?.collect()
+}
+
+pub(crate) fn record_packet(channel: i32, data: &[u8]) {
+    let mut recorder = RECORDER.lock().unwrap();
+    if let Some(rec) = recorder.as_mut() {
+        if let Err(e) = rec.write_packet(channel as u8, data) {
+            warn!("[REC] Failed to record packet: {:?}", e);
+        }
+    }
+}
+
+// Marker from the processing pipeline, dropped when no recording is running
+pub(crate) fn record_marker(value: f64, label: &str) {
+    record_marker_at(now(), value, label);
+}
+
+// Marker stamped with the time of the event it describes rather than now
+pub(crate) fn record_marker_at(timestamp: f64, value: f64, label: &str) {
+    let mut recorder = RECORDER.lock().unwrap();
+    if let Some(rec) = recorder.as_mut() {
+        if let Err(e) = rec.write_marker_at(timestamp, value, label) {
+            warn!("[REC] Failed to record marker: {:?}", e);
+        }
+    }
+}
+
+#[frb]
+pub fn start_recording(session_dir: String, metadata: SessionMetadata) -> Result<String> {
+    let mut recorder = RECORDER
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Failed to lock RECORDER mutex"))?;
+    if recorder.is_some() {
+        bail!("A recording is already running");
+    }
+    let config = RecorderConfig {
+        key: session_crypto::active_key(),
+        ..RecorderConfig::default()
+    };
+    let rec = SessionRecorder::create(&session_dir, config, metadata)?;
+    *recorder = Some(rec);
+    Ok(session_dir)
+}
+
+#[frb]
+pub fn stop_recording() -> Result<SessionManifest> {
+    let mut recorder = RECORDER
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Failed to lock RECORDER mutex"))?;
+    let rec = recorder.take().context("No recording running")?;
+    rec.finish()
+}
+
+#[frb]
+pub fn insert_recording_marker(value: f64, label: String) -> Result<()> {
+    let mut recorder = RECORDER
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Failed to lock RECORDER mutex"))?;
+    let rec = recorder.as_mut().context("No recording running")?;
+    rec.write_marker(value, &label)
+}
+
+#[frb]
+pub fn is_recording() -> bool {
+    RECORDER.lock().map(|r| r.is_some()).unwrap_or(false)
+}
+
+// Call on app start: finalizes every session under `root` that was cut off
+// (app killed, crash, battery) and returns their manifests.
+#[frb]
+pub fn recover_unfinished_sessions(root: String) -> Result<Vec<SessionManifest>> {
+    let root = Path::new(&root);
+    if !root.exists() {
+        return Ok(Vec::new());
+    }
+    let active = RECORDER
+        .lock()
+        .ok()
+        .and_then(|r| r.as_ref().map(|rec| rec.dir().to_path_buf()));
+
+    let mut recovered = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let dir = entry?.path();
+        if !dir.is_dir() || Some(&dir) == active.as_ref() || !is_unfinished_session(&dir) {
+            continue;
+        }
+        match recover_session(&dir) {
+            Ok(manifest) => recovered.push(manifest),
+            Err(e) => warn!("[REC] Recovery of {} failed: {:?}", dir.display(), e),
+        }
+    }
+    Ok(recovered)
+}
+
+// BrainFlow's write_file layout: one line per sample, values as "%lf" joined
+// by tabs
+fn write_board_rows(out: &mut impl Write, rows: &[Vec<f64>], width: usize) -> std::io::Result<()> {
+    for row in rows {
+        for ch in 0..width {
+            if ch > 0 {
+                out.write_all(b"\t")?;
+            }
+            match row.get(ch).copied().unwrap_or(0.0) {
+                value if value.is_nan() => out.write_all(b"nan")?,
+                value => write!(out, "{:.6}", value)?,
+            }
+        }
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+// Writes all sample records of a session in BrainFlow's text format. The
+// rows go straight from memory to file_name, sealed for encrypted sessions,
+// so no plaintext copy is ever written.
+#[frb]
+pub fn export_board_data(session_dir: String, file_name: String) -> Result<usize> {
+    let metadata = session_metadata::read_session_metadata(session_dir.clone())?;
+    // Fails before anything is written when the session's key isn't loaded
+    let rows: Vec<Vec<f64>> = read_session_records(&session_dir)?
+        .into_iter()
+        .filter_map(|r| match r {
+            Record::Samples { values, .. } => Some(values),
+            _ => None,
+        })
+        .collect();
+    let Some(width) = rows.first().map(|r| r.len()) else {
+        bail!("Session has no board data");
+    };
+    let mut out = session_crypto::export_writer(&file_name, &metadata)?;
+    write_board_rows(&mut out, &rows, width)?;
+    out.finish()?;
+    Ok(rows.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::muse_types::{MuseModel, PipelineSettings};
+
+    fn temp_session(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "muse_rec_{}_{}_{}",
+            name,
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn metadata() -> SessionMetadata {
+        let mut metadata = SessionMetadata::new(MuseModel::MuseS, PipelineSettings::default());
+        metadata.created_at = 0.0;
+        metadata
+    }
+
+    fn packet(i: u8) -> Record {
+        Record::Packet {
+            timestamp: i as f64,
+            channel: i % 4,
+            data: vec![i; 20],
+        }
+    }
+
+    #[test]
+    fn board_rows_use_brainflow_text_layout() {
+        let rows = vec![vec![1.0, f64::NAN], vec![-5.0, 3.1234567]];
+        let mut out = Vec::new();
+        write_board_rows(&mut out, &rows, 2).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "1.000000\tnan\n-5.000000\t3.123457\n"
+        );
+    }
+
+    #[test]
+    fn records_roundtrip_through_rotated_chunks() {
+        let dir = temp_session("roundtrip");
+        let config = RecorderConfig {
+            chunk_max_bytes: 256,
+            frame_max_bytes: 64,
+            flush_interval_ms: u64::MAX,
+            ..RecorderConfig::default()
+        };
+        let mut rec = SessionRecorder::create(&dir, config, metadata()).unwrap();
+        for i in 0..50 {
+            rec.write(&packet(i)).unwrap();
+        }
+        rec.write_marker(7.0, "eyes closed").unwrap();
+        // Too long for the u16 body length, refused without losing the rest
+        assert!(rec.write_marker(8.0, &"x".repeat(70_000)).is_err());
+        let manifest = rec.finish().unwrap();
+
+        assert!(manifest.chunks.len() > 1);
+        assert_eq!(manifest.record_count, 51);
+        assert_eq!(manifest.markers.len(), 1);
+        assert!(!manifest.recovered);
+        let records = read_session_records(&dir).unwrap();
+        assert_eq!(records[..50], (0..50).map(packet).collect::<Vec<_>>()[..]);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn range_reader_uses_block_index() {
+        for compress in [true, false] {
+            let dir = temp_session("range");
+            let config = RecorderConfig {
+                frame_max_bytes: 256,
+                flush_interval_ms: u64::MAX,
+                compress,
+                ..RecorderConfig::default()
+            };
+            let mut rec = SessionRecorder::create(&dir, config, metadata()).unwrap();
+            for i in 0..200 {
+                rec.write(&packet(i)).unwrap();
+            }
+            rec.finish().unwrap();
+
+            let index = read_block_index(&dir).unwrap();
+            assert!(index.len() > 5);
+            assert_eq!(index.iter().map(|b| b.records).sum::<u32>(), 200);
+            let records: Vec<Record> = SessionReader::open_range(&dir, 50.0, 60.0)
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap();
+            assert_eq!(records, (50..=60).map(packet).collect::<Vec<_>>());
+            fs::remove_dir_all(&dir).ok();
+        }
+    }
+
+    #[test]
+    fn killed_session_is_repaired_on_recovery() {
+        let dir = temp_session("recovery");
+        let mut rec = SessionRecorder::create(&dir, RecorderConfig::default(), metadata()).unwrap();
+        for i in 0..10 {
+            rec.write(&packet(i)).unwrap();
+        }
+        rec.write_marker(1.0, "start").unwrap();
+        rec.write(&packet(10)).unwrap();
+        // Simulate a kill: the last record never gets flushed and a frame is half written
+        drop(rec);
+        let chunk = dir.join(chunk_file_name(0));
+        let mut file = OpenOptions::new().append(true).open(&chunk).unwrap();
+        // Intact frame whose payload doesn't decode: recovery stops before it
+        let garbage = [1u8, 2, 3];
+        let mut frame = vec![FRAME_MAGIC, 0];
+        frame.extend_from_slice(&(garbage.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&garbage).to_le_bytes());
+        frame.extend_from_slice(&garbage);
+        file.write_all(&frame).unwrap();
+        file.write_all(&[FRAME_MAGIC, 0, 200, 0, 0, 0, 1, 2])
+            .unwrap();
+        drop(file);
+        fs::remove_file(dir.join(session_metadata::METADATA_FILE)).unwrap();
+
+        assert!(is_unfinished_session(&dir));
+        let manifest = recover_session(&dir).unwrap();
+        assert!(manifest.recovered);
+        assert_eq!(manifest.record_count, 11);
+        assert_eq!(manifest.markers[0].label, "start");
+        assert_eq!(
+            fs::metadata(&chunk).unwrap().len(),
+            manifest.chunks[0].bytes
+        );
+        assert!(!is_unfinished_session(&dir));
+        let restored = session_metadata::read_session_metadata(dir.display().to_string());
+        assert_eq!(restored.unwrap(), metadata());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,461 @@
+use crate::recording::{
+    self, is_unfinished_session, Record, RecorderConfig, SessionManifest, SessionReader,
+    SessionRecorder,
+};
+use crate::session_crypto;
+use crate::session_metadata::{self, SessionEdit, SessionMetadata};
+use anyhow::{bail, Context, Result};
+use flutter_rust_bridge::frb;
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Cutting, stripping and merging of finished recordings. Every operation
+// writes a new session (the source is never modified, except by trim_session)
+// and appends a SessionEdit to the metadata so the provenance stays visible.
+// Offsets are seconds relative to the first record of the source session.
+
+pub const GAP_MARKER_LABEL: &str = "recording_gap";
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
+
+struct SourceSession {
+    dir: PathBuf,
+    metadata: SessionMetadata,
+    manifest: SessionManifest,
+}
+
+fn open_source(dir: &str) -> Result<SourceSession> {
+    let path = PathBuf::from(dir);
+    if is_unfinished_session(&path) {
+        bail!("Session {} is unfinished, recover it before editing", dir);
+    }
+    Ok(SourceSession {
+        metadata: session_metadata::read_session_metadata(dir.to_string())?,
+        manifest: recording::read_session_manifest(&path)?,
+        dir: path,
+    })
+}
+
+impl SourceSession {
+    fn start(&self) -> f64 {
+        self.manifest
+            .first_timestamp
+            .unwrap_or(self.manifest.started_at)
+    }
+
+    fn name(&self) -> String {
+        self.dir.display().to_string()
+    }
+}
+
+fn edit(operation: &str, sources: Vec<String>) -> SessionEdit {
+    SessionEdit {
+        operation: operation.to_string(),
+        sources,
+        start_time: None,
+        end_time: None,
+        removed_channels: Vec::new(),
+        created_at: now(),
+    }
+}
+
+// Outputs of an encrypted source are encrypted with the same user key
+fn output_config(metadata: &SessionMetadata) -> Result<RecorderConfig> {
+    Ok(RecorderConfig {
+        key: session_crypto::session_key(metadata)?,
+        ..RecorderConfig::default()
+    })
+}
+
+// Copies the records accepted by `keep` from `reader` into a new session
+fn copy_filtered(
+    reader: SessionReader,
+    output_dir: &str,
+    metadata: SessionMetadata,
+    mut keep: impl FnMut(&Record) -> bool,
+) -> Result<SessionManifest> {
+    let mut out = SessionRecorder::create(output_dir, output_config(&metadata)?, metadata)?;
+    for record in reader {
+        let record = record?;
+        if keep(&record) {
+            out.write(&record)?;
+        }
+    }
+    out.finish()
+}
+
+fn extract_absolute(
+    source: &SourceSession,
+    output_dir: &str,
+    start: f64,
+    end: f64,
+    operation: &str,
+) -> Result<SessionManifest> {
+    if end <= start {
+        bail!("Empty time range [{}, {}]", start, end);
+    }
+    let mut metadata = source.metadata.clone();
+    let mut step = edit(operation, vec![source.name()]);
+    step.start_time = Some(start);
+    step.end_time = Some(end);
+    metadata.edits.push(step);
+
+    let reader = SessionReader::open_range(&source.dir, start, end)?;
+    let manifest = copy_filtered(reader, output_dir, metadata, |_| true)?;
+    info!(
+        "[EDIT] Extracted {:.1}s..{:.1}s of {} into {} ({} records)",
+        start - source.start(),
+        end - source.start(),
+        source.name(),
+        output_dir,
+        manifest.record_count
+    );
+    Ok(manifest)
+}
+
+#[frb]
+pub fn extract_time_range(
+    session_dir: String,
+    output_dir: String,
+    start_offset: f64,
+    end_offset: f64,
+) -> Result<SessionManifest> {
+    let source = open_source(&session_dir)?;
+    let start = source.start();
+    extract_absolute(
+        &source,
+        &output_dir,
+        start + start_offset,
+        start + end_offset,
+        "extract_time_range",
+    )
+}
+
+// Cuts from the first marker with `start_marker` to the next one with `end_marker`
+#[frb]
+pub fn extract_between_markers(
+    session_dir: String,
+    output_dir: String,
+    start_marker: f64,
+    end_marker: f64,
+) -> Result<SessionManifest> {
+    let source = open_source(&session_dir)?;
+    let markers = &source.manifest.markers;
+    let start = markers
+        .iter()
+        .find(|m| m.value == start_marker)
+        .with_context(|| format!("No marker {} in session", start_marker))?
+        .timestamp;
+    let end = markers
+        .iter()
+        .find(|m| m.value == end_marker && m.timestamp > start)
+        .with_context(|| format!("No marker {} after marker {}", end_marker, start_marker))?
+        .timestamp;
+    extract_absolute(&source, &output_dir, start, end, "extract_between_markers")
+}
+
+// Writes one session per occurrence of `marker_value`, covering
+// [marker - pre_seconds, marker + post_seconds], into output_root/epoch_NNN
+#[frb]
+pub fn extract_marker_epochs(
+    session_dir: String,
+    output_root: String,
+    marker_value: f64,
+    pre_seconds: f64,
+    post_seconds: f64,
+) -> Result<Vec<SessionManifest>> {
+    let source = open_source(&session_dir)?;
+    let onsets: Vec<f64> = source
+        .manifest
+        .markers
+        .iter()
+        .filter(|m| m.value == marker_value)
+        .map(|m| m.timestamp)
+        .collect();
+    if onsets.is_empty() {
+        bail!("No marker {} in session", marker_value);
+    }
+
+    let mut manifests = Vec::with_capacity(onsets.len());
+    for (i, onset) in onsets.iter().enumerate() {
+        let dir = Path::new(&output_root).join(format!("epoch_{:03}", i));
+        manifests.push(extract_absolute(
+            &source,
+            &dir.display().to_string(),
+            onset - pre_seconds,
+            onset + post_seconds,
+            "extract_marker_epoch",
+        )?);
+    }
+    Ok(manifests)
+}
+
+// Drops every packet of the given BLE channel indices (see parse_muse_packet);
+// stripped EEG channels leave the metadata channel list, which the exports
+// follow
+#[frb]
+pub fn strip_channels(
+    session_dir: String,
+    output_dir: String,
+    channels: Vec<u8>,
+) -> Result<SessionManifest> {
+    let source = open_source(&session_dir)?;
+    let mut metadata = source.metadata.clone();
+    let mut step = edit("strip_channels", vec![source.name()]);
+    step.removed_channels = channels.clone();
+    metadata.edits.push(step);
+    let names = metadata.device.model.eeg_channel_names();
+    let removed: Vec<&String> = channels
+        .iter()
+        .filter_map(|&c| names.get(c as usize))
+        .collect();
+    metadata
+        .sampling
+        .eeg_channels
+        .retain(|name| !removed.contains(&name));
+
+    copy_filtered(
+        SessionReader::open(&source.dir)?,
+        &output_dir,
+        metadata,
+        |r| match r {
+            Record::Packet { channel, .. } => !channels.contains(channel),
+            _ => true,
+        },
+    )
+}
+
+// Concatenates recordings of one subject in time order. Timestamps stay
+// absolute; every join gets a gap marker whose value is the gap in seconds.
+#[frb]
+pub fn merge_sessions(session_dirs: Vec<String>, output_dir: String) -> Result<SessionManifest> {
+    let mut sources = session_dirs
+        .iter()
+        .map(|d| open_source(d))
+        .collect::<Result<Vec<_>>>()?;
+    if sources.is_empty() {
+        bail!("Nothing to merge");
+    }
+    sources.sort_by(|a, b| a.start().total_cmp(&b.start()));
+
+    let first = &sources[0];
+    for other in &sources[1..] {
+        if other.metadata.device.model != first.metadata.device.model {
+            bail!(
+                "Cannot merge {:?} and {:?} recordings",
+                first.metadata.device.model,
+                other.metadata.device.model
+            );
+        }
+        // Sampling rate, bands or IAF changing mid-session would go unnoticed
+        // by every consumer of the merged recording
+        if other.metadata.pipeline != first.metadata.pipeline {
+            bail!(
+                "Cannot merge {} and {}, their pipeline settings differ",
+                first.name(),
+                other.name()
+            );
+        }
+        if let (Some(a), Some(b)) = (&first.metadata.subject_id, &other.metadata.subject_id) {
+            if a != b {
+                bail!("Cannot merge recordings of subjects {} and {}", a, b);
+            }
+        }
+    }
+
+    let mut metadata = first.metadata.clone();
+    for other in &sources[1..] {
+        if metadata.subject_id.is_none() {
+            metadata.subject_id = other.metadata.subject_id.clone();
+        }
+        for tag in &other.metadata.session_tags {
+            if !metadata.session_tags.contains(tag) {
+                metadata.session_tags.push(tag.clone());
+            }
+        }
+        if !other.metadata.notes.is_empty() {
+            if !metadata.notes.is_empty() {
+                metadata.notes.push('\n');
+            }
+            metadata.notes.push_str(&other.metadata.notes);
+        }
+    }
+    metadata
+        .edits
+        .push(edit("merge", sources.iter().map(|s| s.name()).collect()));
+
+    let mut out = SessionRecorder::create(&output_dir, output_config(&metadata)?, metadata)?;
+    let mut previous_end: Option<f64> = None;
+    for source in &sources {
+        if let Some(end) = previous_end {
+            out.write(&Record::Marker {
+                timestamp: source.start(),
+                value: source.start() - end,
+                label: GAP_MARKER_LABEL.to_string(),
+            })?;
+        }
+        for record in SessionReader::open(&source.dir)? {
+            out.write(&record?)?;
+        }
+        previous_end = source.manifest.last_timestamp.or(previous_end);
+    }
+    out.finish()
+}
+
+// In-place variant of extract_time_range
+#[frb]
+pub fn trim_session(
+    session_dir: String,
+    start_offset: f64,
+    end_offset: f64,
+) -> Result<SessionManifest> {
+    let dir = PathBuf::from(&session_dir);
+    // Appended rather than with_extension, which would cut dotted names
+    let trimmed = PathBuf::from(format!("{}.trim", dir.display()));
+    let backup = PathBuf::from(format!("{}.untrimmed", dir.display()));
+    fs::remove_dir_all(&trimmed).ok();
+
+    let manifest = extract_time_range(
+        session_dir.clone(),
+        trimmed.display().to_string(),
+        start_offset,
+        end_offset,
+    )?;
+    fs::rename(&dir, &backup)?;
+    fs::rename(&trimmed, &dir)?;
+    fs::remove_dir_all(&backup)?;
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::muse_types::{MuseModel, PipelineSettings};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("muse_edit_{}_{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn write_session(dir: &Path, start: f64, seconds: usize, subject: &str) {
+        let mut metadata = SessionMetadata::new(MuseModel::MuseS, PipelineSettings::default());
+        metadata.subject_id = Some(subject.to_string());
+        let mut rec = SessionRecorder::create(dir, RecorderConfig::default(), metadata).unwrap();
+        for i in 0..seconds {
+            let timestamp = start + i as f64;
+            rec.write(&Record::Packet {
+                timestamp,
+                channel: (i % 6) as u8,
+                data: vec![0; 20],
+            })
+            .unwrap();
+            if i == seconds / 2 {
+                rec.write(&Record::Marker {
+                    timestamp,
+                    value: 1.0,
+                    label: "middle".to_string(),
+                })
+                .unwrap();
+            }
+        }
+        rec.finish().unwrap();
+    }
+
+    #[test]
+    fn extract_keeps_range_and_markers() {
+        let root = temp_dir("extract");
+        let src = root.join("src");
+        write_session(&src, 1000.0, 120, "s01");
+
+        let out = root.join("cut");
+        let manifest = extract_time_range(
+            src.display().to_string(),
+            out.display().to_string(),
+            50.0,
+            70.0,
+        )
+        .unwrap();
+        assert_eq!(manifest.first_timestamp, Some(1050.0));
+        assert_eq!(manifest.last_timestamp, Some(1070.0));
+        assert_eq!(manifest.record_count, 22); // 21 packets + marker at 60s
+        assert_eq!(manifest.markers[0].label, "middle");
+
+        let metadata = session_metadata::read_session_metadata(out.display().to_string()).unwrap();
+        assert_eq!(metadata.edits[0].operation, "extract_time_range");
+
+        let stripped = root.join("stripped");
+        strip_channels(
+            src.display().to_string(),
+            stripped.display().to_string(),
+            vec![1],
+        )
+        .unwrap();
+        let metadata =
+            session_metadata::read_session_metadata(stripped.display().to_string()).unwrap();
+        assert_eq!(
+            metadata.sampling.eeg_channels,
+            ["TP9", "AF8", "TP10", "AUX"]
+        );
+
+        // Trimming in place keeps a dotted directory name whole
+        let dotted = root.join("session.v1");
+        write_session(&dotted, 1000.0, 20, "s01");
+        let manifest = trim_session(dotted.display().to_string(), 5.0, 10.0).unwrap();
+        assert_eq!(manifest.first_timestamp, Some(1005.0));
+        assert!(dotted.join("session.json").exists());
+        assert!(!root.join("session.trim").exists());
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn merge_orders_sessions_and_marks_gaps() {
+        let root = temp_dir("merge");
+        let (a, b) = (root.join("a"), root.join("b"));
+        write_session(&a, 5000.0, 10, "s01");
+        write_session(&b, 1000.0, 10, "s01");
+
+        let out = root.join("merged");
+        let manifest = merge_sessions(
+            vec![a.display().to_string(), b.display().to_string()],
+            out.display().to_string(),
+        )
+        .unwrap();
+        assert_eq!(manifest.first_timestamp, Some(1000.0));
+        let gap = manifest
+            .markers
+            .iter()
+            .find(|m| m.label == GAP_MARKER_LABEL)
+            .unwrap();
+        assert_eq!(gap.timestamp, 5000.0);
+        assert_eq!(gap.value, 5000.0 - 1009.0);
+
+        let c = root.join("c");
+        write_session(&c, 9000.0, 10, "s02");
+        assert!(merge_sessions(
+            vec![a.display().to_string(), c.display().to_string()],
+            root.join("bad").display().to_string(),
+        )
+        .is_err());
+
+        let d = root.join("d");
+        write_session(&d, 9000.0, 10, "s01");
+        let mut metadata =
+            session_metadata::read_session_metadata(d.display().to_string()).unwrap();
+        metadata.pipeline.eeg_sampling_rate = 512;
+        session_metadata::write_metadata(&d, &metadata).unwrap();
+        assert!(merge_sessions(
+            vec![a.display().to_string(), d.display().to_string()],
+            root.join("bad_rate").display().to_string(),
+        )
+        .is_err());
+        fs::remove_dir_all(&root).ok();
+    }
+}
@@ -0,0 +1,218 @@
+use crate::muse_parser;
+use crate::muse_types::{MuseModel, MuseProcessedData};
+use crate::recording::{self, Record, SessionReader};
+use crate::session_metadata::{self, SessionMetadata};
+use anyhow::{Context, Result};
+use flutter_rust_bridge::frb;
+use log::info;
+use std::path::Path;
+use std::sync::Mutex;
+
+// Replays a recorded session through the live parser. The parser is
+// re-initialized with the model and pipeline settings from the session
+// metadata, so results match what was computed while recording.
+
+static REPLAY: Mutex<Option<ReplaySession>> = Mutex::new(None);
+
+struct ReplaySession {
+    dir: String,
+    start_time: f64,
+    metadata: SessionMetadata,
+    reader: SessionReader,
+    records_done: u64,
+    records_total: u64,
+}
+
+#[frb]
+pub fn open_replay(session_dir: String) -> Result<SessionMetadata> {
+    let metadata = session_metadata::read_session_metadata(session_dir.clone())?;
+    let manifest = recording::read_session_manifest(&session_dir).ok();
+    let records_total = manifest.as_ref().map(|m| m.record_count).unwrap_or(0);
+    let start_time = manifest.and_then(|m| m.first_timestamp).unwrap_or(0.0);
+    let reader = SessionReader::open(&session_dir)?;
+
+    muse_parser::init_muse_parser_with_settings(metadata.device.model, metadata.pipeline.clone());
+    info!(
+        "[REPLAY] Opened {} ({:?}, {} records)",
+        session_dir, metadata.device.model, records_total
+    );
+
+    let mut replay = REPLAY
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Failed to lock REPLAY mutex"))?;
+    *replay = Some(ReplaySession {
+        dir: session_dir,
+        start_time,
+        metadata: metadata.clone(),
+        reader,
+        records_done: 0,
+        records_total,
+    });
+    Ok(metadata)
+}
+
+// Feeds up to `max_records` recorded packets through the parser. An empty
+// result with replay_finished() == true means the end of the session.
+#[frb]
+pub fn replay_next(max_records: u32) -> Result<Vec<MuseProcessedData>> {
+    let mut replay = REPLAY
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Failed to lock REPLAY mutex"))?;
+    let session = replay.as_mut().context("No replay open")?;
+
+    let mut results = Vec::new();
+    for _ in 0..max_records {
+        let Some(record) = session.reader.next() else {
+            break;
+        };
+        session.records_done += 1;
+        if let Record::Packet {
+            timestamp,
+            channel,
+            data,
+        } = record?
+        {
+            let mut parsed = muse_parser::process_muse_packet(channel as i32, &data, timestamp);
+            for p in &mut parsed {
+                p.timestamp = timestamp;
+            }
+            results.append(&mut parsed);
+        }
+    }
+    Ok(results)
+}
+
+// Jumps to `offset_seconds` after the first record. Only the frames from
+// there on are decoded (via the block index). Parser buffers are reset.
+#[frb]
+pub fn replay_seek(offset_seconds: f64) -> Result<()> {
+    let mut replay = REPLAY
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Failed to lock REPLAY mutex"))?;
+    let session = replay.as_mut().context("No replay open")?;
+
+    let target = session.start_time + offset_seconds.max(0.0);
+    session.reader = SessionReader::open_range(&session.dir, target, f64::INFINITY)?;
+    session.records_done = recording::read_block_index(&session.dir)
+        .map(|index| {
+            index
+                .iter()
+                .filter(|b| b.last_timestamp < target)
+                .map(|b| b.records as u64)
+                .sum()
+        })
+        .unwrap_or(0);
+    muse_parser::init_muse_parser_with_settings(
+        session.metadata.device.model,
+        session.metadata.pipeline.clone(),
+    );
+    info!("[REPLAY] Seeked to {:.1}s", offset_seconds);
+    Ok(())
+}
+
+#[frb]
+pub fn replay_progress() -> f64 {
+    let replay = REPLAY.lock().unwrap();
+    match replay.as_ref() {
+        Some(s) if s.records_total > 0 => (s.records_done as f64 / s.records_total as f64).min(1.0),
+        _ => 0.0,
+    }
+}
+
+#[frb]
+pub fn replay_metadata() -> Option<SessionMetadata> {
+    let replay = REPLAY.lock().unwrap();
+    replay.as_ref().map(|s| s.metadata.clone())
+}
+
+#[frb]
+pub fn close_replay() {
+    let mut replay = REPLAY.lock().unwrap();
+    *replay = None;
+}
+
+pub(crate) struct DecodedEeg {
+    pub start_time: f64,
+    pub channels: Vec<Vec<f64>>,
+}
+
+// Decodes the EEG packets of a session into one continuous sample stream
+// per channel (in microvolts), for exports and offline analysis. Packets
+// dropped over BLE are detected from the package number and filled with
+// linearly interpolated samples, so sample indices stay aligned with time.
+pub(crate) fn decode_eeg_channels(session_dir: &Path, model: MuseModel) -> Result<DecodedEeg> {
+    let channel_count = model.channel_count();
+    let resolution = model.resolution();
+    let mut start_time = None;
+    let mut channels = vec![Vec::new(); channel_count];
+    let mut last_package = vec![None; channel_count];
+    for record in SessionReader::open(session_dir)? {
+        if let Record::Packet {
+            timestamp,
+            channel,
+            data,
+        } = record?
+        {
+            let channel = channel as usize;
+            if channel < channel_count && data.len() == muse_parser::MUSE_PACKET_LEN {
+                start_time.get_or_insert(timestamp);
+                let package_num = u16::from_be_bytes([data[0], data[1]]);
+                let samples = muse_parser::parse_eeg_samples(&data[2..], resolution);
+                append_packet(
+                    &mut channels[channel],
+                    &mut last_package[channel],
+                    package_num,
+                    &samples,
+                );
+            }
+        }
+    }
+    Ok(DecodedEeg {
+        start_time: start_time.unwrap_or(0.0),
+        channels,
+    })
+}
+
+// Longer gaps in the package numbers are taken as a counter reset (e.g. a
+// reconnect) rather than dropped packets, ~10 s at 256 Hz
+const MAX_DROPPED_PACKETS: u16 = 213;
+
+fn append_packet(
+    channel: &mut Vec<f64>,
+    last_package: &mut Option<u16>,
+    package_num: u16,
+    samples: &[f64],
+) {
+    let dropped = last_package
+        .map(|last| package_num.wrapping_sub(last).wrapping_sub(1))
+        .filter(|dropped| *dropped <= MAX_DROPPED_PACKETS)
+        .unwrap_or(0) as usize;
+    if let (Some(&before), Some(&after)) = (channel.last(), samples.first()) {
+        let missing = dropped * samples.len();
+        channel.extend(
+            (1..=missing).map(|i| before + (after - before) * i as f64 / (missing + 1) as f64),
+        );
+    }
+    channel.extend_from_slice(samples);
+    *last_package = Some(package_num);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_packets_are_interpolated() {
+        let mut channel = Vec::new();
+        let mut last = None;
+        append_packet(&mut channel, &mut last, u16::MAX, &[0.0, 0.0]);
+        // Packets 0 and 1 lost across the counter wrap
+        append_packet(&mut channel, &mut last, 2, &[5.0, 5.0]);
+        assert_eq!(channel, vec![0.0, 0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 5.0]);
+
+        // A duplicate or a reset counter appends without filling
+        append_packet(&mut channel, &mut last, 2, &[6.0]);
+        append_packet(&mut channel, &mut last, 1000, &[7.0]);
+        assert_eq!(channel.len(), 10);
+    }
+}
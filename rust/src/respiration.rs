@@ -0,0 +1,349 @@
+use crate::heart_rate::{PpgBuffer, PPG_SAMPLING_RATE};
+use crate::hrv::{band_power, lomb_scargle, FREQUENCY_STEP};
+use flutter_rust_bridge::frb;
+
+// Breathing rate and phase from PPG respiratory modulation and head motion.
+//
+// Breathing modulates the PPG in three ways, each sampled once per beat:
+// baseline (RIIV, the pulse peak level), amplitude (RIAV, peak to preceding
+// trough) and frequency (RIFV, the inter-beat interval, i.e. sinus
+// arrhythmia). The accelerometer adds a fourth view: the small head movement
+// with every breath, averaged into 4 Hz bins per axis. Each series gets a
+// Lomb-Scargle periodogram over 4-40 breaths/min; the peak share of the band
+// power is its quality. Estimates that agree with the best one are averaged
+// by quality ("smart fusion"), and the phase comes from a sinusoid fit of the
+// best source at the fused rate.
+
+const RESPIRATION_BAND: (f64, f64) = (0.067, 0.67);
+const PEAK_HALF_WIDTH: f64 = 0.02;
+const MIN_WINDOW_SECONDS: f64 = 20.0;
+const MIN_BEATS: usize = 12;
+// Estimates below this quality are ignored, ones within AGREEMENT_BPM of
+// the best are fused
+const MIN_QUALITY: f64 = 0.25;
+const AGREEMENT_BPM: f64 = 3.0;
+const ACCEL_BIN_SECONDS: f64 = 0.25;
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RespirationSource {
+    PpgBaseline,
+    PpgAmplitude,
+    PpgFrequency,
+    Accelerometer,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RespirationEstimate {
+    pub source: RespirationSource,
+    pub rate_bpm: f64,
+    // Share of the respiration band power around the peak, 0-1
+    pub quality: f64,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RespirationResult {
+    pub rate_bpm: Option<f64>,
+    // Breath cycle position at `timestamp` in radians (0..2π), 0 at the
+    // maximum of the source's modulation; advance it with rate_bpm between
+    // updates for smooth paced-breathing feedback
+    pub phase: Option<f64>,
+    pub confidence: f64,
+    pub sources: Vec<RespirationEstimate>,
+    pub timestamp: f64,
+}
+
+struct Series {
+    source: RespirationSource,
+    times: Vec<f64>,
+    values: Vec<f64>,
+}
+
+// Dominant breathing frequency (Hz) of a series and its quality
+fn dominant_frequency(times: &[f64], values: &[f64]) -> Option<(f64, f64)> {
+    if times.len() < 8 {
+        return None;
+    }
+    let frequencies: Vec<f64> = (0..)
+        .map(|i| RESPIRATION_BAND.0 + i as f64 * FREQUENCY_STEP)
+        .take_while(|f| *f < RESPIRATION_BAND.1)
+        .collect();
+    let power = lomb_scargle(times, values, &frequencies);
+    let (peak, _) = frequencies
+        .iter()
+        .zip(&power)
+        .max_by(|a, b| a.1.total_cmp(b.1))?;
+    let total = band_power(&frequencies, &power, RESPIRATION_BAND);
+    if total <= 0.0 {
+        return None;
+    }
+    let around = band_power(
+        &frequencies,
+        &power,
+        (peak - PEAK_HALF_WIDTH, peak + PEAK_HALF_WIDTH),
+    );
+    Some((*peak, around / total))
+}
+
+// Phase at `at` of the least squares sinusoid with frequency `f`
+fn phase_at(times: &[f64], values: &[f64], f: f64, at: f64) -> f64 {
+    let w = 2.0 * std::f64::consts::PI * f;
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let (mut cc, mut ss, mut cs, mut yc, mut ys) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for (t, v) in times.iter().zip(values) {
+        let (sin, cos) = (w * t).sin_cos();
+        cc += cos * cos;
+        ss += sin * sin;
+        cs += cos * sin;
+        yc += (v - mean) * cos;
+        ys += (v - mean) * sin;
+    }
+    let det = cc * ss - cs * cs;
+    let a = (ss * yc - cs * ys) / det;
+    let b = (cc * ys - cs * yc) / det;
+    // a cos(wt) + b sin(wt) = A cos(wt - φ)
+    (w * at - b.atan2(a)).rem_euclid(2.0 * std::f64::consts::PI)
+}
+
+// RIIV / RIAV / RIFV series from beat times and the raw IR window ending at
+// `ir_end`; times relative to `origin`
+fn ppg_series(beats: &[f64], ir: &[f64], ir_end: f64, origin: f64) -> Vec<Series> {
+    let fs = PPG_SAMPLING_RATE as f64;
+    let indexed: Vec<(f64, usize)> = beats
+        .iter()
+        .filter_map(|t| {
+            let back = ((ir_end - t) * fs).round();
+            (back >= 0.0 && (back as usize) < ir.len()).then(|| (*t, ir.len() - 1 - back as usize))
+        })
+        .collect();
+
+    let mut baseline = Series {
+        source: RespirationSource::PpgBaseline,
+        times: Vec::new(),
+        values: Vec::new(),
+    };
+    let mut amplitude = Series {
+        source: RespirationSource::PpgAmplitude,
+        times: Vec::new(),
+        values: Vec::new(),
+    };
+    let mut frequency = Series {
+        source: RespirationSource::PpgFrequency,
+        times: Vec::new(),
+        values: Vec::new(),
+    };
+    for w in indexed.windows(2) {
+        let ((t0, i0), (t1, i1)) = (w[0], w[1]);
+        if i1 <= i0 {
+            continue;
+        }
+        let trough = ir[i0..i1].iter().copied().fold(f64::MAX, f64::min);
+        let t = t1 - origin;
+        baseline.times.push(t);
+        baseline.values.push(ir[i1]);
+        amplitude.times.push(t);
+        amplitude.values.push(ir[i1] - trough);
+        frequency.times.push(t);
+        frequency.values.push(t1 - t0);
+    }
+    vec![baseline, amplitude, frequency]
+}
+
+// Accelerometer axes averaged into bins, times relative to `origin`
+fn accel_series(accel: &[(f64, [f64; 3])], origin: f64) -> Vec<Series> {
+    let mut bins: Vec<(f64, [f64; 3], usize)> = Vec::new();
+    for (t, xyz) in accel {
+        let bin = ((t - origin) / ACCEL_BIN_SECONDS).floor();
+        match bins.last_mut() {
+            Some((b, sum, n)) if *b == bin => {
+                for axis in 0..3 {
+                    sum[axis] += xyz[axis];
+                }
+                *n += 1;
+            }
+            _ => bins.push((bin, *xyz, 1)),
+        }
+    }
+    (0..3)
+        .map(|axis| Series {
+            source: RespirationSource::Accelerometer,
+            times: bins
+                .iter()
+                .map(|(b, _, _)| (b + 0.5) * ACCEL_BIN_SECONDS)
+                .collect(),
+            values: bins.iter().map(|(_, s, n)| s[axis] / *n as f64).collect(),
+        })
+        .collect()
+}
+
+// Fused estimate from beats (absolute times), the raw IR window ending at
+// `ir_end` and timestamped accelerometer samples
+pub(crate) fn estimate_respiration(
+    beats: &[f64],
+    ir: &[f64],
+    ir_end: f64,
+    accel: &[(f64, [f64; 3])],
+) -> RespirationResult {
+    let origin = ir_end - ir.len() as f64 / PPG_SAMPLING_RATE as f64;
+    let mut result = RespirationResult {
+        rate_bpm: None,
+        phase: None,
+        confidence: 0.0,
+        sources: Vec::new(),
+        timestamp: ir_end,
+    };
+
+    let mut series = Vec::new();
+    if beats.len() >= MIN_BEATS && ir_end - beats[0] >= MIN_WINDOW_SECONDS {
+        series.extend(ppg_series(beats, ir, ir_end, origin));
+    }
+    let accel: Vec<(f64, [f64; 3])> = accel
+        .iter()
+        .copied()
+        .filter(|(t, _)| *t >= origin)
+        .collect();
+    if accel
+        .first()
+        .is_some_and(|(t, _)| ir_end - t >= MIN_WINDOW_SECONDS)
+    {
+        // Only the axis with the clearest breathing peak
+        let best_axis = accel_series(&accel, origin)
+            .into_iter()
+            .filter_map(|s| dominant_frequency(&s.times, &s.values).map(|d| (s, d)))
+            .max_by(|a, b| a.1 .1.total_cmp(&b.1 .1));
+        series.extend(best_axis.map(|(s, _)| s));
+    }
+
+    let mut estimates: Vec<(RespirationEstimate, &Series)> = series
+        .iter()
+        .filter_map(|s| {
+            let (f, quality) = dominant_frequency(&s.times, &s.values)?;
+            Some((
+                RespirationEstimate {
+                    source: s.source,
+                    rate_bpm: f * 60.0,
+                    quality,
+                },
+                s,
+            ))
+        })
+        .collect();
+    estimates.sort_by(|a, b| b.0.quality.total_cmp(&a.0.quality));
+    result.sources = estimates.iter().map(|(e, _)| e.clone()).collect();
+
+    let valid: Vec<&(RespirationEstimate, &Series)> = estimates
+        .iter()
+        .filter(|(e, _)| e.quality >= MIN_QUALITY)
+        .collect();
+    let Some((best, best_series)) = valid.first() else {
+        return result;
+    };
+    let agreeing: Vec<&RespirationEstimate> = valid
+        .iter()
+        .map(|(e, _)| e)
+        .filter(|e| (e.rate_bpm - best.rate_bpm).abs() <= AGREEMENT_BPM)
+        .collect();
+    let weight: f64 = agreeing.iter().map(|e| e.quality).sum();
+    let rate = agreeing.iter().map(|e| e.rate_bpm * e.quality).sum::<f64>() / weight;
+
+    result.rate_bpm = Some(rate);
+    result.phase = Some(phase_at(
+        &best_series.times,
+        &best_series.values,
+        rate / 60.0,
+        ir_end - origin,
+    ));
+    result.confidence = best.quality * agreeing.len() as f64 / valid.len() as f64;
+    result
+}
+
+pub(crate) struct RespirationTracker {
+    latest: Option<RespirationResult>,
+    fresh: bool,
+}
+
+impl RespirationTracker {
+    pub fn new() -> Self {
+        Self {
+            latest: None,
+            fresh: false,
+        }
+    }
+
+    // Call with the beat and accelerometer history after each heart rate update
+    pub fn update(&mut self, ppg: &PpgBuffer, beats: &[f64], accel: &[(f64, [f64; 3])]) {
+        let ir: Vec<f64> = ppg.channels[0].iter().copied().collect();
+        if ir.is_empty() {
+            return;
+        }
+        self.latest = Some(estimate_respiration(beats, &ir, ppg.last_timestamp, accel));
+        self.fresh = true;
+    }
+
+    pub fn latest(&self) -> Option<&RespirationResult> {
+        self.latest.as_ref()
+    }
+
+    pub fn take_fresh(&mut self) -> Option<RespirationResult> {
+        if !self.fresh {
+            return None;
+        }
+        self.fresh = false;
+        self.latest.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn fuses_ppg_modulation_and_head_motion() {
+        // 30 s at 15 breaths/min (0.25 Hz): sinus arrhythmia, baseline and
+        // amplitude modulation of a 70 BPM pulse, and a slight head nod
+        let breath = |t: f64| (2.0 * PI * 0.25 * t).sin();
+        let end = 1000.0;
+        let start = end - 30.0;
+        let mut beats = vec![start + 0.2];
+        while *beats.last().unwrap() < end - 0.5 {
+            let t = *beats.last().unwrap();
+            beats.push(t + 60.0 / 70.0 + 0.04 * breath(t));
+        }
+        let mut ir = vec![0.0; 30 * PPG_SAMPLING_RATE];
+        for (i, v) in ir.iter_mut().enumerate() {
+            let t = start + (i + 1) as f64 / PPG_SAMPLING_RATE as f64;
+            let last_beat = beats
+                .iter()
+                .rev()
+                .find(|b| **b <= t)
+                .copied()
+                .unwrap_or(start);
+            let pulse = (-(t - last_beat) * 6.0).exp();
+            *v = 100_000.0 + 300.0 * breath(t) + (1000.0 + 150.0 * breath(t)) * pulse;
+        }
+        let accel: Vec<(f64, [f64; 3])> = (0..30 * 52)
+            .map(|i| {
+                let t = start + i as f64 / 52.0;
+                (t, [0.01 * breath(t), 0.0, 1.0])
+            })
+            .collect();
+
+        let result = estimate_respiration(&beats, &ir, end, &accel);
+        let rate = result.rate_bpm.unwrap();
+        assert!((rate - 15.0).abs() < 1.0, "rate {}", rate);
+        assert!(result.confidence > 0.5);
+        assert_eq!(result.sources.len(), 4);
+        assert!(result
+            .sources
+            .iter()
+            .any(|s| s.source == RespirationSource::Accelerometer));
+
+        // Nothing to fuse without enough signal
+        let empty = estimate_respiration(&[], &ir[..64], end, &[]);
+        assert_eq!(empty.rate_bpm, None);
+        assert_eq!(empty.confidence, 0.0);
+    }
+}
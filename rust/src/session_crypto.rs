@@ -0,0 +1,662 @@
+use crate::recording;
+use crate::session_metadata::{self, SessionMetadata};
+use anyhow::{bail, Context, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use flutter_rust_bridge::frb;
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::Zeroize;
+
+// Encryption at rest for recordings.
+//
+// Every session gets a random data key. Frames are sealed one at a time with
+// XChaCha20-Poly1305 under that key, so memory stays bounded by the frame
+// size. The data key is stored in the metadata sidecar, wrapped with the
+// user/app key, which is only ever referenced by its fingerprint (key_id).
+// Rotating the user key re-wraps the data key; the frames are not touched.
+//
+// Subject, tags and notes in metadata.json and the chunk headers, and the
+// marker list of the manifest, are sealed with the data key as well.
+//
+// Exports of encrypted sessions are written as sealed files (MENC): the
+// plain export stream cut into 64 KiB blocks, each sealed on its own.
+
+pub const ENCRYPTION_ALGORITHM: &str = "XChaCha20-Poly1305";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const SEALED_MAGIC: &[u8; 4] = b"MENC";
+const SEALED_VERSION: u16 = 1;
+const SEALED_BLOCK: usize = 64 * 1024;
+const DETAILS_AAD: &[u8] = b"session-details";
+
+static KEYRING: Mutex<Option<KeyRing>> = Mutex::new(None);
+
+struct KeyRing {
+    keys: Vec<RecordingKey>,
+    active: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct RecordingKey {
+    id: String,
+    bytes: [u8; KEY_LEN],
+}
+
+impl RecordingKey {
+    pub fn new(bytes: &[u8]) -> Result<Self> {
+        let Ok(key) = <[u8; KEY_LEN]>::try_from(bytes) else {
+            bail!(
+                "Recording key must be {} bytes, got {}",
+                KEY_LEN,
+                bytes.len()
+            );
+        };
+        let digest = Sha256::new()
+            .chain_update(b"muse-recording-key\0")
+            .chain_update(key)
+            .finalize();
+        Ok(Self {
+            id: hex(&digest[..8]),
+            bytes: key,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.bytes.into())
+    }
+}
+
+impl Drop for RecordingKey {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+    }
+}
+
+// Never print key material
+impl std::fmt::Debug for RecordingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RecordingKey({})", self.id)
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub key_id: String,
+    pub nonce: String,
+    pub ciphertext: String,
+    pub created_at: f64,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyRotation {
+    pub from_key_id: String,
+    pub to_key_id: String,
+    pub rotated_at: f64,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptionInfo {
+    pub algorithm: String,
+    pub data_key: WrappedKey,
+    #[serde(default)]
+    pub rotations: Vec<KeyRotation>,
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(value: &str) -> Result<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        bail!("invalid hex string");
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).context("invalid hex string"))
+        .collect()
+}
+
+fn seal(cipher: &XChaCha20Poly1305, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let sealed = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("XChaCha20-Poly1305 encryption cannot fail for in-memory buffers");
+    let mut out = Vec::with_capacity(NONCE_LEN + sealed.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&sealed);
+    out
+}
+
+fn open(cipher: &XChaCha20Poly1305, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < NONCE_LEN {
+        bail!("truncated ciphertext");
+    }
+    let (nonce, sealed) = data.split_at(NONCE_LEN);
+    cipher
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: sealed, aad })
+        .map_err(|_| anyhow::anyhow!("Decryption failed (wrong key or tampered data)"))
+}
+
+// Per-session (or per-export) data key
+pub(crate) struct FrameCipher(XChaCha20Poly1305);
+
+impl FrameCipher {
+    pub(crate) fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        seal(&self.0, aad, plaintext)
+    }
+
+    pub(crate) fn open(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        open(&self.0, aad, data)
+    }
+
+    // Hex of the sealed JSON of `value`, for fields of the JSON sidecars
+    pub(crate) fn seal_json<T: Serialize>(&self, aad: &[u8], value: &T) -> Result<String> {
+        Ok(hex(&self.seal(aad, &serde_json::to_vec(value)?)))
+    }
+
+    pub(crate) fn open_json<T: DeserializeOwned>(&self, aad: &[u8], sealed: &str) -> Result<T> {
+        Ok(serde_json::from_slice(&self.open(aad, &unhex(sealed)?)?)?)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionDetails {
+    subject_id: Option<String>,
+    session_tags: Vec<String>,
+    notes: String,
+}
+
+// `metadata` as written to disk: the subject, tags and notes of an encrypted
+// session move into sealed_details
+pub(crate) fn seal_details(
+    metadata: &SessionMetadata,
+    cipher: &FrameCipher,
+) -> Result<SessionMetadata> {
+    let mut stored = metadata.clone();
+    let details = SessionDetails {
+        subject_id: stored.subject_id.take(),
+        session_tags: std::mem::take(&mut stored.session_tags),
+        notes: std::mem::take(&mut stored.notes),
+    };
+    stored.sealed_details = Some(cipher.seal_json(DETAILS_AAD, &details)?);
+    Ok(stored)
+}
+
+// Like seal_details with the session's own data key; metadata that is
+// plain or already sealed is kept as it is
+pub(crate) fn stored_metadata(metadata: &SessionMetadata) -> Result<SessionMetadata> {
+    let has_details = metadata.subject_id.is_some()
+        || !metadata.session_tags.is_empty()
+        || !metadata.notes.is_empty();
+    match &metadata.encryption {
+        Some(info) if has_details => seal_details(metadata, &session_cipher(info)?),
+        _ => Ok(metadata.clone()),
+    }
+}
+
+// Restores the sealed subject, tags and notes once the session key is
+// loaded; without it they stay sealed
+pub(crate) fn open_details(metadata: &mut SessionMetadata) -> Result<()> {
+    let (Some(sealed), Some(info)) = (&metadata.sealed_details, &metadata.encryption) else {
+        return Ok(());
+    };
+    let Ok(cipher) = session_cipher(info) else {
+        return Ok(());
+    };
+    let details: SessionDetails = cipher.open_json(DETAILS_AAD, sealed)?;
+    metadata.subject_id = details.subject_id;
+    metadata.session_tags = details.session_tags;
+    metadata.notes = details.notes;
+    metadata.sealed_details = None;
+    Ok(())
+}
+
+fn wrap_data_key(key: &RecordingKey, data_key: &[u8]) -> WrappedKey {
+    let sealed = seal(&key.cipher(), key.id.as_bytes(), data_key);
+    WrappedKey {
+        key_id: key.id.clone(),
+        nonce: hex(&sealed[..NONCE_LEN]),
+        ciphertext: hex(&sealed[NONCE_LEN..]),
+        created_at: now(),
+    }
+}
+
+fn unwrap_data_key(key: &RecordingKey, wrapped: &WrappedKey) -> Result<Vec<u8>> {
+    let mut sealed = unhex(&wrapped.nonce)?;
+    sealed.extend(unhex(&wrapped.ciphertext)?);
+    open(&key.cipher(), key.id.as_bytes(), &sealed)
+}
+
+fn new_data_key(key: &RecordingKey) -> (WrappedKey, FrameCipher) {
+    let mut data_key = XChaCha20Poly1305::generate_key(&mut OsRng);
+    let wrapped = wrap_data_key(key, &data_key);
+    let cipher = FrameCipher(XChaCha20Poly1305::new(&data_key));
+    data_key.zeroize();
+    (wrapped, cipher)
+}
+
+fn open_data_key(wrapped: &WrappedKey) -> Result<FrameCipher> {
+    let key = loaded_key(&wrapped.key_id)?;
+    let mut data_key = unwrap_data_key(&key, wrapped)?;
+    let cipher = XChaCha20Poly1305::new_from_slice(&data_key)
+        .map_err(|_| anyhow::anyhow!("Invalid data key length"))?;
+    data_key.zeroize();
+    Ok(FrameCipher(cipher))
+}
+
+fn loaded_key(key_id: &str) -> Result<RecordingKey> {
+    let keyring = KEYRING
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Failed to lock KEYRING mutex"))?;
+    keyring
+        .as_ref()
+        .and_then(|k| k.keys.iter().find(|k| k.id == key_id).cloned())
+        .with_context(|| format!("Encrypted with key {}, which is not loaded", key_id))
+}
+
+pub(crate) fn new_session_encryption(key: &RecordingKey) -> (EncryptionInfo, FrameCipher) {
+    let (data_key, cipher) = new_data_key(key);
+    let info = EncryptionInfo {
+        algorithm: ENCRYPTION_ALGORITHM.to_string(),
+        data_key,
+        rotations: Vec::new(),
+    };
+    (info, cipher)
+}
+
+pub(crate) fn session_cipher(info: &EncryptionInfo) -> Result<FrameCipher> {
+    if info.algorithm != ENCRYPTION_ALGORITHM {
+        bail!("Unsupported encryption algorithm {}", info.algorithm);
+    }
+    open_data_key(&info.data_key)
+}
+
+// The user key a session is wrapped with, so edits and exports of an
+// encrypted session stay encrypted under the same key.
+pub(crate) fn session_key(metadata: &SessionMetadata) -> Result<Option<RecordingKey>> {
+    metadata
+        .encryption
+        .as_ref()
+        .map(|info| loaded_key(&info.data_key.key_id))
+        .transpose()
+}
+
+pub(crate) fn active_key() -> Option<RecordingKey> {
+    let keyring = KEYRING.lock().ok()?;
+    let keyring = keyring.as_ref()?;
+    let active = keyring.active.as_ref()?;
+    keyring.keys.iter().find(|k| &k.id == active).cloned()
+}
+
+fn add_key(key: RecordingKey, make_active: bool) -> Result<String> {
+    let mut keyring = KEYRING
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Failed to lock KEYRING mutex"))?;
+    let keyring = keyring.get_or_insert_with(|| KeyRing {
+        keys: Vec::new(),
+        active: None,
+    });
+    let id = key.id.clone();
+    if !keyring.keys.iter().any(|k| k.id == id) {
+        keyring.keys.push(key);
+    }
+    if make_active {
+        keyring.active = Some(id.clone());
+    }
+    Ok(id)
+}
+
+// Sealed output file for exports; plain when no key is given
+pub(crate) enum ExportWriter {
+    Plain(BufWriter<File>),
+    Sealed {
+        out: BufWriter<File>,
+        cipher: FrameCipher,
+        buf: Vec<u8>,
+        block: u64,
+    },
+}
+
+fn sealed_block_aad(block: u64, last: bool) -> [u8; 9] {
+    let mut aad = [0u8; 9];
+    aad[..8].copy_from_slice(&block.to_le_bytes());
+    aad[8] = last as u8;
+    aad
+}
+
+impl ExportWriter {
+    pub(crate) fn create(path: &str, key: Option<&RecordingKey>) -> Result<Self> {
+        let mut out = BufWriter::new(
+            File::create(path).with_context(|| format!("Failed to create {}", path))?,
+        );
+        let Some(key) = key else {
+            return Ok(ExportWriter::Plain(out));
+        };
+        let (wrapped, cipher) = new_data_key(key);
+        let json = serde_json::to_vec(&wrapped)?;
+        out.write_all(SEALED_MAGIC)?;
+        out.write_all(&SEALED_VERSION.to_le_bytes())?;
+        out.write_all(&(json.len() as u32).to_le_bytes())?;
+        out.write_all(&json)?;
+        Ok(ExportWriter::Sealed {
+            out,
+            cipher,
+            buf: Vec::with_capacity(SEALED_BLOCK),
+            block: 0,
+        })
+    }
+
+    fn seal_blocks(&mut self, last: bool) -> std::io::Result<()> {
+        if let ExportWriter::Sealed {
+            out,
+            cipher,
+            buf,
+            block,
+        } = self
+        {
+            while buf.len() >= SEALED_BLOCK || last {
+                let take = buf.len().min(SEALED_BLOCK);
+                let is_last = last && take == buf.len();
+                let sealed = cipher.seal(&sealed_block_aad(*block, is_last), &buf[..take]);
+                out.write_all(&[is_last as u8])?;
+                out.write_all(&(sealed.len() as u32).to_le_bytes())?;
+                out.write_all(&sealed)?;
+                buf.drain(..take);
+                *block += 1;
+                if is_last {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<()> {
+        self.seal_blocks(true)?;
+        let out = match &mut self {
+            ExportWriter::Plain(out) | ExportWriter::Sealed { out, .. } => out,
+        };
+        out.flush()?;
+        out.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+impl Write for ExportWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ExportWriter::Plain(out) => out.write(data),
+            ExportWriter::Sealed { buf, .. } => {
+                buf.extend_from_slice(data);
+                self.seal_blocks(false)?;
+                Ok(data.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // Sealed blocks are only written when full or on finish()
+        match self {
+            ExportWriter::Plain(out) => out.flush(),
+            ExportWriter::Sealed { .. } => Ok(()),
+        }
+    }
+}
+
+// Key for the export of `metadata`'s session: encrypted sessions give sealed exports
+pub(crate) fn export_writer(path: &str, metadata: &SessionMetadata) -> Result<ExportWriter> {
+    ExportWriter::create(path, session_key(metadata)?.as_ref())
+}
+
+// Makes `key` (32 bytes) the key for new recordings and adds it to the keyring.
+// Returns its key_id.
+#[frb]
+pub fn set_recording_key(key: Vec<u8>) -> Result<String> {
+    let id = add_key(RecordingKey::new(&key)?, true)?;
+    info!("[CRYPTO] Recording key {} active", id);
+    Ok(id)
+}
+
+// Adds a key that is only used to open existing sessions (e.g. before rotation)
+#[frb]
+pub fn add_decryption_key(key: Vec<u8>) -> Result<String> {
+    add_key(RecordingKey::new(&key)?, false)
+}
+
+#[frb]
+pub fn active_recording_key_id() -> Option<String> {
+    active_key().map(|k| k.id.clone())
+}
+
+// Forgets all keys; new recordings are unencrypted until set_recording_key
+#[frb]
+pub fn clear_recording_keys() {
+    let mut keyring = KEYRING.lock().unwrap();
+    *keyring = None;
+}
+
+// Re-wraps the session's data key with `new_key` and records the rotation in
+// the metadata sidecar. The current key must be loaded.
+#[frb]
+pub fn rotate_session_key(session_dir: String, new_key: Vec<u8>) -> Result<SessionMetadata> {
+    let dir = Path::new(&session_dir);
+    if recording::is_unfinished_session(dir) {
+        bail!("Session {} is unfinished, recover it first", session_dir);
+    }
+    let mut metadata = session_metadata::read_session_metadata(session_dir.clone())?;
+    let info = metadata
+        .encryption
+        .as_mut()
+        .context("Session is not encrypted")?;
+
+    let old_key = loaded_key(&info.data_key.key_id)?;
+    let new_key = RecordingKey::new(&new_key)?;
+    let mut data_key = unwrap_data_key(&old_key, &info.data_key)?;
+    info.data_key = wrap_data_key(&new_key, &data_key);
+    data_key.zeroize();
+    info.rotations.push(KeyRotation {
+        from_key_id: old_key.id.clone(),
+        to_key_id: new_key.id.clone(),
+        rotated_at: now(),
+    });
+
+    // The details are resealed on write, which opens the data key with the new key
+    add_key(new_key.clone(), false)?;
+    // Chunk headers carry a metadata copy, the old wrapping must not survive there
+    recording::replace_chunk_metadata(dir, &metadata)?;
+    session_metadata::write_metadata(dir, &metadata)?;
+    info!(
+        "[CRYPTO] Rotated {} from key {} to {}",
+        session_dir,
+        old_key.id,
+        metadata.encryption.as_ref().unwrap().data_key.key_id
+    );
+    Ok(metadata)
+}
+
+// Decrypts a sealed export (EDF/XDF/CSV of an encrypted session) for sharing
+#[frb]
+pub fn decrypt_export_file(input_file: String, output_file: String) -> Result<u64> {
+    let mut input = BufReader::new(
+        File::open(&input_file).with_context(|| format!("Failed to open {}", input_file))?,
+    );
+    let mut header = [0u8; 10];
+    input.read_exact(&mut header)?;
+    if &header[..4] != SEALED_MAGIC {
+        bail!("{} is not a sealed export", input_file);
+    }
+    let version = u16::from_le_bytes(header[4..6].try_into()?);
+    if version > SEALED_VERSION {
+        bail!("Unsupported sealed export version {}", version);
+    }
+    let mut json = vec![0u8; u32::from_le_bytes(header[6..10].try_into()?) as usize];
+    input.read_exact(&mut json)?;
+    let cipher = open_data_key(&serde_json::from_slice(&json)?)?;
+
+    let tmp = format!("{}.tmp", output_file);
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    let mut written = 0u64;
+    let mut block = 0u64;
+    loop {
+        let mut block_header = [0u8; 5];
+        input
+            .read_exact(&mut block_header)
+            .context("Sealed export is truncated")?;
+        let last = block_header[0] != 0;
+        let mut sealed = vec![0u8; u32::from_le_bytes(block_header[1..5].try_into()?) as usize];
+        input.read_exact(&mut sealed)?;
+        let plain = cipher.open(&sealed_block_aad(block, last), &sealed)?;
+        out.write_all(&plain)?;
+        written += plain.len() as u64;
+        block += 1;
+        if last {
+            break;
+        }
+    }
+    out.flush()?;
+    drop(out);
+    fs::rename(&tmp, &output_file)?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::muse_types::{MuseModel, PipelineSettings};
+    use crate::recording::{read_session_records, Record, RecorderConfig, SessionRecorder};
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("muse_crypto_{}_{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn record(dir: &Path, compress: bool, key: &RecordingKey) -> Vec<Record> {
+        let config = RecorderConfig {
+            frame_max_bytes: 512,
+            compress,
+            key: Some(key.clone()),
+            ..RecorderConfig::default()
+        };
+        let mut metadata = SessionMetadata::new(MuseModel::MuseS, PipelineSettings::default());
+        metadata.subject_id = Some("participant-secret".to_string());
+        metadata.notes = "participant-secret".to_string();
+        let mut rec = SessionRecorder::create(dir, config, metadata).unwrap();
+        let mut records = Vec::new();
+        for i in 0..100u8 {
+            let r = Record::Packet {
+                timestamp: 1000.0 + i as f64,
+                channel: i % 4,
+                data: vec![i; 20],
+            };
+            rec.write(&r).unwrap();
+            records.push(r);
+        }
+        rec.write(&Record::Marker {
+            timestamp: 1100.0,
+            value: 1.0,
+            label: "participant-secret".to_string(),
+        })
+        .unwrap();
+        rec.finish().unwrap();
+        records
+    }
+
+    fn assert_no_plaintext(dir: &Path) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let bytes = fs::read(&path).unwrap();
+            assert!(
+                !bytes
+                    .windows(b"participant-secret".len())
+                    .any(|w| w == b"participant-secret"),
+                "{} leaks the secret",
+                path.display()
+            );
+        }
+    }
+
+    // One test so the shared keyring is not cleared under a parallel test
+    #[test]
+    fn encrypted_sessions_roundtrip_and_rotate() {
+        let root = temp_dir("roundtrip");
+        let key = RecordingKey::new(&[7u8; 32]).unwrap();
+        let new_key = [9u8; 32];
+        set_recording_key(vec![7u8; 32]).unwrap();
+
+        for compress in [true, false] {
+            let dir = root.join(format!("compress_{}", compress));
+            let expected = record(&dir, compress, &key);
+            assert_no_plaintext(&dir);
+            assert_eq!(read_session_records(&dir).unwrap()[..100], expected[..]);
+            let manifest = recording::read_session_manifest(&dir).unwrap();
+            assert_eq!(manifest.markers[0].label, "participant-secret");
+
+            let rotated = rotate_session_key(dir.display().to_string(), new_key.to_vec()).unwrap();
+            let info = rotated.encryption.unwrap();
+            assert_eq!(info.rotations.len(), 1);
+            assert_eq!(info.rotations[0].from_key_id, key.id());
+            assert_no_plaintext(&dir);
+
+            // Only the new key is needed now
+            clear_recording_keys();
+            assert!(read_session_records(&dir).is_err());
+            let locked = session_metadata::read_session_metadata(dir.display().to_string());
+            assert_eq!(locked.unwrap().subject_id, None);
+            add_decryption_key(new_key.to_vec()).unwrap();
+            assert_eq!(read_session_records(&dir).unwrap().len(), 101);
+            let metadata = session_metadata::read_session_metadata(dir.display().to_string());
+            assert_eq!(
+                metadata.unwrap().subject_id.as_deref(),
+                Some("participant-secret")
+            );
+            let range: Vec<Record> = recording::SessionReader::open_range(&dir, 1010.0, 1019.0)
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap();
+            assert_eq!(range, expected[10..20]);
+            set_recording_key(vec![7u8; 32]).unwrap();
+        }
+
+        // Sealed export stream survives block boundaries
+        let sealed = root.join("export.sealed");
+        let plain: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut writer = ExportWriter::create(sealed.to_str().unwrap(), Some(&key)).unwrap();
+        writer.write_all(&plain).unwrap();
+        writer.finish().unwrap();
+        let out = root.join("export.plain");
+        decrypt_export_file(sealed.display().to_string(), out.display().to_string()).unwrap();
+        assert_eq!(fs::read(&out).unwrap(), plain);
+        clear_recording_keys();
+        fs::remove_dir_all(&root).ok();
+    }
+}
@@ -0,0 +1,191 @@
+use crate::api;
+use crate::muse_types::{MuseModel, PipelineSettings};
+use crate::session_crypto::{self, EncryptionInfo};
+use anyhow::{bail, Context, Result};
+use flutter_rust_bridge::frb;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Versioned context document stored next to every recording (metadata.json)
+// and embedded in chunk and export headers. Bump the schema version when a
+// field changes meaning; new optional fields must use #[serde(default)].
+
+pub const METADATA_SCHEMA_VERSION: u32 = 1;
+pub(crate) const METADATA_FILE: &str = "metadata.json";
+
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub model: MuseModel,
+    pub name: Option<String>,
+    pub mac_address: Option<String>,
+    pub serial_number: Option<String>,
+    pub firmware_version: Option<String>,
+    pub hardware_version: Option<String>,
+    pub preset: Option<String>,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SamplingInfo {
+    pub eeg_channels: Vec<String>,
+    pub eeg_sampling_rate: f64,
+    pub eeg_resolution_bits: u32,
+    pub ppg_sampling_rate: Option<f64>,
+    pub imu_sampling_rate: f64,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SoftwareInfo {
+    pub app_version: String,
+    pub brainflow_version: Option<String>,
+    pub recording_format_version: u16,
+}
+
+// One entry per cut/merge/strip applied to produce this session
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionEdit {
+    pub operation: String,
+    pub sources: Vec<String>,
+    pub start_time: Option<f64>,
+    pub end_time: Option<f64>,
+    pub removed_channels: Vec<u8>,
+    pub created_at: f64,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionMetadata {
+    pub schema_version: u32,
+    pub created_at: f64,
+    pub device: DeviceInfo,
+    pub sampling: SamplingInfo,
+    pub pipeline: PipelineSettings,
+    pub software: SoftwareInfo,
+    #[serde(default)]
+    pub subject_id: Option<String>,
+    #[serde(default)]
+    pub session_tags: Vec<String>,
+    #[serde(default)]
+    pub notes: String,
+    #[serde(default)]
+    pub edits: Vec<SessionEdit>,
+    // Wrapped data key and key rotation history, None for plain sessions
+    #[serde(default)]
+    pub encryption: Option<EncryptionInfo>,
+    // Subject, tags and notes of an encrypted session sealed with its data
+    // key; the plain fields above stay empty on disk
+    #[serde(default)]
+    pub sealed_details: Option<String>,
+}
+
+impl SessionMetadata {
+    pub fn new(model: MuseModel, pipeline: PipelineSettings) -> Self {
+        let resolution_bits = match model.resolution() {
+            crate::muse_types::EegResolution::Bits12 => 12,
+            crate::muse_types::EegResolution::Bits14 => 14,
+        };
+        Self {
+            schema_version: METADATA_SCHEMA_VERSION,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64(),
+            device: DeviceInfo {
+                model,
+                name: None,
+                mac_address: None,
+                serial_number: None,
+                firmware_version: None,
+                hardware_version: None,
+                preset: None,
+            },
+            sampling: SamplingInfo {
+                eeg_channels: model.eeg_channel_names(),
+                eeg_sampling_rate: pipeline.eeg_sampling_rate as f64,
+                eeg_resolution_bits: resolution_bits,
+                ppg_sampling_rate: model.has_ppg().then_some(64.0),
+                imu_sampling_rate: 52.0,
+            },
+            pipeline,
+            software: SoftwareInfo {
+                app_version: env!("CARGO_PKG_VERSION").to_string(),
+                brainflow_version: None,
+                recording_format_version: crate::recording::FORMAT_VERSION,
+            },
+            subject_id: None,
+            session_tags: Vec::new(),
+            notes: String::new(),
+            edits: Vec::new(),
+            encryption: None,
+            sealed_details: None,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        let version = value
+            .get("schema_version")
+            .and_then(|v| v.as_u64())
+            .context("Metadata has no schema_version")?;
+        if version > METADATA_SCHEMA_VERSION as u64 {
+            bail!(
+                "Metadata schema version {} is newer than supported {}",
+                version,
+                METADATA_SCHEMA_VERSION
+            );
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+pub(crate) fn write_metadata(dir: &Path, metadata: &SessionMetadata) -> Result<()> {
+    let stored = session_crypto::stored_metadata(metadata)?;
+    crate::recording::write_json_atomic(&dir.join(METADATA_FILE), &stored)
+}
+
+// Template for a new recording, filled from the running parser configuration.
+// Dart adds device details, tags and notes before passing it to start_recording.
+#[frb]
+pub fn new_session_metadata(model: MuseModel) -> SessionMetadata {
+    let mut metadata = SessionMetadata::new(model, crate::muse_parser::get_pipeline_settings());
+    metadata.software.brainflow_version = api::verify_brainflow_version().ok();
+    metadata
+}
+
+#[frb]
+pub fn read_session_metadata(session_dir: String) -> Result<SessionMetadata> {
+    let path = Path::new(&session_dir).join(METADATA_FILE);
+    let json =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut metadata = SessionMetadata::from_json(&json)?;
+    session_crypto::open_details(&mut metadata)?;
+    Ok(metadata)
+}
+
+// Tags and notes can still be edited after the recording is done
+#[frb]
+pub fn update_session_notes(
+    session_dir: String,
+    subject_id: Option<String>,
+    session_tags: Vec<String>,
+    notes: String,
+) -> Result<SessionMetadata> {
+    let mut metadata = read_session_metadata(session_dir.clone())?;
+    if metadata.sealed_details.is_some() {
+        bail!("Session details are sealed with a key that is not loaded");
+    }
+    metadata.subject_id = subject_id;
+    metadata.session_tags = session_tags;
+    metadata.notes = notes;
+    write_metadata(Path::new(&session_dir), &metadata)?;
+    Ok(metadata)
+}
@@ -0,0 +1,542 @@
+use crate::heart_rate::{extract_heart_rate, PPG_SAMPLING_RATE};
+use crate::muse_parser;
+use crate::muse_types::MUSE_GYRO_SCALE_FACTOR;
+use crate::recording::{Record, SessionReader};
+use crate::replay::{self, DecodedEeg};
+use crate::session_metadata;
+use crate::spectral;
+use anyhow::Result;
+use flutter_rust_bridge::frb;
+use log::info;
+use std::path::Path;
+
+// Sleep staging in 30 s epochs (Wake / N1 / N2 / N3 / REM).
+//
+// Features per epoch: relative band powers of the common mode of the frontal
+// channels AF7/AF8, an EOG-like index (slow power of AF7 - AF8, which eye
+// movements drive in opposite polarity, over the common mode), RMS head
+// rotation and heart rate.
+// Staging is rule based on features normalized against the whole night
+// (median / IQR), so it adapts to electrode contact and amplitude. Live
+// staging re-scores all epochs so far after each new epoch; the same code
+// runs offline over a recorded session.
+
+pub(crate) const EPOCH_SECONDS: f64 = 30.0;
+// Frontal channels of the Muse (TP9, AF7, AF8, TP10)
+pub(crate) const FRONTAL_CHANNELS: [usize; 2] = [1, 2];
+const WELCH_SECONDS: f64 = 2.0;
+
+// Fixed AASM bands, not anchored to the individual alpha frequency: the
+// staging rules are defined on them
+const TOTAL_BAND: (f64, f64) = (0.5, 30.0);
+const DELTA_BAND: (f64, f64) = (0.5, 4.0);
+const THETA_BAND: (f64, f64) = (4.0, 8.0);
+const ALPHA_BAND: (f64, f64) = (8.0, 12.0);
+const SIGMA_BAND: (f64, f64) = (12.0, 16.0);
+const BETA_BAND: (f64, f64) = (16.0, 30.0);
+const EOG_BAND: (f64, f64) = (0.5, 2.0);
+
+// RMS head rotation above which an epoch is scored Wake
+const MOVEMENT_WAKE_DPS: f64 = 5.0;
+// Relative delta power of slow wave sleep
+const N3_MIN_DELTA: f64 = 0.7;
+// Beats needed for an epoch heart rate
+const MIN_EPOCH_BEATS: usize = 10;
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepStage {
+    Wake,
+    N1,
+    N2,
+    N3,
+    Rem,
+}
+
+impl SleepStage {
+    pub fn is_sleep(&self) -> bool {
+        *self != SleepStage::Wake
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SleepEpochFeatures {
+    // Relative powers of TOTAL_BAND in the AF7/AF8 common mode
+    pub delta: f64,
+    pub theta: f64,
+    pub alpha: f64,
+    pub sigma: f64,
+    pub beta: f64,
+    // Slow power of AF7 - AF8 over slow power of the common mode
+    pub eog: f64,
+    // RMS gyro rate, deg/s
+    pub movement: f64,
+    pub heart_rate: Option<f64>,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SleepEpoch {
+    pub start: f64,
+    pub stage: SleepStage,
+    pub features: SleepEpochFeatures,
+}
+
+#[frb]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SleepSummary {
+    pub time_in_bed_min: f64,
+    pub total_sleep_time_min: f64,
+    // Total sleep time / time in bed, percent
+    pub sleep_efficiency: f64,
+    pub sleep_onset_latency_min: Option<f64>,
+    // Wake between sleep onset and the last sleep epoch
+    pub waso_min: f64,
+    pub awakenings: u32,
+    // Percent of time in bed
+    pub wake_pct: f64,
+    // Percent of total sleep time
+    pub n1_pct: f64,
+    pub n2_pct: f64,
+    pub n3_pct: f64,
+    pub rem_pct: f64,
+}
+
+#[frb]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Hypnogram {
+    pub epochs: Vec<SleepEpoch>,
+    pub summary: SleepSummary,
+}
+
+// Spectral features of one epoch of the two frontal channels (µV)
+pub(crate) fn epoch_features(
+    af7: &[f64],
+    af8: &[f64],
+    sampling_rate: f64,
+    movement: f64,
+    heart_rate: Option<f64>,
+) -> Option<SleepEpochFeatures> {
+    let nfft = ((WELCH_SECONDS * sampling_rate) as usize).next_power_of_two();
+    // Eye movements cancel in the common mode and dominate the difference
+    let common: Vec<f64> = af7.iter().zip(af8).map(|(a, b)| (a + b) / 2.0).collect();
+    let difference: Vec<f64> = af7.iter().zip(af8).map(|(a, b)| a - b).collect();
+    let common = spectral::welch(&common, sampling_rate, nfft)?;
+    let horizontal = spectral::welch(&difference, sampling_rate, nfft)?;
+
+    let total = common.band_power(TOTAL_BAND.0, TOTAL_BAND.1);
+    if total <= 0.0 {
+        return None;
+    }
+    let relative = [DELTA_BAND, THETA_BAND, ALPHA_BAND, SIGMA_BAND, BETA_BAND]
+        .map(|band| common.band_power(band.0, band.1) / total);
+    let eog = horizontal.band_power(EOG_BAND.0, EOG_BAND.1)
+        / common.band_power(EOG_BAND.0, EOG_BAND.1).max(f64::EPSILON);
+
+    let [delta, theta, alpha, sigma, beta] = relative;
+    Some(SleepEpochFeatures {
+        delta,
+        theta,
+        alpha,
+        sigma,
+        beta,
+        eog,
+        movement,
+        heart_rate,
+    })
+}
+
+// Rate from the median inter-beat interval of an epoch's beats
+pub(crate) fn epoch_heart_rate(beat_times: &[f64]) -> Option<f64> {
+    if beat_times.len() < MIN_EPOCH_BEATS {
+        return None;
+    }
+    let mut ibis: Vec<f64> = beat_times.windows(2).map(|w| w[1] - w[0]).collect();
+    ibis.sort_by(f64::total_cmp);
+    let median = ibis[ibis.len() / 2];
+    (median > 0.0).then(|| 60.0 / median)
+}
+
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let pos = p * (sorted.len() - 1) as f64;
+    let (low, high) = (pos.floor() as usize, pos.ceil() as usize);
+    sorted[low] + (sorted[high] - sorted[low]) * (pos - low as f64)
+}
+
+// Robust z-scores of one feature over the night
+fn robust_z(values: &[Option<f64>]) -> Vec<f64> {
+    let mut sorted: Vec<f64> = values.iter().flatten().copied().collect();
+    if sorted.is_empty() {
+        return vec![0.0; values.len()];
+    }
+    sorted.sort_by(f64::total_cmp);
+    let median = percentile(&sorted, 0.5);
+    let iqr = percentile(&sorted, 0.75) - percentile(&sorted, 0.25);
+    let scale = (iqr / 1.349).max(median.abs() * 0.05).max(1e-9);
+    values
+        .iter()
+        .map(|v| v.map(|v| (v - median) / scale).unwrap_or(0.0))
+        .collect()
+}
+
+// Stages every epoch of a night from its features
+pub(crate) fn classify(features: &[SleepEpochFeatures]) -> Vec<SleepStage> {
+    let column = |f: fn(&SleepEpochFeatures) -> Option<f64>| {
+        robust_z(&features.iter().map(f).collect::<Vec<_>>())
+    };
+    let delta = column(|f| Some(f.delta));
+    let alpha = column(|f| Some(f.alpha));
+    let sigma = column(|f| Some(f.sigma));
+    let eog = column(|f| Some(f.eog));
+    let heart_rate = column(|f| f.heart_rate);
+
+    let mut stages = Vec::with_capacity(features.len());
+    let mut asleep = false;
+    for (i, f) in features.iter().enumerate() {
+        // Wake: moving, alpha dominating theta (relaxed wake) or beta
+        // dominating both (eyes open); alpha dropping out under theta marks
+        // sleep onset
+        let awake = f.alpha > f.theta && alpha[i] > 0.5 || f.beta > f.alpha + f.theta;
+        let stage = if f.movement >= MOVEMENT_WAKE_DPS || awake {
+            SleepStage::Wake
+        } else if f.delta >= N3_MIN_DELTA && delta[i] > 0.5 {
+            SleepStage::N3
+        } else if asleep && eog[i] > 1.0 && sigma[i] < 0.5 && heart_rate[i] > -1.0 {
+            SleepStage::Rem
+        } else if sigma[i] > 0.5 || delta[i] > 1.0 {
+            SleepStage::N2
+        } else {
+            SleepStage::N1
+        };
+        asleep |= matches!(stage, SleepStage::N2 | SleepStage::N3);
+        stages.push(stage);
+    }
+
+    // A single epoch between two epochs of the same other stage follows them,
+    // unless it is Wake from movement
+    for i in 1..stages.len().saturating_sub(1) {
+        let (before, after) = (stages[i - 1], stages[i + 1]);
+        let moved = features[i].movement >= MOVEMENT_WAKE_DPS;
+        if before == after && stages[i] != before && !moved {
+            stages[i] = before;
+        }
+    }
+    stages
+}
+
+pub(crate) fn summarize(stages: &[SleepStage]) -> SleepSummary {
+    let epoch_min = EPOCH_SECONDS / 60.0;
+    let time_in_bed_min = stages.len() as f64 * epoch_min;
+    let onset = stages.iter().position(|s| s.is_sleep());
+    let last_sleep = stages.iter().rposition(|s| s.is_sleep());
+    let count = |stage: SleepStage| stages.iter().filter(|s| **s == stage).count() as f64;
+
+    let sleep_epochs = stages.iter().filter(|s| s.is_sleep()).count() as f64;
+    let total_sleep_time_min = sleep_epochs * epoch_min;
+    let (mut waso_epochs, mut awakenings) = (0.0, 0);
+    if let (Some(onset), Some(last)) = (onset, last_sleep) {
+        for i in onset..=last {
+            if stages[i] == SleepStage::Wake {
+                waso_epochs += 1.0;
+                if stages[i - 1].is_sleep() {
+                    awakenings += 1;
+                }
+            }
+        }
+    }
+    let pct = |n: f64, of: f64| if of > 0.0 { n / of * 100.0 } else { 0.0 };
+    SleepSummary {
+        time_in_bed_min,
+        total_sleep_time_min,
+        sleep_efficiency: pct(total_sleep_time_min, time_in_bed_min),
+        sleep_onset_latency_min: onset.map(|i| i as f64 * epoch_min),
+        waso_min: waso_epochs * epoch_min,
+        awakenings,
+        wake_pct: pct(count(SleepStage::Wake), stages.len() as f64),
+        n1_pct: pct(count(SleepStage::N1), sleep_epochs),
+        n2_pct: pct(count(SleepStage::N2), sleep_epochs),
+        n3_pct: pct(count(SleepStage::N3), sleep_epochs),
+        rem_pct: pct(count(SleepStage::Rem), sleep_epochs),
+    }
+}
+
+pub(crate) fn hypnogram(starts: &[f64], features: &[SleepEpochFeatures]) -> Hypnogram {
+    let stages = classify(features);
+    Hypnogram {
+        summary: summarize(&stages),
+        epochs: starts
+            .iter()
+            .zip(features)
+            .zip(stages)
+            .map(|((start, features), stage)| SleepEpoch {
+                start: *start,
+                stage,
+                features: features.clone(),
+            })
+            .collect(),
+    }
+}
+
+// Live staging: frontal EEG is collected into 30 s epochs, each closed
+// epoch is featurized with the IMU and beats of its time span
+pub(crate) struct SleepStager {
+    sampling_rate: f64,
+    eeg: [Vec<f64>; 2],
+    epoch_start: Option<f64>,
+    starts: Vec<f64>,
+    features: Vec<SleepEpochFeatures>,
+    hypnogram: Hypnogram,
+    fresh: bool,
+}
+
+impl SleepStager {
+    pub fn new(sampling_rate: f64) -> Self {
+        Self {
+            sampling_rate,
+            eeg: [Vec::new(), Vec::new()],
+            epoch_start: None,
+            starts: Vec::new(),
+            features: Vec::new(),
+            hypnogram: Hypnogram::default(),
+            fresh: false,
+        }
+    }
+
+    fn epoch_samples(&self) -> usize {
+        (EPOCH_SECONDS * self.sampling_rate) as usize
+    }
+
+    // Adds the samples of one frontal channel packet ending at `packet_time`
+    pub fn push(&mut self, channel: usize, samples: &[f64], packet_time: f64) {
+        let Some(index) = FRONTAL_CHANNELS.iter().position(|c| *c == channel) else {
+            return;
+        };
+        self.epoch_start
+            .get_or_insert(packet_time - samples.len() as f64 / self.sampling_rate);
+        self.eeg[index].extend_from_slice(samples);
+    }
+
+    // Start and end time of a complete epoch waiting to be closed
+    pub fn pending_epoch(&self) -> Option<(f64, f64)> {
+        let start = self.epoch_start?;
+        let n = self.epoch_samples();
+        (self.eeg.iter().all(|c| c.len() >= n)).then_some((start, start + EPOCH_SECONDS))
+    }
+
+    // Closes the pending epoch and re-stages the night; returns the AF7/AF8
+    // samples of the epoch
+    pub fn close_epoch(&mut self, movement: f64, heart_rate: Option<f64>) -> Option<[Vec<f64>; 2]> {
+        let (start, end) = self.pending_epoch()?;
+        let n = self.epoch_samples();
+        let [af7, af8] = &mut self.eeg;
+        let af7: Vec<f64> = af7.drain(..n).collect();
+        let af8: Vec<f64> = af8.drain(..n).collect();
+        self.epoch_start = Some(end);
+        if let Some(features) = epoch_features(&af7, &af8, self.sampling_rate, movement, heart_rate)
+        {
+            self.add_epoch(start, features);
+        }
+        Some([af7, af8])
+    }
+
+    fn add_epoch(&mut self, start: f64, features: SleepEpochFeatures) {
+        self.starts.push(start);
+        self.features.push(features);
+        self.hypnogram = hypnogram(&self.starts, &self.features);
+        self.fresh = true;
+        if let Some(epoch) = self.hypnogram.epochs.last() {
+            info!("[SLEEP] Epoch at {:.0}: {:?}", start, epoch.stage);
+        }
+    }
+
+    pub fn hypnogram(&self) -> &Hypnogram {
+        &self.hypnogram
+    }
+
+    // The newest epoch if it was closed since the last call
+    pub fn take_fresh(&mut self) -> Option<SleepEpoch> {
+        if !self.fresh {
+            return None;
+        }
+        self.fresh = false;
+        self.hypnogram.epochs.last().cloned()
+    }
+}
+
+// A recorded session staged offline, with the decoded EEG kept for the
+// event detectors
+pub(crate) struct StagedSession {
+    pub hypnogram: Hypnogram,
+    pub eeg: DecodedEeg,
+    pub sampling_rate: f64,
+    pub channel_names: Vec<String>,
+}
+
+pub(crate) fn stage_session(session_dir: &str) -> Result<StagedSession> {
+    let metadata = session_metadata::read_session_metadata(session_dir.to_string())?;
+    let model = metadata.device.model;
+    let sampling_rate = metadata.pipeline.eeg_sampling_rate as f64;
+    let dir = Path::new(session_dir);
+    let eeg = replay::decode_eeg_channels(dir, model)?;
+
+    // Gyro rates and IR PPG with their packet times; channel 6 is EEG on
+    // models with seven EEG channels
+    let mut gyro: Vec<(f64, f64)> = Vec::new();
+    let mut ir: Vec<(f64, Vec<f64>)> = Vec::new();
+    for record in SessionReader::open(dir)? {
+        if let Record::Packet {
+            timestamp,
+            channel,
+            data,
+        } = record?
+        {
+            if data.len() != 20 {
+                continue;
+            }
+            if channel == 6 && model.channel_count() <= 6 {
+                for xyz in muse_parser::parse_imu_samples(&data[2..], MUSE_GYRO_SCALE_FACTOR) {
+                    gyro.push((timestamp, xyz.iter().map(|v| v * v).sum()));
+                }
+            } else if channel == 7 && model.has_ppg() {
+                ir.push((timestamp, muse_parser::parse_ppg_samples(&data[2..])));
+            }
+        }
+    }
+
+    let n = (EPOCH_SECONDS * sampling_rate) as usize;
+    let [af7, af8] =
+        FRONTAL_CHANNELS.map(|c| eeg.channels.get(c).map(Vec::as_slice).unwrap_or_default());
+    let epochs = af7.len().min(af8.len()) / n;
+    let (mut starts, mut features) = (Vec::new(), Vec::new());
+    for i in 0..epochs {
+        let start = eeg.start_time + i as f64 * EPOCH_SECONDS;
+        let end = start + EPOCH_SECONDS;
+        let in_epoch = |t: f64| t >= start && t < end;
+
+        let squares: Vec<f64> = gyro
+            .iter()
+            .filter(|(t, _)| in_epoch(*t))
+            .map(|(_, s)| *s)
+            .collect();
+        let movement = if squares.is_empty() {
+            0.0
+        } else {
+            (squares.iter().sum::<f64>() / squares.len() as f64).sqrt()
+        };
+
+        let ppg: Vec<f64> = ir
+            .iter()
+            .filter(|(t, _)| in_epoch(*t))
+            .flat_map(|(_, s)| s.iter().copied())
+            .collect();
+        let heart_rate = (ppg.len() >= PPG_SAMPLING_RATE * 10)
+            .then(|| extract_heart_rate(ppg, PPG_SAMPLING_RATE))
+            .filter(|hr| hr.quality.is_usable())
+            .map(|hr| hr.bpm);
+
+        let range = i * n..(i + 1) * n;
+        if let Some(f) = epoch_features(
+            &af7[range.clone()],
+            &af8[range],
+            sampling_rate,
+            movement,
+            heart_rate,
+        ) {
+            starts.push(start);
+            features.push(f);
+        }
+    }
+    info!("[SLEEP] Staged {} epochs of {}", starts.len(), session_dir);
+    Ok(StagedSession {
+        hypnogram: hypnogram(&starts, &features),
+        eeg,
+        sampling_rate,
+        channel_names: model.eeg_channel_names(),
+    })
+}
+
+// Stages a recorded session offline
+#[frb]
+pub fn stage_sleep_session(session_dir: String) -> Result<Hypnogram> {
+    Ok(stage_session(&session_dir)?.hypnogram)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    const FS: f64 = 256.0;
+
+    // 30 s of frontal EEG: sines per band, plus eye movements in opposite
+    // polarity on AF7/AF8
+    fn epoch(delta: f64, alpha: f64, sigma: f64, eye: f64) -> (Vec<f64>, Vec<f64>) {
+        let n = (EPOCH_SECONDS * FS) as usize;
+        let mut af7 = Vec::with_capacity(n);
+        let mut af8 = Vec::with_capacity(n);
+        for i in 0..n {
+            let t = i as f64 / FS;
+            let common = delta * (2.0 * PI * 1.5 * t).sin()
+                + 5.0 * (2.0 * PI * 6.0 * t).sin()
+                + alpha * (2.0 * PI * 10.0 * t).sin()
+                + sigma * (2.0 * PI * 13.5 * t).sin()
+                + 3.0 * (2.0 * PI * 20.0 * t).sin();
+            let eyes = eye * (2.0 * PI * 1.0 * t).sin();
+            af7.push(common + eyes);
+            af8.push(common - eyes);
+        }
+        (af7, af8)
+    }
+
+    #[test]
+    fn stages_synthetic_night() {
+        let kinds = [
+            (5.0, 30.0, 2.0, 0.0, 0.0),  // wake, eyes closed alpha
+            (5.0, 3.0, 2.0, 0.0, 0.0),   // N1
+            (15.0, 4.0, 12.0, 0.0, 0.0), // N2, spindles
+            (60.0, 3.0, 4.0, 0.0, 0.0),  // N3, slow waves
+            (8.0, 3.0, 2.0, 40.0, 0.0),  // REM, eye movements
+            (8.0, 10.0, 2.0, 0.0, 20.0), // wake, moving
+        ];
+        let night = [
+            0, 0, 1, 1, 2, 2, 2, 3, 3, 3, 2, 2, 4, 4, 4, 5, 2, 2, 2, 5, 0,
+        ];
+        let mut features = Vec::new();
+        for &k in &night {
+            let (delta, alpha, sigma, eye, movement) = kinds[k];
+            let (af7, af8) = epoch(delta, alpha, sigma, eye);
+            features.push(epoch_features(&af7, &af8, FS, movement, Some(60.0)).unwrap());
+        }
+        assert!(features[7].delta > N3_MIN_DELTA);
+        assert!(features[12].eog > 10.0 * features[4].eog);
+
+        let stages = classify(&features);
+        use SleepStage::*;
+        let expected = [
+            Wake, Wake, N1, N1, N2, N2, N2, N3, N3, N3, N2, N2, Rem, Rem, Rem, Wake, N2, N2, N2,
+            Wake, Wake,
+        ];
+        assert_eq!(stages, expected);
+    }
+
+    #[test]
+    fn summary_counts_waso_and_percentages() {
+        use SleepStage::*;
+        let stages = [Wake, Wake, N1, N2, N2, Wake, N3, N3, Rem, Rem, N2, Wake];
+        let s = summarize(&stages);
+        assert_eq!(s.time_in_bed_min, 6.0);
+        assert_eq!(s.total_sleep_time_min, 4.0);
+        assert!((s.sleep_efficiency - 66.666).abs() < 0.01);
+        assert_eq!(s.sleep_onset_latency_min, Some(1.0));
+        assert_eq!(s.waso_min, 0.5);
+        assert_eq!(s.awakenings, 1);
+        assert_eq!(s.n2_pct, 37.5);
+        assert_eq!(s.rem_pct, 25.0);
+        assert_eq!(summarize(&[]), SleepSummary::default());
+        assert_eq!(
+            epoch_heart_rate(&(0..12).map(|i| i as f64).collect::<Vec<_>>()),
+            Some(60.0)
+        );
+    }
+}
@@ -0,0 +1,510 @@
+use crate::sleep::{self, Hypnogram, SleepStage, EPOCH_SECONDS, FRONTAL_CHANNELS};
+use anyhow::Result;
+use brainflow::data_filter::{self, Band};
+use brainflow::{FilterTypes, WindowOperations};
+use flutter_rust_bridge::frb;
+use log::info;
+
+// Sleep spindle and slow oscillation / K-complex detection on the frontal
+// channels.
+//
+// Spindles: the 11-16 Hz band passed signal's moving RMS above mean + 1.5 SD
+// for 0.5-2 s, confirmed by the relative sigma power of a Welch PSD around
+// the burst. Slow waves: on the 0.3-1.5 Hz band passed signal, a negative
+// half wave of 0.3-1.5 s reaching -40 µV followed by a positive one, 0.8-2 s
+// in total and at least 75 µV peak to peak (Massimini et al. 2004). A slow
+// wave with no other within a few seconds counts as a K-complex. Live
+// detection runs on each closed sleep staging epoch with a few seconds of
+// the previous one as filter lead-in; offline detection on the whole night.
+
+const SPINDLE_BAND: (f64, f64) = (11.0, 16.0);
+const SPINDLE_DURATION: (f64, f64) = (0.5, 2.0);
+const SPINDLE_RMS_WINDOW_SECONDS: f64 = 0.2;
+const SPINDLE_RMS_THRESHOLD_SD: f64 = 1.5;
+const SPINDLE_MIN_RELATIVE_SIGMA: f64 = 0.2;
+const SPINDLE_PSD_SECONDS: f64 = 2.0;
+
+const SLOW_WAVE_BAND: (f64, f64) = (0.3, 1.5);
+const SLOW_WAVE_NEGATIVE_DURATION: (f64, f64) = (0.3, 1.5);
+const SLOW_WAVE_DURATION: (f64, f64) = (0.8, 2.0);
+const SLOW_WAVE_MIN_TROUGH_UV: f64 = -40.0;
+const SLOW_WAVE_MIN_PTP_UV: f64 = 75.0;
+// No other slow wave this close makes a K-complex
+const K_COMPLEX_ISOLATION_SECONDS: f64 = 3.0;
+
+// Previous epoch samples filtered along with a live epoch
+const CONTEXT_SECONDS: f64 = 5.0;
+const FILTER_ORDER: usize = 2;
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepEventKind {
+    Spindle,
+    SlowOscillation,
+    KComplex,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SleepEvent {
+    pub kind: SleepEventKind,
+    pub onset: f64,
+    pub duration: f64,
+    // Peak to peak of the band passed signal, µV
+    pub amplitude: f64,
+    pub frequency: f64,
+    pub channel: String,
+    // Stage of the epoch containing the onset, if staged
+    pub stage: Option<SleepStage>,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SleepEventDensity {
+    pub stage: SleepStage,
+    pub minutes: f64,
+    // Events per minute of the stage
+    pub spindles: f64,
+    pub slow_oscillations: f64,
+    pub k_complexes: f64,
+}
+
+#[frb]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SleepEventReport {
+    pub events: Vec<SleepEvent>,
+    pub densities: Vec<SleepEventDensity>,
+}
+
+// An event in sample indices of the analysed signal
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Detection {
+    pub kind: SleepEventKind,
+    pub start: usize,
+    pub end: usize,
+    pub amplitude: f64,
+    pub frequency: f64,
+}
+
+fn peak_to_peak(x: &[f64]) -> f64 {
+    let max = x.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let min = x.iter().copied().fold(f64::INFINITY, f64::min);
+    max - min
+}
+
+// Centered moving RMS over `window` samples
+fn moving_rms(x: &[f64], window: usize) -> Vec<f64> {
+    let mut cumulative = Vec::with_capacity(x.len() + 1);
+    cumulative.push(0.0);
+    for v in x {
+        cumulative.push(cumulative.last().unwrap() + v * v);
+    }
+    let half = window / 2;
+    (0..x.len())
+        .map(|i| {
+            let (lo, hi) = (i.saturating_sub(half), (i + half + 1).min(x.len()));
+            ((cumulative[hi] - cumulative[lo]) / (hi - lo) as f64).sqrt()
+        })
+        .collect()
+}
+
+// Spindles in a sigma band passed signal
+pub(crate) fn find_spindles(sigma: &[f64], sampling_rate: f64) -> Vec<Detection> {
+    if sigma.is_empty() {
+        return Vec::new();
+    }
+    let rms = moving_rms(sigma, (SPINDLE_RMS_WINDOW_SECONDS * sampling_rate) as usize);
+    let n = rms.len() as f64;
+    let mean = rms.iter().sum::<f64>() / n;
+    let sd = (rms.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt();
+    let threshold = mean + SPINDLE_RMS_THRESHOLD_SD * sd;
+
+    let mut spindles = Vec::new();
+    let mut i = 0;
+    while i < rms.len() {
+        if rms[i] <= threshold {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < rms.len() && rms[i] > threshold {
+            i += 1;
+        }
+        let duration = (i - start) as f64 / sampling_rate;
+        if duration < SPINDLE_DURATION.0 || duration > SPINDLE_DURATION.1 {
+            continue;
+        }
+        let segment = &sigma[start..i];
+        let crossings = segment
+            .windows(2)
+            .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+            .count();
+        let frequency = crossings as f64 / (2.0 * duration);
+        if frequency >= SPINDLE_BAND.0 - 0.5 && frequency <= SPINDLE_BAND.1 + 0.5 {
+            spindles.push(Detection {
+                kind: SleepEventKind::Spindle,
+                start,
+                end: i,
+                amplitude: peak_to_peak(segment),
+                frequency,
+            });
+        }
+    }
+    spindles
+}
+
+// Slow oscillations and K-complexes in a slow band passed signal
+pub(crate) fn find_slow_waves(slow: &[f64], sampling_rate: f64) -> Vec<Detection> {
+    let down: Vec<usize> = (1..slow.len())
+        .filter(|&i| slow[i - 1] >= 0.0 && slow[i] < 0.0)
+        .collect();
+    let mut waves = Vec::new();
+    for pair in down.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        let Some(up) = (start..end).find(|&i| slow[i] >= 0.0) else {
+            continue;
+        };
+        let negative = (up - start) as f64 / sampling_rate;
+        let duration = (end - start) as f64 / sampling_rate;
+        let trough = slow[start..up]
+            .iter()
+            .copied()
+            .fold(f64::INFINITY, f64::min);
+        let amplitude = peak_to_peak(&slow[start..end]);
+        if negative >= SLOW_WAVE_NEGATIVE_DURATION.0
+            && negative <= SLOW_WAVE_NEGATIVE_DURATION.1
+            && duration >= SLOW_WAVE_DURATION.0
+            && duration <= SLOW_WAVE_DURATION.1
+            && trough <= SLOW_WAVE_MIN_TROUGH_UV
+            && amplitude >= SLOW_WAVE_MIN_PTP_UV
+        {
+            waves.push(Detection {
+                kind: SleepEventKind::SlowOscillation,
+                start,
+                end,
+                amplitude,
+                frequency: 1.0 / duration,
+            });
+        }
+    }
+
+    let isolation = (K_COMPLEX_ISOLATION_SECONDS * sampling_rate) as usize;
+    let starts: Vec<usize> = waves.iter().map(|w| w.start).collect();
+    for (i, wave) in waves.iter_mut().enumerate() {
+        let isolated = starts
+            .iter()
+            .enumerate()
+            .all(|(j, s)| j == i || s.abs_diff(wave.start) > isolation);
+        if isolated {
+            wave.kind = SleepEventKind::KComplex;
+        }
+    }
+    waves
+}
+
+fn bandpass(data: &[f64], sampling_rate: usize, band: (f64, f64)) -> Option<Vec<f64>> {
+    let mut filtered = data.to_vec();
+    data_filter::perform_bandpass(
+        &mut filtered,
+        sampling_rate,
+        band.0,
+        band.1,
+        FILTER_ORDER,
+        FilterTypes::ButterworthZeroPhase,
+        0.0,
+    )
+    .ok()?;
+    Some(filtered)
+}
+
+// Sigma over 1-30 Hz power of the Welch PSD around a spindle candidate
+fn relative_sigma(raw: &[f64], detection: &Detection, sampling_rate: usize) -> Option<f64> {
+    let half = (SPINDLE_PSD_SECONDS * sampling_rate as f64 / 2.0) as usize;
+    let center = (detection.start + detection.end) / 2;
+    let mut segment = raw[center.saturating_sub(half)..(center + half).min(raw.len())].to_vec();
+    let nfft = sampling_rate.next_power_of_two();
+    if segment.len() < nfft {
+        return None;
+    }
+    let mut psd = data_filter::get_psd_welch(
+        &mut segment,
+        nfft,
+        nfft / 2,
+        sampling_rate,
+        WindowOperations::Hanning,
+    )
+    .ok()?;
+    let mut power = |freq_start, freq_stop| {
+        data_filter::get_band_power(
+            &mut psd,
+            Band {
+                freq_start,
+                freq_stop,
+            },
+        )
+        .ok()
+    };
+    let sigma = power(SPINDLE_BAND.0, SPINDLE_BAND.1)?;
+    let total = power(1.0, 30.0)?;
+    (total > 0.0).then(|| sigma / total)
+}
+
+// Events of one channel of raw EEG (µV)
+pub(crate) fn detect(raw: &[f64], sampling_rate: f64) -> Vec<Detection> {
+    let fs = sampling_rate as usize;
+    let mut detections = Vec::new();
+    if let Some(sigma) = bandpass(raw, fs, SPINDLE_BAND) {
+        detections.extend(
+            find_spindles(&sigma, sampling_rate)
+                .into_iter()
+                .filter(|d| {
+                    relative_sigma(raw, d, fs).is_none_or(|r| r >= SPINDLE_MIN_RELATIVE_SIGMA)
+                }),
+        );
+    }
+    if let Some(slow) = bandpass(raw, fs, SLOW_WAVE_BAND) {
+        detections.extend(find_slow_waves(&slow, sampling_rate));
+    }
+    detections.sort_by_key(|d| d.start);
+    detections
+}
+
+fn to_event(
+    detection: &Detection,
+    start_time: f64,
+    sampling_rate: f64,
+    channel: &str,
+) -> SleepEvent {
+    SleepEvent {
+        kind: detection.kind,
+        onset: start_time + detection.start as f64 / sampling_rate,
+        duration: (detection.end - detection.start) as f64 / sampling_rate,
+        amplitude: detection.amplitude,
+        frequency: detection.frequency,
+        channel: channel.to_string(),
+        stage: None,
+    }
+}
+
+// Stamps each event with the stage of its epoch and computes per stage rates
+pub(crate) fn report(mut events: Vec<SleepEvent>, hypnogram: &Hypnogram) -> SleepEventReport {
+    for event in &mut events {
+        event.stage = hypnogram
+            .epochs
+            .iter()
+            .find(|e| event.onset >= e.start && event.onset < e.start + EPOCH_SECONDS)
+            .map(|e| e.stage);
+    }
+    let stages = [
+        SleepStage::Wake,
+        SleepStage::N1,
+        SleepStage::N2,
+        SleepStage::N3,
+        SleepStage::Rem,
+    ];
+    let densities = stages
+        .into_iter()
+        .filter_map(|stage| {
+            let epochs = hypnogram.epochs.iter().filter(|e| e.stage == stage).count();
+            if epochs == 0 {
+                return None;
+            }
+            let minutes = epochs as f64 * EPOCH_SECONDS / 60.0;
+            let rate = |kind| {
+                let count = events
+                    .iter()
+                    .filter(|e| e.kind == kind && e.stage == Some(stage))
+                    .count();
+                count as f64 / minutes
+            };
+            Some(SleepEventDensity {
+                stage,
+                minutes,
+                spindles: rate(SleepEventKind::Spindle),
+                slow_oscillations: rate(SleepEventKind::SlowOscillation),
+                k_complexes: rate(SleepEventKind::KComplex),
+            })
+        })
+        .collect();
+    SleepEventReport { events, densities }
+}
+
+// Live detection on the epochs closed by the sleep stager
+pub(crate) struct SleepEventDetector {
+    sampling_rate: f64,
+    channel_names: [String; 2],
+    context: [Vec<f64>; 2],
+    events: Vec<SleepEvent>,
+    pending: Vec<SleepEvent>,
+}
+
+impl SleepEventDetector {
+    pub fn new(sampling_rate: f64, channel_names: &[String]) -> Self {
+        Self {
+            sampling_rate,
+            channel_names: FRONTAL_CHANNELS
+                .map(|c| channel_names.get(c).cloned().unwrap_or_default()),
+            context: [Vec::new(), Vec::new()],
+            events: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    // Detects the events starting within an epoch beginning at `start`
+    pub fn process_epoch(&mut self, start: f64, epoch: &[Vec<f64>; 2], stage: Option<SleepStage>) {
+        let context_len = (CONTEXT_SECONDS * self.sampling_rate) as usize;
+        for (i, samples) in epoch.iter().enumerate() {
+            let lead = self.context[i].len();
+            let mut data = std::mem::take(&mut self.context[i]);
+            data.extend_from_slice(samples);
+            for detection in detect(&data, self.sampling_rate)
+                .iter()
+                .filter(|d| d.start >= lead)
+            {
+                let mut event = to_event(
+                    detection,
+                    start - lead as f64 / self.sampling_rate,
+                    self.sampling_rate,
+                    &self.channel_names[i],
+                );
+                event.stage = stage;
+                self.pending.push(event.clone());
+                self.events.push(event);
+            }
+            self.context[i] = data[data.len().saturating_sub(context_len)..].to_vec();
+        }
+    }
+
+    pub fn report(&self, hypnogram: &Hypnogram) -> SleepEventReport {
+        report(self.events.clone(), hypnogram)
+    }
+
+    // Events detected since the last call
+    pub fn take_pending(&mut self) -> Vec<SleepEvent> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+// Stages a recorded session and detects its events on AF7 and AF8
+#[frb]
+pub fn detect_sleep_session_events(session_dir: String) -> Result<SleepEventReport> {
+    let staged = sleep::stage_session(&session_dir)?;
+    let mut events = Vec::new();
+    for channel in FRONTAL_CHANNELS {
+        let Some(raw) = staged.eeg.channels.get(channel) else {
+            continue;
+        };
+        let name = staged
+            .channel_names
+            .get(channel)
+            .cloned()
+            .unwrap_or_default();
+        events.extend(
+            detect(raw, staged.sampling_rate)
+                .iter()
+                .map(|d| to_event(d, staged.eeg.start_time, staged.sampling_rate, &name)),
+        );
+    }
+    events.sort_by(|a, b| a.onset.total_cmp(&b.onset));
+    info!("[SLEEP] {} events in {}", events.len(), session_dir);
+    Ok(report(events, &staged.hypnogram))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sleep::{SleepEpoch, SleepEpochFeatures};
+    use std::f64::consts::PI;
+
+    const FS: f64 = 256.0;
+
+    #[test]
+    fn finds_spindle_bursts_of_valid_length() {
+        // 12 Hz background, a 1 s 13 Hz spindle at 4 s and a 0.2 s burst at 8 s
+        let sigma: Vec<f64> = (0..(12.0 * FS) as usize)
+            .map(|i| {
+                let t = i as f64 / FS;
+                let burst = if (4.0..5.0).contains(&t) {
+                    20.0 * (PI * (t - 4.0)).sin()
+                } else if (8.0..8.2).contains(&t) {
+                    20.0
+                } else {
+                    0.0
+                };
+                2.0 * (2.0 * PI * 12.0 * t).sin() + burst * (2.0 * PI * 13.0 * t).sin()
+            })
+            .collect();
+        let spindles = find_spindles(&sigma, FS);
+        assert_eq!(spindles.len(), 1, "{:?}", spindles);
+        let s = &spindles[0];
+        let onset = s.start as f64 / FS;
+        let duration = (s.end - s.start) as f64 / FS;
+        assert!(onset > 4.0 && onset < 4.4, "onset {}", onset);
+        assert!(duration > 0.5 && duration < 1.0, "duration {}", duration);
+        assert!(
+            (s.frequency - 13.0).abs() < 1.0,
+            "frequency {}",
+            s.frequency
+        );
+        assert!(s.amplitude > 30.0);
+    }
+
+    #[test]
+    fn tells_k_complexes_from_slow_oscillation_trains() {
+        // One isolated 0.8 Hz wave at 2 s, a train of three from 10 s and a
+        // small wave at 20 s over a 2 Hz background; each wave starts with
+        // its negative half
+        let wave = |t: f64, at: f64, amplitude: f64| {
+            let phase = (t - at) * 0.8;
+            if (0.0..1.0).contains(&phase) {
+                -amplitude * (2.0 * PI * phase).sin()
+            } else {
+                0.0
+            }
+        };
+        let slow: Vec<f64> = (0..(25.0 * FS) as usize)
+            .map(|i| {
+                let t = i as f64 / FS;
+                3.0 * (2.0 * PI * 2.0 * t).sin()
+                    + wave(t, 2.0, 60.0)
+                    + (0..3)
+                        .map(|k| wave(t, 10.0 + k as f64 * 1.25, 60.0))
+                        .sum::<f64>()
+                    + wave(t, 20.0, 25.0)
+            })
+            .collect();
+        let waves = find_slow_waves(&slow, FS);
+        let kinds: Vec<_> = waves.iter().map(|w| w.kind).collect();
+        use SleepEventKind::*;
+        assert_eq!(
+            kinds,
+            vec![KComplex, SlowOscillation, SlowOscillation, SlowOscillation]
+        );
+        assert!((waves[2].frequency - 0.8).abs() < 0.05);
+        assert!((waves[0].amplitude - 120.0).abs() < 8.0);
+
+        let features = SleepEpochFeatures {
+            delta: 0.0,
+            theta: 0.0,
+            alpha: 0.0,
+            sigma: 0.0,
+            beta: 0.0,
+            eog: 0.0,
+            movement: 0.0,
+            heart_rate: None,
+        };
+        let hypnogram = Hypnogram {
+            epochs: vec![SleepEpoch {
+                start: 0.0,
+                stage: SleepStage::N2,
+                features,
+            }],
+            ..Default::default()
+        };
+        let events = waves.iter().map(|w| to_event(w, 0.0, FS, "AF7")).collect();
+        let report = report(events, &hypnogram);
+        assert_eq!(report.densities.len(), 1);
+        assert_eq!(report.densities[0].k_complexes, 2.0);
+        assert_eq!(report.densities[0].slow_oscillations, 6.0);
+    }
+}
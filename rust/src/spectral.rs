@@ -0,0 +1,132 @@
+use std::f64::consts::PI;
+
+// Welch power spectral density in plain Rust.
+//
+// BrainFlow's get_psd_welch only hands the spectrum back through an opaque
+// Psd usable with get_band_power, but the offline analyses (sleep staging,
+// event detection, spectral features) need the bins themselves, and have to
+// run in unit tests without the native library.
+
+// In-place iterative radix-2 FFT; `re.len()` must be a power of two
+fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let tr = re[b] * cos - im[b] * sin;
+                let ti = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+pub(crate) struct Psd {
+    pub frequencies: Vec<f64>,
+    // One-sided density, units²/Hz
+    pub power: Vec<f64>,
+}
+
+impl Psd {
+    // Integrated power in [low, high)
+    pub fn band_power(&self, low: f64, high: f64) -> f64 {
+        let df = self.frequencies.get(1).copied().unwrap_or(0.0);
+        self.frequencies
+            .iter()
+            .zip(&self.power)
+            .filter(|(f, _)| **f >= low && **f < high)
+            .map(|(_, p)| p * df)
+            .sum()
+    }
+}
+
+// FFT length for `len` samples: the largest power of two not above it
+pub(crate) fn nfft_for(len: usize) -> usize {
+    1 << (usize::BITS - 1 - len.max(1).leading_zeros())
+}
+
+// Hann windowed, mean removed segments of `nfft` samples (a power of two)
+// with 50% overlap; None if the data is shorter than one segment
+pub(crate) fn welch(data: &[f64], sampling_rate: f64, nfft: usize) -> Option<Psd> {
+    if nfft < 2 || !nfft.is_power_of_two() || data.len() < nfft {
+        return None;
+    }
+    let window: Vec<f64> = (0..nfft)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / nfft as f64).cos())
+        .collect();
+    let window_power: f64 = window.iter().map(|w| w * w).sum();
+    let bins = nfft / 2 + 1;
+    let mut power = vec![0.0; bins];
+    let mut segments = 0;
+    let step = nfft / 2;
+    let mut start = 0;
+    while start + nfft <= data.len() {
+        let segment = &data[start..start + nfft];
+        let mean = segment.iter().sum::<f64>() / nfft as f64;
+        let mut re: Vec<f64> = segment
+            .iter()
+            .zip(&window)
+            .map(|(v, w)| (v - mean) * w)
+            .collect();
+        let mut im = vec![0.0; nfft];
+        fft(&mut re, &mut im);
+        for (k, p) in power.iter_mut().enumerate() {
+            let mut value = (re[k] * re[k] + im[k] * im[k]) / (sampling_rate * window_power);
+            if k != 0 && k != nfft / 2 {
+                value *= 2.0;
+            }
+            *p += value;
+        }
+        segments += 1;
+        start += step;
+    }
+    for p in &mut power {
+        *p /= segments as f64;
+    }
+    Some(Psd {
+        frequencies: (0..bins)
+            .map(|k| k as f64 * sampling_rate / nfft as f64)
+            .collect(),
+        power,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn welch_keeps_sine_power_in_its_band() {
+        let fs = 256.0;
+        // 10 Hz sine of amplitude 2 (power 2) plus a DC offset
+        let data: Vec<f64> = (0..256 * 8)
+            .map(|i| 5.0 + 2.0 * (2.0 * PI * 10.0 * i as f64 / fs).sin())
+            .collect();
+        let psd = welch(&data, fs, 512).unwrap();
+        let total = psd.band_power(0.5, 128.0);
+        assert!((total - 2.0).abs() < 0.05, "power {}", total);
+        assert!(psd.band_power(8.0, 12.0) / total > 0.99);
+        assert!(welch(&data[..100], fs, 512).is_none());
+    }
+}
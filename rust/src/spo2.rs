@@ -0,0 +1,372 @@
+use crate::heart_rate::{filter_ppg, HeartRateResult, PpgBuffer, PPG_SAMPLING_RATE};
+use anyhow::{Context, Result};
+use brainflow::data_filter;
+use flutter_rust_bridge::frb;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::Mutex;
+
+// SpO2 from the ratio of ratios R = (AC_red / DC_red) / (AC_ir / DC_ir).
+//
+// The beats found by the heart rate tracker split the last window into
+// cardiac cycles. Per cycle AC is the peak to trough swing of the band passed
+// signal and DC the raw mean; the median R over the cycles goes through the
+// calibration curve SpO2 = coef1 * R^2 + coef2 * R + coef3, which is the same
+// curve BrainFlow's get_oxygen_level uses. That BrainFlow estimate on the
+// same window is reported next to ours as the reference.
+
+const SPO2_WINDOW_SECONDS: usize = 10;
+const MIN_BEATS: usize = 3;
+const RATIO_RANGE: (f64, f64) = (0.2, 3.0);
+// Relative IQR of the per-beat ratios above which the beats disagree
+const MAX_RATIO_SPREAD: f64 = 0.25;
+// IR perfusion index (AC/DC in %) below which the pulse is too weak
+const MIN_PERFUSION_INDEX: f64 = 0.02;
+const MIN_PLAUSIBLE_SPO2: f64 = 70.0;
+
+static SPO2_CALIBRATION: Mutex<Option<Spo2Calibration>> = Mutex::new(None);
+
+#[frb]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Spo2Calibration {
+    // Device name (or "default") the coefficients were fitted for
+    pub device: String,
+    pub coef1: f64,
+    pub coef2: f64,
+    pub coef3: f64,
+}
+
+impl Default for Spo2Calibration {
+    // BrainFlow's get_oxygen_level defaults
+    fn default() -> Self {
+        Self {
+            device: "default".to_string(),
+            coef1: 1.5958422,
+            coef2: -34.6596622,
+            coef3: 112.6898759,
+        }
+    }
+}
+
+impl Spo2Calibration {
+    fn apply(&self, ratio: f64) -> f64 {
+        self.coef1 * ratio * ratio + self.coef2 * ratio + self.coef3
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Spo2Unavailable {
+    InsufficientData,
+    PoorPpgQuality,
+    Motion,
+    LowPerfusion,
+    InconsistentBeats,
+    OutOfRange,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spo2Result {
+    // None together with a reason when the signal is unusable
+    pub spo2: Option<f64>,
+    pub reason: Option<Spo2Unavailable>,
+    pub ratio: Option<f64>,
+    pub perfusion_index: Option<f64>,
+    pub brainflow_spo2: Option<f64>,
+    pub beat_count: usize,
+    pub timestamp: f64,
+}
+
+impl Spo2Result {
+    fn unavailable(reason: Spo2Unavailable) -> Self {
+        Self {
+            spo2: None,
+            reason: Some(reason),
+            ratio: None,
+            perfusion_index: None,
+            brainflow_spo2: None,
+            beat_count: 0,
+            timestamp: 0.0,
+        }
+    }
+}
+
+pub(crate) fn active_calibration() -> Spo2Calibration {
+    SPO2_CALIBRATION
+        .lock()
+        .ok()
+        .and_then(|c| c.clone())
+        .unwrap_or_default()
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(f64::total_cmp);
+    values[values.len() / 2]
+}
+
+// (ratio of ratios, IR perfusion index in %) per cardiac cycle between
+// consecutive beat indices
+pub(crate) fn beat_ratios(
+    ir: &[f64],
+    red: &[f64],
+    ir_ac: &[f64],
+    red_ac: &[f64],
+    beats: &[usize],
+) -> Vec<(f64, f64)> {
+    let swing = |x: &[f64]| {
+        let (lo, hi) = x
+            .iter()
+            .fold((f64::MAX, f64::MIN), |(lo, hi), v| (lo.min(*v), hi.max(*v)));
+        hi - lo
+    };
+    let mean = |x: &[f64]| x.iter().sum::<f64>() / x.len() as f64;
+    beats
+        .windows(2)
+        .filter(|w| w[1] > w[0] + 1 && w[1] <= ir.len().min(red.len()))
+        .filter_map(|w| {
+            let (a, b) = (w[0], w[1]);
+            let (dc_ir, dc_red) = (mean(&ir[a..b]), mean(&red[a..b]));
+            let (ac_ir, ac_red) = (swing(&ir_ac[a..b]), swing(&red_ac[a..b]));
+            if dc_ir <= 0.0 || dc_red <= 0.0 || ac_ir <= 0.0 {
+                return None;
+            }
+            let ratio = (ac_red / dc_red) / (ac_ir / dc_ir);
+            Some((ratio, ac_ir / dc_ir * 100.0))
+        })
+        .collect()
+}
+
+// Median ratio -> SpO2, or why the beats do not give a trustworthy value
+pub(crate) fn estimate_from_ratios(
+    ratios: &[(f64, f64)],
+    calibration: &Spo2Calibration,
+) -> Spo2Result {
+    let mut valid: Vec<(f64, f64)> = ratios
+        .iter()
+        .copied()
+        .filter(|(r, _)| (RATIO_RANGE.0..=RATIO_RANGE.1).contains(r))
+        .collect();
+    if valid.len() < MIN_BEATS {
+        return Spo2Result::unavailable(Spo2Unavailable::InconsistentBeats);
+    }
+    let mut perfusion: Vec<f64> = valid.iter().map(|(_, p)| *p).collect();
+    let perfusion_index = median(&mut perfusion);
+    if perfusion_index < MIN_PERFUSION_INDEX {
+        return Spo2Result {
+            perfusion_index: Some(perfusion_index),
+            ..Spo2Result::unavailable(Spo2Unavailable::LowPerfusion)
+        };
+    }
+
+    valid.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let ratio = valid[valid.len() / 2].0;
+    let iqr = valid[valid.len() * 3 / 4].0 - valid[valid.len() / 4].0;
+    let mut result = Spo2Result {
+        ratio: Some(ratio),
+        perfusion_index: Some(perfusion_index),
+        beat_count: valid.len(),
+        ..Spo2Result::unavailable(Spo2Unavailable::InconsistentBeats)
+    };
+    if iqr / ratio > MAX_RATIO_SPREAD {
+        return result;
+    }
+    let spo2 = calibration.apply(ratio);
+    if spo2 < MIN_PLAUSIBLE_SPO2 {
+        result.reason = Some(Spo2Unavailable::OutOfRange);
+        return result;
+    }
+    result.spo2 = Some(spo2.min(100.0));
+    result.reason = None;
+    result
+}
+
+// Full path on aligned raw IR/red windows and beat sample indices
+fn estimate_window(
+    ir: &[f64],
+    red: &[f64],
+    beats: &[usize],
+    sampling_rate: usize,
+    calibration: &Spo2Calibration,
+) -> Spo2Result {
+    let (Some(ir_ac), Some(red_ac)) = (
+        filter_ppg(ir, sampling_rate),
+        filter_ppg(red, sampling_rate),
+    ) else {
+        return Spo2Result::unavailable(Spo2Unavailable::InsufficientData);
+    };
+    let mut result =
+        estimate_from_ratios(&beat_ratios(ir, red, &ir_ac, &red_ac, beats), calibration);
+    let (mut ir, mut red) = (ir.to_vec(), red.to_vec());
+    result.brainflow_spo2 = data_filter::get_oxygen_level(
+        &mut ir,
+        &mut red,
+        sampling_rate,
+        calibration.coef1,
+        calibration.coef2,
+        calibration.coef3,
+    )
+    .ok();
+    result
+}
+
+// One-shot SpO2 of equally long IR and red traces, for offline use
+#[frb]
+pub fn estimate_spo2(ppg_ir: Vec<f64>, ppg_red: Vec<f64>, sampling_rate: usize) -> Spo2Result {
+    let n = ppg_ir.len().min(ppg_red.len());
+    if n < sampling_rate * 5 {
+        return Spo2Result::unavailable(Spo2Unavailable::InsufficientData);
+    }
+    let hr = crate::heart_rate::extract_heart_rate(ppg_ir[..n].to_vec(), sampling_rate);
+    if !hr.quality.is_usable() {
+        return Spo2Result::unavailable(Spo2Unavailable::PoorPpgQuality);
+    }
+    let beats: Vec<usize> = hr
+        .beat_times
+        .iter()
+        .map(|t| (t * sampling_rate as f64).round() as usize)
+        .collect();
+    estimate_window(
+        &ppg_ir[..n],
+        &ppg_red[..n],
+        &beats,
+        sampling_rate,
+        &active_calibration(),
+    )
+}
+
+// Live estimate, recomputed for every new heart rate result
+pub(crate) struct Spo2Tracker {
+    last_beat_update: f64,
+    latest: Option<Spo2Result>,
+    fresh: bool,
+}
+
+impl Spo2Tracker {
+    pub fn new() -> Self {
+        Self {
+            last_beat_update: f64::NEG_INFINITY,
+            latest: None,
+            fresh: false,
+        }
+    }
+
+    // Call once the red samples of a packet are buffered, so IR and red end
+    // at the same sample; `motion_weight` is the motion gate weight of the
+    // heart rate window, any contamination makes the ratios unreliable
+    pub fn update(&mut self, ppg: &PpgBuffer, hr: Option<&HeartRateResult>, motion_weight: f64) {
+        let Some(hr) = hr.filter(|hr| hr.timestamp > self.last_beat_update) else {
+            return;
+        };
+        self.last_beat_update = hr.timestamp;
+
+        let mut result = if motion_weight < 1.0 {
+            Spo2Result::unavailable(Spo2Unavailable::Motion)
+        } else if !hr.quality.is_usable() {
+            Spo2Result::unavailable(Spo2Unavailable::PoorPpgQuality)
+        } else {
+            let ir = ppg.latest(0, SPO2_WINDOW_SECONDS);
+            let red = ppg.latest(1, SPO2_WINDOW_SECONDS);
+            let n = ir.len().min(red.len());
+            if n < PPG_SAMPLING_RATE * 5 {
+                Spo2Result::unavailable(Spo2Unavailable::InsufficientData)
+            } else {
+                let (ir, red) = (&ir[ir.len() - n..], &red[red.len() - n..]);
+                // Absolute beat times -> indices, newest sample at hr.timestamp
+                let start = hr.timestamp - (n - 1) as f64 / PPG_SAMPLING_RATE as f64;
+                let beats: Vec<usize> = hr
+                    .beat_times
+                    .iter()
+                    .filter(|t| **t >= start)
+                    .map(|t| ((t - start) * PPG_SAMPLING_RATE as f64).round() as usize)
+                    .collect();
+                estimate_window(ir, red, &beats, PPG_SAMPLING_RATE, &active_calibration())
+            }
+        };
+        result.timestamp = hr.timestamp;
+        self.latest = Some(result);
+        self.fresh = true;
+    }
+
+    pub fn latest(&self) -> Option<&Spo2Result> {
+        self.latest.as_ref()
+    }
+
+    pub fn take_fresh(&mut self) -> Option<Spo2Result> {
+        if !self.fresh {
+            return None;
+        }
+        self.fresh = false;
+        self.latest.clone()
+    }
+}
+
+#[frb]
+pub fn set_spo2_calibration(calibration: Spo2Calibration) -> Result<()> {
+    let mut active = SPO2_CALIBRATION
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Failed to lock SPO2_CALIBRATION mutex"))?;
+    info!("[SPO2] Using calibration for {}", calibration.device);
+    *active = Some(calibration);
+    Ok(())
+}
+
+#[frb]
+pub fn get_spo2_calibration() -> Spo2Calibration {
+    active_calibration()
+}
+
+// Loads a JSON list of calibrations and activates the entry for `device`
+// (case insensitive), falling back to one named "default".
+#[frb]
+pub fn load_spo2_calibration(path: String, device: String) -> Result<Spo2Calibration> {
+    let json = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path))?;
+    let calibrations: Vec<Spo2Calibration> = serde_json::from_str(&json)
+        .with_context(|| format!("Invalid calibration file {}", path))?;
+    let calibration = calibrations
+        .iter()
+        .find(|c| c.device.eq_ignore_ascii_case(&device))
+        .or_else(|| calibrations.iter().find(|c| c.device == "default"))
+        .cloned()
+        .with_context(|| format!("No SpO2 calibration for {} in {}", device, path))?;
+    set_spo2_calibration(calibration.clone())?;
+    Ok(calibration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Per-beat ratios of one second cycles with the given red/IR modulation
+    fn synthetic_ratios(ratio: f64) -> Vec<(f64, f64)> {
+        let pulse: Vec<f64> = (0..64 * 10)
+            .map(|i| (2.0 * std::f64::consts::PI * i as f64 / 64.0).cos())
+            .collect();
+        let ir_ac: Vec<f64> = pulse.iter().map(|p| p * 1000.0).collect();
+        let red_ac: Vec<f64> = pulse.iter().map(|p| p * 500.0 * ratio).collect();
+        let ir: Vec<f64> = ir_ac.iter().map(|v| 200_000.0 + v).collect();
+        let red: Vec<f64> = red_ac.iter().map(|v| 100_000.0 + v).collect();
+        let beats: Vec<usize> = (0..10).map(|b| b * 64).collect();
+        beat_ratios(&ir, &red, &ir_ac, &red_ac, &beats)
+    }
+
+    #[test]
+    fn ratio_of_ratios_maps_through_calibration() {
+        let calibration = Spo2Calibration::default();
+        let ratios = synthetic_ratios(0.5);
+        assert_eq!(ratios.len(), 9);
+        assert!(ratios.iter().all(|(r, _)| (r - 0.5).abs() < 1e-9));
+
+        let result = estimate_from_ratios(&ratios, &calibration);
+        assert_eq!(result.reason, None);
+        let expected: f64 = 1.5958422 * 0.25 - 34.6596622 * 0.5 + 112.6898759;
+        assert!((result.spo2.unwrap() - expected.min(100.0)).abs() < 1e-6);
+        assert!((result.perfusion_index.unwrap() - 1.0).abs() < 1e-9);
+
+        // A large ratio gives an implausible saturation: no value, a reason
+        let result = estimate_from_ratios(&synthetic_ratios(2.5), &calibration);
+        assert_eq!(result.spo2, None);
+        assert_eq!(result.reason, Some(Spo2Unavailable::OutOfRange));
+    }
+}
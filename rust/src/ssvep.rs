@@ -0,0 +1,485 @@
+use crate::channel_buffers::{self, ChannelBuffers};
+use crate::personal_classifier::solve_spd;
+use crate::spectral;
+use flutter_rust_bridge::frb;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// Steady-state visually evoked potential detection.
+//
+// Every step, each configured window length of the EEG is compared with
+// sine/cosine references at every target frequency and its harmonics by
+// canonical correlation analysis; the score of a target is the largest
+// canonical correlation. When CCA is not selected, or not usable on any of
+// the windows of a step (too few samples for the reference set, singular
+// covariance), every window of that step is scored by the PSD
+// signal-to-noise ratio instead: power at each harmonic over the mean of
+// the neighbouring bins. Scores are averaged over the window lengths that
+// are filled, and a target is selected once it has stayed the best one
+// above the threshold of the method used for the dwell time.
+
+const MAX_QUEUED: usize = 256;
+// Neighbouring bins of the SNR noise estimate, on each side
+const SNR_NEIGHBOUR_HZ: f64 = 1.0;
+const CCA_RIDGE: f64 = 1e-9;
+const POWER_ITERATIONS: usize = 100;
+
+#[frb]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SsvepMethod {
+    Cca,
+    PsdSnr,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SsvepSettings {
+    pub target_frequencies: Vec<f64>,
+    // Reference harmonics, the fundamental included
+    pub harmonics: u32,
+    pub window_lengths_seconds: Vec<f64>,
+    pub method: SsvepMethod,
+    // Channel names; empty uses every EEG channel
+    pub channels: Vec<String>,
+    // Minimum canonical correlation (0-1) of a CCA candidate
+    pub threshold: f64,
+    // Minimum power ratio of a PSD-SNR candidate
+    pub snr_threshold: f64,
+    pub dwell_seconds: f64,
+    pub step_seconds: f64,
+}
+
+impl Default for SsvepSettings {
+    fn default() -> Self {
+        Self {
+            target_frequencies: vec![8.57, 10.0, 12.0, 15.0],
+            harmonics: 2,
+            window_lengths_seconds: vec![1.0, 2.0, 4.0],
+            method: SsvepMethod::Cca,
+            channels: vec![],
+            threshold: 0.4,
+            snr_threshold: 3.0,
+            dwell_seconds: 1.0,
+            step_seconds: 0.25,
+        }
+    }
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SsvepScores {
+    pub timestamp: f64,
+    // Method actually used, for every window; PsdSnr if CCA was not usable
+    // on any of them
+    pub method: SsvepMethod,
+    // Window lengths that were filled
+    pub window_lengths_seconds: Vec<f64>,
+    // Per window length, one score per target
+    pub window_scores: Vec<Vec<f64>>,
+    // Mean over the window lengths, one per target
+    pub scores: Vec<f64>,
+    // Best target if above the threshold
+    pub candidate: Option<u32>,
+}
+
+#[frb]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SsvepSelection {
+    pub timestamp: f64,
+    pub target: u32,
+    pub frequency: f64,
+    pub score: f64,
+}
+
+fn covariance(a: &[Vec<f64>], b: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let n = a.first().map(Vec::len).unwrap_or(0).max(1) as f64;
+    a.iter()
+        .map(|x| {
+            b.iter()
+                .map(|y| x.iter().zip(y).map(|(x, y)| x * y).sum::<f64>() / n)
+                .collect()
+        })
+        .collect()
+}
+
+fn centered(rows: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    rows.iter()
+        .map(|r| {
+            let mean = r.iter().sum::<f64>() / r.len().max(1) as f64;
+            r.iter().map(|v| v - mean).collect()
+        })
+        .collect()
+}
+
+// a^-1 b, column by column
+fn solve_columns(a: &[Vec<f64>], b: &[Vec<f64>]) -> Option<Vec<Vec<f64>>> {
+    let mut a = a.to_vec();
+    for (i, row) in a.iter_mut().enumerate() {
+        row[i] += CCA_RIDGE;
+    }
+    let columns = b.first().map(Vec::len).unwrap_or(0);
+    let solved: Vec<Vec<f64>> = (0..columns)
+        .map(|j| solve_spd(&a, &b.iter().map(|row| row[j]).collect::<Vec<f64>>()))
+        .collect::<Option<_>>()?;
+    // Back from columns to rows
+    Some(
+        (0..b.len())
+            .map(|i| solved.iter().map(|col| col[i]).collect())
+            .collect(),
+    )
+}
+
+fn multiply(a: &[Vec<f64>], b: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    a.iter()
+        .map(|row| {
+            (0..b[0].len())
+                .map(|j| row.iter().zip(b).map(|(x, r)| x * r[j]).sum())
+                .collect()
+        })
+        .collect()
+}
+
+fn transpose(a: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    (0..a[0].len())
+        .map(|j| a.iter().map(|row| row[j]).collect())
+        .collect()
+}
+
+// Sine and cosine of every harmonic of `frequency`
+pub(crate) fn references(frequency: f64, harmonics: u32, samples: usize, fs: f64) -> Vec<Vec<f64>> {
+    (1..=harmonics.max(1))
+        .flat_map(|h| {
+            let w = 2.0 * PI * frequency * h as f64 / fs;
+            [
+                (0..samples).map(|t| (w * t as f64).sin()).collect(),
+                (0..samples).map(|t| (w * t as f64).cos()).collect(),
+            ]
+        })
+        .collect()
+}
+
+// Largest canonical correlation between the rows of `x` and of `y`; None
+// when the covariances are singular or samples are too few
+pub(crate) fn canonical_correlation(x: &[Vec<f64>], y: &[Vec<f64>]) -> Option<f64> {
+    let samples = x.first()?.len();
+    if y.is_empty() || samples <= x.len() + y.len() {
+        return None;
+    }
+    let (x, y) = (centered(x), centered(y));
+    let cxy = covariance(&x, &y);
+    // Cxx^-1 Cxy Cyy^-1 Cyx has the squared canonical correlations as
+    // eigenvalues
+    let a = solve_columns(&covariance(&x, &x), &cxy)?;
+    let b = solve_columns(&covariance(&y, &y), &transpose(&cxy))?;
+    let m = multiply(&a, &b);
+    let mut v = vec![1.0; m.len()];
+    let mut eigenvalue = 0.0;
+    for _ in 0..POWER_ITERATIONS {
+        let next: Vec<f64> = m
+            .iter()
+            .map(|row| row.iter().zip(&v).map(|(a, b)| a * b).sum())
+            .collect();
+        let norm = next.iter().map(|x| x * x).sum::<f64>().sqrt();
+        if norm <= 0.0 || !norm.is_finite() {
+            return Some(0.0);
+        }
+        eigenvalue = norm / v.iter().map(|x| x * x).sum::<f64>().sqrt();
+        v = next.iter().map(|x| x / norm).collect();
+    }
+    Some(eigenvalue.clamp(0.0, 1.0).sqrt())
+}
+
+// Power at each harmonic over the mean of the bins within 1 Hz around it
+// (the peak bin and its direct neighbours left out), averaged
+pub(crate) fn snr_score(channels: &[&[f64]], frequency: f64, harmonics: u32, fs: f64) -> f64 {
+    let samples = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+    if samples < 2 {
+        return 0.0;
+    }
+    let nfft = spectral::nfft_for(samples);
+    let mut total = 0.0;
+    let mut count = 0;
+    for channel in channels {
+        let Some(psd) = spectral::welch(channel, fs, nfft) else {
+            continue;
+        };
+        let df = fs / nfft as f64;
+        for h in 1..=harmonics.max(1) {
+            let f = frequency * h as f64;
+            let peak = (f / df).round() as usize;
+            if peak + 2 >= psd.power.len() {
+                continue;
+            }
+            let reach = (SNR_NEIGHBOUR_HZ / df).ceil().max(2.0) as usize + 1;
+            let noise: Vec<f64> = (peak.saturating_sub(reach)
+                ..=(peak + reach).min(psd.power.len() - 1))
+                .filter(|&k| k.abs_diff(peak) > 1)
+                .map(|k| psd.power[k])
+                .collect();
+            let mean = noise.iter().sum::<f64>() / noise.len().max(1) as f64;
+            if mean > 0.0 {
+                total += psd.power[peak] / mean;
+                count += 1;
+            }
+        }
+    }
+    if count == 0 {
+        0.0
+    } else {
+        total / count as f64
+    }
+}
+
+// Scores of every target on one window; None when CCA is not usable on it
+pub(crate) fn score_window(
+    window: &[&[f64]],
+    method: SsvepMethod,
+    settings: &SsvepSettings,
+    fs: f64,
+) -> Option<Vec<f64>> {
+    let targets = settings.target_frequencies.iter();
+    match method {
+        SsvepMethod::Cca => {
+            let samples = window.iter().map(|c| c.len()).min().unwrap_or(0);
+            let x: Vec<Vec<f64>> = window.iter().map(|c| c[..samples].to_vec()).collect();
+            targets
+                .map(|&f| {
+                    canonical_correlation(&x, &references(f, settings.harmonics, samples, fs))
+                })
+                .collect()
+        }
+        SsvepMethod::PsdSnr => Some(
+            targets
+                .map(|&f| snr_score(window, f, settings.harmonics, fs))
+                .collect(),
+        ),
+    }
+}
+
+// Scores of every window with one method, CCA falling back to PSD-SNR for
+// all of them when it is not usable on any
+fn score_windows(
+    windows: &[Vec<&[f64]>],
+    settings: &SsvepSettings,
+    fs: f64,
+) -> (SsvepMethod, Vec<Vec<f64>>) {
+    let score_all = |method| -> Option<Vec<Vec<f64>>> {
+        windows
+            .iter()
+            .map(|w| score_window(w, method, settings, fs))
+            .collect()
+    };
+    if settings.method == SsvepMethod::Cca {
+        if let Some(scores) = score_all(SsvepMethod::Cca) {
+            return (SsvepMethod::Cca, scores);
+        }
+    }
+    let scores = score_all(SsvepMethod::PsdSnr).unwrap_or_default();
+    (SsvepMethod::PsdSnr, scores)
+}
+
+// Selects a target once it stays the candidate for the dwell time
+pub(crate) struct Dwell {
+    candidate: Option<(usize, f64)>,
+}
+
+impl Dwell {
+    pub fn new() -> Self {
+        Self { candidate: None }
+    }
+
+    pub fn update(&mut self, now: f64, candidate: Option<usize>, dwell: f64) -> Option<usize> {
+        let Some(target) = candidate else {
+            self.candidate = None;
+            return None;
+        };
+        match self.candidate {
+            Some((current, since)) if current == target => {
+                if now - since >= dwell {
+                    // Restart, a held gaze selects again after another dwell
+                    self.candidate = Some((target, now));
+                    return Some(target);
+                }
+                None
+            }
+            _ => {
+                self.candidate = Some((target, now));
+                None
+            }
+        }
+    }
+}
+
+pub(crate) struct SsvepDetector {
+    pub settings: SsvepSettings,
+    sampling_rate: f64,
+    buffers: ChannelBuffers,
+    dwell: Dwell,
+    latest: Option<SsvepScores>,
+    queued_scores: VecDeque<SsvepScores>,
+    selections: VecDeque<SsvepSelection>,
+}
+
+impl SsvepDetector {
+    pub fn new(settings: SsvepSettings, sampling_rate: f64, channel_names: &[String]) -> Self {
+        let (channels, _) = channel_buffers::select_channels(&settings.channels, channel_names);
+        let longest = settings
+            .window_lengths_seconds
+            .iter()
+            .copied()
+            .fold(0.0, f64::max);
+        Self {
+            settings,
+            sampling_rate,
+            buffers: ChannelBuffers::for_window(channels, longest, sampling_rate),
+            dwell: Dwell::new(),
+            latest: None,
+            queued_scores: VecDeque::new(),
+            selections: VecDeque::new(),
+        }
+    }
+
+    fn samples(&self, seconds: f64) -> usize {
+        (seconds * self.sampling_rate) as usize
+    }
+
+    pub fn push(&mut self, channel: usize, samples: &[f64], timestamp: f64) {
+        if self.buffers.push(channel, samples).is_none() {
+            return;
+        }
+        let shortest = self
+            .settings
+            .window_lengths_seconds
+            .iter()
+            .copied()
+            .fold(f64::INFINITY, f64::min);
+        let step = self.samples(self.settings.step_seconds);
+        if self.buffers.step_due(self.samples(shortest), step) {
+            self.evaluate(timestamp);
+        }
+    }
+
+    fn evaluate(&mut self, timestamp: f64) {
+        let mut lengths = Vec::new();
+        let mut windows = Vec::new();
+        for &seconds in &self.settings.window_lengths_seconds {
+            let n = self.samples(seconds);
+            let Some(window) = self.buffers.latest_windows(n).filter(|_| n > 0) else {
+                continue;
+            };
+            lengths.push(seconds);
+            windows.push(window);
+        }
+        if windows.is_empty() {
+            return;
+        }
+        let (method, window_scores) = score_windows(&windows, &self.settings, self.sampling_rate);
+        let threshold = match method {
+            SsvepMethod::Cca => self.settings.threshold,
+            SsvepMethod::PsdSnr => self.settings.snr_threshold,
+        };
+        let targets = self.settings.target_frequencies.len();
+        let scores: Vec<f64> = (0..targets)
+            .map(|t| window_scores.iter().map(|s| s[t]).sum::<f64>() / window_scores.len() as f64)
+            .collect();
+        let best = (0..targets).max_by(|&a, &b| scores[a].total_cmp(&scores[b]));
+        let candidate = best.filter(|&t| scores[t] >= threshold);
+        if let Some(target) = self
+            .dwell
+            .update(timestamp, candidate, self.settings.dwell_seconds)
+        {
+            if self.selections.len() == MAX_QUEUED {
+                self.selections.pop_front();
+            }
+            self.selections.push_back(SsvepSelection {
+                timestamp,
+                target: target as u32,
+                frequency: self.settings.target_frequencies[target],
+                score: scores[target],
+            });
+        }
+        let update = SsvepScores {
+            timestamp,
+            method,
+            window_lengths_seconds: lengths,
+            window_scores,
+            scores,
+            candidate: candidate.map(|t| t as u32),
+        };
+        if self.queued_scores.len() == MAX_QUEUED {
+            self.queued_scores.pop_front();
+        }
+        self.queued_scores.push_back(update.clone());
+        self.latest = Some(update);
+    }
+
+    pub fn latest(&self) -> Option<&SsvepScores> {
+        self.latest.as_ref()
+    }
+
+    pub fn take_scores(&mut self) -> Vec<SsvepScores> {
+        self.queued_scores.drain(..).collect()
+    }
+
+    pub fn take_selections(&mut self) -> Vec<SsvepSelection> {
+        self.selections.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    #[test]
+    fn finds_flicker_frequency_and_selects_after_dwell() {
+        let fs = 256.0;
+        let mut noise = test_util::noise(11);
+        // 12 Hz response with its second harmonic, phase shifted per channel
+        let channels: Vec<Vec<f64>> = (0..2)
+            .map(|c| {
+                (0..512)
+                    .map(|t| {
+                        let t = t as f64 / fs;
+                        3.0 * (2.0 * PI * 12.0 * t + c as f64).sin()
+                            + (2.0 * PI * 24.0 * t).cos()
+                            + 20.0 * noise()
+                    })
+                    .collect()
+            })
+            .collect();
+        let window: Vec<&[f64]> = channels.iter().map(Vec::as_slice).collect();
+
+        let mut settings = SsvepSettings::default();
+        let cca = score_window(&window, SsvepMethod::Cca, &settings, fs).unwrap();
+        assert!(cca[2] > 0.3, "{:?}", cca);
+        assert!(cca.iter().enumerate().all(|(i, s)| i == 2 || *s < cca[2]));
+
+        let snr = score_window(&window, SsvepMethod::PsdSnr, &settings, fs).unwrap();
+        assert!(
+            snr.iter().enumerate().all(|(i, s)| i == 2 || *s < snr[2]),
+            "{:?}",
+            snr
+        );
+
+        // Too few samples for CCA with four harmonics in one window scores
+        // every window by PSD-SNR
+        settings.harmonics = 4;
+        let short: Vec<&[f64]> = window.iter().map(|c| &c[..8]).collect();
+        assert!(score_window(&short, SsvepMethod::Cca, &settings, fs).is_none());
+        let (method, scores) = score_windows(&[short, window.clone()], &settings, fs);
+        assert_eq!(method, SsvepMethod::PsdSnr);
+        assert_eq!(
+            scores[1],
+            score_window(&window, SsvepMethod::PsdSnr, &settings, fs).unwrap()
+        );
+
+        let mut dwell = Dwell::new();
+        assert_eq!(dwell.update(0.0, Some(2), 1.0), None);
+        assert_eq!(dwell.update(0.5, Some(1), 1.0), None);
+        assert_eq!(dwell.update(1.0, Some(1), 1.0), None);
+        assert_eq!(dwell.update(1.5, Some(1), 1.0), Some(1));
+        assert_eq!(dwell.update(2.0, None, 1.0), None);
+        assert_eq!(dwell.update(2.5, Some(1), 1.0), None);
+    }
+}